@@ -15,31 +15,23 @@ void sha3_256(void* out, const void* in, size_t len) {
 	copy_bytes(out, tmp_out, 32);
 }
 
-void heavyhash(const uint16 matrix[64][64], void* pdata, size_t pdata_len,
-	       void* output) {
-	byte hash_first[32] __attribute__((aligned(32)));
-	byte hash_second[32] __attribute__((aligned(32)));
-	byte hash_xored[32] __attribute__((aligned(32)));
-
-	uint16 vector[64] __attribute__((aligned(64)));
-	uint16 product[64] __attribute__((aligned(64)));
-
+// the steps of heavyhash around the matrix-vector product
+static void heavyhash_vector(const void* pdata, size_t pdata_len,
+			     byte hash_first[32], uint16 vector[64]) {
 	// note: slight change from original (remove first length param). For
 	// compatibility with sha3 interface.
-	sha3_256((byte*)hash_first, (const byte*)pdata, pdata_len);
+	sha3_256(hash_first, (const byte*)pdata, pdata_len);
 
 	for (int i = 0; i < 32; ++i) {
 		vector[2 * i] = (hash_first[i] >> 4);
 		vector[2 * i + 1] = hash_first[i] & 0xF;
 	}
+}
 
-	for (int i = 0; i < 64; ++i) {
-		uint16 sum = 0;
-		for (int j = 0; j < 64; ++j) {
-			sum += matrix[i][j] * vector[j];
-		}
-		product[i] = (sum >> 10);
-	}
+static void heavyhash_finish(const byte hash_first[32],
+			     const uint16 product[64], void* output) {
+	byte hash_second[32] __attribute__((aligned(32)));
+	byte hash_xored[32] __attribute__((aligned(32)));
 
 	for (int i = 0; i < 32; ++i) {
 		hash_second[i] = (product[2 * i] << 4) | (product[2 * i + 1]);
@@ -53,3 +45,90 @@ void heavyhash(const uint16 matrix[64][64], void* pdata, size_t pdata_len,
 	// compatibility with sha3 interface.
 	sha3_256((byte*)output, (const byte*)hash_xored, 32);
 }
+
+// The reference implementation. Kept as is, heavyhash_columns must give
+// exactly the same results.
+void heavyhash(const uint16 matrix[64][64], void* pdata, size_t pdata_len,
+	       void* output) {
+	byte hash_first[32] __attribute__((aligned(32)));
+	uint16 vector[64] __attribute__((aligned(64)));
+	uint16 product[64] __attribute__((aligned(64)));
+
+	heavyhash_vector(pdata, pdata_len, hash_first, vector);
+
+	for (int i = 0; i < 64; ++i) {
+		uint16 sum = 0;
+		for (int j = 0; j < 64; ++j) {
+			sum += matrix[i][j] * vector[j];
+		}
+		product[i] = (sum >> 10);
+	}
+
+	heavyhash_finish(hash_first, product, output);
+}
+
+// columns[j][i] = matrix[i][j], the layout heavyhash_columns expects
+void transpose_matrix(const uint16 matrix[64][64], uint16 columns[64][64]) {
+	for (int i = 0; i < 64; ++i) {
+		for (int j = 0; j < 64; ++j) {
+			columns[j][i] = matrix[i][j];
+		}
+	}
+}
+
+#if defined(__GNUC__) || defined(__clang__)
+// vector extensions compile to whatever SIMD the target has (SSE2/AVX2 on
+// x86_64, NEON on aarch64) and to scalar code otherwise
+typedef uint16 v8u16 __attribute__((vector_size(16)));
+
+// product = sum of columns[j] * vector[j]. Matrix and vector entries are 4
+// bit values so no sum exceeds 64 * 15 * 15 and uint16 lanes can't wrap
+// differently from the reference.
+static void matmul_columns(const uint16 columns[64][64],
+			   const uint16 vector[64], uint16 product[64]) {
+	v8u16 acc[8] = {{0}};
+	for (int j = 0; j < 64; ++j) {
+		v8u16 col[8];
+		__builtin_memcpy(col, columns[j], sizeof(col));
+		uint16 s = vector[j];
+		acc[0] += col[0] * s;
+		acc[1] += col[1] * s;
+		acc[2] += col[2] * s;
+		acc[3] += col[3] * s;
+		acc[4] += col[4] * s;
+		acc[5] += col[5] * s;
+		acc[6] += col[6] * s;
+		acc[7] += col[7] * s;
+	}
+	for (int k = 0; k < 8; ++k) {
+		acc[k] >>= 10;
+	}
+	__builtin_memcpy(product, acc, sizeof(acc));
+}
+#else
+static void matmul_columns(const uint16 columns[64][64],
+			   const uint16 vector[64], uint16 product[64]) {
+	uint16 acc[64];
+	set_bytes((byte*)acc, 0, sizeof(acc));
+	for (int j = 0; j < 64; ++j) {
+		for (int i = 0; i < 64; ++i) {
+			acc[i] += columns[j][i] * vector[j];
+		}
+	}
+	for (int i = 0; i < 64; ++i) {
+		product[i] = acc[i] >> 10;
+	}
+}
+#endif
+
+// heavyhash with the matrix given as columns (see transpose_matrix)
+void heavyhash_columns(const uint16 columns[64][64], void* pdata,
+		       size_t pdata_len, void* output) {
+	byte hash_first[32] __attribute__((aligned(32)));
+	uint16 vector[64] __attribute__((aligned(64)));
+	uint16 product[64] __attribute__((aligned(64)));
+
+	heavyhash_vector(pdata, pdata_len, hash_first, vector);
+	matmul_columns(columns, vector, product);
+	heavyhash_finish(hash_first, product, output);
+}
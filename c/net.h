@@ -16,6 +16,9 @@
 #define ERROR_MULTIPLEX_INIT -9
 #define ERROR_GETSOCKNAME -10
 #define ERROR_EAGAIN -11
+#define ERROR_TLS -12
+#define ERROR_TLS_HANDSHAKE -13
+#define ERROR_TLS_CERTIFICATE -14
 
 typedef struct Socket Socket;
 typedef struct Multiplex Multiplex;
@@ -16,7 +16,7 @@ typedef struct {
 
 #define ROTLEFT(a, b) ((a << b) | (a >> (32 - b)))
 
-void sha1_transform(SHA1_CTX *ctx, const unsigned char data[]) {
+static void sha1_transform(SHA1_CTX *ctx, const unsigned char data[]) {
 	unsigned int a, b, c, d, e, i, j, t, m[80];
 
 	for (i = 0, j = 0; i < 16; ++i, j += 4)
@@ -74,7 +74,7 @@ void sha1_transform(SHA1_CTX *ctx, const unsigned char data[]) {
 	ctx->state[4] += e;
 }
 
-void SHA1_Init(SHA1_CTX *ctx) {
+static void SHA1_Init(SHA1_CTX *ctx) {
 	ctx->datalen = 0;
 	ctx->bitlen = 0;
 	ctx->state[0] = 0x67452301;
@@ -88,7 +88,8 @@ void SHA1_Init(SHA1_CTX *ctx) {
 	ctx->k[3] = 0xca62c1d6;
 }
 
-void SHA1_Update(SHA1_CTX *ctx, const unsigned char data[], size_t len) {
+static void SHA1_Update(SHA1_CTX *ctx, const unsigned char data[],
+			size_t len) {
 	size_t i;
 
 	for (i = 0; i < len; ++i) {
@@ -102,7 +103,7 @@ void SHA1_Update(SHA1_CTX *ctx, const unsigned char data[], size_t len) {
 	}
 }
 
-void SHA1_Final(SHA1_CTX *ctx, unsigned char hash[]) {
+static void SHA1_Final(SHA1_CTX *ctx, unsigned char hash[]) {
 	unsigned int i;
 
 	i = ctx->datalen;
@@ -0,0 +1,219 @@
+#include "tls.h"
+
+#include <errno.h>
+#include <openssl/err.h>
+#include <openssl/pem.h>
+#include <openssl/ssl.h>
+#include <openssl/x509v3.h>
+
+#include "nettypes.h"
+
+#ifndef NULL
+#define NULL ((void *)0)
+#endif
+
+static SSL_CTX *tls_context_new(const SSL_METHOD *method) {
+	SSL_CTX *ctx = SSL_CTX_new(method);
+	if (ctx == NULL) return NULL;
+	SSL_CTX_set_min_proto_version(ctx, TLS1_2_VERSION);
+	SSL_CTX_set_mode(ctx, SSL_MODE_ENABLE_PARTIAL_WRITE |
+				  SSL_MODE_ACCEPT_MOVING_WRITE_BUFFER);
+	return ctx;
+}
+
+int tls_server_context(TlsContext **ctx, const char *cert_file,
+		       const char *key_file) {
+	SSL_CTX *sctx = tls_context_new(TLS_server_method());
+	if (sctx == NULL) return ERROR_TLS;
+
+	if (SSL_CTX_use_certificate_chain_file(sctx, cert_file) != 1 ||
+	    SSL_CTX_use_PrivateKey_file(sctx, key_file, SSL_FILETYPE_PEM) !=
+		1 ||
+	    SSL_CTX_check_private_key(sctx) != 1) {
+		ERR_clear_error();
+		SSL_CTX_free(sctx);
+		return ERROR_TLS_CERTIFICATE;
+	}
+
+	*ctx = (TlsContext *)sctx;
+	return 0;
+}
+
+int tls_server_context_pem(TlsContext **ctx, const unsigned char *cert,
+			   unsigned long long cert_len,
+			   const unsigned char *key,
+			   unsigned long long key_len) {
+	SSL_CTX *sctx = tls_context_new(TLS_server_method());
+	if (sctx == NULL) return ERROR_TLS;
+
+	BIO *cbio = BIO_new_mem_buf(cert, (int)cert_len);
+	BIO *kbio = BIO_new_mem_buf(key, (int)key_len);
+	X509 *x509 = cbio ? PEM_read_bio_X509(cbio, NULL, NULL, NULL) : NULL;
+	EVP_PKEY *pkey =
+	    kbio ? PEM_read_bio_PrivateKey(kbio, NULL, NULL, NULL) : NULL;
+
+	int ret = 0;
+	if (x509 == NULL || pkey == NULL ||
+	    SSL_CTX_use_certificate(sctx, x509) != 1 ||
+	    SSL_CTX_use_PrivateKey(sctx, pkey) != 1 ||
+	    SSL_CTX_check_private_key(sctx) != 1) {
+		ret = ERROR_TLS_CERTIFICATE;
+	}
+
+	// any remaining certificates in the buffer are the chain
+	while (ret == 0 && cbio) {
+		X509 *chain = PEM_read_bio_X509(cbio, NULL, NULL, NULL);
+		if (chain == NULL) break;
+		if (SSL_CTX_add0_chain_cert(sctx, chain) != 1) {
+			X509_free(chain);
+			ret = ERROR_TLS_CERTIFICATE;
+		}
+	}
+
+	ERR_clear_error();
+	if (x509) X509_free(x509);
+	if (pkey) EVP_PKEY_free(pkey);
+	if (cbio) BIO_free(cbio);
+	if (kbio) BIO_free(kbio);
+
+	if (ret) {
+		SSL_CTX_free(sctx);
+		return ret;
+	}
+
+	*ctx = (TlsContext *)sctx;
+	return 0;
+}
+
+int tls_client_context(TlsContext **ctx, const char *ca_file) {
+	SSL_CTX *sctx = tls_context_new(TLS_client_method());
+	if (sctx == NULL) return ERROR_TLS;
+
+	int res;
+	if (ca_file == NULL)
+		res = SSL_CTX_set_default_verify_paths(sctx);
+	else
+		res = SSL_CTX_load_verify_locations(sctx, ca_file, NULL);
+
+	if (res != 1) {
+		ERR_clear_error();
+		SSL_CTX_free(sctx);
+		return ERROR_TLS_CERTIFICATE;
+	}
+
+	SSL_CTX_set_verify(sctx, SSL_VERIFY_PEER, NULL);
+	*ctx = (TlsContext *)sctx;
+	return 0;
+}
+
+int tls_client_context_pem(TlsContext **ctx, const unsigned char *ca,
+			   unsigned long long ca_len) {
+	SSL_CTX *sctx = tls_context_new(TLS_client_method());
+	if (sctx == NULL) return ERROR_TLS;
+
+	X509_STORE *store = SSL_CTX_get_cert_store(sctx);
+	BIO *bio = BIO_new_mem_buf(ca, (int)ca_len);
+	int count = 0;
+	while (bio) {
+		X509 *x509 = PEM_read_bio_X509(bio, NULL, NULL, NULL);
+		if (x509 == NULL) break;
+		if (X509_STORE_add_cert(store, x509) == 1) count++;
+		X509_free(x509);
+	}
+
+	ERR_clear_error();
+	if (bio) BIO_free(bio);
+
+	if (count == 0) {
+		SSL_CTX_free(sctx);
+		return ERROR_TLS_CERTIFICATE;
+	}
+
+	SSL_CTX_set_verify(sctx, SSL_VERIFY_PEER, NULL);
+	*ctx = (TlsContext *)sctx;
+	return 0;
+}
+
+void tls_context_free(TlsContext *ctx) { SSL_CTX_free((SSL_CTX *)ctx); }
+
+int tls_session(TlsContext *ctx, TlsSession **session, Socket *s,
+		int is_server, const char *host) {
+	SSL *ssl = SSL_new((SSL_CTX *)ctx);
+	if (ssl == NULL) return ERROR_TLS;
+
+	if (SSL_set_fd(ssl, s->fd) != 1) {
+		SSL_free(ssl);
+		return ERROR_TLS;
+	}
+
+	if (is_server) {
+		SSL_set_accept_state(ssl);
+	} else {
+		if (host != NULL) {
+			X509_VERIFY_PARAM *param = SSL_get0_param(ssl);
+			// an ip address is matched against the certificate's
+			// ip SANs, anything else is treated as a dns name
+			if (X509_VERIFY_PARAM_set1_ip_asc(param, host) != 1) {
+				ERR_clear_error();
+				if (SSL_set_tlsext_host_name(ssl, host) != 1 ||
+				    SSL_set1_host(ssl, host) != 1) {
+					ERR_clear_error();
+					SSL_free(ssl);
+					return ERROR_TLS;
+				}
+			}
+		}
+		SSL_set_connect_state(ssl);
+	}
+
+	*session = (TlsSession *)ssl;
+	return 0;
+}
+
+static long long tls_error(SSL *ssl, int ret) {
+	int err = SSL_get_error(ssl, ret);
+	ERR_clear_error();
+	if (err == SSL_ERROR_WANT_READ || err == SSL_ERROR_WANT_WRITE)
+		return ERROR_EAGAIN;
+	if (err == SSL_ERROR_ZERO_RETURN) return 0;
+	if (err == SSL_ERROR_SYSCALL && errno == EAGAIN) return ERROR_EAGAIN;
+	return ERROR_SOCKET;
+}
+
+int tls_handshake(TlsSession *session) {
+	SSL *ssl = (SSL *)session;
+	int ret = SSL_do_handshake(ssl);
+	if (ret == 1) return 0;
+	if (tls_error(ssl, ret) == ERROR_EAGAIN) return ERROR_EAGAIN;
+	return ERROR_TLS_HANDSHAKE;
+}
+
+long long tls_recv(TlsSession *session, void *buf,
+		   unsigned long long capacity) {
+	SSL *ssl = (SSL *)session;
+	if (capacity > 0x7FFFFFFF) capacity = 0x7FFFFFFF;
+	int ret = SSL_read(ssl, buf, (int)capacity);
+	if (ret > 0) return ret;
+	return tls_error(ssl, ret);
+}
+
+long long tls_send(TlsSession *session, const void *buf,
+		   unsigned long long len) {
+	SSL *ssl = (SSL *)session;
+	if (len == 0) return 0;
+	if (len > 0x7FFFFFFF) len = 0x7FFFFFFF;
+	int ret = SSL_write(ssl, buf, (int)len);
+	if (ret > 0) return ret;
+	ret = tls_error(ssl, ret);
+	// a zero return on write means the peer closed the session
+	return ret == 0 ? ERROR_SOCKET : ret;
+}
+
+int tls_shutdown(TlsSession *session) {
+	SSL *ssl = (SSL *)session;
+	int ret = SSL_shutdown(ssl);
+	ERR_clear_error();
+	return ret < 0 ? ERROR_TLS : 0;
+}
+
+void tls_session_free(TlsSession *session) { SSL_free((SSL *)session); }
@@ -0,0 +1,30 @@
+#ifndef _TLS_H__
+#define _TLS_H__
+
+#include "net.h"
+
+typedef struct TlsContext TlsContext;
+typedef struct TlsSession TlsSession;
+
+int tls_server_context(TlsContext **ctx, const char *cert_file,
+		       const char *key_file);
+int tls_server_context_pem(TlsContext **ctx, const unsigned char *cert,
+			   unsigned long long cert_len,
+			   const unsigned char *key,
+			   unsigned long long key_len);
+int tls_client_context(TlsContext **ctx, const char *ca_file);
+int tls_client_context_pem(TlsContext **ctx, const unsigned char *ca,
+			   unsigned long long ca_len);
+void tls_context_free(TlsContext *ctx);
+
+int tls_session(TlsContext *ctx, TlsSession **session, Socket *s,
+		int is_server, const char *host);
+int tls_handshake(TlsSession *session);
+long long tls_recv(TlsSession *session, void *buf,
+		   unsigned long long capacity);
+long long tls_send(TlsSession *session, const void *buf,
+		   unsigned long long len);
+int tls_shutdown(TlsSession *session);
+void tls_session_free(TlsSession *session);
+
+#endif	// _TLS_H__
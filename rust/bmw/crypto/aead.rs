@@ -0,0 +1,170 @@
+use core::ptr::write_bytes;
+use core::sync::atomic::compiler_fence;
+use core::sync::atomic::Ordering::SeqCst;
+use crypto::aes::Aes256;
+use crypto::constants::AEAD_TAG_SIZE;
+use crypto::errors::*;
+use crypto::hkdf::hkdf_expand;
+use crypto::hmac::HmacSha3_256;
+use misc::{to_be_bytes_u64, to_le_bytes_u64};
+use prelude::*;
+
+// Authenticated encryption: AES-256-CTR, then HMAC-SHA3-256 over the
+// associated data, ciphertext and nonce (encrypt-then-mac). Independent
+// encryption and mac keys are derived from the supplied key.
+//
+// A nonce must never be reused with the same key.
+pub struct Aead {
+	aes: Aes256,
+	mac_key: [u8; 32],
+}
+
+impl Drop for Aead {
+	fn drop(&mut self) {
+		unsafe {
+			write_bytes(self.mac_key.as_mut_ptr(), 0, 32);
+			compiler_fence(SeqCst);
+		}
+	}
+}
+
+impl Aead {
+	pub const TAG_SIZE: usize = AEAD_TAG_SIZE;
+
+	pub fn new(key: &[u8; 32]) -> Result<Self> {
+		let mut enc_key = [0u8; 32];
+		let mut mac_key = [0u8; 32];
+		hkdf_expand(key, b"bmw aead enc", &mut enc_key)?;
+		hkdf_expand(key, b"bmw aead mac", &mut mac_key)?;
+		let aes = Aes256::new(enc_key, [0u8; 16]);
+		unsafe {
+			write_bytes(enc_key.as_mut_ptr(), 0, 32);
+			compiler_fence(SeqCst);
+		}
+		Ok(Self { aes, mac_key })
+	}
+
+	// returns the ciphertext followed by the tag
+	pub fn seal(&self, nonce: u64, aad: &[u8], msg: &[u8]) -> Result<Vec<u8>> {
+		let mut ret = Vec::with_capacity(msg.len() + AEAD_TAG_SIZE)?;
+		ret.extend_from_slice(msg)?;
+		self.aes.set_iv(Self::iv(nonce));
+		self.aes.crypt(&mut ret);
+		let tag = self.tag(nonce, aad, &ret)?;
+		ret.extend_from_slice(&tag[0..AEAD_TAG_SIZE])?;
+		Ok(ret)
+	}
+
+	pub fn open(&self, nonce: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>> {
+		if sealed.len() < AEAD_TAG_SIZE {
+			return err!(ValidationFailed);
+		}
+		let ct_len = sealed.len() - AEAD_TAG_SIZE;
+		let tag = self.tag(nonce, aad, &sealed[0..ct_len])?;
+
+		// constant time comparison
+		let mut diff = 0u8;
+		for i in 0..AEAD_TAG_SIZE {
+			diff |= tag[i] ^ sealed[ct_len + i];
+		}
+		if diff != 0 {
+			return err!(ValidationFailed);
+		}
+
+		let mut ret = Vec::with_capacity(ct_len)?;
+		ret.extend_from_slice(&sealed[0..ct_len])?;
+		self.aes.set_iv(Self::iv(nonce));
+		self.aes.crypt(&mut ret);
+		Ok(ret)
+	}
+
+	// the nonce occupies the high 8 bytes, the block counter the low 8 bytes
+	fn iv(nonce: u64) -> [u8; 16] {
+		let mut iv = [0u8; 16];
+		to_be_bytes_u64(nonce, &mut iv[0..8]);
+		iv
+	}
+
+	fn tag(&self, nonce: u64, aad: &[u8], ct: &[u8]) -> Result<[u8; 32]> {
+		let mut buf = [0u8; 8];
+		let hmac = HmacSha3_256::new(&self.mac_key);
+		hmac.update(aad);
+		hmac.update(ct);
+		to_le_bytes_u64(aad.len() as u64, &mut buf)?;
+		hmac.update(&buf);
+		to_le_bytes_u64(ct.len() as u64, &mut buf)?;
+		hmac.update(&buf);
+		to_be_bytes_u64(nonce, &mut buf);
+		hmac.update(&buf);
+		Ok(hmac.finalize())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_aead_roundtrip() -> Result<()> {
+		let aead = Aead::new(&[7u8; 32])?;
+		let sealed = aead.seal(0, b"header", b"attack at dawn")?;
+		assert_eq!(sealed.len(), 14 + Aead::TAG_SIZE);
+		assert_ne!(&sealed[0..14], b"attack at dawn");
+		let opened = aead.open(0, b"header", &sealed)?;
+		assert_eq!(&opened[..], b"attack at dawn");
+
+		// a second instance with the same key interoperates
+		let aead2 = Aead::new(&[7u8; 32])?;
+		assert_eq!(&aead2.open(0, b"header", &sealed)?[..], b"attack at dawn");
+
+		// the same plaintext under a different nonce gives a different ciphertext
+		let sealed2 = aead.seal(1, b"header", b"attack at dawn")?;
+		assert_ne!(&sealed[0..14], &sealed2[0..14]);
+
+		// empty messages still carry a tag
+		let empty = aead.seal(2, b"", b"")?;
+		assert_eq!(empty.len(), Aead::TAG_SIZE);
+		assert_eq!(aead.open(2, b"", &empty)?.len(), 0);
+
+		// long messages span many counter blocks
+		let mut long = [0u8; 1000];
+		for i in 0..long.len() {
+			long[i] = i as u8;
+		}
+		let sealed = aead.seal(u64::MAX, b"", &long)?;
+		assert_eq!(&aead.open(u64::MAX, b"", &sealed)?[..], &long[..]);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_aead_tamper() -> Result<()> {
+		let aead = Aead::new(&[1u8; 32])?;
+		let mut sealed = aead.seal(5, b"aad", b"some message")?;
+
+		assert_eq!(aead.open(6, b"aad", &sealed).err(), Some(ValidationFailed));
+		assert_eq!(aead.open(5, b"aaa", &sealed).err(), Some(ValidationFailed));
+		assert_eq!(
+			Aead::new(&[2u8; 32])?.open(5, b"aad", &sealed).err(),
+			Some(ValidationFailed)
+		);
+		assert_eq!(
+			aead.open(5, b"aad", &sealed[0..Aead::TAG_SIZE - 1]).err(),
+			Some(ValidationFailed)
+		);
+
+		// flip a ciphertext bit
+		sealed[0] ^= 1;
+		assert_eq!(aead.open(5, b"aad", &sealed).err(), Some(ValidationFailed));
+		sealed[0] ^= 1;
+
+		// flip a tag bit
+		let last = sealed.len() - 1;
+		sealed[last] ^= 0x80;
+		assert_eq!(aead.open(5, b"aad", &sealed).err(), Some(ValidationFailed));
+		sealed[last] ^= 0x80;
+
+		assert_eq!(&aead.open(5, b"aad", &sealed)?[..], b"some message");
+		Ok(())
+	}
+}
@@ -1,9 +1,11 @@
 use crypto::aes::Aes256;
-use crypto::ffi::{generate_matrix, heavyhash};
+use crypto::ffi::{generate_matrix, heavyhash, heavyhash_columns, transpose_matrix};
 use prelude::*;
 
 pub struct Bip52 {
 	matrix: [u16; 4096],
+	// the matrix stored column by column, used by the SIMD matrix-vector product
+	columns: [u16; 4096],
 	aes: Aes256,
 }
 
@@ -12,7 +14,12 @@ impl Bip52 {
 		// init with 0s for iv, we update in ret.reset below
 		let aes = Aes256::new(key, [0u8; 16]);
 		let matrix = [0u16; 4096];
-		let mut ret = Self { matrix, aes };
+		let columns = [0u16; 4096];
+		let mut ret = Self {
+			matrix,
+			columns,
+			aes,
+		};
 		ret.reset(prev_hash);
 		ret
 	}
@@ -28,11 +35,21 @@ impl Bip52 {
 		self.aes.set_iv(iv);
 		unsafe {
 			generate_matrix(self.matrix.as_mut_ptr(), self.aes.as_ptr());
+			transpose_matrix(self.matrix.as_ptr(), self.columns.as_mut_ptr());
 		}
 	}
 
 	#[inline]
 	pub fn hash(&self, b: &[u8]) -> [u8; 32] {
+		let mut ret = [0u8; 32];
+		unsafe {
+			heavyhash_columns(self.columns.as_ptr(), b.as_ptr(), b.len(), ret.as_mut_ptr());
+		}
+		ret
+	}
+
+	// the original scalar implementation. hash must always return the same value.
+	pub fn hash_reference(&self, b: &[u8]) -> [u8; 32] {
 		let mut ret = [0u8; 32];
 		unsafe {
 			heavyhash(self.matrix.as_ptr(), b.as_ptr(), b.len(), ret.as_mut_ptr());
@@ -47,6 +64,7 @@ mod test {
 	use crypto::aes::Aes256;
 	use crypto::cpsrng::Cpsrng;
 	use crypto::ffi::{generate_matrix, heavyhash};
+	use misc::micros;
 
 	#[test]
 	fn test_bip52_struct() -> Result<()> {
@@ -94,4 +112,72 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_bip52_known_answer() -> Result<()> {
+		let bip52 = Bip52::new([9u8; 32], [0u8; 32]);
+		let expected = [
+			103, 171, 108, 152, 198, 211, 80, 225, 118, 88, 245, 164, 151, 205, 69, 24, 38, 103,
+			132, 14, 63, 136, 150, 244, 242, 227, 52, 19, 203, 25, 116, 99,
+		];
+		assert_eq!(bip52.hash("hello".as_bytes()), expected);
+		assert_eq!(bip52.hash_reference("hello".as_bytes()), expected);
+		Ok(())
+	}
+
+	#[test]
+	fn test_bip52_reference() -> Result<()> {
+		let rng = Cpsrng::new()?;
+		let mut bip52 = Bip52::new([0u8; 32], [0u8; 32]);
+		for i in 0..64 {
+			let mut key = [0u8; 32];
+			let mut prev_hash = [0u8; 32];
+			rng.gen(&mut key);
+			rng.gen(&mut prev_hash);
+			if i % 8 == 0 {
+				bip52 = Bip52::new(key, prev_hash);
+			} else {
+				bip52.reset(prev_hash);
+			}
+			for len in [0, 1, 32, 114, 1024] {
+				let mut data = [0u8; 1024];
+				rng.gen(&mut data);
+				let data = &data[0..len];
+				assert_eq!(bip52.hash(data), bip52.hash_reference(data));
+			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_bip52_bench() -> Result<()> {
+		let bip52 = Bip52::new([1u8; 32], [2u8; 32]);
+		let mut data = [3u8; 1024];
+		let iterations = 10_000u32;
+
+		let start = micros();
+		let mut fast = [0u8; 32];
+		for i in 0..iterations {
+			data[0] = i as u8;
+			data[1] = (i >> 8) as u8;
+			fast = bip52.hash(&data);
+		}
+		let fast_micros = micros() - start;
+
+		let start = micros();
+		let mut reference = [0u8; 32];
+		for i in 0..iterations {
+			data[0] = i as u8;
+			data[1] = (i >> 8) as u8;
+			reference = bip52.hash_reference(&data);
+		}
+		let reference_micros = micros() - start;
+
+		assert_eq!(fast, reference);
+		println!(
+			"heavyhash x {}: fast={}us, reference={}us",
+			iterations, fast_micros, reference_micros
+		);
+		Ok(())
+	}
 }
@@ -15,6 +15,10 @@ pub const SECP256K1_EC_COMPRESSED: u32 =
 
 pub const AES_256_CONTEXT_SIZE: usize = 256;
 pub const SHA3_256_CONTEXT_SIZE: usize = 224;
+// keccak rate in bytes, used as the hmac block size
+pub const SHA3_256_RATE: usize = 136;
+pub const AEAD_TAG_SIZE: usize = 16;
+pub const HKDF_MAX_OUTPUT: usize = 255 * 32;
 
 pub const SECP256K1_START_VERIFY: u32 = (1 << 0) | (1 << 8);
 pub const SECP256K1_START_SIGN: u32 = (1 << 0) | (1 << 9);
@@ -18,7 +18,7 @@ use crypto::ffi::{
 };
 use crypto::keys::{PublicKey, PublicKeyUncompressed, SecretKey};
 use crypto::pedersen::{Commitment, CommitmentUncompressed};
-use crypto::range_proof::RangeProof;
+use crypto::range_proof::{MultiPartyProof, RangeProof};
 use crypto::signature::{Message, Signature};
 use crypto::types::{BulletproofGenerators, Secp256k1Context};
 use ffi::{alloc, release};
@@ -244,6 +244,103 @@ impl Ctx {
 		Ok(sig)
 	}
 
+	// Adaptor signatures. A pre-signature is made with the nonce sum
+	// `pubnonce` shifted by an adaptor point T = tG. It verifies as a partial
+	// signature but the aggregate only becomes valid once t is added, see
+	// adapt_signature, and anyone holding the pre-signatures and the final
+	// signature learns t, see extract_adaptor_secret.
+	pub fn adaptor_sign(
+		&self,
+		msg: &Message,
+		seckey: &SecretKey,
+		secnonce: &SecretKey,
+		pubnonce: &PublicKey,
+		pe: &PublicKey,
+		adaptor: &PublicKey,
+	) -> Result<Signature> {
+		let pubnonce = pubnonce.combine(self, adaptor)?;
+		self.sign(msg, seckey, secnonce, &pubnonce, pe)
+	}
+
+	pub fn verify_adaptor(
+		&self,
+		presig: &Signature,
+		msg: &Message,
+		pubnonce: &PublicKey,
+		pubkey: &PublicKey,
+		pe: &PublicKey,
+		adaptor: &PublicKey,
+	) -> Result<()> {
+		let pubnonce = pubnonce.combine(self, adaptor)?;
+		self.verify(presig, msg, &pubnonce, pubkey, pe, true)
+	}
+
+	// aggregates the pre-signatures of every signer and the adaptor secret
+	// into a signature for `pe`
+	pub fn adapt_signature(
+		&self,
+		presigs: &[&Signature],
+		secret: &SecretKey,
+		msg: &Message,
+		pubnonce: &PublicKey,
+		pe: &PublicKey,
+	) -> Result<Signature> {
+		let pubnonce = pubnonce.combine(self, &PublicKey::from(self, secret)?)?;
+		// the signers negated their nonces if the shifted nonce sum has no
+		// quadratic residue y, t has to follow but only the result tells
+		let mut secret = secret.clone();
+		for _ in 0..2 {
+			let mut bytes = [0u8; 64];
+			bytes.subslice_mut(32, 32)?.slice_copy(secret.as_ref())?;
+			let secret_sig = Signature::from_bytes(&bytes)?;
+			let mut sigs = Vec::with_capacity(presigs.len() + 1)?;
+			for presig in presigs {
+				sigs.push(*presig)?;
+			}
+			sigs.push(&secret_sig)?;
+			let sig = self.aggregate_signatures(sigs.slice(0, sigs.len()), &pubnonce)?;
+			if self.verify(&sig, msg, &pubnonce, pe, pe, false).is_ok() {
+				return Ok(sig);
+			}
+			secret.negate(self)?;
+		}
+		err!(ValidationFailed)
+	}
+
+	// the adaptor secret t of `adaptor`, given the final signature and the
+	// pre-signatures it was adapted from
+	pub fn extract_adaptor_secret(
+		&self,
+		sig: &Signature,
+		presigs: &[&Signature],
+		adaptor: &PublicKey,
+	) -> Result<SecretKey> {
+		let s = Self::signature_scalar(sig)?;
+		let mut parts = Vec::with_capacity(presigs.len())?;
+		for presig in presigs {
+			parts.push(Self::signature_scalar(presig)?)?;
+		}
+		let mut negative = Vec::with_capacity(parts.len())?;
+		for part in parts.iter() {
+			negative.push(part)?;
+		}
+		let mut secret = self.blind_sum(&[&s], negative.slice(0, negative.len()))?;
+		if PublicKey::from(self, &secret)? != *adaptor {
+			secret.negate(self)?;
+		}
+		if PublicKey::from(self, &secret)? != *adaptor {
+			return err!(ValidationFailed);
+		}
+		Ok(secret)
+	}
+
+	// the s half of a signature
+	fn signature_scalar(sig: &Signature) -> Result<SecretKey> {
+		let mut ret = SecretKey::zero();
+		ret.as_mut().slice_copy(sig.as_ref().subslice(32, 32)?)?;
+		Ok(ret)
+	}
+
 	pub fn blind_sum(&self, positive: &[&SecretKey], negative: &[&SecretKey]) -> Result<SecretKey> {
 		let total_len = positive.len() + negative.len();
 
@@ -474,6 +571,134 @@ impl Ctx {
 		}
 	}
 
+	// Multi-party range proofs, for an output whose blind is the sum of a
+	// share from each party so that no one can spend it alone. The parties
+	// agree on `mp` and each picks a private nonce, then:
+	// 1. everyone calls multi_party_t, the results are summed
+	// 2. everyone calls multi_party_tau_x with the sums, these are summed too
+	// 3. one party calls multi_party_prove with all the sums
+	pub fn multi_party_t(
+		&self,
+		mp: &MultiPartyProof,
+		blind: &SecretKey,
+		private_nonce: &SecretKey,
+	) -> Result<(PublicKey, PublicKey)> {
+		let mut t = [
+			PublicKeyUncompressed::new([0u8; 64]),
+			PublicKeyUncompressed::new([0u8; 64]),
+		];
+		self.multi_party_round(mp, blind, private_nonce, &mut t, None, None)?;
+		Ok((
+			PublicKey::compress(self, t[0].clone())?,
+			PublicKey::compress(self, t[1].clone())?,
+		))
+	}
+
+	pub fn multi_party_tau_x(
+		&self,
+		mp: &MultiPartyProof,
+		blind: &SecretKey,
+		private_nonce: &SecretKey,
+		t_one: &PublicKey,
+		t_two: &PublicKey,
+	) -> Result<SecretKey> {
+		let mut t = [t_one.decompress(self)?, t_two.decompress(self)?];
+		let mut tau_x = SecretKey::zero();
+		self.multi_party_round(mp, blind, private_nonce, &mut t, Some(&mut tau_x), None)?;
+		Ok(tau_x)
+	}
+
+	pub fn multi_party_prove(
+		&self,
+		mp: &MultiPartyProof,
+		blind: &SecretKey,
+		private_nonce: &SecretKey,
+		t: (&PublicKey, &PublicKey),
+		tau_x: &SecretKey,
+	) -> Result<RangeProof> {
+		let mut t = [t.0.decompress(self)?, t.1.decompress(self)?];
+		let mut tau_x = tau_x.clone();
+		let mut proof = RangeProof {
+			proof: [0; MAX_PROOF_SIZE],
+			plen: MAX_PROOF_SIZE,
+		};
+		self.multi_party_round(
+			mp,
+			blind,
+			private_nonce,
+			&mut t,
+			Some(&mut tau_x),
+			Some(&mut proof),
+		)?;
+		if proof.plen > MAX_PROOF_SIZE {
+			return err!(IllegalState);
+		}
+		Ok(proof)
+	}
+
+	// the prover does round 1 without tau_x, round 2 without a proof and
+	// round 3 with both
+	fn multi_party_round(
+		&self,
+		mp: &MultiPartyProof,
+		blind: &SecretKey,
+		private_nonce: &SecretKey,
+		t: &mut [PublicKeyUncompressed; 2],
+		tau_x: Option<&mut SecretKey>,
+		proof: Option<&mut RangeProof>,
+	) -> Result<()> {
+		let n_bits = 64;
+		let commit = mp.commit.decompress(self)?;
+		let commit_array = [commit.as_ptr() as *const u8];
+		let blind_array = [blind.as_ptr()];
+		let tau_x = match tau_x {
+			Some(tau_x) => tau_x.as_mut_ptr() as *mut u8,
+			None => null_mut(),
+		};
+		let (proof, plen) = match proof {
+			Some(proof) => (proof.proof.as_mut_ptr(), &mut proof.plen as *mut usize),
+			None => (null_mut(), null_mut()),
+		};
+
+		unsafe {
+			let scratch = secp256k1_scratch_space_create(self.secp, SCRATCH_SPACE_SIZE);
+			if scratch.is_null() {
+				return err!(Alloc);
+			}
+
+			let res = secp256k1_bulletproof_rangeproof_prove(
+				self.secp,
+				scratch,
+				self.gens,
+				proof,
+				plen,
+				tau_x,
+				t.as_mut_ptr(),
+				t.as_mut_ptr().add(1),
+				&mp.value,
+				null(), // min_values
+				blind_array.as_ptr(),
+				commit_array.as_ptr(),
+				1,
+				GENERATOR_H.as_ptr(),
+				n_bits,
+				mp.common_nonce.as_ptr(),
+				private_nonce.as_ptr(),
+				null(), // extra_data
+				0,      // extra_data_len
+				null(), // message_ptr
+			);
+
+			secp256k1_scratch_space_destroy(scratch);
+
+			if res == 0 {
+				err!(OperationFailed)
+			} else {
+				Ok(())
+			}
+		}
+	}
+
 	pub fn verify_range_proof(&self, commit: &Commitment, proof: &RangeProof) -> Result<()> {
 		if proof.plen > MAX_PROOF_SIZE {
 			return err!(IllegalArgument);
@@ -651,6 +876,113 @@ mod test {
 		Ok(())
 	}
 
+	#[test]
+	fn test_adaptor_signature() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let msg = Message::new([12u8; 32]);
+		let secret = SecretKey::gen(&ctx);
+		let adaptor = PublicKey::from(&ctx, &secret)?;
+
+		// two signers, only signer 1 knows how the nonce sum is shifted
+		for _ in 0..8 {
+			let seckey1 = SecretKey::gen(&ctx);
+			let secnonce1 = SecretKey::gen(&ctx);
+			let pubnonce1 = PublicKey::from(&ctx, &secnonce1)?;
+			let pubkey1 = PublicKey::from(&ctx, &seckey1)?;
+			let seckey2 = SecretKey::gen(&ctx);
+			let secnonce2 = SecretKey::gen(&ctx);
+			let pubnonce2 = PublicKey::from(&ctx, &secnonce2)?;
+			let pubkey2 = PublicKey::from(&ctx, &seckey2)?;
+			let nonce_sum = pubnonce1.combine(&ctx, &pubnonce2)?;
+			let pk_sum = pubkey1.combine(&ctx, &pubkey2)?;
+
+			let presig1 =
+				ctx.adaptor_sign(&msg, &seckey1, &secnonce1, &nonce_sum, &pk_sum, &adaptor)?;
+			let presig2 =
+				ctx.adaptor_sign(&msg, &seckey2, &secnonce2, &nonce_sum, &pk_sum, &adaptor)?;
+			assert!(ctx
+				.verify_adaptor(&presig1, &msg, &nonce_sum, &pubkey1, &pk_sum, &adaptor)
+				.is_ok());
+			assert!(ctx
+				.verify_adaptor(&presig2, &msg, &nonce_sum, &pubkey2, &pk_sum, &adaptor)
+				.is_ok());
+			assert!(ctx
+				.verify_adaptor(&presig2, &msg, &nonce_sum, &pubkey1, &pk_sum, &adaptor)
+				.is_err());
+
+			// the pre-signatures alone don't make a valid signature
+			let sig = ctx.aggregate_signatures(&[&presig1, &presig2], &nonce_sum)?;
+			assert!(ctx
+				.verify(&sig, &msg, &nonce_sum, &pk_sum, &pk_sum, false)
+				.is_err());
+			assert!(ctx
+				.adapt_signature(&[&presig1, &presig2], &seckey1, &msg, &nonce_sum, &pk_sum)
+				.is_err());
+
+			let sig =
+				ctx.adapt_signature(&[&presig1, &presig2], &secret, &msg, &nonce_sum, &pk_sum)?;
+			let final_nonce = nonce_sum.combine(&ctx, &adaptor)?;
+			assert!(ctx
+				.verify(&sig, &msg, &final_nonce, &pk_sum, &pk_sum, false)
+				.is_ok());
+
+			// publishing the signature reveals the secret
+			let extracted = ctx.extract_adaptor_secret(&sig, &[&presig1, &presig2], &adaptor)?;
+			assert!(extracted == secret);
+			assert!(ctx
+				.extract_adaptor_secret(&sig, &[&presig1], &adaptor)
+				.is_err());
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_multi_party_range_proof() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let value = 1_000;
+		let blind1 = SecretKey::gen(&ctx);
+		let blind2 = SecretKey::gen(&ctx);
+		let nonce1 = SecretKey::gen(&ctx);
+		let nonce2 = SecretKey::gen(&ctx);
+		let blind = ctx.blind_sum(&[&blind1, &blind2], &[])?;
+		let mp = MultiPartyProof {
+			value,
+			commit: ctx.commit(value, &blind)?,
+			common_nonce: SecretKey::gen(&ctx),
+		};
+
+		// round 1
+		let (t_one1, t_two1) = ctx.multi_party_t(&mp, &blind1, &nonce1)?;
+		let (t_one2, t_two2) = ctx.multi_party_t(&mp, &blind2, &nonce2)?;
+		let t_one = t_one1.combine(&ctx, &t_one2)?;
+		let t_two = t_two1.combine(&ctx, &t_two2)?;
+
+		// round 2
+		let tau_x1 = ctx.multi_party_tau_x(&mp, &blind1, &nonce1, &t_one, &t_two)?;
+		let tau_x2 = ctx.multi_party_tau_x(&mp, &blind2, &nonce2, &t_one, &t_two)?;
+		let tau_x = ctx.blind_sum(&[&tau_x1, &tau_x2], &[])?;
+
+		// round 3, either party can do it
+		let proof = ctx.multi_party_prove(&mp, &blind1, &nonce1, (&t_one, &t_two), &tau_x)?;
+		assert!(ctx.verify_range_proof(&mp.commit, &proof).is_ok());
+		let proof = ctx.multi_party_prove(&mp, &blind2, &nonce2, (&t_one, &t_two), &tau_x)?;
+		assert!(ctx.verify_range_proof(&mp.commit, &proof).is_ok());
+		assert_eq!(
+			ctx.rewind_range_proof(&mp.commit, &mp.common_nonce, &proof)?,
+			value
+		);
+		assert!(ctx
+			.verify_range_proof(&ctx.commit(value + 1, &blind)?, &proof)
+			.is_err());
+
+		// a party leaving out its share doesn't make a valid proof
+		let proof = ctx.multi_party_prove(&mp, &blind1, &nonce1, (&t_one, &t_two), &tau_x1)?;
+		assert!(ctx.verify_range_proof(&mp.commit, &proof).is_err());
+
+		Ok(())
+	}
+
 	#[test]
 	fn test_balance_tx() -> Result<()> {
 		let mut secp = Ctx::new()?;
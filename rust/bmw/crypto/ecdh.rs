@@ -15,6 +15,12 @@ impl Debug for SharedSecret {
 	}
 }
 
+impl AsRef<[u8]> for SharedSecret {
+	fn as_ref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
 impl SharedSecret {
 	pub fn new(ctx: &Ctx, exchanged: &PublicKey, local: &SecretKey) -> Result<SharedSecret> {
 		let mut ss = Self([0u8; 32]);
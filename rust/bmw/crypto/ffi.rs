@@ -17,6 +17,8 @@ extern "C" {
 
 	// heavyhash
 	pub fn heavyhash(matrix: *const u16, pdata: *const u8, len: usize, out: *mut u8);
+	pub fn heavyhash_columns(columns: *const u16, pdata: *const u8, len: usize, out: *mut u8);
+	pub fn transpose_matrix(matrix: *const u16, columns: *mut u16);
 	pub fn generate_matrix(matrix: *mut u16, aes: *const AesContext);
 
 	// aes 256
@@ -0,0 +1,87 @@
+use crypto::constants::HKDF_MAX_OUTPUT;
+use crypto::errors::*;
+use crypto::hmac::HmacSha3_256;
+use prelude::*;
+
+// HKDF (RFC 5869) instantiated with HMAC-SHA3-256.
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+	let hmac = HmacSha3_256::new(salt);
+	hmac.update(ikm);
+	hmac.finalize()
+}
+
+pub fn hkdf_expand(prk: &[u8; 32], info: &[u8], out: &mut [u8]) -> Result<()> {
+	if out.len() > HKDF_MAX_OUTPUT {
+		return err!(Overflow);
+	}
+	let mut t = [0u8; 32];
+	let mut offset = 0;
+	let mut counter = 1u8;
+	while offset < out.len() {
+		let hmac = HmacSha3_256::new(prk);
+		if counter > 1 {
+			hmac.update(&t);
+		}
+		hmac.update(info);
+		hmac.update(&[counter]);
+		t = hmac.finalize();
+
+		let mut i = 0;
+		while i < t.len() && offset < out.len() {
+			out[offset] = t[i];
+			offset += 1;
+			i += 1;
+		}
+		counter = counter.wrapping_add(1);
+	}
+	Ok(())
+}
+
+pub fn hkdf(salt: &[u8], ikm: &[u8], info: &[u8], out: &mut [u8]) -> Result<()> {
+	let prk = hkdf_extract(salt, ikm);
+	hkdf_expand(&prk, info, out)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_hkdf() -> Result<()> {
+		let prk = hkdf_extract(b"salt", b"input key material");
+		assert_eq!(
+			prk,
+			[
+				0xd9, 0xf8, 0xc6, 0x9c, 0x7d, 0x8d, 0x4d, 0x17, 0x2b, 0xb2, 0x9b, 0x7b, 0xfb, 0x34,
+				0xf4, 0xf9, 0xd1, 0x91, 0xcd, 0x46, 0x40, 0x05, 0x7f, 0xfa, 0xb9, 0x14, 0x71, 0x1c,
+				0xb2, 0xa4, 0x40, 0x60
+			]
+		);
+
+		// 42 bytes spans two hmac blocks
+		let mut okm = [0u8; 42];
+		hkdf(b"salt", b"input key material", b"info", &mut okm)?;
+		assert_eq!(
+			okm,
+			[
+				0xef, 0x04, 0xde, 0xed, 0x25, 0x41, 0x5f, 0x87, 0x6f, 0x43, 0x9a, 0x4a, 0xeb, 0x00,
+				0x19, 0x5e, 0x6e, 0x98, 0xa1, 0x13, 0x04, 0x71, 0x9f, 0x4a, 0x7a, 0x8e, 0xc1, 0x8a,
+				0xb1, 0x8b, 0x44, 0x5c, 0x0d, 0x57, 0xe8, 0xbf, 0x58, 0x77, 0x87, 0x7d, 0x4f, 0x97
+			]
+		);
+
+		// a prefix of the output doesn't depend on the requested length
+		let mut short = [0u8; 10];
+		hkdf(b"salt", b"input key material", b"info", &mut short)?;
+		assert_eq!(&short[..], &okm[0..10]);
+
+		let mut other = [0u8; 42];
+		hkdf(b"salt", b"input key material", b"other", &mut other)?;
+		assert_ne!(other, okm);
+
+		let mut too_long = [0u8; HKDF_MAX_OUTPUT + 1];
+		assert_eq!(hkdf_expand(&prk, b"", &mut too_long).err(), Some(Overflow));
+
+		Ok(())
+	}
+}
@@ -0,0 +1,116 @@
+use core::ptr::write_bytes;
+use core::sync::atomic::compiler_fence;
+use core::sync::atomic::Ordering::SeqCst;
+use crypto::constants::SHA3_256_RATE;
+use crypto::sha3::Sha3_256;
+
+// HMAC (RFC 2104) over SHA3-256 with the keccak rate as the block size.
+pub struct HmacSha3_256 {
+	inner: Sha3_256,
+	outer: Sha3_256,
+}
+
+impl HmacSha3_256 {
+	pub fn new(key: &[u8]) -> Self {
+		let mut block = [0u8; SHA3_256_RATE];
+		if key.len() > SHA3_256_RATE {
+			let sha3 = Sha3_256::new();
+			sha3.update(key);
+			let digest = sha3.finalize();
+			for i in 0..digest.len() {
+				block[i] = digest[i];
+			}
+		} else {
+			for i in 0..key.len() {
+				block[i] = key[i];
+			}
+		}
+
+		let inner = Sha3_256::new();
+		let outer = Sha3_256::new();
+		let mut pad = [0u8; SHA3_256_RATE];
+		for i in 0..SHA3_256_RATE {
+			pad[i] = block[i] ^ 0x36;
+		}
+		inner.update(&pad);
+		for i in 0..SHA3_256_RATE {
+			pad[i] = block[i] ^ 0x5c;
+		}
+		outer.update(&pad);
+
+		// don't leave key material on the stack
+		unsafe {
+			write_bytes(block.as_mut_ptr(), 0, SHA3_256_RATE);
+			write_bytes(pad.as_mut_ptr(), 0, SHA3_256_RATE);
+			compiler_fence(SeqCst);
+		}
+
+		Self { inner, outer }
+	}
+
+	pub fn update(&self, b: &[u8]) {
+		self.inner.update(b);
+	}
+
+	pub fn finalize(&self) -> [u8; 32] {
+		let digest = self.inner.finalize();
+		self.outer.update(&digest);
+		self.outer.finalize()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use prelude::*;
+
+	fn hex(s: &str) -> Result<Vec<u8>> {
+		let mut ret = Vec::new();
+		let b = s.as_bytes();
+		let mut i = 0;
+		while i + 1 < b.len() {
+			let mut v = 0u8;
+			for c in &b[i..i + 2] {
+				v = (v << 4)
+					| match c {
+						b'0'..=b'9' => c - b'0',
+						b'a'..=b'f' => c - b'a' + 10,
+						_ => return err!(IllegalArgument),
+					};
+			}
+			ret.push(v)?;
+			i += 2;
+		}
+		Ok(ret)
+	}
+
+	#[test]
+	fn test_hmac_sha3_256() -> Result<()> {
+		// NIST HMAC_SHA3-256 example, keylen < blocklen
+		let mut key = [0u8; 32];
+		for i in 0..key.len() {
+			key[i] = i as u8;
+		}
+		let hmac = HmacSha3_256::new(&key);
+		hmac.update(b"Sample message for keylen<blocklen");
+		let expected = hex("4fe8e202c4f058e8dddc23d8c34e467343e23555e24fc2f025d598f558f67205")?;
+		assert_eq!(&hmac.finalize()[..], &expected[..]);
+
+		// keys longer than the block size are hashed first
+		let mut key = [0u8; 168];
+		for i in 0..key.len() {
+			key[i] = i as u8;
+		}
+		let hmac = HmacSha3_256::new(&key);
+		hmac.update(b"Sample message ");
+		hmac.update(b"for keylen>blocklen");
+		let expected = hex("9bcf2c238e235c3ce88404e813bd2f3a97185ac6f238c63d6229a00b07974258")?;
+		assert_eq!(&hmac.finalize()[..], &expected[..]);
+
+		let hmac = HmacSha3_256::new(b"");
+		let expected = hex("e841c164e5b4f10c9f3985587962af72fd607a951196fc92fb3a5251941784ea")?;
+		assert_eq!(&hmac.finalize()[..], &expected[..]);
+
+		Ok(())
+	}
+}
@@ -154,7 +154,28 @@ impl AsRawMut<Self> for PublicKey {
 	}
 }
 
+impl AsRef<[u8]> for PublicKey {
+	fn as_ref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
 impl PublicKey {
+	// parses and validates a 33 byte compressed key
+	pub fn parse(ctx: &Ctx, bytes: &[u8]) -> Result<Self> {
+		if bytes.len() != 33 {
+			return err!(Serialization);
+		}
+		let mut v = Self([0u8; 33]);
+		for i in 0..33 {
+			v.0[i] = bytes[i];
+		}
+		match v.decompress(ctx) {
+			Ok(_) => Ok(v),
+			Err(_) => err!(Serialization),
+		}
+	}
+
 	pub fn from(ctx: &Ctx, secret_key: &SecretKey) -> Result<Self> {
 		let mut v = Self([0u8; 33]);
 		let mut uncomp = PublicKeyUncompressed([0u8; 64]);
@@ -288,4 +309,22 @@ mod test {
 		assert_ne!(skey2, skey4);
 		Ok(())
 	}
+	#[test]
+	fn test_public_key_parse() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let skey = SecretKey::gen(&ctx);
+		let pkey = PublicKey::from(&ctx, &skey)?;
+		let parsed = PublicKey::parse(&ctx, pkey.as_ref())?;
+		assert!(parsed == pkey);
+
+		assert_eq!(
+			PublicKey::parse(&ctx, &pkey.as_ref()[0..32]).err(),
+			Some(Serialization)
+		);
+		// not a valid prefix
+		let mut bad = [0u8; 33];
+		bad[0] = 5;
+		assert_eq!(PublicKey::parse(&ctx, &bad).err(), Some(Serialization));
+		Ok(())
+	}
 }
@@ -1,6 +1,7 @@
 mod constants;
 mod types;
 
+pub mod aead;
 pub mod aes;
 pub mod bip52;
 pub mod cpsrng;
@@ -8,6 +9,8 @@ pub mod ctx;
 pub mod ecdh;
 pub mod errors;
 pub mod ffi;
+pub mod hkdf;
+pub mod hmac;
 pub mod keys;
 pub mod pedersen;
 pub mod range_proof;
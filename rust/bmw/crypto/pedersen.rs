@@ -111,6 +111,17 @@ impl Commitment {
 		Self([0u8; 33])
 	}
 
+	// the commitment serialized as `bytes`, see as_ref. The point isn't
+	// checked until it's used.
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+		let mut ret = Self::zero();
+		if bytes.len() != ret.0.len() {
+			return err!(Serialization);
+		}
+		ret.0.slice_copy(bytes)?;
+		Ok(ret)
+	}
+
 	pub fn decompress(&self, ctx: &Ctx) -> Result<CommitmentUncompressed> {
 		let mut out = CommitmentUncompressed([0u8; 64]);
 		unsafe {
@@ -1,4 +1,6 @@
 use crypto::constants::MAX_PROOF_SIZE;
+use crypto::keys::SecretKey;
+use crypto::pedersen::Commitment;
 use prelude::*;
 
 #[derive(Clone, Copy)]
@@ -22,3 +24,13 @@ impl AsRef<[u8]> for RangeProof {
 		&self.proof
 	}
 }
+
+// What every party to a multi-party range proof uses, see
+// Ctx::multi_party_t.
+pub struct MultiPartyProof {
+	pub value: u64,
+	// the commitment to value with the sum of every party's blind
+	pub commit: Commitment,
+	// known to every party, the proof can be rewound with it
+	pub common_nonce: SecretKey,
+}
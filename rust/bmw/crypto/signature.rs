@@ -1,3 +1,4 @@
+use crypto::errors::*;
 use misc::bytes_to_hex_64;
 use prelude::*;
 
@@ -69,6 +70,12 @@ impl Display for Signature {
 	}
 }
 
+impl AsRef<[u8]> for Signature {
+	fn as_ref(&self) -> &[u8] {
+		&self.0
+	}
+}
+
 impl AsRaw<Self> for Signature {
 	fn as_ptr(&self) -> *const Self {
 		self.0.as_ptr() as *const Self
@@ -84,6 +91,16 @@ impl Signature {
 	pub fn new() -> Self {
 		Self([0u8; 64])
 	}
+
+	// the signature serialized as `bytes`, see as_ref
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+		if bytes.len() != 64 {
+			return err!(Serialization);
+		}
+		let mut ret = Self::new();
+		ret.0.slice_copy(bytes)?;
+		Ok(ret)
+	}
 }
 
 #[cfg(test)]
@@ -1,6 +1,7 @@
 pub const MDB_SUCCESS: i32 = 0;
 pub const MDB_NOTFOUND: i32 = -30798;
 pub const MDB_MAP_FULL: i32 = -30792;
+pub const MDB_DBS_FULL: i32 = -30791;
 pub const MDB_CREATE: u32 = 0x40000;
 pub const MDB_READONLY: u32 = 0x20000;
 pub const FILE_MODE: u32 = 0o664;
@@ -9,3 +10,10 @@ pub const MDB_SET_RANGE: u32 = 17;
 pub const MDB_NEXT: u32 = 8;
 pub const MDB_PREV: u32 = 12;
 pub const MDB_GET_CURRENT: u32 = 4;
+pub const MDB_LAST: u32 = 6;
+pub const LMDB_DEFAULT_GROWTH_FACTOR: f64 = 2.0;
+// how long resizing waits for open transactions to end, in milliseconds
+pub const LMDB_DEFAULT_RESIZE_TIMEOUT: u64 = 10_000;
+// map sizes are rounded up to a multiple of this, which is a multiple of
+// any os page size
+pub const LMDB_MAP_ALIGN: usize = 1024 * 1024;
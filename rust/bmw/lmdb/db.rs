@@ -1,14 +1,30 @@
+use core::ops::{Fn, FnMut};
 use core::ptr::null_mut;
-use lmdb::constants::{FILE_MODE, MDB_CREATE, MDB_MAX_DBS, MDB_READONLY, MDB_SUCCESS};
+use ffi::getmicros;
+use lmdb::constants::{
+	FILE_MODE, LMDB_DEFAULT_GROWTH_FACTOR, LMDB_DEFAULT_RESIZE_TIMEOUT, LMDB_MAP_ALIGN, MDB_CREATE,
+	MDB_DBS_FULL, MDB_MAX_DBS, MDB_READONLY, MDB_SUCCESS,
+};
 use lmdb::errors::*;
 use lmdb::ffi::*;
 use lmdb::txn::LmdbTxn;
 use lmdb::types::{MDB_dbi, MDB_env, MDB_txn};
 use prelude::*;
 use util::cstring::CString;
+use util::lock::LockBox;
 
+// shared by every handle on an environment
 struct LmdbEnv {
 	env: *mut MDB_env,
+	map_size: usize,
+	growth_factor: f64,
+	// the number of open transactions, counted under `lock`. The map can
+	// only be resized while there are none, so resizing checks for zero and
+	// sets the size while holding `lock` for writing.
+	active: Rc<u64>,
+	// milliseconds to wait for `active` to drop to zero before giving up
+	resize_timeout: u64,
+	lock: LockBox,
 }
 
 impl Drop for LmdbEnv {
@@ -33,7 +49,6 @@ impl TryClone for Lmdb {
 		Ok(Self {
 			env,
 			dbi: self.dbi,
-			map_size: self.map_size,
 			c_path,
 			c_name,
 		})
@@ -43,21 +58,26 @@ impl TryClone for Lmdb {
 pub struct Lmdb {
 	env: Rc<LmdbEnv>,
 	dbi: MDB_dbi,
-	map_size: usize,
 	c_path: CString,
 	c_name: CString,
 }
 
 impl Lmdb {
 	pub fn new(path: &str, name: &str, map_size: usize) -> Result<Self> {
-		let env = Rc::new(LmdbEnv { env: null_mut() })?;
+		let env = Rc::new(LmdbEnv {
+			env: null_mut(),
+			map_size,
+			growth_factor: LMDB_DEFAULT_GROWTH_FACTOR,
+			active: Rc::new(0)?,
+			resize_timeout: LMDB_DEFAULT_RESIZE_TIMEOUT,
+			lock: lock_box!()?,
+		})?;
 		let c_path = CString::new(path)?;
 		let c_name = CString::new(name)?;
 		let dbi = MDB_dbi(0);
 		let mut lmdb = Lmdb {
 			env,
 			dbi,
-			map_size,
 			c_path,
 			c_name,
 		};
@@ -65,21 +85,104 @@ impl Lmdb {
 		Ok(lmdb)
 	}
 
-	pub fn write(&self) -> Result<LmdbTxn> {
-		let mut txn: *mut MDB_txn = null_mut();
-		if unsafe { mdb_txn_begin(self.env.env, null_mut(), 0, &mut txn) != MDB_SUCCESS } {
-			return err!(LmdbBeginTxn);
+	// a handle on another named database in the same environment. A
+	// transaction started on any handle can be switched to the others with
+	// `LmdbTxn::db`, so writes to several databases commit atomically.
+	// The database is opened in `txn` if given, which must be a write
+	// transaction of this environment as lmdb allows only one at a time.
+	// The handle is then usable in `txn` and, once it commits, everywhere.
+	// An environment holds at most MDB_MAX_DBS databases.
+	pub fn open(&self, name: &str, txn: Option<LmdbTxn>) -> Result<Self> {
+		if self.env.env.is_null() {
+			return err!(IllegalState);
 		}
-		LmdbTxn::new(txn, self.dbi, true)
+		let c_name = CString::new(name)?;
+		let dbi = match txn {
+			Some(txn) => {
+				txn.check_env(self)?;
+				if !txn.is_write() {
+					return err!(IllegalState);
+				}
+				unsafe { Self::open_dbi(txn.ptr(), &c_name)? }
+			}
+			None => {
+				let txn = self.begin(0)?;
+				let dbi = unsafe { Self::open_dbi(txn.ptr(), &c_name)? };
+				txn.commit()?;
+				dbi
+			}
+		};
+		Ok(Self {
+			env: self.env.clone(),
+			dbi,
+			c_path: CString::new(self.c_path.as_str()?.as_str())?,
+			c_name,
+		})
+	}
+
+	pub fn dbi(&self) -> MDB_dbi {
+		self.dbi
+	}
+
+	pub(crate) fn env_ptr(&self) -> *mut MDB_env {
+		self.env.env
+	}
+
+	pub fn write(&self) -> Result<LmdbTxn> {
+		self.begin(0)
 	}
 
 	pub fn read(&self) -> Result<LmdbTxn> {
-		let mut txn: *mut MDB_txn = null_mut();
-		let r = unsafe { mdb_txn_begin(self.env.env, null_mut(), MDB_READONLY, &mut txn) };
-		if r != MDB_SUCCESS {
-			return err!(LmdbBeginTxn);
+		self.begin(MDB_READONLY)
+	}
+
+	// runs `f` in a write transaction and commits it. If the map fills up,
+	// the transaction is aborted, the map grown and `f` called again, so
+	// `f` must not keep any clone of the transaction it is given. Growing
+	// fails with LmdbBusy if the calling thread holds another transaction.
+	pub fn update<F: FnMut(&mut LmdbTxn) -> Result<()>>(&self, mut f: F) -> Result<()> {
+		loop {
+			let size = self.size();
+			let res = {
+				let mut txn = self.write()?;
+				match f(&mut txn) {
+					Ok(_) => txn.commit(),
+					Err(e) => Err(e),
+				}
+			};
+			match res {
+				Err(e) if e == LmdbFull => self.grow_from(size)?,
+				_ => return res,
+			}
 		}
-		LmdbTxn::new(txn, self.dbi, false)
+	}
+
+	// grows the map by the growth factor. Waits for all transactions of
+	// the environment to end and fails with LmdbBusy if they don't within
+	// the resize timeout, as when the calling thread holds one.
+	pub fn grow(&self) -> Result<()> {
+		self.grow_from(self.size())
+	}
+
+	// the map is multiplied by `factor` each time it is grown
+	pub fn set_growth_factor(&self, factor: f64) -> Result<()> {
+		if !(factor > 1.0) {
+			return err!(IllegalArgument);
+		}
+		let mut env = self.env.clone();
+		let lock = env.lock.clone();
+		let _l = lock.write();
+		env.growth_factor = factor;
+		Ok(())
+	}
+
+	// how long resizing waits for open transactions, in milliseconds
+	pub fn set_resize_timeout(&self, millis: u64) -> Result<()> {
+		let mut env = self.env.clone();
+		let lock = env.lock.clone();
+		let _l = lock.write();
+		env.resize_timeout = millis;
+		Ok(())
 	}
 
 	pub fn close(&mut self) {
@@ -89,14 +192,95 @@ impl Lmdb {
 		}
 	}
 
-	pub fn resize(&mut self, nsize: usize) -> Result<()> {
-		self.close();
-		self.map_size = nsize;
-		self.init()
+	// sets the map size of the environment. Other handles on the
+	// environment remain valid. Like `grow`, this waits for open
+	// transactions to end and fails with LmdbBusy if they don't.
+	pub fn resize(&self, nsize: usize) -> Result<()> {
+		self.set_map_size(|_| Some(nsize))
 	}
 
 	pub fn size(&self) -> usize {
-		self.map_size
+		let _l = self.env.lock.read();
+		self.env.map_size
+	}
+
+	fn begin(&self, flags: u32) -> Result<LmdbTxn> {
+		let env = self.env.clone();
+		if env.env.is_null() {
+			return err!(IllegalState);
+		}
+		let mut active = env.active.clone();
+		{
+			let _l = env.lock.read();
+			aadd!(&mut *active, 1);
+		}
+		let mut txn: *mut MDB_txn = null_mut();
+		if unsafe { mdb_txn_begin(env.env, null_mut(), flags, &mut txn) } != MDB_SUCCESS {
+			asub!(&mut *active, 1);
+			return err!(LmdbBeginTxn);
+		}
+		let write = flags & MDB_READONLY == 0;
+		match LmdbTxn::new(txn, self.dbi, write, env.active.clone()) {
+			Ok(txn) => Ok(txn),
+			Err(e) => {
+				asub!(&mut *active, 1);
+				Err(e)
+			}
+		}
+	}
+
+	// another thread may have grown the map since ours filled up, in that
+	// case just retry
+	fn grow_from(&self, size: usize) -> Result<()> {
+		self.set_map_size(|env| {
+			if env.map_size != size {
+				return None;
+			}
+			Some((env.map_size as f64 * env.growth_factor) as usize)
+		})
+	}
+
+	// sets the map size to what `nsize` returns for the environment, or
+	// leaves it if that's None. mdb_env_set_mapsize requires that no
+	// transactions are active, so this waits for them to end. The lock is
+	// only held to check and set, other threads can keep using the
+	// environment meanwhile. Transactions of the calling thread would never
+	// end so it gives up with LmdbBusy after the resize timeout.
+	fn set_map_size<F: Fn(&LmdbEnv) -> Option<usize>>(&self, nsize: F) -> Result<()> {
+		let mut env = self.env.clone();
+		let lock = env.lock.clone();
+		let timeout = {
+			let _l = lock.read();
+			env.resize_timeout
+		};
+		let deadline = unsafe { getmicros() } + timeout * 1_000;
+		loop {
+			{
+				let _l = lock.write();
+				if env.env.is_null() {
+					return err!(IllegalState);
+				}
+				let nsize = match nsize(&env) {
+					Some(nsize) => nsize,
+					None => return Ok(()),
+				};
+				if aload!(&*env.active) == 0 {
+					let nsize = match nsize.checked_add(LMDB_MAP_ALIGN - 1) {
+						Some(n) => n / LMDB_MAP_ALIGN * LMDB_MAP_ALIGN,
+						None => return err!(CapacityExceeded),
+					};
+					if unsafe { mdb_env_set_mapsize(env.env, nsize) } != MDB_SUCCESS {
+						return err!(Alloc);
+					}
+					env.map_size = nsize;
+					return Ok(());
+				}
+			}
+			if unsafe { getmicros() } >= deadline {
+				return err!(LmdbBusy);
+			}
+			sleep(1);
+		}
 	}
 
 	fn init(&mut self) -> Result<()> {
@@ -105,7 +289,7 @@ impl Lmdb {
 			if mdb_env_create(&mut self.env.env) != MDB_SUCCESS {
 				return err!(LmdbCreate);
 			}
-			if mdb_env_set_mapsize(self.env.env, self.map_size) != MDB_SUCCESS {
+			if mdb_env_set_mapsize(self.env.env, self.env.map_size) != MDB_SUCCESS {
 				self.close();
 				return err!(Alloc);
 			}
@@ -122,22 +306,39 @@ impl Lmdb {
 			if mdb_txn_begin(self.env.env, null_mut(), 0, &mut txn) != MDB_SUCCESS {
 				return err!(LmdbBeginTxn);
 			}
-			let rc = mdb_dbi_open(txn, self.c_name.as_ptr(), MDB_CREATE, &mut self.dbi);
-			if rc != MDB_SUCCESS {
-				return err!(LmdbOpen);
-			}
+			self.dbi = match Self::open_dbi(txn, &self.c_name) {
+				Ok(dbi) => dbi,
+				Err(e) => {
+					mdb_txn_abort(txn);
+					return Err(e);
+				}
+			};
 			if mdb_txn_commit(txn) != MDB_SUCCESS {
 				return err!(LmdbCommit);
 			}
 		}
 		Ok(())
 	}
+
+	// opens (creating if needed) a database
+	unsafe fn open_dbi(txn: *mut MDB_txn, c_name: &CString) -> Result<MDB_dbi> {
+		let mut dbi = MDB_dbi(0);
+		let rc = mdb_dbi_open(txn, c_name.as_ptr(), MDB_CREATE, &mut dbi);
+		if rc != MDB_SUCCESS {
+			if rc == MDB_DBS_FULL {
+				return err!(LmdbDbsFull);
+			}
+			return err!(LmdbOpen);
+		}
+		Ok(dbi)
+	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
 	use lmdb::txn::test::{make_lmdb_test_dir, remove_lmdb_test_dir};
+	use util::thread::spawnj;
 
 	#[test]
 	fn test_lmdb3() -> Result<()> {
@@ -182,49 +383,114 @@ mod test {
 		Ok(())
 	}
 
-	/*
 	#[test]
-	fn test_lmdb_resize() -> Result<() > {
-		let db_size = 1024 * 100;
+	fn test_lmdb_resize() -> Result<()> {
+		let db_size = 1024 * 1024;
 		let db_name = "mydb";
 		let db_dir = "bin/.lmdb4";
 		make_lmdb_test_dir(db_dir)?;
-		let mut db = Lmdb::new(db_dir, db_name, db_size)?;
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
 		let mut err = 0;
-		{
-			loop {
-				let mut txn = db.write()?;
-				let a = String::new("a")?;
-				let b = [0u8; 1024 * 1024];
-				match txn.put(&a, &b) {
-					Ok(_) => {}
-					Err(e) => {
-						assert_eq!(e, err!(LmdbFull));
-						err += 1;
-						let nsize = 1024 * 1024 * 10;
-						db.resize(nsize)?;
-						continue;
-					}
-				}
-				match txn.commit() {
-					Ok(_) => {
-						break;
-					}
-					Err(e) => {
-						assert_eq!(e, err!(LmdbFull);
-						err += 1;
-						let nsize = 1024 * 1024 * 10;
-						db.resize(nsize)?;
-					}
+		let a = String::new("a")?;
+		let mut b = Vec::new();
+		b.resize(2 * 1024 * 1024)?;
+		loop {
+			let mut txn = db.write()?;
+			match txn.put(&a, &b) {
+				Ok(_) => {}
+				Err(e) => {
+					assert_eq!(e, LmdbFull);
+					// the failed transaction is aborted and unusable
+					assert_eq!(txn.put(&a, b"x").err(), Some(IllegalState));
+					err += 1;
+					db.resize(1024 * 1024 * 10)?;
+					continue;
 				}
 			}
+			txn.commit()?;
+			break;
 		}
-		remove_lmdb_test_dir(db_dir)?;
 		assert_eq!(err, 1);
+		assert_eq!(db.size(), 1024 * 1024 * 10);
+		assert_eq!(db.read()?.get(&a)?.unwrap().len(), b.len());
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_lmdb_grow() -> Result<()> {
+		let db_size = 1024 * 1024;
+		let db_dir = "bin/.lmdb_grow";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", db_size)?;
+		let other = db.open("other", None)?;
+		let clone = db.try_clone()?;
+		assert_eq!(db.set_growth_factor(1.0).err(), Some(IllegalArgument));
+		db.set_growth_factor(1.5)?;
+
+		// a transaction of the calling thread can't end while it waits
+		db.set_resize_timeout(50)?;
+		{
+			let _txn = clone.read()?;
+			assert_eq!(db.grow().err(), Some(LmdbBusy));
+			assert_eq!(db.resize(db_size * 2).err(), Some(LmdbBusy));
+		}
+		assert_eq!(db.size(), db_size);
+		db.set_resize_timeout(LMDB_DEFAULT_RESIZE_TIMEOUT)?;
+
+		// a reader in another thread holds up growth until it's done
+		let state = Rc::new(0u64)?;
+		let (mut state1, reader) = (state.clone(), clone.try_clone()?);
+		let mut jh = spawnj(move || {
+			let _txn = reader.read().unwrap();
+			aadd!(&mut *state1, 1);
+			sleep(100);
+			aadd!(&mut *state1, 1);
+		})?;
+		while aload!(&*state) == 0 {
+			sleep(1);
+		}
+
+		// four megabytes in one transaction
+		let mut value = Vec::new();
+		value.resize(64 * 1024)?;
+		let mut calls = 0;
+		db.update(|txn| {
+			calls += 1;
+			for i in 0..64u8 {
+				value[0] = i;
+				txn.put(&[i], &value)?;
+				txn.db(&other)?.put(&[i], &[i])?;
+			}
+			Ok(())
+		})?;
+		assert!(calls > 1);
+		assert_eq!(aload!(&*state), 2);
+		jh.join()?;
+
+		// every handle sees the new size and the data
+		assert!(db.size() > 4 * 1024 * 1024);
+		assert_eq!(db.size() % LMDB_MAP_ALIGN, 0);
+		assert_eq!(clone.size(), db.size());
+		assert_eq!(other.size(), db.size());
+		{
+			let txn = clone.read()?;
+			for i in 0..64u8 {
+				assert_eq!(txn.get(&[i])?.unwrap()[0], i);
+				assert_eq!(txn.db(&other)?.get(&[i])?, Some(&[i][..]));
+			}
+		}
 
+		// errors other than a full map are returned as is
+		assert_eq!(
+			other
+				.update(|_txn| -> Result<()> { err!(IllegalState) })
+				.err(),
+			Some(IllegalState)
+		);
+		remove_lmdb_test_dir(db_dir)?;
 		Ok(())
 	}
-		*/
 
 	#[test]
 	fn test_lmdb_multi() -> Result<()> {
@@ -305,4 +571,111 @@ mod test {
 		remove_lmdb_test_dir(db_dir)?;
 		Ok(())
 	}
+
+	#[test]
+	fn test_lmdb_named_dbs() -> Result<()> {
+		let db_size = 1024 * 1024 * 100;
+		let db_dir = "bin/.lmdb_named";
+		make_lmdb_test_dir(db_dir)?;
+		let mut db = Lmdb::new(db_dir, "mydb", db_size)?;
+		let headers = db.open("headers", None)?;
+		let bodies = db.open("bodies", None)?;
+		let key = String::new("k1")?;
+
+		// one transaction writes the same key to each database
+		{
+			let mut txn = headers.write()?;
+			txn.put(&key, b"header")?;
+			txn.db(&bodies)?.put(&key, b"body")?;
+			txn.db(&db)?.put(&key, b"default")?;
+			txn.db(&bodies)?.put(b"k2", b"body2")?;
+			txn.commit()?;
+		}
+
+		{
+			let txn = db.read()?;
+			assert_eq!(txn.get(&key)?, Some(&b"default"[..]));
+			assert_eq!(txn.db(&headers)?.get(&key)?, Some(&b"header"[..]));
+			assert_eq!(txn.db(&bodies)?.get(&key)?, Some(&b"body"[..]));
+			assert_eq!(txn.db(&headers)?.get(b"k2")?, None);
+
+			// each database has its own cursors
+			let mut count = 0;
+			for (k, _) in txn.db(&bodies)?.iter(b"k")? {
+				assert!(k[0] == b'k');
+				count += 1;
+			}
+			assert_eq!(count, 2);
+			assert_eq!(txn.db(&headers)?.iter(b"k")?.count(), 1);
+		}
+
+		// an uncommitted transaction is rolled back in every database
+		{
+			let mut txn = bodies.write()?;
+			txn.del(&key)?;
+			txn.db(&headers)?.put(b"k3", b"header3")?;
+		}
+		{
+			let txn = headers.read()?;
+			assert_eq!(txn.get(b"k3")?, None);
+			assert_eq!(txn.db(&bodies)?.get(&key)?, Some(&b"body"[..]));
+		}
+
+		// views of a committed transaction can't be used
+		{
+			let txn = headers.write()?;
+			let mut view = txn.db(&bodies)?;
+			txn.commit()?;
+			assert_eq!(view.put(&key, b"x").err(), Some(IllegalState));
+		}
+
+		// a database can be opened in a write transaction already held
+		{
+			let txn = db.write()?;
+			let names = db.open("names", Some(txn.clone()))?;
+			txn.db(&names)?.put(b"k", b"v")?;
+			txn.commit()?;
+			assert_eq!(names.read()?.get(b"k")?, Some(&b"v"[..]));
+		}
+		// but not in a read transaction or in one of another environment
+		assert_eq!(db.open("names", Some(db.read()?)).err(), Some(IllegalState));
+		let other_dir = "bin/.lmdb_named_other";
+		make_lmdb_test_dir(other_dir)?;
+		{
+			let other = Lmdb::new(other_dir, "mydb", db_size)?;
+			let txn = other.write()?;
+			assert_eq!(txn.db(&headers).err(), Some(IllegalArgument));
+			assert_eq!(
+				db.open("names", Some(txn.clone())).err(),
+				Some(IllegalArgument)
+			);
+		}
+		remove_lmdb_test_dir(other_dir)?;
+
+		// handles stay valid across a resize and the databases persist
+		db.resize(db_size * 2)?;
+		{
+			let txn = db.read()?;
+			assert_eq!(txn.db(&headers)?.get(&key)?, Some(&b"header"[..]));
+			assert_eq!(txn.db(&bodies)?.get(&key)?, Some(&b"body"[..]));
+		}
+		db.close();
+
+		let db = Lmdb::new(db_dir, "mydb", db_size)?;
+		let bodies = db.open("bodies", None)?;
+		assert_eq!(bodies.read()?.get(b"k2")?, Some(&b"body2"[..]));
+
+		// mydb and bodies plus eight more reach MDB_MAX_DBS
+		let mut handles = Vec::new();
+		for i in 2..MDB_MAX_DBS {
+			let name = format!("db{}", i)?;
+			handles.push(db.open(name.as_str(), None)?)?;
+		}
+		assert_eq!(db.open("one_too_many", None).err(), Some(LmdbDbsFull));
+		// opening an existing name again is fine
+		assert!(db.open("db2", None).is_ok());
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
 }
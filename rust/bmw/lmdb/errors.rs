@@ -9,5 +9,7 @@ errors!(
 	LmdbOpen,
 	LmdbBeginTxn,
 	LmdbCreate,
-	LmdbDel
+	LmdbDel,
+	LmdbDbsFull,
+	LmdbBusy
 );
@@ -14,6 +14,7 @@ extern "C" {
 	) -> i32;
 	pub fn mdb_txn_commit(txn: *mut MDB_txn) -> i32;
 	pub fn mdb_txn_abort(txn: *mut MDB_txn);
+	pub fn mdb_txn_env(txn: *mut MDB_txn) -> *mut MDB_env;
 	pub fn mdb_dbi_open(txn: *mut MDB_txn, name: *const u8, flags: u32, dbi: *mut MDB_dbi) -> i32;
 	pub fn mdb_put(
 		txn: *mut MDB_txn,
@@ -1,10 +1,13 @@
 use core::convert::AsRef;
 use core::iter::Iterator;
+use core::ops::Bound::{self, Excluded, Included, Unbounded};
 use core::ptr::null_mut;
 use core::slice::from_raw_parts;
 use lmdb::constants::{
-	MDB_GET_CURRENT, MDB_MAP_FULL, MDB_NEXT, MDB_NOTFOUND, MDB_PREV, MDB_SET_RANGE, MDB_SUCCESS,
+	MDB_GET_CURRENT, MDB_LAST, MDB_MAP_FULL, MDB_NEXT, MDB_NOTFOUND, MDB_PREV, MDB_SET_RANGE,
+	MDB_SUCCESS,
 };
+use lmdb::db::Lmdb;
 use lmdb::errors::*;
 use lmdb::ffi::*;
 use lmdb::types::{MDB_cursor, MDB_dbi, MDB_txn, MDB_val};
@@ -14,6 +17,13 @@ use util::cstring::CString;
 
 struct LmdbTxnInner {
 	txn: *mut MDB_txn,
+	// the environment's count of open transactions
+	active: Option<Rc<u64>>,
+	// for savepoints, the transaction they are nested in
+	parent: Option<Rc<LmdbTxnInner>>,
+	// lmdb doesn't allow a transaction to be used while it has an open
+	// child
+	child: bool,
 }
 
 pub struct LmdbTxn {
@@ -26,15 +36,41 @@ pub struct LmdbCursor {
 	cursor: *mut MDB_cursor,
 	prefix: CString,
 	is_first: bool,
+	// range scans: the bounds and whether each is inclusive
+	start: Option<(Vec<u8>, bool)>,
+	end: Option<(Vec<u8>, bool)>,
+	reverse: bool,
+	// set when there was nothing to position the cursor on
+	exhausted: bool,
 }
 
 impl Drop for LmdbTxnInner {
 	fn drop(&mut self) {
-		unsafe {
-			if !self.txn.is_null() {
-				mdb_txn_abort(self.txn);
-				self.txn = null_mut();
+		self.end(true);
+	}
+}
+
+impl LmdbTxnInner {
+	// a transaction ends when it is committed or aborted. A committed
+	// transaction is freed by lmdb even if the commit fails.
+	fn end(&mut self, abort: bool) {
+		if !self.txn.is_null() {
+			if abort {
+				unsafe {
+					mdb_txn_abort(self.txn);
+				}
+			}
+			self.txn = null_mut();
+		}
+		match self.active.take() {
+			Some(mut active) => {
+				asub!(&mut *active, 1);
 			}
+			None => {}
+		}
+		match self.parent.take() {
+			Some(mut parent) => parent.child = false,
+			None => {}
 		}
 	}
 }
@@ -59,113 +95,119 @@ impl Iterator for LmdbCursor {
 	type Item = (Vec<u8>, Vec<u8>);
 
 	fn next(&mut self) -> Option<Self::Item> {
-		if self.cursor.is_null() {
-			return None;
+		let (key, value) = match self.next_ref() {
+			Ok(Some(kv)) => kv,
+			_ => return None,
+		};
+		let mut key_vec = Vec::new();
+		match key_vec.extend_from_slice(key) {
+			Ok(_) => {}
+			Err(_e) => return None,
 		}
-		unsafe {
-			let mut key_val = MDB_val {
-				mv_size: self.prefix.len(),
-				mv_data: self.prefix.as_mut_ptr(),
-			};
-
-			let mut data_val = MDB_val {
-				mv_size: 0,
-				mv_data: null_mut(),
-			};
-
-			let rc = if self.is_first {
-				self.is_first = false;
-				mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_GET_CURRENT)
-			} else {
-				mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_NEXT)
-			};
-
-			if rc != MDB_SUCCESS {
-				return None;
-			}
-
-			let key_slice = from_raw_parts(key_val.mv_data, key_val.mv_size);
-			let mut key_vec = Vec::new();
-			match key_vec.extend_from_slice(key_slice) {
-				Ok(_) => {}
-				Err(_e) => return None,
-			}
-
-			let prefix_slice = from_raw_parts(self.prefix.as_ptr(), self.prefix.len());
-			if !slice_starts_with(key_vec.as_ref(), prefix_slice) {
-				return None;
-			}
-
-			let data_slice = from_raw_parts(data_val.mv_data, data_val.mv_size);
-			let mut data_vec = Vec::new();
-			match data_vec.extend_from_slice(data_slice) {
-				Ok(_) => {}
-				Err(_e) => return None,
-			}
-
-			Some((key_vec, data_vec))
+		let mut data_vec = Vec::new();
+		match data_vec.extend_from_slice(value) {
+			Ok(_) => {}
+			Err(_e) => return None,
 		}
+		Some((key_vec, data_vec))
 	}
 }
 
 impl LmdbCursor {
-	pub fn next_bytes(
-		&mut self,
-		key: &mut [u8],
-		value: &mut [u8],
-	) -> Result<Option<(usize, usize)>> {
+	// the next entry without copying it. The slices point into the map and
+	// are only valid until the cursor moves.
+	pub fn next_ref(&mut self) -> Result<Option<(&[u8], &[u8])>> {
 		if self.cursor.is_null() {
 			return err!(IllegalState);
 		}
-		unsafe {
+		if self.exhausted {
+			return Ok(None);
+		}
+		loop {
 			let mut key_val = MDB_val {
 				mv_size: 0,
 				mv_data: null_mut(),
 			};
-
 			let mut data_val = MDB_val {
 				mv_size: 0,
 				mv_data: null_mut(),
 			};
-
-			let rc = if self.is_first {
+			let op = if self.is_first {
 				self.is_first = false;
-				mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_GET_CURRENT)
+				MDB_GET_CURRENT
+			} else if self.reverse {
+				MDB_PREV
 			} else {
-				mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_NEXT)
+				MDB_NEXT
 			};
-
+			let rc = unsafe { mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, op) };
 			if rc == MDB_NOTFOUND {
 				return Ok(None);
 			} else if rc != MDB_SUCCESS {
 				return err!(LmdbCursor);
 			}
 
-			let key_len = if key_val.mv_size > key.len() {
-				key.len()
-			} else {
-				key_val.mv_size
-			};
-
-			let key_slice = from_raw_parts(key_val.mv_data, key_val.mv_size);
-
-			let prefix_slice = from_raw_parts(self.prefix.as_ptr(), self.prefix.len());
-			if !slice_starts_with(key_slice, prefix_slice) {
+			let key = unsafe { from_raw_parts(key_val.mv_data, key_val.mv_size) };
+			if !self.has_prefix(key) {
 				return Ok(None);
 			}
+			// entries outside the range are skipped until the range is
+			// reached and end the scan once it has been left
+			if self.before_start(key) {
+				if self.reverse {
+					return Ok(None);
+				}
+				continue;
+			}
+			if self.after_end(key) {
+				if self.reverse {
+					continue;
+				}
+				return Ok(None);
+			}
+			let value = unsafe { from_raw_parts(data_val.mv_data, data_val.mv_size) };
+			return Ok(Some((key, value)));
+		}
+	}
 
-			slice_copy(key_slice, key, key_len)?;
+	pub fn next_bytes(
+		&mut self,
+		key: &mut [u8],
+		value: &mut [u8],
+	) -> Result<Option<(usize, usize)>> {
+		let (key_slice, data_slice) = match self.next_ref()? {
+			Some(kv) => kv,
+			None => return Ok(None),
+		};
 
-			let value_len = if data_val.mv_size > value.len() {
-				value.len()
-			} else {
-				data_val.mv_size
-			};
+		let key_len = if key_slice.len() > key.len() {
+			key.len()
+		} else {
+			key_slice.len()
+		};
+		slice_copy(key_slice, key, key_len)?;
+
+		let value_len = if data_slice.len() > value.len() {
+			value.len()
+		} else {
+			data_slice.len()
+		};
+		slice_copy(data_slice, value, value_len)?;
 
-			let data_slice = from_raw_parts(data_val.mv_data, data_val.mv_size);
-			slice_copy(data_slice, value, value_len)?;
+		Ok(Some((key_slice.len(), data_slice.len())))
+	}
 
-			Ok(Some((key_val.mv_size, data_val.mv_size)))
+	// positions the cursor so that the next entry is the first one at or
+	// after `key`, or for reverse cursors the last one at or before it.
+	// The range of the cursor still applies.
+	pub fn seek(&mut self, key: &[u8]) -> Result<()> {
+		if self.cursor.is_null() {
+			return err!(IllegalState);
+		}
+		if self.reverse {
+			self.position_last(key, false)
+		} else {
+			self.position_first(key)
 		}
 	}
 
@@ -180,13 +222,14 @@ impl LmdbCursor {
 		};
 
 		// If at initial position, no previous key to rewind to
-		if self.is_first {
+		if self.is_first || self.exhausted {
 			self.close();
 			return err!(OutOfBounds);
 		}
 
 		// Move to previous key
-		let res = unsafe { mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_PREV) };
+		let op = if self.reverse { MDB_NEXT } else { MDB_PREV };
+		let res = unsafe { mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, op) };
 		if res == MDB_NOTFOUND {
 			self.is_first = true;
 			Ok(())
@@ -194,9 +237,13 @@ impl LmdbCursor {
 			self.close();
 			err!(LmdbCursor)
 		} else {
-			let key_slice = unsafe { from_raw_parts(key_val.mv_data, key_val.mv_size) };
-			let prefix_slice = unsafe { from_raw_parts(self.prefix.as_ptr(), self.prefix.len()) };
-			if !slice_starts_with(key_slice, prefix_slice) {
+			let key = unsafe { from_raw_parts(key_val.mv_data, key_val.mv_size) };
+			let outside = if self.reverse {
+				self.after_end(key)
+			} else {
+				self.before_start(key)
+			};
+			if !self.has_prefix(key) || outside {
 				self.close();
 				return err!(OutOfBounds);
 			}
@@ -205,6 +252,160 @@ impl LmdbCursor {
 		}
 	}
 
+	fn new(
+		cursor: *mut MDB_cursor,
+		prefix: &[u8],
+		start: Bound<&[u8]>,
+		end: Bound<&[u8]>,
+		reverse: bool,
+	) -> Result<Self> {
+		let mut ret = Self {
+			cursor,
+			prefix: CString::from_slice(b"")?,
+			is_first: true,
+			start: None,
+			end: None,
+			reverse,
+			exhausted: false,
+		};
+		// the cursor is closed when ret is dropped
+		ret.prefix = CString::from_slice(prefix)?;
+		ret.start = Self::bound(start)?;
+		ret.end = Self::bound(end)?;
+
+		if reverse {
+			match end {
+				Included(key) => ret.position_last(key, false)?,
+				Excluded(key) => ret.position_last(key, true)?,
+				Unbounded => match Self::successor(prefix)? {
+					Some(key) => ret.position_last(&key, true)?,
+					None => ret.position(MDB_LAST)?,
+				},
+			}
+		} else {
+			match start {
+				Included(key) | Excluded(key) => ret.position_first(key)?,
+				Unbounded => ret.position_first(prefix)?,
+			}
+		}
+		Ok(ret)
+	}
+
+	fn bound(b: Bound<&[u8]>) -> Result<Option<(Vec<u8>, bool)>> {
+		let (key, inclusive) = match b {
+			Included(key) => (key, true),
+			Excluded(key) => (key, false),
+			Unbounded => return Ok(None),
+		};
+		let mut v = Vec::new();
+		v.extend_from_slice(key)?;
+		Ok(Some((v, inclusive)))
+	}
+
+	// the smallest key greater than every key starting with `prefix`, None
+	// if there isn't one
+	fn successor(prefix: &[u8]) -> Result<Option<Vec<u8>>> {
+		let mut ret = Vec::new();
+		ret.extend_from_slice(prefix)?;
+		while ret.len() > 0 {
+			let last = ret.len() - 1;
+			if ret[last] != 0xff {
+				ret[last] += 1;
+				return Ok(Some(ret));
+			}
+			ret.truncate(last)?;
+		}
+		Ok(None)
+	}
+
+	fn position(&mut self, op: u32) -> Result<()> {
+		let mut key_val = MDB_val {
+			mv_size: 0,
+			mv_data: null_mut(),
+		};
+		let mut data_val = MDB_val {
+			mv_size: 0,
+			mv_data: null_mut(),
+		};
+		let rc = unsafe { mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, op) };
+		if rc != MDB_SUCCESS && rc != MDB_NOTFOUND {
+			return err!(LmdbCursor);
+		}
+		self.is_first = true;
+		self.exhausted = rc == MDB_NOTFOUND;
+		Ok(())
+	}
+
+	// at the first key >= `key`
+	fn position_first(&mut self, key: &[u8]) -> Result<()> {
+		let mut key_val = MDB_val {
+			mv_size: key.len(),
+			mv_data: key.as_ptr() as *mut u8,
+		};
+		let mut data_val = MDB_val {
+			mv_size: 0,
+			mv_data: null_mut(),
+		};
+		let rc = unsafe { mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_SET_RANGE) };
+		if rc != MDB_SUCCESS && rc != MDB_NOTFOUND {
+			return err!(LmdbCursor);
+		}
+		self.is_first = true;
+		self.exhausted = rc == MDB_NOTFOUND;
+		Ok(())
+	}
+
+	// at the last key <= `key`, or < `key` if `exclusive`
+	fn position_last(&mut self, key: &[u8], exclusive: bool) -> Result<()> {
+		let mut key_val = MDB_val {
+			mv_size: key.len(),
+			mv_data: key.as_ptr() as *mut u8,
+		};
+		let mut data_val = MDB_val {
+			mv_size: 0,
+			mv_data: null_mut(),
+		};
+		let rc = unsafe { mdb_cursor_get(self.cursor, &mut key_val, &mut data_val, MDB_SET_RANGE) };
+		if rc == MDB_NOTFOUND {
+			return self.position(MDB_LAST);
+		} else if rc != MDB_SUCCESS {
+			return err!(LmdbCursor);
+		}
+		let found = unsafe { from_raw_parts(key_val.mv_data, key_val.mv_size) };
+		if found > key || (exclusive && found == key) {
+			self.position(MDB_PREV)?;
+		} else {
+			self.is_first = true;
+			self.exhausted = false;
+		}
+		Ok(())
+	}
+
+	fn has_prefix(&self, key: &[u8]) -> bool {
+		let prefix = unsafe { from_raw_parts(self.prefix.as_ptr(), self.prefix.len()) };
+		slice_starts_with(key, prefix)
+	}
+
+	fn before_start(&self, key: &[u8]) -> bool {
+		match &self.start {
+			Some((start, inclusive)) => {
+				let start: &[u8] = start;
+				key < start || (!inclusive && key == start)
+			}
+			None => false,
+		}
+	}
+
+	fn after_end(&self, key: &[u8]) -> bool {
+		match &self.end {
+			Some((end, inclusive)) => {
+				let end: &[u8] = end;
+				key > end || (!inclusive && key == end)
+			}
+			None => false,
+		}
+	}
+
 	fn close(&mut self) {
 		if !self.cursor.is_null() {
 			unsafe {
@@ -216,54 +417,88 @@ impl LmdbCursor {
 }
 
 impl LmdbTxn {
-	pub fn new(txn: *mut MDB_txn, dbi: MDB_dbi, write: bool) -> Result<Self> {
+	pub fn new(txn: *mut MDB_txn, dbi: MDB_dbi, write: bool, active: Rc<u64>) -> Result<Self> {
+		match Rc::new(LmdbTxnInner {
+			txn,
+			active: Some(active),
+			parent: None,
+			child: false,
+		}) {
+			Ok(inner) => Ok(Self {
+				txn: inner,
+				dbi,
+				write,
+			}),
+			Err(e) => {
+				unsafe {
+					mdb_txn_abort(txn);
+				}
+				Err(e)
+			}
+		}
+	}
+
+	pub fn ptr(&self) -> *mut MDB_txn {
+		self.txn.txn
+	}
+
+	// this transaction on another database of the same environment. The
+	// views share the transaction: a commit through any of them commits
+	// the writes to every database. Fails with IllegalArgument if `db` is
+	// a handle on another environment.
+	pub fn db(&self, db: &Lmdb) -> Result<LmdbTxn> {
+		self.check_env(db)?;
 		Ok(Self {
-			txn: Rc::new(LmdbTxnInner { txn })?,
-			dbi,
-			write,
+			txn: self.txn.clone(),
+			dbi: db.dbi(),
+			write: self.write,
 		})
 	}
 
+	pub fn is_write(&self) -> bool {
+		self.write
+	}
+
+	// a cursor over the keys starting with `key`
 	pub fn iter<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Result<LmdbCursor> {
-		if self.txn.txn.is_null() {
-			return err!(IllegalState);
-		}
-		unsafe {
-			let mut cursor: *mut MDB_cursor = null_mut();
-			let rc = mdb_cursor_open(self.txn.txn, self.dbi, &mut cursor);
-			if rc != MDB_SUCCESS {
-				return err!(LmdbCursor);
-			}
+		self.cursor(key.as_ref(), Unbounded, Unbounded, false)
+	}
 
-			// Create prefix from raw bytes, supporting binary data
-			let key_bytes = key.as_ref();
-			let prefix_cstr = CString::from_slice(key_bytes)?;
+	// the keys starting with `key` from last to first
+	pub fn iter_rev<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Result<LmdbCursor> {
+		self.cursor(key.as_ref(), Unbounded, Unbounded, true)
+	}
 
-			// Position cursor at key or next
-			let mut key_val = MDB_val {
-				mv_size: key_bytes.len(),
-				mv_data: key_bytes.as_ptr() as *mut u8,
-			};
-			let mut data_val = MDB_val {
-				mv_size: 0,
-				mv_data: null_mut(),
-			};
-			let rc = mdb_cursor_get(cursor, &mut key_val, &mut data_val, MDB_SET_RANGE);
-			if rc != MDB_SUCCESS && rc != MDB_NOTFOUND {
-				mdb_cursor_close(cursor);
-				return err!(LmdbCursor);
-			}
+	// a cursor over the keys between `start` and `end`
+	pub fn range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<LmdbCursor> {
+		self.cursor(b"", start, end, false)
+	}
+
+	// the keys between `start` and `end` from last to first
+	pub fn range_rev(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<LmdbCursor> {
+		self.cursor(b"", start, end, true)
+	}
 
-			Ok(LmdbCursor {
-				cursor,
-				prefix: prefix_cstr,
-				is_first: true,
-			})
+	fn cursor(
+		&self,
+		prefix: &[u8],
+		start: Bound<&[u8]>,
+		end: Bound<&[u8]>,
+		reverse: bool,
+	) -> Result<LmdbCursor> {
+		if !self.usable() {
+			return err!(IllegalState);
 		}
+		let mut cursor: *mut MDB_cursor = null_mut();
+		let rc = unsafe { mdb_cursor_open(self.txn.txn, self.dbi, &mut cursor) };
+		if rc != MDB_SUCCESS {
+			return err!(LmdbCursor);
+		}
+		LmdbCursor::new(cursor, prefix, start, end, reverse)
 	}
 
 	pub fn get<K: AsRef<[u8]> + ?Sized>(&self, key: &K) -> Result<Option<&[u8]>> {
-		if self.txn.txn.is_null() {
+		if !self.usable() {
 			return err!(IllegalState);
 		}
 		let mut key_val = MDB_val {
@@ -292,7 +527,7 @@ impl LmdbTxn {
 		key: &K,
 		value: &V,
 	) -> Result<()> {
-		if self.txn.txn.is_null() || !self.write {
+		if !self.usable() || !self.write {
 			return err!(IllegalState);
 		}
 		let mut key_val = MDB_val {
@@ -308,8 +543,8 @@ impl LmdbTxn {
 			if r == MDB_SUCCESS {
 				Ok(())
 			} else if r == MDB_MAP_FULL {
-				// set txn to null because drop should not abort in this case
-				self.txn.txn = null_mut();
+				// the transaction can't be used any more
+				self.txn.end(true);
 				return err!(LmdbFull);
 			} else {
 				return err!(LmdbPut);
@@ -318,7 +553,7 @@ impl LmdbTxn {
 	}
 
 	pub fn del<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K) -> Result<bool> {
-		if self.txn.txn.is_null() || !self.write {
+		if !self.usable() || !self.write {
 			return err!(IllegalState);
 		}
 		let mut key_val = MDB_val {
@@ -332,8 +567,8 @@ impl LmdbTxn {
 			} else if r == MDB_NOTFOUND {
 				Ok(false)
 			} else if r == MDB_MAP_FULL {
-				// set txn to null because drop should not abort in this case
-				self.txn.txn = null_mut();
+				// the transaction can't be used any more
+				self.txn.end(true);
 				return err!(LmdbFull);
 			} else {
 				return err!(LmdbDel);
@@ -341,22 +576,79 @@ impl LmdbTxn {
 		}
 	}
 
-	pub fn commit(mut self) -> Result<()> {
-		if self.txn.txn.is_null() || !self.write {
+	// begins a transaction nested in this one. Committing it applies its
+	// writes to this transaction and aborting or dropping it discards them,
+	// either way without ending this transaction. This transaction can't
+	// be used until the savepoint ends.
+	pub fn savepoint(&self) -> Result<LmdbTxn> {
+		if !self.usable() || !self.write {
 			return err!(IllegalState);
 		}
-		unsafe {
-			let r = mdb_txn_commit(self.txn.txn);
-			if r != MDB_SUCCESS {
-				if r == MDB_MAP_FULL {
-					self.txn.txn = null_mut();
-					return err!(LmdbFull);
-				} else {
-					return err!(LmdbCommit);
+		let mut txn: *mut MDB_txn = null_mut();
+		let rc = unsafe {
+			let env = mdb_txn_env(self.txn.txn);
+			mdb_txn_begin(env, self.txn.txn, 0, &mut txn)
+		};
+		if rc != MDB_SUCCESS {
+			return err!(LmdbBeginTxn);
+		}
+		let inner = match Rc::new(LmdbTxnInner {
+			txn,
+			active: None,
+			parent: Some(self.txn.clone()),
+			child: false,
+		}) {
+			Ok(inner) => inner,
+			Err(e) => {
+				unsafe {
+					mdb_txn_abort(txn);
 				}
+				return Err(e);
 			}
+		};
+		let mut parent = self.txn.clone();
+		parent.child = true;
+		Ok(Self {
+			txn: inner,
+			dbi: self.dbi,
+			write: true,
+		})
+	}
+
+	// discards the writes of the transaction. Clones of it can't be used
+	// afterwards.
+	pub fn abort(mut self) {
+		self.txn.end(true);
+	}
+
+	pub fn commit(mut self) -> Result<()> {
+		if !self.usable() || !self.write {
+			return err!(IllegalState);
+		}
+		let r = unsafe { mdb_txn_commit(self.txn.txn) };
+		self.txn.end(false);
+		if r == MDB_MAP_FULL {
+			err!(LmdbFull)
+		} else if r != MDB_SUCCESS {
+			err!(LmdbCommit)
+		} else {
+			Ok(())
+		}
+	}
+
+	fn usable(&self) -> bool {
+		!self.txn.txn.is_null() && !self.txn.child
+	}
+
+	// fails with IllegalArgument unless `db` is a handle on the environment
+	// of this transaction
+	pub(crate) fn check_env(&self, db: &Lmdb) -> Result<()> {
+		if self.txn.txn.is_null() {
+			return err!(IllegalState);
+		}
+		if unsafe { mdb_txn_env(self.txn.txn) } != db.env_ptr() {
+			return err!(IllegalArgument);
 		}
-		self.txn.txn = null_mut();
 		Ok(())
 	}
 }
@@ -366,7 +658,6 @@ pub mod test {
 	use super::*;
 	use core::convert::AsMut;
 	use ffi::{mkdir, rmdir, unlink};
-	use lmdb::db::Lmdb;
 
 	pub fn make_lmdb_test_dir(s: &str) -> Result<()> {
 		remove_lmdb_test_dir(s)?;
@@ -788,6 +1079,134 @@ pub mod test {
 		Ok(())
 	}
 
+	#[test]
+	fn test_range_and_reverse() -> Result<()> {
+		let db_dir = "bin/.lmdb_range_and_reverse";
+		let db_size = 1024 * 1024 * 100;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+
+		{
+			let mut txn = db.write()?;
+			for i in 0..10u8 {
+				txn.put(&[b'h', i], &[i])?;
+			}
+			txn.put(&[b'z'], &[0xff])?;
+			txn.commit()?;
+		}
+
+		{
+			let txn = db.read()?;
+
+			let mut keys = Vec::new();
+			for (k, _v) in txn.range(Included(&[b'h', 2][..]), Excluded(&[b'h', 5][..]))? {
+				keys.push(k[1])?;
+			}
+			assert_eq!(keys, vec![2, 3, 4]?);
+
+			let mut keys = Vec::new();
+			for (k, _v) in txn.range(Excluded(&[b'h', 2][..]), Included(&[b'h', 5][..]))? {
+				keys.push(k[1])?;
+			}
+			assert_eq!(keys, vec![3, 4, 5]?);
+
+			let mut keys = Vec::new();
+			for (k, _v) in txn.range_rev(Included(&[b'h', 2][..]), Excluded(&[b'h', 5][..]))? {
+				keys.push(k[1])?;
+			}
+			assert_eq!(keys, vec![4, 3, 2]?);
+
+			let mut keys = Vec::new();
+			for (k, _v) in txn.iter_rev(&[b'h'])? {
+				keys.push(k[1])?;
+			}
+			assert_eq!(keys, vec![9, 8, 7, 6, 5, 4, 3, 2, 1, 0]?);
+
+			let mut iter = txn.range_rev(Unbounded, Unbounded)?;
+			assert_eq!(iter.next().unwrap().0, vec![b'z']?);
+			assert_eq!(iter.next().unwrap().0, vec![b'h', 9]?);
+
+			let mut iter = txn.iter(&[b'h'])?;
+			iter.seek(&[b'h', 7])?;
+			assert_eq!(iter.next_ref()?, Some((&[b'h', 7][..], &[7u8][..])));
+			assert_eq!(iter.next_ref()?, Some((&[b'h', 8][..], &[8u8][..])));
+			assert_eq!(iter.next_ref()?, Some((&[b'h', 9][..], &[9u8][..])));
+			assert_eq!(iter.next_ref()?, None);
+
+			let mut iter = txn.iter_rev(&[b'h'])?;
+			iter.seek(&[b'h', 1])?;
+			assert_eq!(iter.next().unwrap().0, vec![b'h', 1]?);
+			assert_eq!(iter.next().unwrap().0, vec![b'h', 0]?);
+			assert!(iter.next().is_none());
+
+			let mut iter = txn.range(Included(&[b'i'][..]), Unbounded)?;
+			assert_eq!(iter.next().unwrap().0, vec![b'z']?);
+			assert!(iter.next().is_none());
+		}
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_savepoint() -> Result<()> {
+		let db_dir = "bin/.lmdb_savepoint";
+		let db_size = 1024 * 1024 * 100;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+
+		{
+			let mut txn = db.write()?;
+			txn.put(b"a", b"1")?;
+
+			// an aborted savepoint leaves the parent as it was
+			let mut sp = txn.savepoint()?;
+			assert_eq!(txn.get(b"a").err(), Some(IllegalState));
+			sp.put(b"a", b"2")?;
+			sp.put(b"b", b"2")?;
+			assert_eq!(sp.get(b"a")?, Some(&b"2"[..]));
+			sp.abort();
+			assert_eq!(txn.get(b"a")?, Some(&b"1"[..]));
+			assert_eq!(txn.get(b"b")?, None);
+
+			// so does a dropped one
+			{
+				let mut sp = txn.savepoint()?;
+				sp.del(b"a")?;
+			}
+			assert_eq!(txn.get(b"a")?, Some(&b"1"[..]));
+
+			// savepoints nest and commit into their parent
+			let mut sp = txn.savepoint()?;
+			sp.put(b"c", b"3")?;
+			let mut sp2 = sp.savepoint()?;
+			sp2.put(b"d", b"4")?;
+			sp2.commit()?;
+			assert_eq!(sp.get(b"d")?, Some(&b"4"[..]));
+			sp.commit()?;
+			assert_eq!(txn.get(b"c")?, Some(&b"3"[..]));
+
+			let sp = txn.savepoint()?;
+			assert_eq!(txn.clone().commit().err(), Some(IllegalState));
+			sp.abort();
+			txn.commit()?;
+		}
+
+		{
+			let txn = db.read()?;
+			assert!(txn.savepoint().is_err());
+			assert_eq!(txn.get(b"a")?, Some(&b"1"[..]));
+			assert_eq!(txn.get(b"b")?, None);
+			assert_eq!(txn.get(b"c")?, Some(&b"3"[..]));
+			assert_eq!(txn.get(b"d")?, Some(&b"4"[..]));
+		}
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+
 	/*
 	#[test]
 	fn test_bad_txn_scenarios() -> Result<() > {
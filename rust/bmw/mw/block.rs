@@ -2,7 +2,7 @@
 
 use bible::Bible;
 use core::mem::size_of;
-use core::slice::from_raw_parts;
+use core::slice::{from_raw_parts, from_raw_parts_mut};
 use crypto::bip52::Bip52;
 use crypto::ctx::Ctx;
 use crypto::keys::{PublicKey, SecretKey};
@@ -77,6 +77,12 @@ pub struct ShadowHeader {
 	ki: [u8; 4],
 }
 
+// the bytes hashed for a header: the header followed by the verse selected by
+// prev_hash, zero padded
+pub(crate) struct HashBuffer {
+	buffer: [u8; 1024],
+}
+
 pub struct Block {
 	// The block's header
 	header: BlockHeader,
@@ -114,6 +120,133 @@ impl BlockHeader {
 		let _ = to_le_bytes_u64(seconds, &mut bytes);
 		[bytes[0], bytes[1], bytes[2], bytes[3], bytes[4]]
 	}
+
+	pub fn timestamp(&self) -> u64 {
+		let mut bytes = [0u8; 8];
+		let _ = slice_copy(&self.timestamp, &mut bytes, 5);
+		from_le_bytes_u64(&bytes).unwrap_or(0)
+	}
+
+	pub fn nonce(&self) -> u32 {
+		from_le_bytes_u32(&self.nonce).unwrap_or(0)
+	}
+
+	pub fn kp_proposed(&self) -> [u8; 4] {
+		self.kp_proposed
+	}
+
+	pub fn ki_proposed(&self) -> [u8; 4] {
+		self.ki_proposed
+	}
+
+	pub fn prev_hash(&self) -> [u8; 32] {
+		self.prev_hash
+	}
+
+	pub fn sync_state_hash(&self) -> [u8; 32] {
+		self.sync_state_hash
+	}
+
+	pub fn aux_data_hash(&self) -> [u8; 32] {
+		self.aux_data_hash
+	}
+
+	pub fn to_bytes(&self) -> [u8; BLOCK_HEADER_SIZE] {
+		let mut ret = [0u8; BLOCK_HEADER_SIZE];
+		let header_ptr = self as *const BlockHeader as *const u8;
+		let header_slice = unsafe { from_raw_parts(header_ptr, BLOCK_HEADER_SIZE) };
+		let _ = slice_copy(header_slice, &mut ret, BLOCK_HEADER_SIZE);
+		ret
+	}
+
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+		if bytes.len() != BLOCK_HEADER_SIZE {
+			return err!(IllegalArgument);
+		}
+		let mut ret = Self::new([0u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+		let header_ptr = &mut ret as *mut BlockHeader as *mut u8;
+		// all fields are byte arrays so any bytes are a valid header
+		let header_slice = unsafe { from_raw_parts_mut(header_ptr, BLOCK_HEADER_SIZE) };
+		slice_copy(bytes, header_slice, BLOCK_HEADER_SIZE)?;
+		Ok(ret)
+	}
+
+	pub(crate) fn set_nonce(&mut self, nonce: u32) {
+		let _ = to_le_bytes_u32(nonce, &mut self.nonce);
+	}
+
+	pub(crate) fn set_timestamp(&mut self, seconds: u64) {
+		self.timestamp = Self::timestamp_to_bytes_le(seconds);
+	}
+
+	pub(crate) fn set_aux_data_hash(&mut self, aux_data_hash: [u8; 32]) {
+		self.aux_data_hash = aux_data_hash;
+	}
+
+	pub(crate) fn set_votes(&mut self, kp_proposed: [u8; 4], ki_proposed: [u8; 4]) {
+		self.kp_proposed = kp_proposed;
+		self.ki_proposed = ki_proposed;
+	}
+
+	#[inline]
+	pub(crate) fn hash(&self, bip52: &Bip52, bible: &Bible) -> [u8; 32] {
+		self.hash_buffer(bible).hash(bip52)
+	}
+
+	// builds the buffer that is hashed for this header. Only the nonce and
+	// aux_data_hash change while mining so the buffer can be reused.
+	pub(crate) fn hash_buffer(&self, bible: &Bible) -> HashBuffer {
+		// largest verse is 533 bytes and header 114 so 1024 is enough
+		let mut buffer = [0u8; 1024];
+		let header_ptr = self as *const BlockHeader as *const u8;
+		let header_slice = unsafe { from_raw_parts(header_ptr, size_of::<BlockHeader>()) };
+		let end_prev_hash = match self.prev_hash.subslice(24, 8) {
+			Ok(eph) => eph,
+			Err(e) => exit!("unexpected error returned from subslice: {}", e),
+		};
+		let val = match from_le_bytes_u64(end_prev_hash) {
+			Ok(val) => val,
+			Err(e) => exit!("unexpected error returned from from_le_bytes_u64: {}", e),
+		};
+		let verse = bible.find_mod(val as usize);
+		let verse_ref = verse.as_ref();
+
+		// we know this is not out of bounds
+		let _ = slice_copy(header_slice, &mut buffer, header_slice.len());
+		let header_sub_slice =
+			match subslice_mut(&mut buffer, header_slice.len(), 1024 - header_slice.len()) {
+				Ok(s) => s,
+				Err(e) => exit!("unexpected error returned by sub_slice: {}", e),
+			};
+		let _ = slice_copy(verse_ref, header_sub_slice, verse.len());
+
+		HashBuffer { buffer }
+	}
+}
+
+impl HashBuffer {
+	pub(crate) fn set_nonce(&mut self, nonce: u32) {
+		match subslice_mut(&mut self.buffer, HEADER_NONCE_OFFSET, 4) {
+			Ok(s) => {
+				let _ = to_le_bytes_u32(nonce, s);
+			}
+			Err(e) => exit!("unexpected error returned by sub_slice: {}", e),
+		}
+	}
+
+	pub(crate) fn set_aux_data_hash(&mut self, aux_data_hash: [u8; 32]) {
+		match subslice_mut(&mut self.buffer, HEADER_AUX_DATA_HASH_OFFSET, 32) {
+			Ok(s) => {
+				let _ = slice_copy(&aux_data_hash, s, 32);
+			}
+			Err(e) => exit!("unexpected error returned by sub_slice: {}", e),
+		}
+	}
+
+	#[inline]
+	pub(crate) fn hash(&self, bip52: &Bip52) -> [u8; 32] {
+		bip52.hash(&self.buffer)
+	}
 }
 
 impl Block {
@@ -141,15 +274,17 @@ impl Block {
 		bible: &Bible,
 	) -> Result<[u8; 32]> {
 		let mut nonce = from_le_bytes_u32(&self.header.nonce)?;
+		let mut buffer = self.header.hash_buffer(bible);
 
-		for i in 0..iterations {
-			nonce = nonce.wrapping_add(i);
+		for _ in 0..iterations {
 			to_le_bytes_u32(nonce, &mut self.header.nonce)?;
-			let hash = self.calculate_hash(bip52, bible);
+			buffer.set_nonce(nonce);
+			let hash = buffer.hash(bip52);
 			if u256_less_than_or_equal(&target, &hash) {
 				// difficulty met - block found
 				return Ok(hash);
 			}
+			nonce = nonce.wrapping_add(1);
 		}
 		err!(NotFound)
 	}
@@ -174,19 +309,23 @@ impl Block {
 	}
 
 	pub fn finalize_header(&mut self) -> Result<()> {
-		// update timestamp
-		let timestamp = unsafe { getmicros() / 1_000_000u64 };
-		let timestamp = BlockHeader::timestamp_to_bytes_le(timestamp);
-		self.header.timestamp = timestamp;
+		self.finalize(None, None)
+	}
 
-		let sha3 = Sha3_256::new();
-		// TODO: currently we just hash the kernel merkle root, but we need to add in the
-		// other sync_state_hash.
-		let kmr = self.tx.kernel_merkle_root()?;
-		sha3.update(kmr.as_ref());
-		self.header.sync_state_hash = sha3.finalize();
+	// like finalize_header with a fixed timestamp instead of the current
+	// time, for blocks that must hash the same wherever they're built
+	pub(crate) fn finalize_header_at(&mut self, timestamp: u64) -> Result<()> {
+		self.finalize(Some(timestamp), None)
+	}
 
-		Ok(())
+	// finalizes the header committing to the state of the output pmmr after
+	// this block is applied
+	pub fn finalize_header_with_outputs(
+		&mut self,
+		output_peak_data_hash: [u8; 32],
+		output_last_pos: u64,
+	) -> Result<()> {
+		self.finalize(None, Some((output_peak_data_hash, output_last_pos)))
 	}
 
 	pub fn with_coinbase(&self, ctx: &Ctx, output_blind: &SecretKey, overage: u64) -> Result<Self> {
@@ -219,7 +358,7 @@ impl Block {
 
 		tx.merge(ctx, coinbase)?;
 		tx.set_offset_zero();
-		tx.validate(ctx, overage)?;
+		tx.validate_coinbase(ctx, overage)?;
 
 		// clone header
 		let header = self.header.clone();
@@ -230,33 +369,45 @@ impl Block {
 		self.tx.fees()
 	}
 
-	#[inline]
-	fn calculate_hash(&self, bip52: &Bip52, bible: &Bible) -> [u8; 32] {
-		// largest verse is 533 bytes and header 114 so 1024 is enough
-		let mut buffer = [0u8; 1024];
-		let header_ptr = &self.header as *const BlockHeader as *const u8;
-		let header_slice = unsafe { from_raw_parts(header_ptr, size_of::<BlockHeader>()) };
-		let end_prev_hash = match self.header.prev_hash.subslice(24, 8) {
-			Ok(eph) => eph,
-			Err(e) => exit!("unexpected error returned from subslice: {}", e),
-		};
-		let val = match from_le_bytes_u64(end_prev_hash) {
-			Ok(val) => val,
-			Err(e) => exit!("unexpected error returned from from_le_bytes_u64: {}", e),
+	pub fn tx(&self) -> &Transaction {
+		&self.tx
+	}
+
+	pub fn header(&self) -> &BlockHeader {
+		&self.header
+	}
+
+	pub(crate) fn set_header(&mut self, header: BlockHeader) {
+		self.header = header;
+	}
+
+	fn finalize(&mut self, timestamp: Option<u64>, outputs: Option<([u8; 32], u64)>) -> Result<()> {
+		// update timestamp
+		let timestamp = match timestamp {
+			Some(timestamp) => timestamp,
+			None => unsafe { getmicros() / 1_000_000u64 },
 		};
-		let verse = bible.find_mod(val as usize);
-		let verse_ref = verse.as_ref();
+		self.header.set_timestamp(timestamp);
 
-		// we know this is not out of bounds
-		let _ = slice_copy(header_slice, &mut buffer, header_slice.len());
-		let header_sub_slice =
-			match subslice_mut(&mut buffer, header_slice.len(), 1024 - header_slice.len()) {
-				Ok(s) => s,
-				Err(e) => exit!("unexpected error returned by sub_slice: {}", e),
-			};
-		let _ = slice_copy(verse_ref, header_sub_slice, verse.len());
+		let sha3 = Sha3_256::new();
+		// TODO: the bitmap data and bitmap rewind data merkle roots still need
+		// to be added to the sync_state_hash.
+		let kmr = self.tx.kernel_merkle_root()?;
+		sha3.update(kmr.as_ref());
+		if let Some((peak_data_hash, last_pos)) = outputs {
+			let mut last_pos_bytes = [0u8; 8];
+			to_le_bytes_u64(last_pos, &mut last_pos_bytes)?;
+			sha3.update(&peak_data_hash);
+			sha3.update(&last_pos_bytes);
+		}
+		self.header.sync_state_hash = sha3.finalize();
 
-		bip52.hash(&buffer)
+		Ok(())
+	}
+
+	#[inline]
+	fn calculate_hash(&self, bip52: &Bip52, bible: &Bible) -> [u8; 32] {
+		self.header.hash(bip52, bible)
 	}
 }
 
@@ -270,6 +421,15 @@ mod test {
 	#[test]
 	fn test_blocks1() -> Result<()> {
 		assert_eq!(size_of::<BlockHeader>(), 114);
+		assert_eq!(size_of::<BlockHeader>(), BLOCK_HEADER_SIZE);
+
+		let header = BlockHeader::new([1u8; 32], [2u8; 32], [3u8; 4], [4u8; 4]);
+		let bytes = header.to_bytes();
+		let header2 = BlockHeader::from_bytes(&bytes)?;
+		assert_eq!(header2.to_bytes(), bytes);
+		assert_eq!(header2.prev_hash(), [1u8; 32]);
+		assert_eq!(header2.aux_data_hash(), [2u8; 32]);
+		assert_eq!(BlockHeader::from_bytes(&bytes[1..]).err(), Some(IllegalArgument));
 		Ok(())
 	}
 
@@ -459,4 +619,33 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_hash_buffer() -> Result<()> {
+		let bible = Bible::new();
+		let bip52 = Bip52::new([1u8; 32], [77u8; 32]);
+		let mut header = BlockHeader::new([77u8; 32], [3u8; 32], [9u8; 4], [81u8; 4]);
+
+		// the offsets match the header layout
+		let base = &header as *const BlockHeader as usize;
+		assert_eq!(header.nonce.as_ptr() as usize - base, HEADER_NONCE_OFFSET);
+		assert_eq!(
+			header.aux_data_hash.as_ptr() as usize - base,
+			HEADER_AUX_DATA_HASH_OFFSET
+		);
+
+		let mut buffer = header.hash_buffer(&bible);
+		assert_eq!(buffer.hash(&bip52), header.hash(&bip52, &bible));
+		for i in 0..32u32 {
+			let mut aux_data_hash = [5u8; 32];
+			aux_data_hash[31] = i as u8;
+			header.set_nonce(i * 7919);
+			header.set_aux_data_hash(aux_data_hash);
+			buffer.set_nonce(i * 7919);
+			buffer.set_aux_data_hash(aux_data_hash);
+			assert_eq!(buffer.hash(&bip52), header.hash(&bip52, &bible));
+			assert_eq!(buffer.hash(&bip52), header.hash_buffer(&bible).hash(&bip52));
+		}
+		Ok(())
+	}
 }
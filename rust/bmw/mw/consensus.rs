@@ -0,0 +1,335 @@
+use bible::Bible;
+use crypto::bip52::Bip52;
+use crypto::ctx::Ctx;
+use crypto::keys::SecretKey;
+use crypto::pedersen::Commitment;
+use lmdb::txn::LmdbTxn;
+use mw::block::Block;
+use mw::constants::*;
+use mw::errors::*;
+use prelude::*;
+use store::{KernelMmr, Pmmr};
+
+// Coins are minted only by coinbases. Block `height` mints reward(height),
+// which starts at INITIAL_BLOCK_REWARD and halves every HALVING_INTERVAL
+// blocks until it reaches 0. The genesis block (height 0) is fixed by the
+// chain parameters and mints nothing.
+pub fn reward(height: u64) -> u64 {
+	let halvings = height / HALVING_INTERVAL;
+	if height == 0 || halvings >= 64 {
+		0
+	} else {
+		INITIAL_BLOCK_REWARD >> halvings
+	}
+}
+
+// the coins minted by blocks 0 through `height`
+pub fn emission(height: u64) -> u64 {
+	let mut total = 0;
+	let mut start = 1;
+	while start <= height {
+		let reward = reward(start);
+		if reward == 0 {
+			break;
+		}
+		// the first height of the next era
+		let end = (start / HALVING_INTERVAL + 1) * HALVING_INTERVAL;
+		if height < end {
+			total += reward * (height - start + 1);
+			break;
+		}
+		total += reward * (end - start);
+		start = end;
+	}
+	total
+}
+
+// the coins that will ever exist
+pub fn total_supply() -> u64 {
+	emission(u64::MAX)
+}
+
+// checks that the coinbase of the block at `height` mints exactly
+// reward(height). Fees may be paid to the coinbase but nothing more. Once
+// the reward is 0 the coinbase is paid only the fees.
+pub fn validate_reward(ctx: &Ctx, block: &Block, height: u64) -> Result<()> {
+	block.tx().validate_coinbase(ctx, reward(height))
+}
+
+// the checks of the block at `height` that don't depend on the chain
+// state: its hash meets `target` and its coinbase mints reward(height)
+pub fn validate_block(
+	ctx: &Ctx,
+	block: &Block,
+	height: u64,
+	bip52: &Bip52,
+	target: [u8; 32],
+	bible: &Bible,
+) -> Result<()> {
+	let hash = block.header().hash(bip52, bible);
+	block.validate_hash(bip52, target, hash, bible)?;
+	validate_reward(ctx, block, height)
+}
+
+// checks that `outputs` (all unspent outputs) are worth exactly
+// emission(height) given the `excesses` of every kernel and the sum of all
+// offsets. Each transaction balancing on its own doesn't rule out value
+// minted by a block that was never validated, e.g. before a sync horizon.
+pub fn audit_supply(
+	ctx: &Ctx,
+	outputs: &[Commitment],
+	excesses: &[Commitment],
+	offset: Option<&SecretKey>,
+	height: u64,
+) -> Result<()> {
+	let mut negative = Vec::with_capacity(outputs.len() + excesses.len() + 1)?;
+	for output in outputs {
+		negative.push(output.clone())?;
+	}
+	for excess in excesses {
+		negative.push(excess.clone())?;
+	}
+	if let Some(offset) = offset {
+		negative.push(ctx.commit(0, offset)?)?;
+	}
+	let negative = negative.slice(0, negative.len());
+	ctx.verify_balance_owned(&[], negative, -(emission(height) as i128))
+}
+
+// The whole chain check: the unspent outputs in `outputs` minus every
+// kernel in `kernels` and the sum of all block offsets `offset` must be
+// exactly the emission up to the height of the last block in `kernels`.
+// This is what a node synced from a horizon relies on instead of the
+// history, and what a running node can use as a periodic self-check.
+pub fn audit_chain(
+	ctx: &Ctx,
+	outputs: &Pmmr,
+	kernels: &KernelMmr,
+	offset: Option<&SecretKey>,
+	txn: &LmdbTxn,
+) -> Result<()> {
+	// fails if an unspent output can't be read, as in an output pmmr written
+	// before the leaf data was kept
+	let mut unspent = Vec::new();
+	for value in &outputs.leaf_values(txn)? {
+		unspent.push(Commitment::from_bytes(value)?)?;
+	}
+	let excesses = kernels.excesses(Some(txn.clone()))?;
+	match kernels.height(Some(txn.clone()))? {
+		Some(height) => audit_supply(
+			ctx,
+			unspent.slice(0, unspent.len()),
+			excesses.slice(0, excesses.len()),
+			offset,
+			height,
+		),
+		// nothing is minted before the genesis block
+		None => {
+			if unspent.len() == 0 && excesses.len() == 0 {
+				Ok(())
+			} else {
+				err!(ValidationFailed)
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use lmdb::db::Lmdb;
+	use lmdb::{make_lmdb_test_dir, remove_lmdb_test_dir};
+	use mw::keychain::KeyChain;
+	use mw::params::{ChainParams, Network};
+	use mw::slate::Slate;
+
+	// applies the block at `height` to the output and kernel mmrs
+	fn apply(
+		block: &Block,
+		height: u64,
+		outputs: &mut Pmmr,
+		kernels: &mut KernelMmr,
+		txn: &LmdbTxn,
+	) -> Result<()> {
+		for (output, _) in block.tx().outputs().iter() {
+			outputs.append(output.as_ref(), Some(txn.clone()))?;
+		}
+		for input in block.tx().inputs().iter() {
+			outputs.prune(input.as_ref(), Some(txn.clone()))?;
+		}
+		kernels.apply_block(height, block.tx(), Some(txn.clone()))
+	}
+
+	#[test]
+	fn test_reward() -> Result<()> {
+		assert_eq!(reward(0), 0);
+		assert_eq!(reward(1), INITIAL_BLOCK_REWARD);
+		assert_eq!(reward(HALVING_INTERVAL - 1), INITIAL_BLOCK_REWARD);
+		assert_eq!(reward(HALVING_INTERVAL), INITIAL_BLOCK_REWARD / 2);
+		assert_eq!(reward(HALVING_INTERVAL * 3 + 7), INITIAL_BLOCK_REWARD / 8);
+		assert_eq!(reward(HALVING_INTERVAL * 64), 0);
+		assert_eq!(reward(u64::MAX), 0);
+
+		assert_eq!(emission(0), 0);
+		assert_eq!(emission(1), INITIAL_BLOCK_REWARD);
+		assert_eq!(emission(10), INITIAL_BLOCK_REWARD * 10);
+		assert_eq!(
+			emission(HALVING_INTERVAL),
+			INITIAL_BLOCK_REWARD * (HALVING_INTERVAL - 1) + INITIAL_BLOCK_REWARD / 2
+		);
+
+		// the supply is the sum of every era and no more is ever minted
+		let mut supply = INITIAL_BLOCK_REWARD * (HALVING_INTERVAL - 1);
+		let mut era = 1;
+		while reward(era * HALVING_INTERVAL) != 0 {
+			supply += reward(era * HALVING_INTERVAL) * HALVING_INTERVAL;
+			era += 1;
+		}
+		assert_eq!(total_supply(), supply);
+		assert_eq!(emission(era * HALVING_INTERVAL), supply);
+		assert_eq!(emission(era * HALVING_INTERVAL - 1), supply);
+		assert!(total_supply() < INITIAL_BLOCK_REWARD * HALVING_INTERVAL * 2);
+		Ok(())
+	}
+
+	#[test]
+	fn test_audit_supply() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let keychain = KeyChain::from_seed([5u8; 48])?;
+		let mut outputs = Vec::new();
+		let mut excesses = Vec::new();
+
+		for height in 1..4u64 {
+			let blind = keychain.derive_key(&ctx, &[0, height]);
+			let block = Block::new([height as u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+			let block = block.with_coinbase(&ctx, &blind, reward(height))?;
+			assert!(validate_reward(&ctx, &block, height).is_ok());
+			for (output, _) in block.tx().outputs().iter() {
+				outputs.push(output.clone())?;
+			}
+			for kernel in block.tx().kernels().iter() {
+				excesses.push(kernel.excess().clone())?;
+			}
+		}
+		let outputs = outputs.slice(0, outputs.len());
+		let excesses = excesses.slice(0, excesses.len());
+		assert!(audit_supply(&ctx, outputs, excesses, None, 3).is_ok());
+		assert!(audit_supply(&ctx, outputs, excesses, None, 2).is_err());
+		assert!(audit_supply(&ctx, outputs, excesses, None, 4).is_err());
+		// a missing kernel or output breaks the sum
+		assert!(audit_supply(&ctx, outputs, &excesses[0..2], None, 3).is_err());
+		assert!(audit_supply(&ctx, &outputs[1..3], excesses, None, 3).is_err());
+		// an offset that isn't part of the chain breaks it too
+		let offset = SecretKey::gen(&ctx);
+		assert!(audit_supply(&ctx, outputs, excesses, Some(&offset), 3).is_err());
+
+		// a coinbase minting more than the reward is rejected
+		let blind = keychain.derive_key(&ctx, &[1, 0]);
+		let block = Block::new([9u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+		let block = block.with_coinbase(&ctx, &blind, reward(5) + 1)?;
+		assert!(validate_reward(&ctx, &block, 5).is_err());
+		assert!(validate_reward(&ctx, &block, HALVING_INTERVAL).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_zero_reward() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let height = HALVING_INTERVAL * 64;
+		assert_eq!(reward(height), 0);
+
+		// past the last halving the coinbase is paid only the fees
+		let kc = KeyChain::from_seed([6u8; 48])?;
+		let input = kc.derive_key(&ctx, &[0, 0]);
+		let change = kc.derive_key(&ctx, &[0, 1]);
+		let fee = 10;
+		let mut slate = Slate::new(fee, SecretKey::gen(&ctx));
+		let id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 100 - fee)])?;
+		slate.sign(&ctx, id, &[&input], &[&change])?;
+		let mut block = Block::new([3u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+		block.add_tx(&ctx, slate.finalize(&ctx)?)?;
+		let coinbase = kc.derive_key(&ctx, &[1, 0]);
+		let mut paid = block.with_coinbase(&ctx, &coinbase, 0)?;
+		assert!(validate_reward(&ctx, &paid, height).is_ok());
+		assert!(validate_reward(&ctx, &paid, 1).is_err());
+
+		// minting anything, or leaving the fees unclaimed, is rejected
+		let minted = block.with_coinbase(&ctx, &coinbase, 1)?;
+		assert!(validate_reward(&ctx, &minted, height).is_err());
+		assert!(validate_reward(&ctx, &block, height).is_err());
+
+		// block validation checks the reward along with the hash
+		let params = ChainParams::get(Network::Regtest);
+		let bible = Bible::new();
+		let bip52 = params.bip52([3u8; 32]);
+		paid.mine_block(&bip52, 1024 * 1024, params.target, &bible)?;
+		assert!(validate_block(&ctx, &paid, height, &bip52, params.target, &bible).is_ok());
+		assert!(validate_block(&ctx, &paid, 1, &bip52, params.target, &bible).is_err());
+		assert!(validate_block(&ctx, &paid, height, &bip52, DIFFICULTY_HARD, &bible).is_err());
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_audit_chain() -> Result<()> {
+		let db_dir = "bin/.audit_chain";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let mut outputs = Pmmr::new(db.try_clone()?, "outputs")?;
+		let mut kernels = KernelMmr::new(db.try_clone()?, "kernels")?;
+		let ctx = Ctx::new()?;
+		let txn = db.write()?;
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &txn).is_ok());
+
+		// the genesis block mints nothing
+		let genesis = ChainParams::get(Network::Regtest).genesis()?;
+		apply(&genesis, 0, &mut outputs, &mut kernels, &txn)?;
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &txn).is_ok());
+
+		let kc1 = KeyChain::from_seed([1u8; 48])?;
+		let coinbase = kc1.derive_key(&ctx, &[0, 0]);
+		let block = Block::new([0u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+		let block = block.with_coinbase(&ctx, &coinbase, reward(1))?;
+		apply(&block, 1, &mut outputs, &mut kernels, &txn)?;
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &txn).is_ok());
+
+		// block 2 spends it, the fee goes to its coinbase
+		let fee = 10;
+		let mut slate = Slate::new(fee, SecretKey::gen(&ctx));
+		let change = kc1.derive_key(&ctx, &[0, 1]);
+		let user1_id = slate.commit(&ctx, &[(&coinbase, reward(1))], &[(&change, 10)])?;
+		let kc2 = KeyChain::from_seed([2u8; 48])?;
+		let output = kc2.derive_key(&ctx, &[0, 0]);
+		let user2_id = slate.commit(&ctx, &[], &[(&output, reward(1) - 10 - fee)])?;
+		slate.sign(&ctx, user2_id, &[], &[&output])?;
+		slate.sign(&ctx, user1_id, &[&coinbase], &[&change])?;
+		let mut block = Block::new([1u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+		block.add_tx(&ctx, slate.finalize(&ctx)?)?;
+		let block = block.with_coinbase(&ctx, &kc2.derive_key(&ctx, &[1, 0]), reward(2))?;
+		apply(&block, 2, &mut outputs, &mut kernels, &txn)?;
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &txn).is_ok());
+
+		// the offsets were all folded into the coinbase kernels
+		let offset = SecretKey::gen(&ctx);
+		assert!(audit_chain(&ctx, &outputs, &kernels, Some(&offset), &txn).is_err());
+
+		// an output that nothing minted
+		let sp = txn.savepoint()?;
+		let extra = ctx.commit(1, &SecretKey::gen(&ctx))?;
+		outputs.append(extra.as_ref(), Some(sp.clone()))?;
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &sp).is_err());
+		sp.abort();
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &txn).is_ok());
+
+		// a block minting more than its reward
+		let block = Block::new([2u8; 32], [0u8; 32], [0u8; 4], [0u8; 4]);
+		let block = block.with_coinbase(&ctx, &kc2.derive_key(&ctx, &[2, 0]), reward(3) + 1)?;
+		apply(&block, 3, &mut outputs, &mut kernels, &txn)?;
+		assert!(audit_chain(&ctx, &outputs, &kernels, None, &txn).is_err());
+
+		txn.abort();
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+}
@@ -1,6 +1,27 @@
 pub const BLOCK_HEADER_VERSION: u8 = 0;
+pub const KERNEL_SIZE: usize = 114;
+// see Kernel::height_locked
+pub const KERNEL_FEATURE_HEIGHT_LOCKED: u8 = 2;
+pub const PAYMENT_PROOF_SIZE: usize = 204;
+pub const BLOCK_HEADER_SIZE: usize = 114;
+// offsets of the fields that change while mining within the serialized header
+pub const HEADER_NONCE_OFFSET: usize = 6;
+pub const HEADER_AUX_DATA_HASH_OFFSET: usize = 82;
 
-#[cfg(test)]
+// amounts are in the smallest unit, one coin is COIN units
+pub const COIN: u64 = 1_000_000_000;
+// emission, see consensus::reward. At a block a minute the reward halves
+// about every 4 years.
+pub const INITIAL_BLOCK_REWARD: u64 = 50 * COIN;
+pub const HALVING_INTERVAL: u64 = 2_102_400;
+
+// block weight is what limits the size of a block
+pub const INPUT_WEIGHT: u64 = 1;
+pub const OUTPUT_WEIGHT: u64 = 21;
+pub const KERNEL_WEIGHT: u64 = 3;
+pub const MAX_BLOCK_WEIGHT: u64 = 40_000;
+
+// the regtest target, about one hash in 16 meets it
 pub const DIFFICULTY_4BIT_LEADING: [u8; 32] = [
 	0x0F, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8,
 	255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8, 255u8,
@@ -1,3 +1,3 @@
 use prelude::*;
 
-errors!(ValidationFailed, Duplicate, NotFound);
+errors!(ValidationFailed, Duplicate, NotFound, Cancelled);
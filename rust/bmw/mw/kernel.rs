@@ -3,9 +3,10 @@ use crypto::ffi::secp256k1_schnorrsig_verify;
 use crypto::pedersen::Commitment;
 use crypto::sha3::Sha3_256;
 use crypto::signature::{Message, Signature};
+use mw::constants::{KERNEL_FEATURE_HEIGHT_LOCKED, KERNEL_SIZE};
 use mw::errors::*;
 use prelude::*;
-use misc::to_be_bytes_u64;
+use misc::{slice_copy, subslice_mut, to_be_bytes_u64};
 
 #[derive(Ord, PartialOrd, PartialEq, Eq, Clone)]
 pub struct Kernel {
@@ -13,6 +14,8 @@ pub struct Kernel {
 	signature: Signature,
 	fee: u64,
 	features: u8,
+	// the first height the kernel may be in, 0 unless height locked
+	lock_height: u64,
 }
 
 impl Kernel {
@@ -22,6 +25,24 @@ impl Kernel {
 			signature,
 			fee,
 			features,
+			lock_height: 0,
+		}
+	}
+
+	// a kernel that can't be in a block below `lock_height`, e.g. a refund
+	// that must not be spendable before a swap times out
+	pub fn height_locked(
+		excess: Commitment,
+		signature: Signature,
+		fee: u64,
+		lock_height: u64,
+	) -> Self {
+		Self {
+			excess,
+			signature,
+			fee,
+			features: KERNEL_FEATURE_HEIGHT_LOCKED,
+			lock_height,
 		}
 	}
 
@@ -41,6 +62,10 @@ impl Kernel {
 		self.features
 	}
 
+	pub fn lock_height(&self) -> u64 {
+		self.lock_height
+	}
+
 	pub fn validate(&self, ctx: &Ctx) -> Result<()> {
 		let msg = self.message();
 		let excess = self.excess.to_pubkey(ctx)?.decompress(ctx)?;
@@ -62,7 +87,11 @@ impl Kernel {
 	}
 
 	pub fn message(&self) -> Message {
-		Self::message_for(self.excess(), self.fee(), self.features())
+		if self.features == KERNEL_FEATURE_HEIGHT_LOCKED {
+			Self::height_locked_message_for(self.excess(), self.fee(), self.lock_height())
+		} else {
+			Self::message_for(self.excess(), self.fee(), self.features())
+		}
 	}
 
 	pub fn message_for(excess: &Commitment, fee: u64, features: u8) -> Message {
@@ -84,6 +113,38 @@ impl Kernel {
 		Message::new(sha3.finalize())
 	}
 
+	// the message of a height locked kernel, the lock height is signed too
+	pub fn height_locked_message_for(excess: &Commitment, fee: u64, lock_height: u64) -> Message {
+		let sha3 = Sha3_256::new();
+		sha3.update(excess.as_ref());
+		let mut buf64 = [0u8; 8];
+		let _ = to_be_bytes_u64(fee, &mut buf64);
+		sha3.update(&buf64);
+		sha3.update(&[KERNEL_FEATURE_HEIGHT_LOCKED]);
+		let _ = to_be_bytes_u64(lock_height, &mut buf64);
+		sha3.update(&buf64);
+		Message::new(sha3.finalize())
+	}
+
+	// excess || signature || fee (big endian) || features || lock height
+	// (big endian)
+	pub fn to_bytes(&self) -> [u8; KERNEL_SIZE] {
+		let mut ret = [0u8; KERNEL_SIZE];
+		// the lengths are fixed so these can't fail
+		let _ = slice_copy(self.excess.as_ref(), &mut ret, 33);
+		if let Ok(sig) = subslice_mut(&mut ret, 33, 64) {
+			let _ = slice_copy(self.signature.as_ref(), sig, 64);
+		}
+		if let Ok(fee) = subslice_mut(&mut ret, 97, 8) {
+			to_be_bytes_u64(self.fee, fee);
+		}
+		ret[105] = self.features;
+		if let Ok(lock_height) = subslice_mut(&mut ret, 106, 8) {
+			to_be_bytes_u64(self.lock_height, lock_height);
+		}
+		ret
+	}
+
 	pub fn sha3(&self, sha3: &Sha3_256) {
 		sha3.update(self.message().as_ref());
 	}
@@ -113,4 +174,30 @@ mod test {
 		assert!(kernel.validate(&ctx).is_ok());
 		Ok(())
 	}
+
+	#[test]
+	fn test_height_locked_kernel() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let blind = SecretKey::gen(&ctx);
+		let excess = ctx.commit(0, &blind)?;
+		let message = Kernel::height_locked_message_for(&excess, 10, 100);
+		let secnonce = SecretKey::gen(&ctx);
+		let pubnonce = PublicKey::from(&ctx, &secnonce)?;
+		let pubkey = excess.to_pubkey(&ctx)?;
+		let s = ctx.sign(&message, &blind, &secnonce, &pubnonce, &pubkey)?;
+		let kernel = Kernel::height_locked(excess.clone(), s.clone(), 10, 100);
+		assert_eq!(kernel.lock_height(), 100);
+		assert_eq!(kernel.features(), KERNEL_FEATURE_HEIGHT_LOCKED);
+		assert!(kernel.validate(&ctx).is_ok());
+		assert_eq!(kernel.to_bytes()[113], 100);
+
+		// the lock height is signed
+		assert!(Kernel::height_locked(excess.clone(), s.clone(), 10, 99)
+			.validate(&ctx)
+			.is_err());
+		assert!(Kernel::new(excess, s, 10, KERNEL_FEATURE_HEIGHT_LOCKED)
+			.validate(&ctx)
+			.is_err());
+		Ok(())
+	}
 }
@@ -0,0 +1,271 @@
+use bible::Bible;
+use crypto::bip52::Bip52;
+use misc::u256_less_than_or_equal;
+use mw::block::{Block, BlockHeader};
+use mw::errors::*;
+use prelude::*;
+
+// A multi-threaded miner. The 4 byte nonce space is split into one range
+// per worker. When a worker exhausts its range it rolls aux_data_hash as an
+// extra nonce and starts over, so workers never overlap.
+#[derive(Clone)]
+pub struct Miner {
+	threads: usize,
+	// bumped by cancel, workers stop once it no longer matches the value
+	// seen when mining started
+	generation: Rc<u64>,
+	nonce_space: u64,
+}
+
+struct Worker<'a> {
+	start: u64,
+	end: u64,
+	rounds: u64,
+	key: [u8; 32],
+	target: [u8; 32],
+	header: BlockHeader,
+	bible: &'a Bible,
+	generation: u64,
+}
+
+impl Miner {
+	pub fn new(threads: usize) -> Result<Self> {
+		if threads == 0 {
+			return err!(IllegalArgument);
+		}
+		Ok(Self {
+			threads,
+			generation: Rc::new(0)?,
+			nonce_space: 1 << 32,
+		})
+	}
+
+	// stop any mine call in progress, e.g. when a new tip arrives. Mine calls
+	// started afterwards are unaffected.
+	pub fn cancel(&self) {
+		let mut generation = self.generation.clone();
+		aadd!(&mut *generation, 1);
+	}
+
+	// mines `block` with a Bip52 hasher keyed by `key`. Each worker tries
+	// `rounds` aux_data_hash values over its nonce range. On success the
+	// block's nonce and aux_data_hash are updated and the hash is returned.
+	pub fn mine(
+		&self,
+		block: &mut Block,
+		key: [u8; 32],
+		target: [u8; 32],
+		rounds: u64,
+		bible: &Bible,
+	) -> Result<[u8; 32]> {
+		let generation = aload!(&*self.generation);
+		let done = Rc::new(0u64)?;
+		let mut slots = Vec::new();
+		let mut handles = Vec::new();
+		let mut ret = Ok(());
+		for i in 0..self.threads {
+			let threads = self.threads as u64;
+			let worker = Worker {
+				start: self.nonce_space * i as u64 / threads,
+				end: self.nonce_space * (i as u64 + 1) / threads,
+				rounds,
+				key,
+				target,
+				header: block.header().clone(),
+				bible,
+				generation,
+			};
+			let slot: Rc<Option<(BlockHeader, [u8; 32])>> = match Rc::new(None) {
+				Ok(slot) => slot,
+				Err(e) => {
+					ret = Err(e);
+					break;
+				}
+			};
+			let (miner, done, found) = (self.clone(), done.clone(), slot.clone());
+			match spawnj(move || miner.work(worker, done, found)) {
+				Ok(jh) => {
+					if let Err(e) = handles.push(jh) {
+						ret = Err(e);
+						break;
+					}
+				}
+				Err(e) => {
+					ret = Err(e);
+					break;
+				}
+			}
+			if let Err(e) = slots.push(slot) {
+				ret = Err(e);
+				break;
+			}
+		}
+
+		// workers borrow the bible, always wait for them before returning
+		if ret.is_err() {
+			let mut done = done.clone();
+			astore!(&mut *done, 1);
+		}
+		for jh in handles.iter_mut() {
+			if let Err(e) = jh.join() {
+				if ret.is_ok() {
+					ret = Err(e);
+				}
+			}
+		}
+		ret?;
+
+		for slot in slots.iter_mut() {
+			if let Some((header, hash)) = slot.take() {
+				block.set_header(header);
+				return Ok(hash);
+			}
+		}
+		if aload!(&*self.generation) != generation {
+			err!(Cancelled)
+		} else {
+			err!(NotFound)
+		}
+	}
+
+	fn work(
+		&self,
+		mut worker: Worker,
+		mut done: Rc<u64>,
+		mut found: Rc<Option<(BlockHeader, [u8; 32])>>,
+	) {
+		let bip52 = Bip52::new(worker.key, worker.header.prev_hash());
+		let aux_data_hash = worker.header.aux_data_hash();
+		let mut buffer = worker.header.hash_buffer(worker.bible);
+		for round in 0..worker.rounds {
+			let rolled = Self::roll(aux_data_hash, round);
+			buffer.set_aux_data_hash(rolled);
+			for nonce in worker.start..worker.end {
+				if aload!(&*done) != 0 || aload!(&*self.generation) != worker.generation {
+					return;
+				}
+				buffer.set_nonce(nonce as u32);
+				let hash = buffer.hash(&bip52);
+				if u256_less_than_or_equal(&worker.target, &hash) {
+					worker.header.set_aux_data_hash(rolled);
+					worker.header.set_nonce(nonce as u32);
+					*found = Some((worker.header.clone(), hash));
+					astore!(&mut *done, 1);
+					return;
+				}
+			}
+		}
+	}
+
+	// adds `round` to aux_data_hash as a little endian 256 bit integer
+	fn roll(mut aux_data_hash: [u8; 32], round: u64) -> [u8; 32] {
+		let mut carry = round as u128;
+		for b in aux_data_hash.iter_mut() {
+			if carry == 0 {
+				break;
+			}
+			let sum = *b as u128 + (carry & 0xFF);
+			*b = sum as u8;
+			carry = (carry >> 8) + (sum >> 8);
+		}
+		aux_data_hash
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crypto::ctx::Ctx;
+	use ffi::sleep_millis;
+	use mw::constants::*;
+	use mw::keychain::KeyChain;
+
+	fn block(ctx: &Ctx) -> Result<Block> {
+		let block = Block::new([77u8; 32], [3u8; 32], [9u8; 4], [81u8; 4]);
+		let miner_keychain = KeyChain::from_seed([5u8; 48])?;
+		let coinbase_blind = miner_keychain.derive_key(ctx, &[0, 0]);
+		let mut complete = block.with_coinbase(ctx, &coinbase_blind, 1000)?;
+		complete.finalize_header()?;
+		Ok(complete)
+	}
+
+	#[test]
+	fn test_miner() -> Result<()> {
+		let bible = Bible::new();
+		let ctx = Ctx::new()?;
+		let key = [1u8; 32];
+		let bip52 = Bip52::new(key, [77u8; 32]);
+		let mut block = block(&ctx)?;
+
+		assert_eq!(Miner::new(0).err(), Some(IllegalArgument));
+		let miner = Miner::new(4)?;
+		let hash = miner.mine(&mut block, key, DIFFICULTY_4BIT_LEADING, 1, &bible)?;
+		assert!(hash[0] & 0xF0 == 0);
+		assert!(block
+			.validate_hash(&bip52, DIFFICULTY_4BIT_LEADING, hash, &bible)
+			.is_ok());
+		assert_eq!(block.header().aux_data_hash(), [3u8; 32]);
+
+		// with a single nonce per round the aux_data_hash is rolled instead
+		let mut miner = Miner::new(1)?;
+		miner.nonce_space = 1;
+		let hash = miner.mine(&mut block, key, DIFFICULTY_4BIT_LEADING, 1024, &bible)?;
+		assert_eq!(block.header().nonce(), 0);
+		assert!(block
+			.validate_hash(&bip52, DIFFICULTY_4BIT_LEADING, hash, &bible)
+			.is_ok());
+
+		// nothing found
+		let mut miner = Miner::new(2)?;
+		miner.nonce_space = 16;
+		assert_eq!(
+			miner
+				.mine(&mut block, key, DIFFICULTY_HARD, 2, &bible)
+				.err(),
+			Some(NotFound)
+		);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_miner_roll() -> Result<()> {
+		assert_eq!(Miner::roll([0u8; 32], 0), [0u8; 32]);
+		let mut aux = [255u8; 32];
+		aux[2] = 0;
+		let mut expected = [0u8; 32];
+		expected[0] = 1;
+		expected[2] = 1;
+		for i in 3..32 {
+			expected[i] = 255;
+		}
+		assert_eq!(Miner::roll(aux, 2), expected);
+		Ok(())
+	}
+
+	#[test]
+	fn test_miner_cancel() -> Result<()> {
+		let bible = Bible::new();
+		let ctx = Ctx::new()?;
+		let mut block = block(&ctx)?;
+		let miner = Miner::new(2)?;
+
+		// a cancel before mining starts doesn't affect it
+		miner.cancel();
+		let canceller = miner.clone();
+		let mut jh = spawnj(move || {
+			unsafe {
+				sleep_millis(100);
+			}
+			canceller.cancel();
+		})?;
+		assert_eq!(
+			miner
+				.mine(&mut block, [1u8; 32], DIFFICULTY_HARD, u64::MAX, &bible)
+				.err(),
+			Some(Cancelled)
+		);
+		jh.join()?;
+		Ok(())
+	}
+}
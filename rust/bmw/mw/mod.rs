@@ -2,7 +2,13 @@ mod constants;
 mod errors;
 
 pub mod block;
+pub mod consensus;
 pub mod kernel;
 pub mod keychain;
+pub mod miner;
+pub mod multisig;
+pub mod params;
+pub mod payment_proof;
 pub mod slate;
+pub mod template;
 pub mod transaction;
@@ -0,0 +1,274 @@
+use crypto::ctx::Ctx;
+use crypto::keys::{PublicKey, SecretKey};
+use crypto::pedersen::Commitment;
+use crypto::range_proof::{MultiPartyProof, RangeProof};
+use prelude::*;
+
+struct ParticipantData {
+	commit: Commitment,
+	t: Option<(PublicKey, PublicKey)>,
+	tau_x: Option<SecretKey>,
+}
+
+// what a participant keeps to itself while building a MultisigOutput. Only
+// values derived from it go in the output: anyone holding another party's
+// private nonce could solve its tau_x share for its blind share.
+pub struct MultisigParticipant {
+	id: usize,
+	share: SecretKey,
+	private_nonce: SecretKey,
+}
+
+// An n-of-n output. Its blind is the sum of a share from each participant
+// so it can only be spent by all of them together, see Slate::commit_shared.
+// Like a Slate it is passed around the participants: everyone commits their
+// share, then everyone does t_round, then everyone does tau_x_round and
+// finally any of them finalizes to get the output and its range proof.
+pub struct MultisigOutput {
+	pdata: Vec<ParticipantData>,
+	value: u64,
+	// shared by the participants, the range proof can be rewound with it
+	common_nonce: SecretKey,
+}
+
+impl MultisigOutput {
+	pub fn new(ctx: &Ctx, value: u64) -> Self {
+		Self {
+			pdata: Vec::new(),
+			value,
+			common_nonce: SecretKey::gen(ctx),
+		}
+	}
+
+	pub fn value(&self) -> u64 {
+		self.value
+	}
+
+	pub fn common_nonce(&self) -> &SecretKey {
+		&self.common_nonce
+	}
+
+	// adds a participant owning `share`, returns its local state which is
+	// passed to the later rounds. Everyone must commit before the first
+	// t_round.
+	pub fn commit(&mut self, ctx: &Ctx, share: &SecretKey) -> Result<MultisigParticipant> {
+		if self.pdata.iter().any(|pd| pd.t.is_some()) {
+			return err!(IllegalState);
+		}
+		// the first participant commits to the value too
+		let value = if self.pdata.len() == 0 { self.value } else { 0 };
+		let pd = ParticipantData {
+			commit: ctx.commit(value, share)?,
+			t: None,
+			tau_x: None,
+		};
+		self.pdata.push(pd)?;
+		Ok(MultisigParticipant {
+			id: self.pdata.len() - 1,
+			share: share.clone(),
+			private_nonce: SecretKey::gen(ctx),
+		})
+	}
+
+	// the commitment of the output once everyone committed
+	pub fn commitment(&self, ctx: &Ctx) -> Result<Commitment> {
+		if self.pdata.len() == 0 {
+			return err!(IllegalState);
+		}
+		let mut ret = self.pdata[0].commit.clone();
+		for i in 1..self.pdata.len() {
+			ret = ret.combine(ctx, &self.pdata[i].commit)?;
+		}
+		Ok(ret)
+	}
+
+	pub fn t_round(&mut self, ctx: &Ctx, participant: &MultisigParticipant) -> Result<()> {
+		self.check_participant(ctx, participant)?;
+		let mp = self.proof_data(ctx)?;
+		let t = ctx.multi_party_t(&mp, &participant.share, &participant.private_nonce)?;
+		self.pdata[participant.id].t = Some(t);
+		Ok(())
+	}
+
+	// can only be done once everyone did t_round
+	pub fn tau_x_round(&mut self, ctx: &Ctx, participant: &MultisigParticipant) -> Result<()> {
+		self.check_participant(ctx, participant)?;
+		let mp = self.proof_data(ctx)?;
+		let (t_one, t_two) = self.t_sum(ctx)?;
+		let tau_x = ctx.multi_party_tau_x(
+			&mp,
+			&participant.share,
+			&participant.private_nonce,
+			&t_one,
+			&t_two,
+		)?;
+		self.pdata[participant.id].tau_x = Some(tau_x);
+		Ok(())
+	}
+
+	// can only be done once everyone did tau_x_round, returns the output
+	// and its range proof
+	pub fn finalize(
+		&self,
+		ctx: &Ctx,
+		participant: &MultisigParticipant,
+	) -> Result<(Commitment, RangeProof)> {
+		self.check_participant(ctx, participant)?;
+		let mp = self.proof_data(ctx)?;
+		let (t_one, t_two) = self.t_sum(ctx)?;
+		let mut tau_xs = Vec::with_capacity(self.pdata.len())?;
+		for i in 0..self.pdata.len() {
+			match &self.pdata[i].tau_x {
+				Some(tau_x) => tau_xs.push(tau_x)?,
+				None => return err!(IllegalState),
+			}
+		}
+		let tau_x = ctx.blind_sum(tau_xs.slice(0, tau_xs.len()), &[])?;
+		let proof = ctx.multi_party_prove(
+			&mp,
+			&participant.share,
+			&participant.private_nonce,
+			(&t_one, &t_two),
+			&tau_x,
+		)?;
+		ctx.verify_range_proof(&mp.commit, &proof)?;
+		Ok((mp.commit, proof))
+	}
+
+	// `participant` must have committed to this output
+	fn check_participant(&self, ctx: &Ctx, participant: &MultisigParticipant) -> Result<()> {
+		if participant.id >= self.pdata.len() {
+			return err!(IllegalArgument);
+		}
+		let value = if participant.id == 0 { self.value } else { 0 };
+		if ctx.commit(value, &participant.share)? != self.pdata[participant.id].commit {
+			return err!(IllegalArgument);
+		}
+		Ok(())
+	}
+
+	fn proof_data(&self, ctx: &Ctx) -> Result<MultiPartyProof> {
+		Ok(MultiPartyProof {
+			value: self.value,
+			commit: self.commitment(ctx)?,
+			common_nonce: self.common_nonce.clone(),
+		})
+	}
+
+	fn t_sum(&self, ctx: &Ctx) -> Result<(PublicKey, PublicKey)> {
+		let mut ret: Option<(PublicKey, PublicKey)> = None;
+		for i in 0..self.pdata.len() {
+			let (t_one, t_two) = match &self.pdata[i].t {
+				Some(t) => t,
+				None => return err!(IllegalState),
+			};
+			ret = match ret {
+				Some((sum_one, sum_two)) => {
+					Some((sum_one.combine(ctx, t_one)?, sum_two.combine(ctx, t_two)?))
+				}
+				None => Some((t_one.clone(), t_two.clone())),
+			};
+		}
+		match ret {
+			Some(ret) => Ok(ret),
+			None => err!(IllegalState),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use mw::keychain::KeyChain;
+	use mw::slate::Slate;
+
+	#[test]
+	fn test_multisig_output() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let share1 = KeyChain::from_seed([1u8; 48])?.derive_key(&ctx, &[5, 0]);
+		let share2 = KeyChain::from_seed([2u8; 48])?.derive_key(&ctx, &[5, 0]);
+
+		let mut output = MultisigOutput::new(&ctx, 90);
+		let user1 = output.commit(&ctx, &share1)?;
+		let user2 = output.commit(&ctx, &share2)?;
+		assert_eq!(output.tau_x_round(&ctx, &user1).err(), Some(IllegalState));
+
+		// a participant of another output is rejected
+		let mut other = MultisigOutput::new(&ctx, 90);
+		let other_user = other.commit(&ctx, &share2)?;
+		assert_eq!(
+			output.t_round(&ctx, &other_user).err(),
+			Some(IllegalArgument)
+		);
+
+		output.t_round(&ctx, &user1)?;
+		output.t_round(&ctx, &user2)?;
+		assert_eq!(
+			output.commit(&ctx, &SecretKey::gen(&ctx)).err(),
+			Some(IllegalState)
+		);
+		output.tau_x_round(&ctx, &user2)?;
+		assert_eq!(output.finalize(&ctx, &user2).err(), Some(IllegalState));
+		output.tau_x_round(&ctx, &user1)?;
+		let (commit, proof) = output.finalize(&ctx, &user2)?;
+
+		let blind = ctx.blind_sum(&[&share1, &share2], &[])?;
+		assert!(commit == ctx.commit(90, &blind)?);
+		assert!(ctx.verify_range_proof(&commit, &proof).is_ok());
+		assert_eq!(
+			ctx.rewind_range_proof(&commit, output.common_nonce(), &proof)?,
+			90
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_multisig_slate() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let kc1 = KeyChain::from_seed([3u8; 48])?;
+		let kc2 = KeyChain::from_seed([4u8; 48])?;
+		let share1 = kc1.derive_key(&ctx, &[5, 0]);
+		let share2 = kc2.derive_key(&ctx, &[5, 0]);
+
+		// user1 funds a 2-of-2 output with user2
+		let mut output = MultisigOutput::new(&ctx, 90);
+		let user1 = output.commit(&ctx, &share1)?;
+		let user2 = output.commit(&ctx, &share2)?;
+		output.t_round(&ctx, &user1)?;
+		output.t_round(&ctx, &user2)?;
+		output.tau_x_round(&ctx, &user1)?;
+		output.tau_x_round(&ctx, &user2)?;
+		let (commit, proof) = output.finalize(&ctx, &user1)?;
+
+		let input = kc1.derive_key(&ctx, &[0, 0]);
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		let user1_id = slate.commit_shared(&ctx, &[(&input, 100)], &[], &[], &[&share1])?;
+		let user2_id = slate.commit_shared(&ctx, &[], &[], &[], &[&share2])?;
+		slate.add_shared_output(&ctx, user1_id, commit.clone(), proof)?;
+		slate.sign(&ctx, user2_id, &[], &[&share2])?;
+		slate.sign(&ctx, user1_id, &[&input], &[&share1])?;
+		let tx = slate.finalize(&ctx)?;
+		assert!(tx.validate(&ctx, 0).is_ok());
+		assert_eq!(tx.outputs().len(), 1);
+
+		// neither can spend it alone
+		let output2 = kc2.derive_key(&ctx, &[0, 0]);
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		let user2_id = slate.commit_shared(&ctx, &[], &[(&output2, 80)], &[&share2], &[])?;
+		slate.add_shared_input(user2_id, commit.clone())?;
+		slate.sign(&ctx, user2_id, &[&share2], &[&output2])?;
+		assert!(slate.finalize(&ctx)?.validate(&ctx, 0).is_err());
+
+		// but they can together, here paying 80 to user2
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		let user1_id = slate.commit_shared(&ctx, &[], &[], &[&share1], &[])?;
+		let user2_id = slate.commit_shared(&ctx, &[], &[(&output2, 80)], &[&share2], &[])?;
+		slate.add_shared_input(user1_id, commit.clone())?;
+		slate.sign(&ctx, user2_id, &[&share2], &[&output2])?;
+		slate.sign(&ctx, user1_id, &[&share1], &[])?;
+		let tx = slate.finalize(&ctx)?;
+		assert!(tx.validate(&ctx, 0).is_ok());
+		assert!(tx.inputs()[0] == commit);
+		Ok(())
+	}
+}
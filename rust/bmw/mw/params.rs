@@ -0,0 +1,241 @@
+use bible::Bible;
+use crypto::bip52::Bip52;
+use crypto::sha3::Sha3_256;
+use mw::block::Block;
+use mw::constants::*;
+use mw::template::ChainTip;
+use prelude::*;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Network {
+	Mainnet,
+	Testnet,
+	// a local network where blocks are found almost instantly
+	Regtest,
+}
+
+// Everything that differs between networks. Nodes of different networks
+// don't connect (magic), can't use each other's blocks (bip52_key) and
+// start from different genesis blocks.
+pub struct ChainParams {
+	pub network: Network,
+	// the first bytes of every p2p message
+	pub magic: [u8; 4],
+	// the key of the Bip52 hasher
+	pub bip52_key: [u8; 32],
+	// the PI controller values until the first epoch votes on new ones
+	pub kp: [u8; 4],
+	pub ki: [u8; 4],
+	// the target of the first block after genesis
+	pub target: [u8; 32],
+	// the genesis header fields that aren't derived
+	genesis_timestamp: u64,
+	genesis_nonce: u32,
+	// hashed into the genesis aux_data_hash
+	genesis_message: &'static str,
+}
+
+pub const MAINNET: ChainParams = ChainParams {
+	network: Network::Mainnet,
+	magic: [0xb3, 0x4d, 0x57, 0xe1],
+	bip52_key: [
+		0x66, 0xb5, 0x90, 0x30, 0x43, 0x8f, 0x7d, 0x3d, 0x65, 0xd9, 0xa3, 0x51, 0x2b, 0x66, 0xfc,
+		0x52, 0xfc, 0x7e, 0xae, 0xb2, 0x7b, 0x95, 0x3b, 0xe0, 0x63, 0x02, 0x5b, 0xcf, 0xfc, 0xe5,
+		0x55, 0x67,
+	],
+	kp: [100, 0, 0, 0],
+	ki: [10, 0, 0, 0],
+	target: [
+		0x00, 0x00, 0x0F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+		0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+		0xFF, 0xFF,
+	],
+	genesis_timestamp: 1_798_761_600,
+	genesis_nonce: 0,
+	genesis_message: "bmw mainnet genesis",
+};
+
+pub const TESTNET: ChainParams = ChainParams {
+	network: Network::Testnet,
+	magic: [0xb3, 0x4d, 0x57, 0x7e],
+	bip52_key: [
+		0x6b, 0x2b, 0x62, 0xfd, 0x3d, 0x60, 0x7a, 0xce, 0x27, 0x29, 0xed, 0xdc, 0xc4, 0x63, 0x23,
+		0x44, 0x7b, 0x0a, 0xcd, 0x62, 0x34, 0x92, 0x06, 0x71, 0x82, 0x1d, 0x5b, 0x84, 0x2e, 0x54,
+		0x9a, 0x45,
+	],
+	kp: [100, 0, 0, 0],
+	ki: [10, 0, 0, 0],
+	target: [
+		0x00, 0x0F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+		0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+		0xFF, 0xFF,
+	],
+	genesis_timestamp: 1_767_225_600,
+	genesis_nonce: 0,
+	genesis_message: "bmw testnet genesis",
+};
+
+pub const REGTEST: ChainParams = ChainParams {
+	network: Network::Regtest,
+	magic: [0xb3, 0x4d, 0x57, 0x00],
+	bip52_key: [
+		0xf3, 0xcb, 0xe0, 0x91, 0xd8, 0x6c, 0x16, 0x28, 0x18, 0xfb, 0x5e, 0xf3, 0x3c, 0x55, 0x84,
+		0x18, 0x5d, 0xdd, 0x93, 0x4d, 0x70, 0xb4, 0xe0, 0xd3, 0x86, 0xb6, 0x84, 0xf9, 0x15, 0x1a,
+		0x99, 0xf5,
+	],
+	kp: [100, 0, 0, 0],
+	ki: [10, 0, 0, 0],
+	target: DIFFICULTY_4BIT_LEADING,
+	genesis_timestamp: 1_700_000_000,
+	genesis_nonce: 0,
+	genesis_message: "bmw regtest genesis",
+};
+
+impl ChainParams {
+	pub fn get(network: Network) -> &'static ChainParams {
+		match network {
+			Network::Mainnet => &MAINNET,
+			Network::Testnet => &TESTNET,
+			Network::Regtest => &REGTEST,
+		}
+	}
+
+	// the hasher for the block built on `prev_hash`
+	pub fn bip52(&self, prev_hash: [u8; 32]) -> Bip52 {
+		Bip52::new(self.bip52_key, prev_hash)
+	}
+
+	// The genesis block. It has no transactions and mints nothing, see
+	// consensus::reward. Every node builds the same one so it isn't checked
+	// against any target.
+	pub fn genesis(&self) -> Result<Block> {
+		let sha3 = Sha3_256::new();
+		sha3.update(self.genesis_message.as_bytes());
+		let aux_data_hash = sha3.finalize();
+
+		let mut block = Block::new([0u8; 32], aux_data_hash, self.ki, self.kp);
+		let mut header = block.header().clone();
+		header.set_nonce(self.genesis_nonce);
+		block.set_header(header);
+		block.finalize_header_at(self.genesis_timestamp)?;
+		Ok(block)
+	}
+
+	// the tip of a chain that only has the genesis block
+	pub fn genesis_tip(&self, bible: &Bible) -> Result<ChainTip> {
+		let genesis = self.genesis()?;
+		let hash = genesis.header().hash(&self.bip52([0u8; 32]), bible);
+		Ok(ChainTip {
+			height: 0,
+			hash,
+			target: self.target,
+			window_secs: 0,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crypto::ctx::Ctx;
+	use lmdb::db::Lmdb;
+	use lmdb::{make_lmdb_test_dir, remove_lmdb_test_dir};
+	use mw::consensus::validate_reward;
+	use mw::keychain::KeyChain;
+	use mw::template::BlockTemplate;
+	use store::Pmmr;
+
+	#[test]
+	fn test_chain_params() -> Result<()> {
+		let bible = Bible::new();
+		let networks = [Network::Mainnet, Network::Testnet, Network::Regtest];
+		// the header bytes of each genesis block are pinned, the proof of work
+		// hash is not as it depends on the bible too
+		let header_hashes = [
+			[
+				106, 223, 130, 186, 183, 191, 80, 53, 211, 223, 90, 43, 45, 35, 104, 233, 12, 158,
+				191, 101, 139, 65, 59, 235, 17, 17, 10, 131, 235, 169, 240, 13,
+			],
+			[
+				190, 181, 20, 44, 184, 2, 211, 233, 176, 252, 100, 179, 249, 12, 195, 81, 230, 176,
+				93, 224, 246, 26, 195, 188, 231, 198, 203, 246, 119, 71, 201, 226,
+			],
+			[
+				247, 177, 19, 67, 32, 80, 249, 13, 37, 148, 218, 233, 250, 234, 166, 206, 65, 62,
+				12, 176, 189, 249, 134, 175, 48, 60, 252, 253, 246, 251, 246, 122,
+			],
+		];
+		let mut hashes = Vec::new();
+		for i in 0..networks.len() {
+			let network = networks[i];
+			let params = ChainParams::get(network);
+			assert_eq!(params.network, network);
+
+			// the genesis block doesn't depend on when or where it's built
+			let genesis = params.genesis()?;
+			assert_eq!(
+				genesis.header().to_bytes(),
+				params.genesis()?.header().to_bytes()
+			);
+			assert_eq!(genesis.header().prev_hash(), [0u8; 32]);
+			assert_eq!(genesis.header().timestamp(), params.genesis_timestamp);
+			assert_eq!(genesis.header().nonce(), params.genesis_nonce);
+			assert_eq!(genesis.header().kp_proposed(), params.kp);
+			assert_eq!(genesis.header().ki_proposed(), params.ki);
+			assert_eq!(genesis.tx().kernels().len(), 0);
+			assert_eq!(genesis.tx().outputs().len(), 0);
+			let sha3 = Sha3_256::new();
+			sha3.update(&genesis.header().to_bytes());
+			assert_eq!(sha3.finalize(), header_hashes[i]);
+
+			let tip = params.genesis_tip(&bible)?;
+			assert_eq!(tip.height, 0);
+			assert_eq!(tip.hash, params.genesis_tip(&bible)?.hash);
+			assert_eq!(tip.target, params.target);
+			hashes.push(tip.hash)?;
+		}
+		for i in 0..networks.len() {
+			for j in i + 1..networks.len() {
+				let (a, b) = (ChainParams::get(networks[i]), ChainParams::get(networks[j]));
+				assert!(hashes[i] != hashes[j]);
+				assert!(a.magic != b.magic);
+				assert!(a.bip52_key != b.bip52_key);
+			}
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_regtest_chain() -> Result<()> {
+		let db_dir = "bin/.regtest_chain";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let mut outputs = Pmmr::new(db.try_clone()?, "outputs")?;
+		let bible = Bible::new();
+		let ctx = Ctx::new()?;
+		let params = ChainParams::get(Network::Regtest);
+
+		// mine block 1 on the genesis block
+		let tip = params.genesis_tip(&bible)?;
+		let coinbase_blind = KeyChain::from_seed([3u8; 48])?.derive_key(&ctx, &[0, 0]);
+		let txn = db.write()?;
+		let mut template =
+			BlockTemplate::new(&ctx, &tip, &[], &coinbase_blind, &mut outputs, &txn)?;
+		txn.abort();
+		assert_eq!(template.height, 1);
+		assert_eq!(template.target, DIFFICULTY_4BIT_LEADING);
+		assert!(validate_reward(&ctx, &template.block, 1).is_ok());
+
+		let bip52 = params.bip52(tip.hash);
+		let hash = template
+			.block
+			.mine_block(&bip52, 1024 * 1024, template.target, &bible)?;
+		assert!(template
+			.block
+			.validate_hash(&bip52, template.target, hash, &bible)
+			.is_ok());
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+}
@@ -0,0 +1,194 @@
+use crypto::ctx::Ctx;
+use crypto::keys::{PublicKey, SecretKey};
+use crypto::pedersen::Commitment;
+use crypto::sha3::Sha3_256;
+use crypto::signature::{Message, Signature};
+use lmdb::txn::LmdbTxn;
+use misc::{
+	from_le_bytes_u64, slice_copy, subslice, subslice_mut, to_be_bytes_u64, to_le_bytes_u64,
+};
+use mw::constants::PAYMENT_PROOF_SIZE;
+use mw::errors::*;
+use prelude::*;
+use store::{KernelMmr, KernelPos};
+
+// A recipient's signature over (amount, kernel excess, sender address) with
+// its identity key. Anyone can check it and, once the kernel is confirmed,
+// that the payment happened, so a sender can prove to a third party that the
+// recipient was paid.
+#[derive(Clone)]
+pub struct PaymentProof {
+	amount: u64,
+	excess: Commitment,
+	sender: PublicKey,
+	recipient: PublicKey,
+	pubnonce: PublicKey,
+	signature: Signature,
+}
+
+impl PaymentProof {
+	// signs the proof as the owner of `recipient_key`
+	pub fn create(
+		ctx: &Ctx,
+		amount: u64,
+		excess: Commitment,
+		sender: PublicKey,
+		recipient_key: &SecretKey,
+	) -> Result<Self> {
+		let recipient = PublicKey::from(ctx, recipient_key)?;
+		let msg = Self::message_for(amount, &excess, &sender);
+		let secnonce = SecretKey::gen(ctx);
+		let pubnonce = PublicKey::from(ctx, &secnonce)?;
+		let signature = ctx.sign(&msg, recipient_key, &secnonce, &pubnonce, &recipient)?;
+		Ok(Self {
+			amount,
+			excess,
+			sender,
+			recipient,
+			pubnonce,
+			signature,
+		})
+	}
+
+	pub fn amount(&self) -> u64 {
+		self.amount
+	}
+
+	pub fn excess(&self) -> &Commitment {
+		&self.excess
+	}
+
+	pub fn sender(&self) -> &PublicKey {
+		&self.sender
+	}
+
+	pub fn recipient(&self) -> &PublicKey {
+		&self.recipient
+	}
+
+	// checks the recipient's signature. This alone doesn't show the payment
+	// was made, see confirmed.
+	pub fn verify(&self, ctx: &Ctx) -> Result<()> {
+		let msg = Self::message_for(self.amount, &self.excess, &self.sender);
+		match ctx.verify(
+			&self.signature,
+			&msg,
+			&self.pubnonce,
+			&self.recipient,
+			&self.recipient,
+			false,
+		) {
+			Ok(_) => Ok(()),
+			Err(_) => err!(ValidationFailed),
+		}
+	}
+
+	// checks the signature and that the kernel is in the chain, returning
+	// where it was confirmed. NotFound if it isn't (yet).
+	pub fn confirmed(
+		&self,
+		ctx: &Ctx,
+		kernels: &KernelMmr,
+		txn: Option<LmdbTxn>,
+	) -> Result<KernelPos> {
+		self.verify(ctx)?;
+		match kernels.find(&self.excess, txn)? {
+			Some(pos) => Ok(pos),
+			None => err!(NotFound),
+		}
+	}
+
+	pub fn message_for(amount: u64, excess: &Commitment, sender: &PublicKey) -> Message {
+		let sha3 = Sha3_256::new();
+		let mut buf64 = [0u8; 8];
+		to_be_bytes_u64(amount, &mut buf64);
+		sha3.update(&buf64);
+		sha3.update(excess.as_ref());
+		sha3.update(sender.as_ref());
+		Message::new(sha3.finalize())
+	}
+
+	// amount (little endian) || excess || sender || recipient || pubnonce ||
+	// signature
+	pub fn to_bytes(&self) -> [u8; PAYMENT_PROOF_SIZE] {
+		let mut ret = [0u8; PAYMENT_PROOF_SIZE];
+		// the lengths are fixed so these can't fail
+		if let Ok(amount) = subslice_mut(&mut ret, 0, 8) {
+			let _ = to_le_bytes_u64(self.amount, amount);
+		}
+		let parts: [&[u8]; 5] = [
+			self.excess.as_ref(),
+			self.sender.as_ref(),
+			self.recipient.as_ref(),
+			self.pubnonce.as_ref(),
+			self.signature.as_ref(),
+		];
+		let mut offset = 8;
+		for part in parts {
+			if let Ok(dst) = subslice_mut(&mut ret, offset, part.len()) {
+				let _ = slice_copy(part, dst, part.len());
+			}
+			offset += part.len();
+		}
+		ret
+	}
+
+	// parses a proof serialized by to_bytes. The signature isn't checked,
+	// see verify.
+	pub fn from_bytes(ctx: &Ctx, bytes: &[u8]) -> Result<Self> {
+		if bytes.len() != PAYMENT_PROOF_SIZE {
+			return err!(IllegalArgument);
+		}
+		Ok(Self {
+			amount: from_le_bytes_u64(subslice(bytes, 0, 8)?)?,
+			excess: Commitment::from_bytes(subslice(bytes, 8, 33)?)?,
+			sender: PublicKey::parse(ctx, subslice(bytes, 41, 33)?)?,
+			recipient: PublicKey::parse(ctx, subslice(bytes, 74, 33)?)?,
+			pubnonce: PublicKey::parse(ctx, subslice(bytes, 107, 33)?)?,
+			signature: Signature::from_bytes(subslice(bytes, 140, 64)?)?,
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_payment_proof() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let recipient_key = SecretKey::gen(&ctx);
+		let sender = PublicKey::from(&ctx, &SecretKey::gen(&ctx))?;
+		let excess = ctx.commit(0, &SecretKey::gen(&ctx))?;
+
+		let proof = PaymentProof::create(&ctx, 80, excess, sender.clone(), &recipient_key)?;
+		assert!(proof.verify(&ctx).is_ok());
+		assert_eq!(proof.amount(), 80);
+		assert!(proof.recipient() == &PublicKey::from(&ctx, &recipient_key)?);
+
+		let bytes = proof.to_bytes();
+		let proof2 = PaymentProof::from_bytes(&ctx, &bytes)?;
+		assert!(proof2.verify(&ctx).is_ok());
+		assert_eq!(proof2.to_bytes(), bytes);
+		assert!(PaymentProof::from_bytes(&ctx, &bytes[1..]).is_err());
+
+		// changing anything that was signed breaks it
+		let mut bytes2 = bytes;
+		bytes2[0] = 81;
+		assert!(PaymentProof::from_bytes(&ctx, &bytes2)?
+			.verify(&ctx)
+			.is_err());
+		let mut forged = proof.clone();
+		forged.sender = PublicKey::from(&ctx, &SecretKey::gen(&ctx))?;
+		assert!(forged.verify(&ctx).is_err());
+		let mut forged = proof.clone();
+		forged.excess = ctx.commit(0, &SecretKey::gen(&ctx))?;
+		assert!(forged.verify(&ctx).is_err());
+		// someone else can't claim to be the recipient
+		let mut forged = proof.clone();
+		forged.recipient = PublicKey::from(&ctx, &SecretKey::gen(&ctx))?;
+		assert!(forged.verify(&ctx).is_err());
+
+		Ok(())
+	}
+}
@@ -3,7 +3,9 @@ use crypto::keys::{PublicKey, SecretKey};
 use crypto::pedersen::Commitment;
 use crypto::range_proof::RangeProof;
 use crypto::signature::{Message, Signature};
+use mw::errors::*;
 use mw::kernel::Kernel;
+use mw::payment_proof::PaymentProof;
 use mw::transaction::Transaction;
 use prelude::*;
 
@@ -21,6 +23,14 @@ pub struct Slate {
 	pdata: Vec<ParticipantData>,
 	fee: u64,
 	offset: SecretKey,
+	// the amount, sender and recipient addresses a payment proof was
+	// requested for
+	proof_request: Option<(u64, PublicKey, PublicKey)>,
+	payment_proof: Option<PaymentProof>,
+	// 0 unless the kernel is height locked
+	lock_height: u64,
+	// the adaptor point the partial signatures are made with, see set_adaptor
+	adaptor: Option<PublicKey>,
 }
 
 impl Slate {
@@ -29,19 +39,100 @@ impl Slate {
 			pdata: Vec::new(),
 			fee,
 			offset,
+			proof_request: None,
+			payment_proof: None,
+			lock_height: 0,
+			adaptor: None,
 		}
 	}
 
+	// makes the kernel height locked, the transaction can't be in a block
+	// below `lock_height`. Must be set before anyone signs.
+	pub fn set_lock_height(&mut self, lock_height: u64) -> Result<()> {
+		self.check_unsigned()?;
+		self.lock_height = lock_height;
+		Ok(())
+	}
+
+	// Atomic swaps. The party selling coins on this chain (A) trades with a
+	// party (B) who locked coins on another chain so that they can be claimed
+	// with a secret t, refundable to B after a timeout. B gives A the adaptor
+	// point T = tG and everyone signs with it, so the partial signatures only
+	// make a kernel signature together with t. B completes the transaction
+	// with finalize_adapted, which publishes t in the kernel, and A reads t
+	// back with adaptor_secret to claim on the other chain. The refund is a
+	// second slate spending A's inputs with set_lock_height at a height that
+	// is reached before B's timeout, so A gets the coins back if B never
+	// finalizes. Must be set before anyone signs.
+	pub fn set_adaptor(&mut self, adaptor: PublicKey) -> Result<()> {
+		self.check_unsigned()?;
+		self.adaptor = Some(adaptor);
+		Ok(())
+	}
+
+	// called by the sender before sending the slate. The owner of
+	// `recipient` must then sign a payment proof for `amount` to `sender` or
+	// finalize fails.
+	pub fn request_payment_proof(&mut self, amount: u64, sender: PublicKey, recipient: PublicKey) {
+		self.proof_request = Some((amount, sender, recipient));
+	}
+
+	// called by the recipient once everyone has committed, as the kernel
+	// excess is fixed from then on. `amount` is what the recipient agrees to
+	// have received and must be what was requested, as must be the address
+	// of `recipient_key`.
+	pub fn sign_payment_proof(
+		&mut self,
+		ctx: &Ctx,
+		amount: u64,
+		recipient_key: &SecretKey,
+	) -> Result<()> {
+		let sender = match &self.proof_request {
+			Some((requested, sender, recipient)) => {
+				if *requested != amount || PublicKey::from(ctx, recipient_key)? != *recipient {
+					return err!(IllegalArgument);
+				}
+				sender.clone()
+			}
+			None => return err!(IllegalState),
+		};
+		let excess = self.excess_commit_sum(ctx)?;
+		let proof = PaymentProof::create(ctx, amount, excess, sender, recipient_key)?;
+		self.payment_proof = Some(proof);
+		Ok(())
+	}
+
+	// the payment proof, after finalize it is known to be for the kernel
+	pub fn payment_proof(&self) -> Option<&PaymentProof> {
+		self.payment_proof.as_ref()
+	}
+
 	pub fn commit(
 		&mut self,
 		ctx: &Ctx,
 		input_keys: &[(&SecretKey, u64)],
 		output_keys: &[(&SecretKey, u64)],
+	) -> Result<usize> {
+		self.commit_shared(ctx, input_keys, output_keys, &[], &[])
+	}
+
+	// like commit, for a participant that also owns shares of multisig
+	// outputs (see MultisigOutput) that are spent (`input_shares`) or
+	// created (`output_shares`). The shares must be passed to sign along
+	// with the other keys. The shared outputs themselves are added once, by
+	// any of their owners, with add_shared_input and add_shared_output.
+	pub fn commit_shared(
+		&mut self,
+		ctx: &Ctx,
+		input_keys: &[(&SecretKey, u64)],
+		output_keys: &[(&SecretKey, u64)],
+		input_shares: &[&SecretKey],
+		output_shares: &[&SecretKey],
 	) -> Result<usize> {
 		let mut inputs = Vec::with_capacity(input_keys.len())?;
 		let mut outputs = Vec::with_capacity(output_keys.len())?;
-		let mut input_keys_only = Vec::with_capacity(input_keys.len())?;
-		let mut output_keys_only = Vec::with_capacity(output_keys.len())?;
+		let mut input_keys_only = Vec::with_capacity(input_keys.len() + input_shares.len())?;
+		let mut output_keys_only = Vec::with_capacity(output_keys.len() + output_shares.len())?;
 		let sec_nonce = SecretKey::gen(&ctx);
 
 		for input_key in input_keys {
@@ -55,6 +146,12 @@ impl Slate {
 			outputs.push((commit, proof))?;
 			output_keys_only.push(output_key.0)?;
 		}
+		for share in input_shares {
+			input_keys_only.push(*share)?;
+		}
+		for share in output_shares {
+			output_keys_only.push(*share)?;
+		}
 		if self.pdata.len() == 0 {
 			output_keys_only.push(&self.offset)?;
 		}
@@ -80,6 +177,31 @@ impl Slate {
 		Ok(self.pdata.len() - 1)
 	}
 
+	// adds a multisig output, see commit_shared
+	pub fn add_shared_output(
+		&mut self,
+		ctx: &Ctx,
+		participant_id: usize,
+		output: Commitment,
+		proof: RangeProof,
+	) -> Result<()> {
+		self.check_unsigned()?;
+		if participant_id >= self.pdata.len() {
+			return err!(IllegalArgument);
+		}
+		ctx.verify_range_proof(&output, &proof)?;
+		self.pdata[participant_id].outputs.push((output, proof))
+	}
+
+	// adds a multisig output being spent, see commit_shared
+	pub fn add_shared_input(&mut self, participant_id: usize, input: Commitment) -> Result<()> {
+		self.check_unsigned()?;
+		if participant_id >= self.pdata.len() {
+			return err!(IllegalArgument);
+		}
+		self.pdata[participant_id].inputs.push(input)
+	}
+
 	pub fn sign(
 		&mut self,
 		ctx: &Ctx,
@@ -99,18 +221,28 @@ impl Slate {
 		let pub_nonce_sum = self.pub_nonce_sum(ctx)?;
 		let pub_blind_sum = self.pub_blind_sum(ctx)?;
 		let excess_commit = self.excess_commit_sum(ctx)?;
-		let msg = Kernel::message_for(&excess_commit, self.fee, 0);
+		let msg = self.message(&excess_commit);
 
 		self.verify_part_sigs(ctx, participant_id, &msg, &pub_nonce_sum, &pub_blind_sum)?;
 		let sec_nonce = &self.pdata[participant_id].sec_nonce;
 
-		let part_sig = ctx.sign(
-			&msg,
-			&excess_blind,
-			sec_nonce,
-			&pub_nonce_sum,
-			&pub_blind_sum,
-		)?;
+		let part_sig = match &self.adaptor {
+			Some(adaptor) => ctx.adaptor_sign(
+				&msg,
+				&excess_blind,
+				sec_nonce,
+				&pub_nonce_sum,
+				&pub_blind_sum,
+				adaptor,
+			)?,
+			None => ctx.sign(
+				&msg,
+				&excess_blind,
+				sec_nonce,
+				&pub_nonce_sum,
+				&pub_blind_sum,
+			)?,
+		};
 
 		self.pdata[participant_id].part_sig = Some(part_sig);
 
@@ -121,26 +253,72 @@ impl Slate {
 	}
 
 	pub fn finalize(&mut self, ctx: &Ctx) -> Result<Transaction> {
-		if self.pdata.len() == 0 {
+		// an adapted slate can only be finalized with the adaptor secret
+		if self.pdata.len() == 0 || self.adaptor.is_some() {
 			return err!(IllegalState);
 		}
 		let excess_commit = self.excess_commit_sum(ctx)?;
 		let pub_nonce_sum = self.pub_nonce_sum(ctx)?;
+		let partial_sigs = self.partial_sigs()?;
+		self.check_payment_proof(ctx, &excess_commit)?;
+		let aggsig =
+			ctx.aggregate_signatures(partial_sigs.slice(0, partial_sigs.len()), &pub_nonce_sum)?;
+		self.build(excess_commit, aggsig)
+	}
 
-		let mut partial_sigs = Vec::with_capacity(self.pdata.len())?;
-		for i in 0..self.pdata.len() {
-			match &self.pdata[i].part_sig {
-				Some(part_sig) => {
-					partial_sigs.push(part_sig)?;
-				}
-				None => {
-					return err!(IllegalState);
+	// finalizes a slate signed with an adaptor, `secret` is the t of
+	// set_adaptor and ends up in the kernel signature
+	pub fn finalize_adapted(&mut self, ctx: &Ctx, secret: &SecretKey) -> Result<Transaction> {
+		match &self.adaptor {
+			Some(adaptor) => {
+				if PublicKey::from(ctx, secret)? != *adaptor {
+					return err!(IllegalArgument);
 				}
 			}
+			None => return err!(IllegalState),
 		}
-		let aggsig =
-			ctx.aggregate_signatures(partial_sigs.slice(0, partial_sigs.len()), &pub_nonce_sum)?;
-		let kernel = Kernel::new(excess_commit, aggsig, self.fee, 0);
+		let excess_commit = self.excess_commit_sum(ctx)?;
+		let pub_nonce_sum = self.pub_nonce_sum(ctx)?;
+		let pub_blind_sum = self.pub_blind_sum(ctx)?;
+		let partial_sigs = self.partial_sigs()?;
+		self.check_payment_proof(ctx, &excess_commit)?;
+		let aggsig = ctx.adapt_signature(
+			partial_sigs.slice(0, partial_sigs.len()),
+			secret,
+			&self.message(&excess_commit),
+			&pub_nonce_sum,
+			&pub_blind_sum,
+		)?;
+		self.build(excess_commit, aggsig)
+	}
+
+	// the adaptor secret, from `tx` (or a block containing it) once it was
+	// finalized with finalize_adapted. NotFound if the kernel isn't in it.
+	pub fn adaptor_secret(&self, ctx: &Ctx, tx: &Transaction) -> Result<SecretKey> {
+		let adaptor = match &self.adaptor {
+			Some(adaptor) => adaptor,
+			None => return err!(IllegalState),
+		};
+		let excess_commit = self.excess_commit_sum(ctx)?;
+		let partial_sigs = self.partial_sigs()?;
+		for kernel in tx.kernels().iter() {
+			if kernel.excess() == &excess_commit {
+				return ctx.extract_adaptor_secret(
+					kernel.signature(),
+					partial_sigs.slice(0, partial_sigs.len()),
+					adaptor,
+				);
+			}
+		}
+		err!(NotFound)
+	}
+
+	fn build(&self, excess_commit: Commitment, aggsig: Signature) -> Result<Transaction> {
+		let kernel = if self.lock_height > 0 {
+			Kernel::height_locked(excess_commit, aggsig, self.fee, self.lock_height)
+		} else {
+			Kernel::new(excess_commit, aggsig, self.fee, 0)
+		};
 		let mut tx = Transaction::new(self.offset.clone());
 		tx.add_kernel(kernel)?;
 		for i in 0..self.pdata.len() {
@@ -158,6 +336,58 @@ impl Slate {
 		Ok(tx)
 	}
 
+	fn partial_sigs(&self) -> Result<Vec<&Signature>> {
+		let mut partial_sigs = Vec::with_capacity(self.pdata.len())?;
+		for i in 0..self.pdata.len() {
+			match &self.pdata[i].part_sig {
+				Some(part_sig) => {
+					partial_sigs.push(part_sig)?;
+				}
+				None => {
+					return err!(IllegalState);
+				}
+			}
+		}
+		Ok(partial_sigs)
+	}
+
+	fn check_unsigned(&self) -> Result<()> {
+		for i in 0..self.pdata.len() {
+			if self.pdata[i].part_sig.is_some() {
+				return err!(IllegalState);
+			}
+		}
+		Ok(())
+	}
+
+	fn message(&self, excess_commit: &Commitment) -> Message {
+		if self.lock_height > 0 {
+			Kernel::height_locked_message_for(excess_commit, self.fee, self.lock_height)
+		} else {
+			Kernel::message_for(excess_commit, self.fee, 0)
+		}
+	}
+
+	fn check_payment_proof(&self, ctx: &Ctx, excess: &Commitment) -> Result<()> {
+		let (amount, sender, recipient) = match &self.proof_request {
+			Some(request) => request,
+			None => return Ok(()),
+		};
+		match &self.payment_proof {
+			Some(proof) => {
+				if proof.amount() != *amount
+					|| proof.sender() != sender
+					|| proof.recipient() != recipient
+					|| proof.excess() != excess
+				{
+					return err!(ValidationFailed);
+				}
+				proof.verify(ctx)
+			}
+			None => err!(ValidationFailed),
+		}
+	}
+
 	fn verify_part_sigs(
 		&self,
 		ctx: &Ctx,
@@ -169,16 +399,24 @@ impl Slate {
 		for i in 0..self.pdata.len() {
 			if i != participant_id {
 				match &self.pdata[i].part_sig {
-					Some(part_sig) => {
-						ctx.verify(
+					Some(part_sig) => match &self.adaptor {
+						Some(adaptor) => ctx.verify_adaptor(
+							part_sig,
+							msg,
+							pub_nonce_sum,
+							&self.pdata[i].pub_blind_excess,
+							pub_blind_sum,
+							adaptor,
+						)?,
+						None => ctx.verify(
 							part_sig,
 							msg,
 							pub_nonce_sum,
 							&self.pdata[i].pub_blind_excess,
 							pub_blind_sum,
 							true,
-						)?;
-					}
+						)?,
+					},
 					None => {}
 				}
 			}
@@ -225,7 +463,11 @@ impl Slate {
 #[cfg(test)]
 mod test {
 	use super::*;
+	use lmdb::db::Lmdb;
+	use lmdb::{make_lmdb_test_dir, remove_lmdb_test_dir};
 	use mw::keychain::KeyChain;
+	use store::errors::LockHeight;
+	use store::KernelMmr;
 
 	#[test]
 	fn test_slate1() -> Result<()> {
@@ -394,4 +636,179 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_slate_payment_proof() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let kc1 = KeyChain::from_seed([5u8; 48])?;
+		let kc2 = KeyChain::from_seed([6u8; 48])?;
+		let sender = PublicKey::from(&ctx, &kc1.derive_key(&ctx, &[9, 0]))?;
+		let recipient_key = kc2.derive_key(&ctx, &[9, 0]);
+		let recipient = PublicKey::from(&ctx, &recipient_key)?;
+		let input = kc1.derive_key(&ctx, &[0, 0]);
+		let change = kc1.derive_key(&ctx, &[0, 1]);
+		let output = kc2.derive_key(&ctx, &[0, 0]);
+
+		// the sender asks for a proof of the 80 coins it pays
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		slate.request_payment_proof(80, sender.clone(), recipient.clone());
+		let user1_id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 10)])?;
+		// the recipient can't sign for another amount
+		let user2_id = slate.commit(&ctx, &[], &[(&output, 80)])?;
+		assert_eq!(
+			slate.sign_payment_proof(&ctx, 79, &recipient_key).err(),
+			Some(IllegalArgument)
+		);
+		// nor can anyone else sign
+		assert_eq!(
+			slate
+				.sign_payment_proof(&ctx, 80, &kc2.derive_key(&ctx, &[9, 1]))
+				.err(),
+			Some(IllegalArgument)
+		);
+		slate.sign_payment_proof(&ctx, 80, &recipient_key)?;
+		slate.sign(&ctx, user2_id, &[], &[&output])?;
+		slate.sign(&ctx, user1_id, &[&input], &[&change])?;
+		let tx = slate.finalize(&ctx)?;
+		assert!(tx.validate(&ctx, 0).is_ok());
+
+		let proof = slate.payment_proof().unwrap().clone();
+		assert_eq!(proof.amount(), 80);
+		assert!(proof.sender() == &sender);
+		assert!(proof.recipient() == &recipient);
+		let kernel = tx.kernels().iter().next().unwrap();
+		assert!(proof.excess() == kernel.excess());
+
+		// once the kernel is in the chain the proof is confirmed
+		let db_dir = "bin/.slate_payment_proof";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let mut kernels = KernelMmr::new(db.try_clone()?, "kernels")?;
+		let txn = db.write()?;
+		assert_eq!(
+			proof.confirmed(&ctx, &kernels, Some(txn.clone())).err(),
+			Some(NotFound)
+		);
+		for height in 0..7 {
+			kernels.apply_block(height, &Transaction::empty(), Some(txn.clone()))?;
+		}
+		kernels.apply_block(7, &tx, Some(txn.clone()))?;
+		let pos = proof.confirmed(&ctx, &kernels, Some(txn.clone()))?;
+		assert_eq!(pos.height, 7);
+		txn.abort();
+		remove_lmdb_test_dir(db_dir)?;
+
+		// without a proof from the recipient finalize fails
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		assert_eq!(
+			slate.sign_payment_proof(&ctx, 80, &recipient_key).err(),
+			Some(IllegalState)
+		);
+		slate.request_payment_proof(80, sender.clone(), recipient.clone());
+		let user1_id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 10)])?;
+		let user2_id = slate.commit(&ctx, &[], &[(&output, 80)])?;
+		slate.sign(&ctx, user2_id, &[], &[&output])?;
+		slate.sign(&ctx, user1_id, &[&input], &[&change])?;
+		assert_eq!(slate.finalize(&ctx).err(), Some(ValidationFailed));
+
+		// nor does a proof signed before everyone committed
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		slate.request_payment_proof(80, sender.clone(), recipient.clone());
+		let user1_id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 10)])?;
+		slate.sign_payment_proof(&ctx, 80, &recipient_key)?;
+		let user2_id = slate.commit(&ctx, &[], &[(&output, 80)])?;
+		slate.sign(&ctx, user2_id, &[], &[&output])?;
+		slate.sign(&ctx, user1_id, &[&input], &[&change])?;
+		assert_eq!(slate.finalize(&ctx).err(), Some(ValidationFailed));
+
+		// nor a proof for another recipient than the sender expects
+		let other_key = kc2.derive_key(&ctx, &[9, 1]);
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		slate.request_payment_proof(80, sender.clone(), PublicKey::from(&ctx, &other_key)?);
+		let user1_id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 10)])?;
+		let user2_id = slate.commit(&ctx, &[], &[(&output, 80)])?;
+		slate.sign_payment_proof(&ctx, 80, &other_key)?;
+		slate.proof_request = Some((80, sender, recipient));
+		slate.sign(&ctx, user2_id, &[], &[&output])?;
+		slate.sign(&ctx, user1_id, &[&input], &[&change])?;
+		assert_eq!(slate.finalize(&ctx).err(), Some(ValidationFailed));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_atomic_swap() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let kc1 = KeyChain::from_seed([7u8; 48])?;
+		let kc2 = KeyChain::from_seed([8u8; 48])?;
+		let input = kc1.derive_key(&ctx, &[0, 0]);
+		let change = kc1.derive_key(&ctx, &[0, 1]);
+		let output = kc2.derive_key(&ctx, &[0, 0]);
+
+		// user2 locked coins on the other chain behind `secret`
+		let secret = SecretKey::gen(&ctx);
+		let adaptor = PublicKey::from(&ctx, &secret)?;
+
+		let mut slate = Slate::new(10, SecretKey::gen(&ctx));
+		slate.set_adaptor(adaptor)?;
+		let user1_id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 10)])?;
+		let user2_id = slate.commit(&ctx, &[], &[(&output, 80)])?;
+		slate.sign(&ctx, user2_id, &[], &[&output])?;
+		slate.sign(&ctx, user1_id, &[&input], &[&change])?;
+		assert_eq!(
+			slate.set_adaptor(PublicKey::from(&ctx, &secret)?).err(),
+			Some(IllegalState)
+		);
+
+		// user1's refund, spending the same input back to itself once the
+		// swap times out
+		let refund_output = kc1.derive_key(&ctx, &[0, 2]);
+		let mut refund = Slate::new(10, SecretKey::gen(&ctx));
+		refund.set_lock_height(100)?;
+		let refund_id = refund.commit(&ctx, &[(&input, 100)], &[(&refund_output, 90)])?;
+		refund.sign(&ctx, refund_id, &[&input], &[&refund_output])?;
+		let refund_tx = refund.finalize(&ctx)?;
+		assert!(refund_tx.validate(&ctx, 0).is_ok());
+		assert_eq!(refund_tx.lock_height(), 100);
+
+		// the partial signatures alone don't make a transaction
+		assert_eq!(slate.finalize(&ctx).err(), Some(IllegalState));
+		assert_eq!(
+			slate.finalize_adapted(&ctx, &SecretKey::gen(&ctx)).err(),
+			Some(IllegalArgument)
+		);
+
+		// user2 claims with the secret
+		let tx = slate.finalize_adapted(&ctx, &secret)?;
+		assert!(tx.validate(&ctx, 0).is_ok());
+		assert_eq!(tx.lock_height(), 0);
+
+		// which user1 learns from the kernel, also within a block
+		assert!(slate.adaptor_secret(&ctx, &tx)? == secret);
+		let mut block = Transaction::empty();
+		block.merge(&ctx, tx)?;
+		assert!(slate.adaptor_secret(&ctx, &block)? == secret);
+		assert_eq!(slate.adaptor_secret(&ctx, &refund_tx).err(), Some(NotFound));
+
+		// the refund can't be confirmed before its lock height
+		let db_dir = "bin/.atomic_swap";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let mut kernels = KernelMmr::new(db.try_clone()?, "kernels")?;
+		let txn = db.write()?;
+		for height in 0..100 {
+			assert_eq!(
+				kernels
+					.apply_block(height, &refund_tx, Some(txn.clone()))
+					.err(),
+				Some(LockHeight)
+			);
+			kernels.apply_block(height, &Transaction::empty(), Some(txn.clone()))?;
+		}
+		kernels.apply_block(100, &refund_tx, Some(txn.clone()))?;
+		txn.abort();
+		remove_lmdb_test_dir(db_dir)?;
+
+		Ok(())
+	}
 }
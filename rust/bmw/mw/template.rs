@@ -0,0 +1,328 @@
+use crypto::ctx::Ctx;
+use crypto::keys::SecretKey;
+use crypto::pedersen::Commitment;
+use lmdb::txn::LmdbTxn;
+use mw::block::Block;
+use mw::consensus::reward;
+use mw::constants::*;
+use mw::transaction::Transaction;
+use prelude::*;
+use store::Pmmr;
+
+// what a template needs to know about the block it builds on
+pub struct ChainTip {
+	pub height: u64,
+	pub hash: [u8; 32],
+	// the target of the block built on the tip. The template doesn't
+	// retarget, the caller works the target out with the PI controller and
+	// the Kp/Ki in effect at the tip.
+	pub target: [u8; 32],
+	// seconds the caller's retarget window took up to the tip, the template
+	// doesn't use it
+	pub window_secs: u64,
+}
+
+// A block ready to be mined with `Block::mine_block` at `target`.
+pub struct BlockTemplate {
+	pub block: Block,
+	pub height: u64,
+	pub target: [u8; 32],
+}
+
+impl BlockTemplate {
+	// builds a block on `tip`. Mempool transactions are picked by fee per
+	// weight while they fit, skipping any that are height locked above the
+	// new block or that spend an output that is not in `outputs` or that is
+	// already spent by a transaction picked before it. The coinbase pays reward(height) plus fees to `coinbase_blind`.
+	// `outputs` is the output pmmr at the tip. The block is applied to it in
+	// a savepoint of `txn` to compute the sync_state_hash and then discarded.
+	pub fn new(
+		ctx: &Ctx,
+		tip: &ChainTip,
+		mempool: &[Transaction],
+		coinbase_blind: &SecretKey,
+		outputs: &mut Pmmr,
+		txn: &LmdbTxn,
+	) -> Result<Self> {
+		let height = tip.height + 1;
+
+		let max_weight = MAX_BLOCK_WEIGHT - OUTPUT_WEIGHT - KERNEL_WEIGHT;
+		let picked = Self::select(mempool, height, max_weight, outputs, txn)?;
+		let block = Block::new(tip.hash, [0u8; 32], [0u8; 4], [0u8; 4]);
+		let mut block = Self::fill(ctx, block, mempool, &picked)?;
+		block = block.with_coinbase(ctx, coinbase_blind, reward(height))?;
+
+		// outputs are appended before inputs are pruned, see Pmmr::prune
+		let sp = txn.savepoint()?;
+		let result = Self::apply(&block, outputs, &sp);
+		let (peak_data_hash, last_pos) = match result {
+			Ok(state) => state,
+			Err(e) => {
+				sp.abort();
+				return Err(e);
+			}
+		};
+		sp.abort();
+		block.finalize_header_with_outputs(peak_data_hash, last_pos)?;
+
+		Ok(Self {
+			block,
+			height,
+			target: tip.target,
+		})
+	}
+
+	// sets the Kp and Ki the block proposes, see BlockHeader. A template
+	// proposes zeros until this is called, miners that don't vote for a
+	// change should propose the values in effect at the tip.
+	pub fn vote(&mut self, kp: [u8; 4], ki: [u8; 4]) {
+		let mut header = self.block.header().clone();
+		header.set_votes(kp, ki);
+		self.block.set_header(header);
+	}
+
+	// the indices of the mempool transactions to include, highest fee per
+	// weight first
+	fn select(
+		mempool: &[Transaction],
+		height: u64,
+		max_weight: u64,
+		outputs: &Pmmr,
+		txn: &LmdbTxn,
+	) -> Result<Vec<usize>> {
+		let mut order: Vec<usize> = Vec::with_capacity(mempool.len())?;
+		for i in 0..mempool.len() {
+			// insertion sort, ties keep mempool order
+			let mut j = order.len();
+			order.push(i)?;
+			while j > 0 && Self::better(&mempool[i], &mempool[order[j - 1]]) {
+				order[j] = order[j - 1];
+				j -= 1;
+			}
+			order[j] = i;
+		}
+
+		let mut picked = Vec::new();
+		let mut spent: Vec<Commitment> = Vec::new();
+		let mut weight = 0;
+		for i in order.iter() {
+			let tx = &mempool[*i];
+			if weight + tx.weight() > max_weight || tx.lock_height() > height {
+				continue;
+			}
+			let mut ok = true;
+			for input in tx.inputs().iter() {
+				if spent.iter().any(|s| s == input)
+					|| outputs.pos(input.as_ref(), Some(txn.clone()))?.is_none()
+				{
+					ok = false;
+					break;
+				}
+			}
+			if !ok {
+				continue;
+			}
+			for input in tx.inputs().iter() {
+				spent.push(input.clone())?;
+			}
+			weight += tx.weight();
+			picked.push(*i)?;
+		}
+		Ok(picked)
+	}
+
+	// whether `a` pays more fee per weight than `b`
+	fn better(a: &Transaction, b: &Transaction) -> bool {
+		a.fees() as u128 * b.weight() as u128 > b.fees() as u128 * a.weight() as u128
+	}
+
+	// adds the picked transactions. One repeating a kernel already in the
+	// block is left out before merging (a failed merge can leave part of its
+	// kernels behind), the coinbase is built afterwards from the block's
+	// fees so it only counts what was added. Any other error is returned.
+	fn fill(
+		ctx: &Ctx,
+		mut block: Block,
+		mempool: &[Transaction],
+		picked: &[usize],
+	) -> Result<Block> {
+		for i in picked {
+			let tx = &mempool[*i];
+			let kernels = block.tx().kernels();
+			if tx.kernels().iter().any(|k| kernels.find(k).is_some()) {
+				continue;
+			}
+			block.add_tx(ctx, tx.try_clone()?)?;
+		}
+		Ok(block)
+	}
+
+	// applies the block's outputs and inputs to `outputs` and returns its
+	// peak data hash and last pos afterwards
+	fn apply(block: &Block, outputs: &mut Pmmr, txn: &LmdbTxn) -> Result<([u8; 32], u64)> {
+		for (output, _) in block.tx().outputs().iter() {
+			outputs.append(output.as_ref(), Some(txn.clone()))?;
+		}
+		for input in block.tx().inputs().iter() {
+			outputs.prune(input.as_ref(), Some(txn.clone()))?;
+		}
+		Ok((
+			outputs.peak_data_hash(Some(txn.clone()))?,
+			outputs.last_pos(Some(txn.clone()))?,
+		))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use bible::Bible;
+	use crypto::bip52::Bip52;
+	use lmdb::db::Lmdb;
+	use lmdb::{make_lmdb_test_dir, remove_lmdb_test_dir};
+	use mw::consensus::validate_reward;
+	use mw::keychain::KeyChain;
+	use mw::slate::Slate;
+
+	// a transaction spending `value` from the key derived from `seed`, and
+	// the input it spends
+	fn tx(ctx: &Ctx, seed: u8, value: u64, fee: u64) -> Result<(Transaction, Commitment)> {
+		let mut slate = Slate::new(fee, SecretKey::gen(ctx));
+		let kc1 = KeyChain::from_seed([seed; 48])?;
+		let input = kc1.derive_key(ctx, &[0, 0]);
+		let change = kc1.derive_key(ctx, &[0, 1]);
+		let user1_id = slate.commit(ctx, &[(&input, value)], &[(&change, 10)])?;
+		let kc2 = KeyChain::from_seed([seed.wrapping_add(100); 48])?;
+		let output = kc2.derive_key(ctx, &[0, 0]);
+		let user2_id = slate.commit(ctx, &[], &[(&output, value - 10 - fee)])?;
+		slate.sign(ctx, user2_id, &[], &[&output])?;
+		slate.sign(ctx, user1_id, &[&input], &[&change])?;
+		Ok((slate.finalize(ctx)?, ctx.commit(value, &input)?))
+	}
+
+	#[test]
+	fn test_block_template() -> Result<()> {
+		let db_dir = "bin/.block_template";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let mut outputs = Pmmr::new(db.try_clone()?, "outputs")?;
+		let ctx = Ctx::new()?;
+
+		let (tx1, input1) = tx(&ctx, 1, 100, 10)?;
+		let (tx2, input2) = tx(&ctx, 2, 200, 20)?;
+		// spends the same output as tx2 for a lower fee
+		let (tx3, _) = tx(&ctx, 2, 200, 5)?;
+		// spends an output that doesn't exist
+		let (tx4, _) = tx(&ctx, 4, 300, 50)?;
+		outputs.append(input1.as_ref(), None)?;
+		outputs.append(input2.as_ref(), None)?;
+		let last_pos = outputs.last_pos(None)?;
+		let mempool = [tx1, tx3, tx4, tx2];
+
+		let tip = ChainTip {
+			height: 0,
+			hash: [7u8; 32],
+			target: DIFFICULTY_4BIT_LEADING,
+			window_secs: 0,
+		};
+		let coinbase_blind = KeyChain::from_seed([9u8; 48])?.derive_key(&ctx, &[0, 0]);
+		let txn = db.write()?;
+		let mut template =
+			BlockTemplate::new(&ctx, &tip, &mempool, &coinbase_blind, &mut outputs, &txn)?;
+		assert_eq!(template.height, 1);
+		assert_eq!(template.target, DIFFICULTY_4BIT_LEADING);
+		assert_eq!(template.block.header().prev_hash(), [7u8; 32]);
+		template.vote([100, 0, 0, 0], [10, 0, 0, 0]);
+		assert_eq!(template.block.header().kp_proposed(), [100, 0, 0, 0]);
+		assert_eq!(template.block.header().ki_proposed(), [10, 0, 0, 0]);
+
+		// tx2 and tx1 plus the coinbase
+		let block_tx = template.block.tx();
+		assert_eq!(block_tx.kernels().len(), 3);
+		assert_eq!(block_tx.inputs().len(), 2);
+		assert_eq!(block_tx.outputs().len(), 5);
+		assert_eq!(block_tx.fees(), 30);
+		assert!(validate_reward(&ctx, &template.block, 1).is_ok());
+
+		// a transaction repeating a kernel already in the block is left out
+		let block = Block::new(tip.hash, [0u8; 32], [0u8; 4], [0u8; 4]);
+		let block = BlockTemplate::fill(&ctx, block, &mempool, &[0, 0])?;
+		assert_eq!(block.tx().kernels().len(), 1);
+		assert_eq!(block.fees(), 10);
+
+		// the sync state commits to the outputs after the block, which
+		// aren't written
+		assert_eq!(outputs.last_pos(Some(txn.clone()))?, last_pos);
+		let sp = txn.savepoint()?;
+		let (peak_data_hash, last_pos) = BlockTemplate::apply(&template.block, &mut outputs, &sp)?;
+		sp.abort();
+		let sync_state_hash = template.block.header().sync_state_hash();
+		template
+			.block
+			.finalize_header_with_outputs(peak_data_hash, last_pos)?;
+		assert_eq!(template.block.header().sync_state_hash(), sync_state_hash);
+		template.block.finalize_header()?;
+		assert!(template.block.header().sync_state_hash() != sync_state_hash);
+		txn.abort();
+
+		// ready to mine
+		let bible = Bible::new();
+		let bip52 = Bip52::new([1u8; 32], [7u8; 32]);
+		let target = template.target;
+		let hash = template
+			.block
+			.mine_block(&bip52, 1024 * 1024, target, &bible)?;
+		assert!(template
+			.block
+			.validate_hash(&bip52, target, hash, &bible)
+			.is_ok());
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_block_template_select() -> Result<()> {
+		let db_dir = "bin/.block_template_select";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let mut outputs = Pmmr::new(db.try_clone()?, "outputs")?;
+		let ctx = Ctx::new()?;
+
+		let mut mempool = Vec::new();
+		for (seed, fee) in [(1, 10), (2, 30), (3, 20)] {
+			let (tx, input) = tx(&ctx, seed, 100, fee)?;
+			outputs.append(input.as_ref(), None)?;
+			mempool.push(tx)?;
+		}
+		// each is one input, two outputs and a kernel
+		let weight = mempool[0].weight();
+		assert_eq!(weight, INPUT_WEIGHT + 2 * OUTPUT_WEIGHT + KERNEL_WEIGHT);
+
+		// the best paying one is height locked
+		let mut slate = Slate::new(50, SecretKey::gen(&ctx));
+		slate.set_lock_height(5)?;
+		let kc = KeyChain::from_seed([4u8; 48])?;
+		let input = kc.derive_key(&ctx, &[0, 0]);
+		let change = kc.derive_key(&ctx, &[0, 1]);
+		let id = slate.commit(&ctx, &[(&input, 100)], &[(&change, 50)])?;
+		slate.sign(&ctx, id, &[&input], &[&change])?;
+		mempool.push(slate.finalize(&ctx)?)?;
+		outputs.append(ctx.commit(100, &input)?.as_ref(), None)?;
+
+		let txn = db.read()?;
+		let picked = BlockTemplate::select(&mempool, 1, 3 * weight, &outputs, &txn)?;
+		assert_eq!(picked.as_ref(), &[1, 2, 0]);
+		let picked = BlockTemplate::select(&mempool, 1, 2 * weight + 1, &outputs, &txn)?;
+		assert_eq!(picked.as_ref(), &[1, 2]);
+		let picked = BlockTemplate::select(&mempool, 1, weight - 1, &outputs, &txn)?;
+		assert_eq!(picked.len(), 0);
+		let picked = BlockTemplate::select(&mempool, 4, 4 * weight, &outputs, &txn)?;
+		assert_eq!(picked.as_ref(), &[1, 2, 0]);
+		let picked = BlockTemplate::select(&mempool, 5, 4 * weight, &outputs, &txn)?;
+		assert_eq!(picked.as_ref(), &[3, 1, 2, 0]);
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+}
@@ -4,6 +4,7 @@ use crypto::pedersen::Commitment;
 use crypto::range_proof::RangeProof;
 use crypto::sha3::Sha3_256;
 use crypto::signature::Message;
+use mw::constants::{INPUT_WEIGHT, KERNEL_WEIGHT, OUTPUT_WEIGHT};
 use mw::errors::*;
 use mw::kernel::Kernel;
 use prelude::*;
@@ -202,6 +203,24 @@ impl Transaction {
 	}
 
 	pub fn validate(&self, ctx: &Ctx, overage: u64) -> Result<()> {
+		// if there's no overage (not coinbase), we just add fee
+		// otherwise, we negate the overage
+		let adjustment: i128 = if overage == 0 {
+			self.fees() as i128
+		} else {
+			-1i128 * (overage as i128)
+		};
+		self.validate_balance(ctx, adjustment)
+	}
+
+	// validates the transaction of a block, whose coinbase mints `reward`
+	// and is paid the fees. Unlike `validate`, a zero reward still means
+	// there is a coinbase, one that is only paid the fees.
+	pub fn validate_coinbase(&self, ctx: &Ctx, reward: u64) -> Result<()> {
+		self.validate_balance(ctx, -(reward as i128))
+	}
+
+	fn validate_balance(&self, ctx: &Ctx, adjustment: i128) -> Result<()> {
 		if self.kernels.root().is_null() || self.outputs.len() == 0 {
 			return err!(NotFound);
 		}
@@ -223,8 +242,6 @@ impl Transaction {
 			output_commits.push(offset_commit)?;
 		}
 
-		let fee = self.fees();
-
 		self.verify_kernels(self.kernels.root(), ctx, &mut output_commits)?;
 
 		let inputs = if input_commits.len() == 0 {
@@ -233,14 +250,6 @@ impl Transaction {
 			input_commits.slice(0, input_commits.len())
 		};
 
-		// if there's no overage (not coinbase), we just add fee
-		// otherwise, we negate the overage
-		let adjustment: i128 = if overage == 0 {
-			fee as i128
-		} else {
-			-1i128 * (overage as i128)
-		};
-
 		ctx.verify_balance_owned(
 			inputs,
 			output_commits.slice(0, output_commits.len()),
@@ -248,6 +257,12 @@ impl Transaction {
 		)
 	}
 
+	pub fn weight(&self) -> u64 {
+		self.inputs.len() as u64 * INPUT_WEIGHT
+			+ self.outputs.len() as u64 * OUTPUT_WEIGHT
+			+ self.kernels.len() as u64 * KERNEL_WEIGHT
+	}
+
 	pub fn fees(&self) -> u64 {
 		let mut fee = 0;
 		let root = self.kernels.root();
@@ -257,6 +272,18 @@ impl Transaction {
 		fee
 	}
 
+	// the first height the transaction may be in a block at, the highest
+	// lock height of its kernels
+	pub fn lock_height(&self) -> u64 {
+		let mut ret = 0;
+		for kernel in self.kernels.iter() {
+			if kernel.lock_height() > ret {
+				ret = kernel.lock_height();
+			}
+		}
+		ret
+	}
+
 	fn verify_kernels(
 		&self,
 		ptr: Ptr<RbTreeNode<Kernel>>,
@@ -10,6 +10,9 @@ pub const ERROR_ACCEPT: i32 = -6;
 pub const ERROR_FCNTL: i32 = -7;
 pub const ERROR_GETSOCKNAME: i32 = -10;
 pub const ERROR_EAGAIN: i32 = -11;
+pub const ERROR_TLS: i32 = -12;
+pub const ERROR_TLS_HANDSHAKE: i32 = -13;
+pub const ERROR_TLS_CERTIFICATE: i32 = -14;
 
 #[cfg(target_os = "linux")]
 pub const EVENT_SIZE: usize = 12;
@@ -22,5 +25,61 @@ pub const EVENT_SIZE: usize = 12;
 pub const EVENT_SIZE: usize = 32;
 pub const EVH_MAX_EVENTS: usize = 128;
 pub const EVH_MAX_BYTES_PER_READ: usize = 16 * 1024;
+// how often the event loop checks connection deadlines, see
+// Connection::close_after
+pub const EVH_HOUSEKEEPING_MILLIS: u64 = 100;
+
+pub const TLS_HANDSHAKE_TIMEOUT_MILLIS: u64 = 10_000;
+
+pub const NOISE_PROTOCOL_NAME: &[u8] = b"Noise_XX_secp256k1_AES256CTR-HMAC_SHA3-256";
+pub const NOISE_HANDSHAKE_TIMEOUT_MILLIS: u64 = 10_000;
+pub const NOISE_KEY_SIZE: usize = 33;
+pub const NOISE_TAG_SIZE: usize = 16;
+// e
+pub const NOISE_MSG1_LEN: usize = NOISE_KEY_SIZE;
+// e, ee, s, es, payload
+pub const NOISE_MSG2_LEN: usize = 2 * NOISE_KEY_SIZE + 2 * NOISE_TAG_SIZE;
+// s, se, payload
+pub const NOISE_MSG3_LEN: usize = NOISE_KEY_SIZE + 2 * NOISE_TAG_SIZE;
+pub const NOISE_FRAME_HEADER_LEN: usize = 4;
+pub const NOISE_MAX_FRAME_PAYLOAD: usize = 64 * 1024;
+// writes fail with EAgain while this much output is waiting for the socket
+pub const NOISE_MAX_PENDING: usize = 1024 * 1024;
 
 pub const WEBSOCKET_MAGIC_STRING: &[u8; 36] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const WS_OP_CONTINUATION: u8 = 0x0;
+pub const WS_OP_TEXT: u8 = 0x1;
+pub const WS_OP_BINARY: u8 = 0x2;
+pub const WS_OP_CLOSE: u8 = 0x8;
+pub const WS_OP_PING: u8 = 0x9;
+pub const WS_OP_PONG: u8 = 0xA;
+
+pub const WS_CLOSE_NORMAL: u16 = 1000;
+pub const WS_CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const WS_CLOSE_INVALID_DATA: u16 = 1007;
+pub const WS_CLOSE_TOO_BIG: u16 = 1009;
+
+pub const WS_MAX_CONTROL_PAYLOAD: usize = 125;
+pub const WS_DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+// how long to wait for the peer to echo our close frame
+pub const WS_DEFAULT_CLOSE_TIMEOUT_MILLIS: u64 = 5_000;
+
+pub const HTTP_MAX_HEADER_SIZE: usize = 16 * 1024;
+pub const HTTP_MAX_HEADERS: usize = 100;
+// a chunk-size line, including any chunk extensions
+pub const HTTP_MAX_CHUNK_LINE_SIZE: usize = 1024;
+pub const HTTP_DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+pub const RPC_PARSE_ERROR: i64 = -32700;
+pub const RPC_INVALID_REQUEST: i64 = -32600;
+pub const RPC_METHOD_NOT_FOUND: i64 = -32601;
+pub const RPC_INVALID_PARAMS: i64 = -32602;
+pub const RPC_INTERNAL_ERROR: i64 = -32603;
+pub const RPC_TX_REJECTED: i64 = -32000;
+pub const RPC_NO_SUBSCRIPTIONS: i64 = -32001;
+
+pub const STRATUM_STALE_JOB: i64 = -32010;
+pub const STRATUM_INVALID_SHARE: i64 = -32011;
+pub const STRATUM_DUPLICATE_SHARE: i64 = -32012;
+pub const STRATUM_MAX_JOBS: usize = 8;
@@ -10,5 +10,21 @@ errors!(
 	GetSockNameError,
 	AcceptError,
 	SocketClosed,
-	EAgain
+	EAgain,
+	TlsError,
+	TlsHandshake,
+	TlsCertificate,
+	NoiseHandshake,
+	NoiseFrame,
+	HttpBadRequest,
+	HttpHeaderTooLarge,
+	HttpBodyTooLarge,
+	HttpNotImplemented,
+	RpcMethodNotFound,
+	RpcInvalidParams,
+	RpcTxRejected,
+	RpcNoSubscriptions,
+	StratumStaleJob,
+	StratumInvalidShare,
+	StratumDuplicateShare
 );
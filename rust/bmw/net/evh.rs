@@ -2,22 +2,37 @@
 #![allow(unused_variables)]
 
 use core::marker::PhantomData;
-use core::mem::forget;
+use core::mem::{forget, swap};
 use core::ops::FnMut;
+use crypto::keys::PublicKey;
 use net::constants::*;
 use net::errors::*;
 use net::multiplex::Event;
 use net::multiplex::Multiplex;
 use net::multiplex::RegisterType;
+use net::noise::{NoiseConfig, NoiseStream};
 use net::socket::Socket;
+use net::tls::{TlsClientConfig, TlsServerConfig, TlsStream};
 use prelude::*;
 use util::channel::{channel, Receiver, Sender};
-use util::lock::{LockReadGuard, LockWriteGuard};
+use util::lock::{LockBox, LockReadGuard, LockWriteGuard};
 
 pub type OnRecv<C, V> = Box<dyn FnMut(&mut C, &mut Connection<C, V>, &[u8]) -> Result<()>>;
 pub type OnAccept<C, V> = Box<dyn FnMut(&mut C, &mut Connection<C, V>) -> Result<()>>;
 pub type OnClose<C, V> = Box<dyn FnMut(&mut C, &mut Connection<C, V>) -> Result<()>>;
 
+enum TransportConfig {
+	Plain,
+	Tls(TlsServerConfig),
+	Noise(NoiseConfig),
+}
+
+enum Transport {
+	Plain,
+	Tls(TlsStream),
+	Noise(NoiseStream),
+}
+
 struct AcceptorData<C, V>
 where
 	C: Clone,
@@ -28,6 +43,7 @@ where
 	on_accept: Rc<OnAccept<C, V>>,
 	on_close: Rc<OnClose<C, V>>,
 	ctx: C,
+	transport: TransportConfig,
 }
 
 struct InboundData<C, V>
@@ -40,7 +56,9 @@ where
 	is_closed: bool,
 	lock: Lock,
 	multiplex: Multiplex,
+	deadlines: Option<Rc<Deadlines<C, V>>>,
 	opt: Option<V>,
+	transport: Transport,
 }
 
 struct OutboundData<C, V>
@@ -53,8 +71,12 @@ where
 	on_close: Rc<OnClose<C, V>>,
 	is_closed: bool,
 	lock: Lock,
+	// set by Evh::register
+	multiplex: Multiplex,
+	deadlines: Option<Rc<Deadlines<C, V>>>,
 	ctx: C,
 	opt: Option<V>,
+	transport: Transport,
 }
 
 enum ConnectionData<C, V>
@@ -77,6 +99,16 @@ where
 	inner: Rc<ConnectionData<C, V>>,
 }
 
+// connections to close at a time, see Connection::close_after
+struct Deadlines<C, V>
+where
+	C: Clone,
+	V: Clone,
+{
+	lock: LockBox,
+	conns: Vec<(u64, Connection<C, V>)>,
+}
+
 struct CloseData {
 	flag: bool,
 	port: u16,
@@ -94,6 +126,7 @@ where
 {
 	multiplex: Multiplex,
 	close: Rc<CloseData>,
+	deadlines: Rc<Deadlines<C, V>>,
 	_phantom_data: PhantomData<(C, V)>,
 }
 
@@ -109,15 +142,43 @@ where
 		on_close: Rc<OnClose<C, V>>,
 		ctx: C,
 	) -> Result<Self> {
-		let inner = Rc::new(ConnectionData::Acceptor(AcceptorData {
+		Self::acceptor_impl(
 			socket,
 			on_recv,
 			on_accept,
 			on_close,
 			ctx,
-		}))?;
+			TransportConfig::Plain,
+		)
+	}
 
-		Ok(Self { inner })
+	// accepted connections are wrapped in a TLS session. The closures only
+	// see plaintext.
+	pub fn acceptor_tls(
+		socket: Socket,
+		tls: TlsServerConfig,
+		on_recv: Rc<OnRecv<C, V>>,
+		on_accept: Rc<OnAccept<C, V>>,
+		on_close: Rc<OnClose<C, V>>,
+		ctx: C,
+	) -> Result<Self> {
+		let transport = TransportConfig::Tls(tls);
+		Self::acceptor_impl(socket, on_recv, on_accept, on_close, ctx, transport)
+	}
+
+	// accepted connections perform a noise handshake identifying this node
+	// with `noise`. on_accept is called once the handshake completes, and the
+	// closures only see plaintext.
+	pub fn acceptor_noise(
+		socket: Socket,
+		noise: NoiseConfig,
+		on_recv: Rc<OnRecv<C, V>>,
+		on_accept: Rc<OnAccept<C, V>>,
+		on_close: Rc<OnClose<C, V>>,
+		ctx: C,
+	) -> Result<Self> {
+		let transport = TransportConfig::Noise(noise);
+		Self::acceptor_impl(socket, on_recv, on_accept, on_close, ctx, transport)
 	}
 
 	pub fn outbound(
@@ -126,17 +187,35 @@ where
 		on_close: Rc<OnClose<C, V>>,
 		ctx: C,
 	) -> Result<Self> {
-		let inner = Rc::new(ConnectionData::Outbound(OutboundData {
-			socket,
-			on_recv,
-			on_close,
-			lock: lock!(),
-			is_closed: false,
-			ctx,
-			opt: None,
-		}))?;
+		Self::outbound_impl(socket, on_recv, on_close, ctx, Transport::Plain)
+	}
 
-		Ok(Self { inner })
+	// performs the (blocking) TLS handshake with the server before returning.
+	// `host` is verified against the server's certificate.
+	pub fn outbound_tls(
+		socket: Socket,
+		tls: &TlsClientConfig,
+		host: &str,
+		on_recv: Rc<OnRecv<C, V>>,
+		on_close: Rc<OnClose<C, V>>,
+		ctx: C,
+	) -> Result<Self> {
+		let stream = tls.connect(socket, host)?;
+		Self::outbound_impl(socket, on_recv, on_close, ctx, Transport::Tls(stream))
+	}
+
+	// performs the (blocking) noise handshake with the server before
+	// returning. If `remote` is specified, the server must hold that identity.
+	pub fn outbound_noise(
+		socket: Socket,
+		noise: &NoiseConfig,
+		remote: Option<&PublicKey>,
+		on_recv: Rc<OnRecv<C, V>>,
+		on_close: Rc<OnClose<C, V>>,
+		ctx: C,
+	) -> Result<Self> {
+		let stream = noise.connect(socket, remote)?;
+		Self::outbound_impl(socket, on_recv, on_close, ctx, Transport::Noise(stream))
 	}
 
 	pub fn socket(&self) -> Socket {
@@ -155,7 +234,7 @@ where
 		}
 		let res = match &*self.inner {
 			ConnectionData::Inbound(inbound) => {
-				let res = match inbound.socket.send(b) {
+				let res = match self.send(inbound.socket, &inbound.transport, b) {
 					Ok(res) => Ok(res),
 					Err(e) => {
 						if e != EAgain {
@@ -167,7 +246,7 @@ where
 				res
 			}
 			ConnectionData::Outbound(outbound) => {
-				let res = match outbound.socket.send(b) {
+				let res = match self.send(outbound.socket, &outbound.transport, b) {
 					Ok(res) => Ok(res),
 					Err(e) => {
 						if e != EAgain {
@@ -190,12 +269,69 @@ where
 			return err!(SocketClosed);
 		}
 		match &*self.inner {
-			ConnectionData::Inbound(inbound) => inbound.socket.shutdown(),
-			ConnectionData::Outbound(outbound) => outbound.socket.shutdown(),
+			ConnectionData::Inbound(inbound) => Self::shutdown(inbound.socket, &inbound.transport),
+			ConnectionData::Outbound(outbound) => {
+				Self::shutdown(outbound.socket, &outbound.transport)
+			}
 			_ => err!(IllegalState),
 		}
 	}
 
+	// closes the connection if it is still open after `millis`. The Evh
+	// checks deadlines every EVH_HOUSEKEEPING_MILLIS, see Evh::housekeep.
+	pub fn close_after(&self, millis: u64) -> Result<()> {
+		let deadlines = match &*self.inner {
+			ConnectionData::Inbound(x) => &x.deadlines,
+			ConnectionData::Outbound(x) => &x.deadlines,
+			_ => return err!(IllegalState),
+		};
+		let mut deadlines = match deadlines {
+			Some(deadlines) => deadlines.clone(),
+			// not registered yet
+			None => return err!(IllegalState),
+		};
+		let deadline = micros() + millis * 1_000;
+		let lock = deadlines.lock.clone();
+		let _l = lock.write();
+		deadlines.conns.push((deadline, self.clone()))
+	}
+
+	pub fn is_tls(&self) -> bool {
+		match &*self.inner {
+			ConnectionData::Acceptor(x) => match x.transport {
+				TransportConfig::Tls(_) => true,
+				_ => false,
+			},
+			ConnectionData::Outbound(x) => match x.transport {
+				Transport::Tls(_) => true,
+				_ => false,
+			},
+			ConnectionData::Inbound(x) => match x.transport {
+				Transport::Tls(_) => true,
+				_ => false,
+			},
+			ConnectionData::Close => false,
+		}
+	}
+
+	pub fn is_noise(&self) -> bool {
+		match &*self.inner {
+			ConnectionData::Acceptor(x) => match x.transport {
+				TransportConfig::Noise(_) => true,
+				_ => false,
+			},
+			_ => self.noise().is_some(),
+		}
+	}
+
+	// the authenticated identity of the peer on a noise connection
+	pub fn remote_identity(&self) -> Option<PublicKey> {
+		match self.noise() {
+			Some(noise) => noise.remote_identity(),
+			None => None,
+		}
+	}
+
 	pub fn attach(&mut self) -> Result<Option<&mut V>> {
 		match &mut *self.inner {
 			ConnectionData::Inbound(conn) => Ok(conn.opt.as_mut()),
@@ -222,7 +358,56 @@ where
 		Self { inner }
 	}
 
-	fn inbound(socket: Socket, acceptor: Connection<C, V>, multiplex: Multiplex) -> Result<Self> {
+	fn acceptor_impl(
+		socket: Socket,
+		on_recv: Rc<OnRecv<C, V>>,
+		on_accept: Rc<OnAccept<C, V>>,
+		on_close: Rc<OnClose<C, V>>,
+		ctx: C,
+		transport: TransportConfig,
+	) -> Result<Self> {
+		let inner = Rc::new(ConnectionData::Acceptor(AcceptorData {
+			socket,
+			on_recv,
+			on_accept,
+			on_close,
+			ctx,
+			transport,
+		}))?;
+
+		Ok(Self { inner })
+	}
+
+	fn outbound_impl(
+		socket: Socket,
+		on_recv: Rc<OnRecv<C, V>>,
+		on_close: Rc<OnClose<C, V>>,
+		ctx: C,
+		transport: Transport,
+	) -> Result<Self> {
+		let inner = Rc::new(ConnectionData::Outbound(OutboundData {
+			socket,
+			on_recv,
+			on_close,
+			lock: lock!(),
+			is_closed: false,
+			multiplex: Multiplex::uninit(),
+			deadlines: None,
+			ctx,
+			opt: None,
+			transport,
+		}))?;
+
+		Ok(Self { inner })
+	}
+
+	fn inbound(
+		socket: Socket,
+		acceptor: Connection<C, V>,
+		multiplex: Multiplex,
+		deadlines: Rc<Deadlines<C, V>>,
+		transport: Transport,
+	) -> Result<Self> {
 		Ok(Self {
 			inner: Rc::new(ConnectionData::Inbound(InboundData {
 				socket,
@@ -230,11 +415,124 @@ where
 				is_closed: false,
 				lock: lock!(),
 				multiplex,
+				deadlines: Some(deadlines),
 				opt: None,
+				transport,
 			}))?,
 		})
 	}
 
+	fn send(&self, socket: Socket, transport: &Transport, b: &[u8]) -> Result<usize> {
+		match transport {
+			Transport::Plain => socket.send(b),
+			Transport::Tls(tls) => tls.send(b),
+			Transport::Noise(noise) => {
+				let len = noise.send(socket, b)?;
+				self.watch_pending()?;
+				Ok(len)
+			}
+		}
+	}
+
+	// noise output the socket didn't take is queued in the stream. Ask for
+	// a write event to flush it, see Evh::proc_write.
+	fn watch_pending(&self) -> Result<()> {
+		let socket = self.socket();
+		match self.noise() {
+			Some(noise) => {
+				if !noise.flush(socket)? {
+					let multiplex = self.multiplex();
+					multiplex.register(socket, RegisterType::RW, Some(self.attachment()))?;
+				}
+			}
+			None => {}
+		}
+		Ok(())
+	}
+
+	// called on write events, drops the write event once nothing is queued
+	fn flush_pending(&self) -> Result<()> {
+		let _l = self.rlock()?;
+		if self.is_closed()? {
+			return Ok(());
+		}
+		let socket = self.socket();
+		let mut multiplex = self.multiplex();
+		let noise = match self.noise() {
+			Some(noise) => noise,
+			None => return Ok(()),
+		};
+		if noise.flush(socket)? {
+			multiplex.unregister_write(socket, Some(self.attachment()))?;
+			// a write may have queued more before the write event was dropped
+			if !noise.flush(socket)? {
+				multiplex.register(socket, RegisterType::RW, Some(self.attachment()))?;
+			}
+		}
+		Ok(())
+	}
+
+	// the pointer the connection is registered with, see Evh::try_register
+	fn attachment(&self) -> *const u8 {
+		let ptr = unsafe { self.inner.clone().into_raw() };
+		let _rc: Rc<ConnectionData<C, V>> = unsafe { Rc::from_raw(ptr) };
+		ptr.raw() as *const u8
+	}
+
+	fn shutdown(socket: Socket, transport: &Transport) -> Result<()> {
+		match transport {
+			Transport::Tls(tls) => {
+				let _ = tls.shutdown();
+			}
+			_ => {}
+		}
+		socket.shutdown()
+	}
+
+	fn recv(&self, buf: &mut [u8]) -> Result<usize> {
+		let (socket, transport) = match &*self.inner {
+			ConnectionData::Inbound(x) => (x.socket, &x.transport),
+			ConnectionData::Outbound(x) => (x.socket, &x.transport),
+			_ => return err!(IllegalState),
+		};
+		match transport {
+			Transport::Tls(tls) => tls.recv(buf),
+			_ => socket.recv(buf),
+		}
+	}
+
+	fn noise(&self) -> Option<&NoiseStream> {
+		let transport = match &*self.inner {
+			ConnectionData::Inbound(x) => &x.transport,
+			ConnectionData::Outbound(x) => &x.transport,
+			_ => return None,
+		};
+		match transport {
+			Transport::Noise(noise) => Some(noise),
+			_ => None,
+		}
+	}
+
+	// inbound noise connections aren't visible to the closures until the
+	// handshake completes
+	fn is_handshaking(&self) -> bool {
+		match self.noise() {
+			Some(noise) => !noise.is_established(),
+			None => false,
+		}
+	}
+
+	fn accept_transport(&self, socket: Socket) -> Result<Transport> {
+		match &*self.inner {
+			ConnectionData::Acceptor(x) => match &x.transport {
+				TransportConfig::Plain => Ok(Transport::Plain),
+				TransportConfig::Tls(tls) => Ok(Transport::Tls(tls.accept(socket)?)),
+				TransportConfig::Noise(noise) => Ok(Transport::Noise(noise.accept()?)),
+			},
+			_ => err!(IllegalState),
+		}
+	}
+
 	fn get_acceptor(&mut self) -> Result<&mut Connection<C, V>> {
 		match &mut *self.inner {
 			ConnectionData::Acceptor(_) => err!(IllegalState),
@@ -262,6 +560,15 @@ where
 		}
 	}
 
+	fn on_accept(&mut self, conn: &mut Connection<C, V>) -> Result<()> {
+		match &mut *self.inner {
+			ConnectionData::Acceptor(acc) => (acc.on_accept)(&mut acc.ctx, conn),
+			ConnectionData::Outbound(_) => err!(IllegalState),
+			ConnectionData::Inbound(_) => err!(IllegalState),
+			ConnectionData::Close => err!(IllegalState),
+		}
+	}
+
 	fn on_close(&mut self, conn: &mut Connection<C, V>) -> Result<()> {
 		match &mut *self.inner {
 			ConnectionData::Acceptor(acc) => (acc.on_close)(&mut acc.ctx, conn),
@@ -299,6 +606,14 @@ where
 		}
 	}
 
+	fn multiplex(&self) -> Multiplex {
+		match &*self.inner {
+			ConnectionData::Inbound(x) => x.multiplex,
+			ConnectionData::Outbound(x) => x.multiplex,
+			_ => Multiplex::uninit(),
+		}
+	}
+
 	fn is_closed(&self) -> Result<bool> {
 		match &*self.inner {
 			ConnectionData::Outbound(x) => Ok(x.is_closed),
@@ -344,23 +659,31 @@ where
 			recv,
 		})?;
 
+		let deadlines = Rc::new(Deadlines {
+			lock: lock_box!()?,
+			conns: Vec::new(),
+		})?;
+
 		let inner = Rc::new(ConnectionData::<C, V>::Close)?;
 		Self::try_register(multiplex, socket, unsafe { inner.into_raw() })?;
 		Ok(Self {
 			multiplex,
 			close,
+			deadlines,
 			_phantom_data: PhantomData,
 		})
 	}
 
-	pub fn register(&mut self, conn: Connection<C, V>) -> Result<()> {
+	pub fn register(&mut self, mut conn: Connection<C, V>) -> Result<()> {
 		let inner_clone = conn.inner.clone();
 
-		match &*conn.inner {
+		match &mut *conn.inner {
 			ConnectionData::Acceptor(c) => {
 				Self::try_register(self.multiplex, c.socket, unsafe { inner_clone.into_raw() })
 			}
 			ConnectionData::Outbound(c) => {
+				c.multiplex = self.multiplex;
+				c.deadlines = Some(self.deadlines.clone());
 				Self::try_register(self.multiplex, c.socket, unsafe { inner_clone.into_raw() })
 			}
 			_ => err!(IllegalArgument),
@@ -377,11 +700,13 @@ where
 	pub fn start(&mut self) -> Result<()> {
 		let multiplex = self.multiplex;
 		let mut close = self.close.clone();
+		let mut deadlines = self.deadlines.clone();
 		spawn(move || {
 			let mut events = [Event::new(); EVH_MAX_EVENTS];
 			let mut do_exit = false;
 			while !do_exit {
-				let count = match multiplex.wait(&mut events, None) {
+				let timeout = Some(EVH_HOUSEKEEPING_MILLIS as i64);
+				let count = match multiplex.wait(&mut events, timeout) {
 					Ok(count) => count,
 					Err(e) => {
 						println!(
@@ -392,8 +717,15 @@ where
 					}
 				};
 				for i in 0..count {
+					// writes first, a read may drop the connection
+					if i < events.len() && events[i].is_write() {
+						match Self::proc_write(events[i]) {
+							Ok(_) => {}
+							Err(e) => println!("WARN: unexpected error in proc_write(): {}", e),
+						}
+					}
 					if i < events.len() && events[i].is_read() {
-						match Self::proc_read(events[i], multiplex, &mut close) {
+						match Self::proc_read(events[i], multiplex, &deadlines, &mut close) {
 							Ok(exit) => {
 								if exit {
 									do_exit = true;
@@ -407,6 +739,10 @@ where
 						}
 					}
 				}
+				match Self::housekeep(&mut deadlines) {
+					Ok(_) => {}
+					Err(e) => println!("WARN: unexpected error in housekeep(): {}", e),
+				}
 			}
 			let _ = multiplex.close();
 			let _ = close.send.send(());
@@ -415,7 +751,42 @@ where
 		Ok(())
 	}
 
-	fn proc_read(evt: Event, multiplex: Multiplex, close: &mut Rc<CloseData>) -> Result<bool> {
+	// closes the connections whose deadline passed. Closing only shuts the
+	// socket down, the read side then sees the close as usual.
+	fn housekeep(deadlines: &mut Rc<Deadlines<C, V>>) -> Result<()> {
+		let now = micros();
+		let mut expired = Vec::new();
+		{
+			let lock = deadlines.lock.clone();
+			let _l = lock.write();
+			if deadlines.conns.len() == 0 {
+				return Ok(());
+			}
+			let mut pending = Vec::new();
+			swap(&mut pending, &mut deadlines.conns);
+			for (deadline, conn) in &pending {
+				if conn.is_closed()? {
+					continue;
+				}
+				if *deadline <= now {
+					expired.push(conn.clone())?;
+				} else {
+					deadlines.conns.push((*deadline, conn.clone()))?;
+				}
+			}
+		}
+		for conn in &expired {
+			let _ = conn.close();
+		}
+		Ok(())
+	}
+
+	fn proc_read(
+		evt: Event,
+		multiplex: Multiplex,
+		deadlines: &Rc<Deadlines<C, V>>,
+		close: &mut Rc<CloseData>,
+	) -> Result<bool> {
 		let mut inner: Rc<ConnectionData<C, V>> =
 			unsafe { Rc::from_raw(Ptr::new(evt.attachment() as *const ConnectionData<C, V>)) };
 		match &*inner {
@@ -444,7 +815,7 @@ where
 		}
 		let conn = Connection::from_inner(inner.clone());
 		let drop = match &mut *inner {
-			ConnectionData::Acceptor(_) => Self::proc_accept(conn, multiplex)?,
+			ConnectionData::Acceptor(_) => Self::proc_accept(conn, multiplex, deadlines)?,
 			ConnectionData::Outbound(_) => Self::proc_recv(conn)?,
 			ConnectionData::Inbound(_) => Self::proc_recv(conn)?,
 			ConnectionData::Close => false,
@@ -457,6 +828,22 @@ where
 		Ok(false)
 	}
 
+	fn proc_write(evt: Event) -> Result<()> {
+		let inner: Rc<ConnectionData<C, V>> =
+			unsafe { Rc::from_raw(Ptr::new(evt.attachment() as *const ConnectionData<C, V>)) };
+		let conn = Connection::from_inner(inner.clone());
+		// the registration keeps its reference
+		forget(inner);
+		match conn.flush_pending() {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				// the read side sees the shutdown and closes the connection
+				let _ = conn.socket().shutdown();
+				Err(e)
+			}
+		}
+	}
+
 	fn try_register(
 		multiplex: Multiplex,
 		socket: Socket,
@@ -480,7 +867,7 @@ where
 		let mut bytes = [0u8; EVH_MAX_BYTES_PER_READ];
 		let mut socket = conn.socket();
 		loop {
-			let len = match socket.recv(&mut bytes) {
+			let len = match conn.recv(&mut bytes) {
 				Ok(len) => len,
 				Err(e) => {
 					match e == EAgain {
@@ -489,22 +876,54 @@ where
 					}
 				}
 			};
+			let was_handshaking = conn.is_handshaking();
+			let mut established = false;
+			let mut plaintext = Vec::new();
+			let len = match conn.noise() {
+				Some(noise) if len > 0 && len <= bytes.len() => {
+					match noise.process(&bytes[0..len], &mut plaintext) {
+						Ok(done) => match conn.watch_pending() {
+							Ok(_) => {
+								established = done;
+								len
+							}
+							Err(_) => 0,
+						},
+						Err(_) => 0, // close on handshake or authentication failures
+					}
+				}
+				_ => len,
+			};
+			let data = match conn.noise() {
+				Some(_) => &plaintext[..],
+				None => &bytes[0..len.min(bytes.len())],
+			};
 			let mut conn_clone = conn.clone();
 			match &mut *conn.inner {
 				ConnectionData::Inbound(_) => {
 					if len == 0 {
 						conn_clone.close_impl()?;
 						let acc = conn_clone.get_acceptor()?;
-						match acc.on_close(&mut conn) {
-							Ok(_) => {}
-							Err(e) => println!("WARN: on_close closure generated error: {}", e),
+						if !was_handshaking {
+							match acc.on_close(&mut conn) {
+								Ok(_) => {}
+								Err(e) => println!("WARN: on_close closure generated error: {}", e),
+							}
 						}
 						let _ = socket.close();
 						return Ok(true);
 					} else {
 						let acc = conn_clone.get_acceptor()?;
-						if len <= bytes.len() {
-							match acc.on_recv(&mut conn, &bytes[0..len]) {
+						if established {
+							match acc.on_accept(&mut conn) {
+								Ok(_) => {}
+								Err(e) => {
+									println!("WARN: on_accept closure generated error: {}", e)
+								}
+							}
+						}
+						if data.len() > 0 {
+							match acc.on_recv(&mut conn, data) {
 								Ok(_) => {}
 								Err(e) => println!("WARN: on_recv closure generated error: {}", e),
 							}
@@ -521,8 +940,8 @@ where
 						let _ = socket.close();
 						return Ok(true);
 					} else {
-						if len <= bytes.len() {
-							match (ob.on_recv)(&mut ob.ctx, &mut conn_clone, &bytes[0..len]) {
+						if data.len() > 0 {
+							match (ob.on_recv)(&mut ob.ctx, &mut conn_clone, data) {
 								Ok(_) => {}
 								Err(e) => println!("WARN: on_recv closure generated error: {}", e),
 							}
@@ -534,7 +953,11 @@ where
 		}
 	}
 
-	fn proc_accept(mut conn: Connection<C, V>, multiplex: Multiplex) -> Result<bool> {
+	fn proc_accept(
+		mut conn: Connection<C, V>,
+		multiplex: Multiplex,
+		deadlines: &Rc<Deadlines<C, V>>,
+	) -> Result<bool> {
 		let mut acc = conn.socket();
 		loop {
 			let mut nsock = match acc.accept() {
@@ -554,7 +977,20 @@ where
 					}
 				}
 			};
-			let mut nconn = match Connection::inbound(nsock, conn.clone(), multiplex) {
+			let transport = match conn.accept_transport(nsock) {
+				Ok(transport) => transport,
+				Err(e) => {
+					println!(
+						"WARN: Could not create transport session due to error: {}",
+						e
+					);
+					let _ = nsock.close();
+					continue;
+				}
+			};
+			let deadlines = deadlines.clone();
+			let nconn = Connection::inbound(nsock, conn.clone(), multiplex, deadlines, transport);
+			let mut nconn = match nconn {
 				Ok(nconn) => nconn,
 				Err(e) => {
 					println!(
@@ -576,6 +1012,11 @@ where
 				}
 			}
 
+			// noise connections are accepted once their handshake completes
+			if nconn.is_handshaking() {
+				continue;
+			}
+
 			match &mut *conn.inner {
 				ConnectionData::Acceptor(acc) => match (acc.on_accept)(&mut acc.ctx, &mut nconn) {
 					Ok(_) => {}
@@ -988,6 +1429,80 @@ mod test {
 		Ok(())
 	}
 
+	#[test]
+	fn test_evh_close_after() -> Result<()> {
+		let mut evh: Evh<u64, u64> = Evh::new()?;
+		let lock = lock_box!()?;
+		let lock_clone = lock.clone();
+		let count = Rc::new(0)?;
+		let mut count_clone = count.clone();
+
+		let (port, mut s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		let recv: OnRecv<u64, u64> = Box::new(
+			move |ctx: &mut u64, conn: &mut Connection<u64, u64>, bytes: &[u8]| -> Result<()> {
+				Ok(())
+			},
+		)?;
+		let accept: OnAccept<u64, u64> = Box::new(
+			move |ctx: &mut u64, conn: &mut Connection<u64, u64>| -> Result<()> {
+				conn.close_after(50)
+			},
+		)?;
+		let close: OnClose<u64, u64> = Box::new(
+			move |ctx: &mut u64, conn: &mut Connection<u64, u64>| -> Result<()> {
+				let _l = lock_clone.write();
+				*count_clone += 1;
+				Ok(())
+			},
+		)?;
+
+		let rc_close = Rc::new(close)?;
+		let rc_accept = Rc::new(accept)?;
+		let rc_recv = Rc::new(recv)?;
+
+		let mut server = Connection::acceptor(s, rc_recv, rc_accept, rc_close, 0u64)?;
+		evh.register(server.clone())?;
+
+		evh.start()?;
+
+		sleep(1); // 1ms sleep to prevent intermittent connect issues.
+
+		// the client never sends anything, the server drops it at the deadline
+		let start = micros();
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		let mut buf = [0u8; 10];
+		let len = loop {
+			match client.recv(&mut buf) {
+				Ok(len) => break len,
+				Err(e) => assert_eq!(e, EAgain),
+			}
+			sleep(1);
+		};
+		assert_eq!(len, 0);
+		assert!(micros() - start >= 50_000);
+
+		loop {
+			{
+				let _l = lock.read();
+				if *count == 1 {
+					break;
+				}
+			}
+			sleep(1);
+		}
+
+		client.close()?;
+		evh.stop()?;
+		s.close()?;
+		// just to make address sanitizer report no memory leaks - normal case server just
+		// runs forever.
+		unsafe {
+			server.drop_rc();
+		}
+
+		Ok(())
+	}
+
 	#[test]
 	fn test_evh2servers() -> Result<()> {
 		let mut evh: Evh<u64, u64> = Evh::new()?;
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 use net::multiplex::{Event, Multiplex};
 use net::socket::Socket;
+use net::types::{TlsContext, TlsSession};
 
 extern "C" {
 	// Sockets
@@ -47,4 +48,34 @@ extern "C" {
 	pub fn Base64decode(plain: *mut u8, bufcoded: *const u8) -> i32;
 	pub fn Base64encode(encoded: *mut u8, plain: *const u8, len: i32) -> i32;
 
+	// TLS
+	pub fn tls_server_context(
+		ctx: *mut *const TlsContext,
+		cert_file: *const u8,
+		key_file: *const u8,
+	) -> i32;
+	pub fn tls_server_context_pem(
+		ctx: *mut *const TlsContext,
+		cert: *const u8,
+		cert_len: usize,
+		key: *const u8,
+		key_len: usize,
+	) -> i32;
+	pub fn tls_client_context(ctx: *mut *const TlsContext, ca_file: *const u8) -> i32;
+	pub fn tls_client_context_pem(ctx: *mut *const TlsContext, ca: *const u8, ca_len: usize)
+		-> i32;
+	pub fn tls_context_free(ctx: *const TlsContext);
+	pub fn tls_session(
+		ctx: *const TlsContext,
+		session: *mut *const TlsSession,
+		socket: *const Socket,
+		is_server: i32,
+		host: *const u8,
+	) -> i32;
+	pub fn tls_handshake(session: *const TlsSession) -> i32;
+	pub fn tls_recv(session: *const TlsSession, buf: *mut u8, capacity: usize) -> i64;
+	pub fn tls_send(session: *const TlsSession, buf: *const u8, len: usize) -> i64;
+	pub fn tls_shutdown(session: *const TlsSession) -> i32;
+	pub fn tls_session_free(session: *const TlsSession);
+
 }
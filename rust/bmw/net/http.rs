@@ -0,0 +1,762 @@
+use core::ops::FnMut;
+use net::constants::*;
+use net::errors::*;
+use prelude::*;
+
+pub type HttpOnRequest = Box<dyn FnMut(&HttpRequest, &mut HttpResponse) -> Result<()>>;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum HttpMethod {
+	Get,
+	Head,
+	Post,
+	Put,
+	Delete,
+	Options,
+	Patch,
+}
+
+pub struct HttpRequest {
+	method: HttpMethod,
+	path: String,
+	query: String,
+	minor_version: u8,
+	headers: Vec<(String, String)>,
+	body: Vec<u8>,
+}
+
+pub struct HttpResponse {
+	status: u16,
+	headers: Vec<(String, String)>,
+	body: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct HttpRoute {
+	method: HttpMethod,
+	path: String,
+	on_request: Rc<HttpOnRequest>,
+}
+
+impl HttpMethod {
+	fn parse(b: &[u8]) -> Result<Self> {
+		match b {
+			b"GET" => Ok(Self::Get),
+			b"HEAD" => Ok(Self::Head),
+			b"POST" => Ok(Self::Post),
+			b"PUT" => Ok(Self::Put),
+			b"DELETE" => Ok(Self::Delete),
+			b"OPTIONS" => Ok(Self::Options),
+			b"PATCH" => Ok(Self::Patch),
+			_ => {
+				if b.len() == 0 || b.iter().any(|c| !c.is_ascii_uppercase()) {
+					err!(HttpBadRequest)
+				} else {
+					err!(HttpNotImplemented)
+				}
+			}
+		}
+	}
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Self::Get => "GET",
+			Self::Head => "HEAD",
+			Self::Post => "POST",
+			Self::Put => "PUT",
+			Self::Delete => "DELETE",
+			Self::Options => "OPTIONS",
+			Self::Patch => "PATCH",
+		}
+	}
+}
+
+impl HttpRequest {
+	// parses a complete request from the start of `bytes`. Returns None if
+	// more data is needed, otherwise the request and the number of bytes it
+	// used. Errors are HttpBadRequest, HttpHeaderTooLarge, HttpBodyTooLarge
+	// and HttpNotImplemented.
+	pub fn parse(bytes: &[u8], max_body: usize) -> Result<Option<(Self, usize)>> {
+		let end = match Self::find(bytes, 0, b"\r\n\r\n") {
+			Some(end) => end,
+			None => {
+				if bytes.len() > HTTP_MAX_HEADER_SIZE {
+					return err!(HttpHeaderTooLarge);
+				}
+				return Ok(None);
+			}
+		};
+		if end > HTTP_MAX_HEADER_SIZE {
+			return err!(HttpHeaderTooLarge);
+		}
+
+		let mut lines = bytes[0..end].split(|b| *b == b'\n');
+		let mut request_line = match lines.next() {
+			Some(line) => Self::trim_cr(line).split(|b| *b == b' '),
+			None => return err!(HttpBadRequest),
+		};
+		let (method, target, version) = match (
+			request_line.next(),
+			request_line.next(),
+			request_line.next(),
+		) {
+			(Some(method), Some(target), Some(version)) => (method, target, version),
+			_ => return err!(HttpBadRequest),
+		};
+		if request_line.next().is_some() {
+			return err!(HttpBadRequest);
+		}
+		let method = HttpMethod::parse(method)?;
+		let minor_version = match version {
+			b"HTTP/1.1" => 1,
+			b"HTTP/1.0" => 0,
+			_ => return err!(HttpBadRequest),
+		};
+		if target.len() == 0 || target[0] != b'/' {
+			return err!(HttpBadRequest);
+		}
+		let (path, query) = match target.iter().position(|b| *b == b'?') {
+			Some(i) => (&target[0..i], &target[i + 1..]),
+			None => (target, &b""[..]),
+		};
+
+		let mut headers = Vec::new();
+		for line in lines {
+			let line = Self::trim_cr(line);
+			// obsolete line folding is rejected (RFC 9112 5.2)
+			if line.len() == 0 || line[0] == b' ' || line[0] == b'\t' {
+				return err!(HttpBadRequest);
+			}
+			let colon = match line.iter().position(|b| *b == b':') {
+				Some(colon) => colon,
+				None => return err!(HttpBadRequest),
+			};
+			let name = &line[0..colon];
+			if name.len() == 0 || name.iter().any(|b| *b <= b' ' || *b >= 0x7f) {
+				return err!(HttpBadRequest);
+			}
+			if headers.len() == HTTP_MAX_HEADERS {
+				return err!(HttpHeaderTooLarge);
+			}
+			let value = Self::trim(&line[colon + 1..]);
+			headers.push((Self::string(name)?, Self::string(value)?))?;
+		}
+
+		let mut ret = Self {
+			method,
+			path: Self::string(path)?,
+			query: Self::string(query)?,
+			minor_version,
+			headers,
+			body: Vec::new(),
+		};
+
+		let offset = end + 4;
+		let mut content_length = None;
+		for (name, value) in ret.headers.iter() {
+			if name.as_str().eq_ignore_ascii_case("content-length") {
+				let len = Self::parse_decimal(value.as_bytes())?;
+				match content_length {
+					Some(v) if v != len => return err!(HttpBadRequest),
+					_ => content_length = Some(len),
+				}
+			}
+		}
+
+		let consumed = match ret.header("transfer-encoding") {
+			Some(te) => {
+				// a body can't be delimited both ways (RFC 9112 6.3)
+				if content_length.is_some() {
+					return err!(HttpBadRequest);
+				}
+				if !te.eq_ignore_ascii_case("chunked") {
+					return err!(HttpNotImplemented);
+				}
+				match Self::chunked(bytes, offset, max_body, &mut ret.body)? {
+					Some(consumed) => consumed,
+					None => return Ok(None),
+				}
+			}
+			None => {
+				let len = match content_length {
+					Some(len) => len,
+					None => 0,
+				};
+				if len > max_body {
+					return err!(HttpBodyTooLarge);
+				}
+				if bytes.len() < offset + len {
+					return Ok(None);
+				}
+				ret.body.extend_from_slice(&bytes[offset..offset + len])?;
+				offset + len
+			}
+		};
+		Ok(Some((ret, consumed)))
+	}
+
+	pub fn method(&self) -> HttpMethod {
+		self.method
+	}
+
+	pub fn path(&self) -> &str {
+		self.path.as_str()
+	}
+
+	pub fn query(&self) -> &str {
+		self.query.as_str()
+	}
+
+	pub fn body(&self) -> &[u8] {
+		&self.body
+	}
+
+	// the value of the first header named `name` (case insensitive)
+	pub fn header(&self, name: &str) -> Option<&str> {
+		for (n, v) in self.headers.iter() {
+			if n.as_str().eq_ignore_ascii_case(name) {
+				return Some(v.as_str());
+			}
+		}
+		None
+	}
+
+	pub fn headers(&self) -> &Vec<(String, String)> {
+		&self.headers
+	}
+
+	// HTTP/1.1 connections persist unless the client says otherwise,
+	// HTTP/1.0 connections only if the client asks
+	pub fn keep_alive(&self) -> bool {
+		let mut close = false;
+		let mut keep_alive = false;
+		for (n, v) in self.headers.iter() {
+			if n.as_str().eq_ignore_ascii_case("connection") {
+				for token in v.as_bytes().split(|b| *b == b',') {
+					let token = Self::trim(token);
+					close |= token.eq_ignore_ascii_case(b"close");
+					keep_alive |= token.eq_ignore_ascii_case(b"keep-alive");
+				}
+			}
+		}
+		if self.minor_version == 0 {
+			keep_alive && !close
+		} else {
+			!close
+		}
+	}
+
+	// a request to switch this connection to the websocket protocol
+	pub fn is_upgrade(&self) -> bool {
+		match self.header("upgrade") {
+			Some(upgrade) => upgrade.eq_ignore_ascii_case("websocket"),
+			None => false,
+		}
+	}
+
+	fn chunked(
+		bytes: &[u8],
+		mut offset: usize,
+		max_body: usize,
+		body: &mut Vec<u8>,
+	) -> Result<Option<usize>> {
+		loop {
+			let eol = match Self::find(bytes, offset, b"\r\n") {
+				Some(eol) => eol,
+				None => {
+					if bytes.len() - offset > HTTP_MAX_CHUNK_LINE_SIZE {
+						return err!(HttpBadRequest);
+					}
+					return Ok(None);
+				}
+			};
+			if eol - offset > HTTP_MAX_CHUNK_LINE_SIZE {
+				return err!(HttpBadRequest);
+			}
+			// chunk extensions are ignored
+			let mut line = &bytes[offset..eol];
+			match line.iter().position(|b| *b == b';') {
+				Some(i) => line = &line[0..i],
+				None => {}
+			}
+			let size = Self::parse_hex(Self::trim(line))?;
+			offset = eol + 2;
+
+			if size == 0 {
+				// skip any trailer fields up to the empty line, they are
+				// capped like the header
+				let start = offset;
+				loop {
+					let eol = match Self::find(bytes, offset, b"\r\n") {
+						Some(eol) => eol,
+						None => {
+							if bytes.len() - start > HTTP_MAX_HEADER_SIZE {
+								return err!(HttpHeaderTooLarge);
+							}
+							return Ok(None);
+						}
+					};
+					if eol - start > HTTP_MAX_HEADER_SIZE {
+						return err!(HttpHeaderTooLarge);
+					}
+					let empty = eol == offset;
+					offset = eol + 2;
+					if empty {
+						return Ok(Some(offset));
+					}
+				}
+			}
+
+			if body.len() + size > max_body {
+				return err!(HttpBodyTooLarge);
+			}
+			if bytes.len() < offset + size + 2 {
+				return Ok(None);
+			}
+			if &bytes[offset + size..offset + size + 2] != b"\r\n" {
+				return err!(HttpBadRequest);
+			}
+			body.extend_from_slice(&bytes[offset..offset + size])?;
+			offset += size + 2;
+		}
+	}
+
+	fn find(bytes: &[u8], offset: usize, target: &[u8]) -> Option<usize> {
+		if offset > bytes.len() {
+			return None;
+		}
+		match bytes[offset..]
+			.windows(target.len())
+			.position(|window| window == target)
+		{
+			Some(pos) => Some(offset + pos),
+			None => None,
+		}
+	}
+
+	fn parse_decimal(b: &[u8]) -> Result<usize> {
+		if b.len() == 0 || b.len() > 18 {
+			return err!(HttpBadRequest);
+		}
+		let mut ret = 0;
+		for c in b {
+			if !c.is_ascii_digit() {
+				return err!(HttpBadRequest);
+			}
+			ret = ret * 10 + (c - b'0') as usize;
+		}
+		Ok(ret)
+	}
+
+	fn parse_hex(b: &[u8]) -> Result<usize> {
+		if b.len() == 0 || b.len() > 15 {
+			return err!(HttpBadRequest);
+		}
+		let mut ret = 0;
+		for c in b {
+			let v = match c {
+				b'0'..=b'9' => c - b'0',
+				b'a'..=b'f' => c - b'a' + 10,
+				b'A'..=b'F' => c - b'A' + 10,
+				_ => return err!(HttpBadRequest),
+			};
+			ret = (ret << 4) | v as usize;
+		}
+		Ok(ret)
+	}
+
+	fn string(b: &[u8]) -> Result<String> {
+		match String::newb(b) {
+			Ok(s) => Ok(s),
+			Err(_) => err!(HttpBadRequest),
+		}
+	}
+
+	fn trim_cr(line: &[u8]) -> &[u8] {
+		match line.last() {
+			Some(b'\r') => &line[0..line.len() - 1],
+			_ => line,
+		}
+	}
+
+	fn trim(mut b: &[u8]) -> &[u8] {
+		while b.len() > 0 && (b[0] == b' ' || b[0] == b'\t') {
+			b = &b[1..];
+		}
+		while b.len() > 0 && (b[b.len() - 1] == b' ' || b[b.len() - 1] == b'\t') {
+			b = &b[0..b.len() - 1];
+		}
+		b
+	}
+}
+
+impl HttpResponse {
+	pub fn new() -> Self {
+		Self {
+			status: 200,
+			headers: Vec::new(),
+			body: Vec::new(),
+		}
+	}
+
+	pub fn status(&self) -> u16 {
+		self.status
+	}
+
+	pub fn set_status(&mut self, status: u16) {
+		self.status = status;
+	}
+
+	// Content-Length and Connection are set by the server. A name or value
+	// containing CR or LF would split the response so it's rejected with
+	// IllegalArgument.
+	pub fn add_header(&mut self, name: &str, value: &str) -> Result<()> {
+		let is_crlf = |b: &u8| *b == b'\r' || *b == b'\n';
+		if name.as_bytes().iter().any(is_crlf) || value.as_bytes().iter().any(is_crlf) {
+			return err!(IllegalArgument);
+		}
+		self.headers.push((String::new(name)?, String::new(value)?))
+	}
+
+	// appends to the body
+	pub fn write(&mut self, bytes: &[u8]) -> Result<()> {
+		self.body.extend_from_slice(bytes)
+	}
+
+	pub fn body(&self) -> &[u8] {
+		&self.body
+	}
+
+	pub fn serialize(&self, keep_alive: bool, head: bool) -> Result<Vec<u8>> {
+		let mut ret = Vec::new();
+		let line = format!("HTTP/1.1 {} {}\r\n", self.status, Self::reason(self.status))?;
+		ret.extend_from_slice(line.as_bytes())?;
+		for (name, value) in self.headers.iter() {
+			let line = format!("{}: {}\r\n", name, value)?;
+			ret.extend_from_slice(line.as_bytes())?;
+		}
+		let line = format!("Content-Length: {}\r\n", self.body.len())?;
+		ret.extend_from_slice(line.as_bytes())?;
+		if !keep_alive {
+			ret.extend_from_slice(b"Connection: close\r\n")?;
+		}
+		ret.extend_from_slice(b"\r\n")?;
+		// responses to HEAD have the headers of a GET but no body
+		if !head {
+			ret.extend_from_slice(&self.body)?;
+		}
+		Ok(ret)
+	}
+
+	fn reason(status: u16) -> &'static str {
+		match status {
+			100 => "Continue",
+			101 => "Switching Protocols",
+			200 => "OK",
+			201 => "Created",
+			202 => "Accepted",
+			204 => "No Content",
+			301 => "Moved Permanently",
+			302 => "Found",
+			304 => "Not Modified",
+			400 => "Bad Request",
+			401 => "Unauthorized",
+			403 => "Forbidden",
+			404 => "Not Found",
+			405 => "Method Not Allowed",
+			408 => "Request Timeout",
+			413 => "Content Too Large",
+			426 => "Upgrade Required",
+			431 => "Request Header Fields Too Large",
+			500 => "Internal Server Error",
+			501 => "Not Implemented",
+			503 => "Service Unavailable",
+			_ => "Unknown",
+		}
+	}
+}
+
+impl HttpRoute {
+	pub fn new(method: HttpMethod, path: &str, on_request: Rc<HttpOnRequest>) -> Result<Self> {
+		if !path.starts_with("/") {
+			return err!(IllegalArgument);
+		}
+		Ok(Self {
+			method,
+			path: String::new(path)?,
+			on_request,
+		})
+	}
+
+	pub fn add(routes: &mut Vec<HttpRoute>, route: HttpRoute) -> Result<()> {
+		for r in routes.iter() {
+			if r.method == route.method && r.path == route.path {
+				return err!(IllegalArgument);
+			}
+		}
+		routes.push(route)
+	}
+
+	// finds the route for the request and builds the response. Paths
+	// served under other methods get a 405, unknown paths a 404 and
+	// handler errors a 500. HEAD falls back to the GET route.
+	pub fn dispatch(routes: &Vec<HttpRoute>, req: &HttpRequest) -> Result<HttpResponse> {
+		let mut found = None;
+		let mut fallback = None;
+		let mut allow = Vec::new();
+		for route in routes.iter() {
+			if route.path.as_str() != req.path() {
+				continue;
+			}
+			if route.method == req.method() {
+				found = Some(route.clone());
+			} else if route.method == HttpMethod::Get && req.method() == HttpMethod::Head {
+				fallback = Some(route.clone());
+			}
+			if allow.len() > 0 {
+				allow.extend_from_slice(b", ")?;
+			}
+			allow.extend_from_slice(route.method.as_str().as_bytes())?;
+		}
+		if found.is_none() {
+			found = fallback;
+		}
+
+		let mut resp = HttpResponse::new();
+		match found {
+			Some(mut route) => match (route.on_request)(req, &mut resp) {
+				Ok(_) => {}
+				Err(_) => {
+					resp = HttpResponse::new();
+					resp.set_status(500);
+				}
+			},
+			None => {
+				if allow.len() > 0 {
+					resp.set_status(405);
+					resp.add_header("Allow", String::newb(&allow)?.as_str())?;
+				} else {
+					resp.set_status(404);
+				}
+			}
+		}
+		Ok(resp)
+	}
+
+	// the response for a request that failed to parse
+	pub fn error_response(e: Error) -> HttpResponse {
+		let mut resp = HttpResponse::new();
+		resp.set_status(if e == HttpHeaderTooLarge {
+			431
+		} else if e == HttpBodyTooLarge {
+			413
+		} else if e == HttpNotImplemented {
+			501
+		} else {
+			400
+		});
+		resp
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn parse(b: &[u8]) -> Result<Option<(HttpRequest, usize)>> {
+		HttpRequest::parse(b, 100)
+	}
+
+	#[test]
+	fn test_http_parse() -> Result<()> {
+		let req = b"GET /a/b?x=1&y=2 HTTP/1.1\r\nHost: localhost\r\nX-Test:  v a l \r\n\r\n";
+		let (r, len) = parse(req)?.unwrap();
+		assert_eq!(len, req.len());
+		assert!(r.method() == HttpMethod::Get);
+		assert_eq!(r.path(), "/a/b");
+		assert_eq!(r.query(), "x=1&y=2");
+		assert_eq!(r.header("x-test"), Some("v a l"));
+		assert_eq!(r.header("HOST"), Some("localhost"));
+		assert_eq!(r.header("missing"), None);
+		assert_eq!(r.headers().len(), 2);
+		assert!(r.keep_alive());
+		assert!(!r.is_upgrade());
+
+		// incomplete requests need more data
+		assert!(parse(&req[0..req.len() - 1])?.is_none());
+
+		// content-length body, followed by a pipelined request
+		let req = b"POST /p HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.0\r\n\r\n";
+		let (r, len) = parse(req)?.unwrap();
+		assert_eq!(r.body(), b"hello");
+		assert_eq!(len, req.len() - 18);
+		let (r, _) = parse(&req[len..])?.unwrap();
+		assert_eq!(r.path(), "/");
+		assert!(!r.keep_alive());
+		assert!(parse(&req[0..40])?.is_none());
+
+		// chunked body with an extension and a trailer
+		let req =
+			b"PUT /c HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;x=y\r\nabc\r\nA\r\n0123456789\r\n0\r\nT: v\r\n\r\n";
+		let (r, len) = parse(req)?.unwrap();
+		assert_eq!(r.body(), b"abc0123456789");
+		assert_eq!(len, req.len());
+		for i in 45..req.len() {
+			assert!(parse(&req[0..i])?.is_none());
+		}
+
+		let req = b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n";
+		assert!(parse(req)?.unwrap().0.keep_alive());
+		let req = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+		assert!(!parse(req)?.unwrap().0.keep_alive());
+		let req = b"GET / HTTP/1.1\r\nUpgrade: WebSocket\r\n\r\n";
+		assert!(parse(req)?.unwrap().0.is_upgrade());
+		Ok(())
+	}
+
+	#[test]
+	fn test_http_parse_errors() -> Result<()> {
+		for req in [
+			&b"GET /\r\n\r\n"[..],
+			b"GET / HTTP/2.0\r\n\r\n",
+			b"GET  / HTTP/1.1\r\n\r\n",
+			b"GET x HTTP/1.1\r\n\r\n",
+			b"get / HTTP/1.1\r\n\r\n",
+			b"GET / HTTP/1.1\r\nNoColon\r\n\r\n",
+			b"GET / HTTP/1.1\r\nA: b\r\n folded\r\n\r\n",
+			b"GET / HTTP/1.1\r\nBad Name: b\r\n\r\n",
+			b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+			b"GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n",
+			b"GET / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n",
+			b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n",
+			b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n",
+		] {
+			assert_eq!(parse(req).err(), Some(HttpBadRequest));
+		}
+		assert_eq!(
+			parse(b"BREW / HTTP/1.1\r\n\r\n").err(),
+			Some(HttpNotImplemented)
+		);
+		assert_eq!(
+			parse(b"GET / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").err(),
+			Some(HttpNotImplemented)
+		);
+		assert_eq!(
+			parse(b"POST / HTTP/1.1\r\nContent-Length: 101\r\n\r\n").err(),
+			Some(HttpBodyTooLarge)
+		);
+		assert_eq!(
+			parse(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n65\r\n").err(),
+			Some(HttpBodyTooLarge)
+		);
+
+		let mut big = Vec::new();
+		big.extend_from_slice(b"GET / HTTP/1.1\r\n")?;
+		while big.len() <= HTTP_MAX_HEADER_SIZE {
+			big.extend_from_slice(b"X: y\r\n")?;
+		}
+		assert_eq!(parse(&big).err(), Some(HttpHeaderTooLarge));
+		let mut many = Vec::new();
+		many.extend_from_slice(b"GET / HTTP/1.1\r\n")?;
+		for _ in 0..HTTP_MAX_HEADERS + 1 {
+			many.extend_from_slice(b"X: y\r\n")?;
+		}
+		many.extend_from_slice(b"\r\n")?;
+		assert_eq!(parse(&many).err(), Some(HttpHeaderTooLarge));
+
+		// a chunk-size line or trailer that never ends
+		let mut chunk = Vec::new();
+		chunk.extend_from_slice(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;")?;
+		let header_len = chunk.len();
+		while chunk.len() - header_len <= HTTP_MAX_CHUNK_LINE_SIZE {
+			chunk.extend_from_slice(b"x")?;
+		}
+		assert_eq!(parse(&chunk).err(), Some(HttpBadRequest));
+		let mut trailer = Vec::new();
+		trailer.extend_from_slice(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n")?;
+		while trailer.len() <= 2 * HTTP_MAX_HEADER_SIZE {
+			trailer.extend_from_slice(b"T: v\r\n")?;
+		}
+		assert_eq!(parse(&trailer).err(), Some(HttpHeaderTooLarge));
+		Ok(())
+	}
+
+	#[test]
+	fn test_http_dispatch() -> Result<()> {
+		let mut routes = Vec::new();
+		let on_get: HttpOnRequest =
+			Box::new(|req: &HttpRequest, resp: &mut HttpResponse| -> Result<()> {
+				resp.add_header("Content-Type", "text/plain")?;
+				resp.write(b"get ")?;
+				resp.write(req.query().as_bytes())
+			})?;
+		let on_post: HttpOnRequest = Box::new(
+			|_req: &HttpRequest, _resp: &mut HttpResponse| -> Result<()> { err!(IllegalState) },
+		)?;
+		let on_get = Rc::new(on_get)?;
+		HttpRoute::add(
+			&mut routes,
+			HttpRoute::new(HttpMethod::Get, "/r", on_get.clone())?,
+		)?;
+		HttpRoute::add(
+			&mut routes,
+			HttpRoute::new(HttpMethod::Post, "/r", Rc::new(on_post)?)?,
+		)?;
+		assert_eq!(
+			HttpRoute::add(
+				&mut routes,
+				HttpRoute::new(HttpMethod::Get, "/r", on_get.clone())?
+			)
+			.err(),
+			Some(IllegalArgument)
+		);
+		assert_eq!(
+			HttpRoute::new(HttpMethod::Get, "r", on_get).err(),
+			Some(IllegalArgument)
+		);
+
+		let (req, _) = parse(b"GET /r?q HTTP/1.1\r\n\r\n")?.unwrap();
+		let resp = HttpRoute::dispatch(&routes, &req)?;
+		assert_eq!(resp.status(), 200);
+		assert_eq!(
+			&resp.serialize(true, false)?[..],
+			b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nget q"
+		);
+
+		let (req, _) = parse(b"HEAD /r?q HTTP/1.1\r\n\r\n")?.unwrap();
+		let resp = HttpRoute::dispatch(&routes, &req)?;
+		assert_eq!(
+			&resp.serialize(false, true)?[..],
+			b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nConnection: close\r\n\r\n"
+		);
+
+		let (req, _) = parse(b"POST /r HTTP/1.1\r\n\r\n")?.unwrap();
+		assert_eq!(HttpRoute::dispatch(&routes, &req)?.status(), 500);
+
+		let (req, _) = parse(b"DELETE /r HTTP/1.1\r\n\r\n")?.unwrap();
+		let resp = HttpRoute::dispatch(&routes, &req)?;
+		assert_eq!(
+			&resp.serialize(true, false)?[..],
+			b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, POST\r\nContent-Length: 0\r\n\r\n"
+		);
+
+		let (req, _) = parse(b"GET /none HTTP/1.1\r\n\r\n")?.unwrap();
+		assert_eq!(HttpRoute::dispatch(&routes, &req)?.status(), 404);
+
+		// headers can't inject CR or LF into the response
+		let mut resp = HttpResponse::new();
+		assert_eq!(
+			resp.add_header("X", "a\r\nSet-Cookie: b").err(),
+			Some(IllegalArgument)
+		);
+		assert_eq!(resp.add_header("X\n", "a").err(), Some(IllegalArgument));
+		assert_eq!(
+			&resp.serialize(true, false)?[..],
+			b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+		);
+
+		assert_eq!(HttpRoute::error_response(HttpBodyTooLarge).status(), 413);
+		assert_eq!(HttpRoute::error_response(IllegalState).status(), 400);
+		Ok(())
+	}
+}
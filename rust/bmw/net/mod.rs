@@ -1,9 +1,15 @@
 mod constants;
 mod ffi;
+mod types;
 mod util;
 
 pub mod errors;
 pub mod evh;
+pub mod http;
 pub mod multiplex;
+pub mod noise;
+pub mod rpc;
 pub mod socket;
+pub mod stratum;
+pub mod tls;
 pub mod ws;
@@ -0,0 +1,1013 @@
+use core::cell::UnsafeCell;
+use core::ptr::write_bytes;
+use core::sync::atomic::compiler_fence;
+use core::sync::atomic::Ordering::SeqCst;
+use crypto::aead::Aead;
+use crypto::ctx::Ctx;
+use crypto::ecdh::SharedSecret;
+use crypto::hkdf::hkdf;
+use crypto::keys::{PublicKey, SecretKey};
+use crypto::sha3::Sha3_256;
+use misc::to_be_bytes_u32;
+use net::constants::*;
+use net::errors::*;
+use net::socket::Socket;
+use prelude::*;
+
+// An encrypted, mutually authenticated transport modeled on the Noise XX
+// pattern:
+//
+//   -> e
+//   <- e, ee, s, es
+//   -> s, se
+//
+// Both sides use an ephemeral secp256k1 key per connection and a static key
+// as their node identity. Keys are chained with HKDF over HMAC-SHA3-256 and
+// every handshake message after the first is bound to the transcript hash.
+// Once complete, each direction gets its own key and a counter nonce, and
+// data is carried in length prefixed frames sealed with `Aead`.
+
+struct NoiseConfigInner {
+	ctx: Ctx,
+	secret: SecretKey,
+	public: PublicKey,
+	lock: Lock,
+}
+
+#[derive(Clone)]
+pub struct NoiseConfig {
+	inner: Rc<NoiseConfigInner>,
+}
+
+struct SymmetricState {
+	ck: [u8; 32],
+	h: [u8; 32],
+	k: Option<Aead>,
+	n: u64,
+}
+
+#[derive(PartialEq)]
+enum Stage {
+	InitiatorWaitResponse,
+	ResponderWaitInit,
+	ResponderWaitFinal,
+}
+
+struct Handshake {
+	sym: SymmetricState,
+	e: SecretKey,
+	stage: Stage,
+}
+
+enum NoiseState {
+	Handshake(Handshake),
+	Transport {
+		send: Aead,
+		recv: Aead,
+		send_n: u64,
+		recv_n: u64,
+	},
+}
+
+struct NoiseStreamData {
+	state: NoiseState,
+	remote: Option<PublicKey>,
+	buffer: Vec<u8>,
+	// output not yet taken by the socket starts at pending_offset, see
+	// flush
+	pending: Vec<u8>,
+	pending_offset: usize,
+}
+
+pub struct NoiseStream {
+	config: NoiseConfig,
+	data: UnsafeCell<NoiseStreamData>,
+	lock: Lock,
+}
+
+impl NoiseConfig {
+	// `secret` is the static key this node is identified by
+	pub fn new(secret: SecretKey) -> Result<Self> {
+		let ctx = Ctx::new()?;
+		secret.validate(&ctx)?;
+		Self::build(ctx, secret)
+	}
+
+	// uses a newly generated identity
+	pub fn generate() -> Result<Self> {
+		let ctx = Ctx::new()?;
+		let secret = SecretKey::gen(&ctx);
+		Self::build(ctx, secret)
+	}
+
+	pub fn identity(&self) -> &PublicKey {
+		&self.inner.public
+	}
+
+	// the handshake is driven by the data passed to `NoiseStream::process`
+	pub fn accept(&self) -> Result<NoiseStream> {
+		let hs = Handshake::new(self, Stage::ResponderWaitInit)?;
+		NoiseStream::new(self.clone(), NoiseState::Handshake(hs))
+	}
+
+	// blocks until the handshake completes. If `remote` is specified, the
+	// server must prove it holds that identity.
+	pub fn connect(&self, socket: Socket, remote: Option<&PublicKey>) -> Result<NoiseStream> {
+		let deadline = micros() + NOISE_HANDSHAKE_TIMEOUT_MILLIS * 1_000;
+		let mut hs = Handshake::new(self, Stage::InitiatorWaitResponse)?;
+		let msg1 = hs.write_init(self)?;
+		write_all(socket, &msg1, Some(deadline))?;
+
+		let mut msg2 = [0u8; NOISE_MSG2_LEN];
+		let mut offset = 0;
+		while offset < msg2.len() {
+			match socket.recv(&mut msg2[offset..]) {
+				Ok(0) => return err!(NoiseHandshake),
+				Ok(len) => offset += len,
+				Err(e) => {
+					if e != EAgain {
+						return err!(NoiseHandshake);
+					}
+					if micros() > deadline {
+						return err!(NoiseHandshake);
+					}
+					sched_yield!();
+				}
+			}
+		}
+
+		let (msg3, rs) = hs.read_response(self, &msg2)?;
+		match remote {
+			Some(remote) => {
+				if !(*remote == rs) {
+					return err!(NoiseHandshake);
+				}
+			}
+			None => {}
+		}
+		write_all(socket, &msg3, Some(deadline))?;
+
+		let stream = NoiseStream::new(self.clone(), hs.split()?)?;
+		unsafe {
+			(*stream.data.get()).remote = Some(rs);
+		}
+		Ok(stream)
+	}
+
+	fn build(ctx: Ctx, secret: SecretKey) -> Result<Self> {
+		let public = PublicKey::from(&ctx, &secret)?;
+		Ok(Self {
+			inner: Rc::new(NoiseConfigInner {
+				ctx,
+				secret,
+				public,
+				lock: lock!(),
+			})?,
+		})
+	}
+
+	fn ctx(&self) -> &Ctx {
+		&self.inner.ctx
+	}
+
+	fn dh(&self, remote: &PublicKey, local: &SecretKey) -> Result<SharedSecret> {
+		match SharedSecret::new(self.ctx(), remote, local) {
+			Ok(ss) => Ok(ss),
+			Err(_) => err!(NoiseHandshake),
+		}
+	}
+
+	fn parse(&self, bytes: &[u8]) -> Result<PublicKey> {
+		match PublicKey::parse(self.ctx(), bytes) {
+			Ok(key) => Ok(key),
+			Err(_) => err!(NoiseHandshake),
+		}
+	}
+
+	fn gen(&self) -> SecretKey {
+		// the rng in ctx is shared by all connections
+		let _l = self.inner.lock.write();
+		SecretKey::gen(self.ctx())
+	}
+}
+
+impl SymmetricState {
+	fn new() -> Self {
+		let sha3 = Sha3_256::new();
+		sha3.update(NOISE_PROTOCOL_NAME);
+		let h = sha3.finalize();
+		Self {
+			ck: h,
+			h,
+			k: None,
+			n: 0,
+		}
+	}
+
+	fn mix_hash(&mut self, data: &[u8]) {
+		let sha3 = Sha3_256::new();
+		sha3.update(&self.h);
+		sha3.update(data);
+		self.h = sha3.finalize();
+	}
+
+	fn mix_key(&mut self, ikm: &[u8]) -> Result<()> {
+		let (ck, k) = Self::hkdf2(&self.ck, ikm)?;
+		self.ck = ck;
+		self.k = Some(Aead::new(&k)?);
+		self.n = 0;
+		Ok(())
+	}
+
+	fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+		let c = match &self.k {
+			Some(k) => k.seal(self.n, &self.h, plaintext)?,
+			None => return err!(IllegalState),
+		};
+		self.n += 1;
+		self.mix_hash(&c);
+		Ok(c)
+	}
+
+	fn decrypt_and_hash(&mut self, c: &[u8]) -> Result<Vec<u8>> {
+		let p = match &self.k {
+			Some(k) => match k.open(self.n, &self.h, c) {
+				Ok(p) => p,
+				Err(_) => return err!(NoiseHandshake),
+			},
+			None => return err!(IllegalState),
+		};
+		self.n += 1;
+		self.mix_hash(c);
+		Ok(p)
+	}
+
+	// returns the initiator to responder and responder to initiator keys
+	fn split(&self) -> Result<(Aead, Aead)> {
+		let (k1, k2) = Self::hkdf2(&self.ck, b"")?;
+		Ok((Aead::new(&k1)?, Aead::new(&k2)?))
+	}
+
+	fn hkdf2(ck: &[u8; 32], ikm: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+		let mut out = [0u8; 64];
+		hkdf(ck, ikm, b"", &mut out)?;
+		let mut k1 = [0u8; 32];
+		let mut k2 = [0u8; 32];
+		k1.slice_copy(&out[0..32])?;
+		k2.slice_copy(&out[32..64])?;
+		unsafe {
+			write_bytes(out.as_mut_ptr(), 0, 64);
+			compiler_fence(SeqCst);
+		}
+		Ok((k1, k2))
+	}
+}
+
+impl Handshake {
+	fn new(config: &NoiseConfig, stage: Stage) -> Result<Self> {
+		Ok(Self {
+			sym: SymmetricState::new(),
+			e: config.gen(),
+			stage,
+		})
+	}
+
+	// initiator: -> e
+	fn write_init(&mut self, config: &NoiseConfig) -> Result<Vec<u8>> {
+		let e_pub = PublicKey::from(config.ctx(), &self.e)?;
+		self.sym.mix_hash(e_pub.as_ref());
+		let mut ret = Vec::with_capacity(NOISE_MSG1_LEN)?;
+		ret.extend_from_slice(e_pub.as_ref())?;
+		Ok(ret)
+	}
+
+	// responder: <- e, ee, s, es
+	fn read_init(&mut self, config: &NoiseConfig, msg: &[u8]) -> Result<Vec<u8>> {
+		let re = config.parse(msg)?;
+		self.sym.mix_hash(msg);
+
+		let e_pub = PublicKey::from(config.ctx(), &self.e)?;
+		let mut ret = Vec::with_capacity(NOISE_MSG2_LEN)?;
+		ret.extend_from_slice(e_pub.as_ref())?;
+		self.sym.mix_hash(e_pub.as_ref());
+		self.sym.mix_key(config.dh(&re, &self.e)?.as_ref())?;
+		let s = self.sym.encrypt_and_hash(config.identity().as_ref())?;
+		ret.extend_from_slice(&s)?;
+		self.sym
+			.mix_key(config.dh(&re, &config.inner.secret)?.as_ref())?;
+		let payload = self.sym.encrypt_and_hash(b"")?;
+		ret.extend_from_slice(&payload)?;
+
+		self.stage = Stage::ResponderWaitFinal;
+		Ok(ret)
+	}
+
+	// initiator: processes the response and returns the final message along
+	// with the responder's identity
+	fn read_response(&mut self, config: &NoiseConfig, msg: &[u8]) -> Result<(Vec<u8>, PublicKey)> {
+		if self.stage != Stage::InitiatorWaitResponse || msg.len() != NOISE_MSG2_LEN {
+			return err!(IllegalState);
+		}
+		let re_bytes = &msg[0..NOISE_KEY_SIZE];
+		let re = config.parse(re_bytes)?;
+		self.sym.mix_hash(re_bytes);
+		self.sym.mix_key(config.dh(&re, &self.e)?.as_ref())?;
+		let s_end = 2 * NOISE_KEY_SIZE + NOISE_TAG_SIZE;
+		let rs = config.parse(&self.sym.decrypt_and_hash(&msg[NOISE_KEY_SIZE..s_end])?)?;
+		self.sym.mix_key(config.dh(&rs, &self.e)?.as_ref())?;
+		self.sym.decrypt_and_hash(&msg[s_end..])?;
+
+		// -> s, se
+		let mut ret = Vec::with_capacity(NOISE_MSG3_LEN)?;
+		let s = self.sym.encrypt_and_hash(config.identity().as_ref())?;
+		ret.extend_from_slice(&s)?;
+		self.sym
+			.mix_key(config.dh(&re, &config.inner.secret)?.as_ref())?;
+		let payload = self.sym.encrypt_and_hash(b"")?;
+		ret.extend_from_slice(&payload)?;
+
+		Ok((ret, rs))
+	}
+
+	// responder: processes the final message and returns the initiator's identity
+	fn read_final(&mut self, config: &NoiseConfig, msg: &[u8]) -> Result<PublicKey> {
+		let s_end = NOISE_KEY_SIZE + NOISE_TAG_SIZE;
+		let rs = config.parse(&self.sym.decrypt_and_hash(&msg[0..s_end])?)?;
+		self.sym.mix_key(config.dh(&rs, &self.e)?.as_ref())?;
+		self.sym.decrypt_and_hash(&msg[s_end..])?;
+		Ok(rs)
+	}
+
+	fn split(&self) -> Result<NoiseState> {
+		let (i2r, r2i) = self.sym.split()?;
+		let (send, recv) = match self.stage {
+			Stage::InitiatorWaitResponse => (i2r, r2i),
+			_ => (r2i, i2r),
+		};
+		Ok(NoiseState::Transport {
+			send,
+			recv,
+			send_n: 0,
+			recv_n: 0,
+		})
+	}
+}
+
+impl NoiseStream {
+	fn new(config: NoiseConfig, state: NoiseState) -> Result<Self> {
+		Ok(Self {
+			config,
+			data: UnsafeCell::new(NoiseStreamData {
+				state,
+				remote: None,
+				buffer: Vec::new(),
+				pending: Vec::new(),
+				pending_offset: 0,
+			}),
+			lock: lock!(),
+		})
+	}
+
+	pub fn is_established(&self) -> bool {
+		let _l = self.lock.read();
+		match unsafe { &(*self.data.get()).state } {
+			NoiseState::Transport { .. } => true,
+			_ => false,
+		}
+	}
+
+	// the peer's static key, once the handshake has completed
+	pub fn remote_identity(&self) -> Option<PublicKey> {
+		let _l = self.lock.read();
+		unsafe { (*self.data.get()).remote.clone() }
+	}
+
+	// feeds bytes read from the socket. Handshake responses are queued for
+	// `flush` and decrypted application data is appended to `out`. Returns
+	// true if this call completed the handshake. Any error means the
+	// connection can't continue and should be closed.
+	pub fn process(&self, bytes: &[u8], out: &mut Vec<u8>) -> Result<bool> {
+		let _l = self.lock.write();
+		let data = unsafe { &mut *self.data.get() };
+		data.buffer.extend_from_slice(bytes)?;
+		let mut established = false;
+		loop {
+			let mut transport = None;
+			let consumed = match &mut data.state {
+				NoiseState::Handshake(hs) => match hs.stage {
+					Stage::ResponderWaitInit => {
+						if data.buffer.len() < NOISE_MSG1_LEN {
+							break;
+						}
+						let msg2 = hs.read_init(&self.config, &data.buffer[0..NOISE_MSG1_LEN])?;
+						data.pending.extend(&msg2)?;
+						NOISE_MSG1_LEN
+					}
+					Stage::ResponderWaitFinal => {
+						if data.buffer.len() < NOISE_MSG3_LEN {
+							break;
+						}
+						let rs = hs.read_final(&self.config, &data.buffer[0..NOISE_MSG3_LEN])?;
+						transport = Some(hs.split()?);
+						data.remote = Some(rs);
+						established = true;
+						NOISE_MSG3_LEN
+					}
+					Stage::InitiatorWaitResponse => return err!(IllegalState),
+				},
+				NoiseState::Transport { recv, recv_n, .. } => {
+					if data.buffer.len() < NOISE_FRAME_HEADER_LEN {
+						break;
+					}
+					let b = &data.buffer;
+					let len = ((b[0] as usize) << 24)
+						| ((b[1] as usize) << 16)
+						| ((b[2] as usize) << 8)
+						| (b[3] as usize);
+					if len < NOISE_TAG_SIZE || len > NOISE_MAX_FRAME_PAYLOAD + NOISE_TAG_SIZE {
+						return err!(NoiseFrame);
+					}
+					let end = NOISE_FRAME_HEADER_LEN + len;
+					if data.buffer.len() < end {
+						break;
+					}
+					let plaintext = recv.open(
+						*recv_n,
+						&data.buffer[0..NOISE_FRAME_HEADER_LEN],
+						&data.buffer[NOISE_FRAME_HEADER_LEN..end],
+					)?;
+					*recv_n += 1;
+					out.extend_from_slice(&plaintext)?;
+					end
+				}
+			};
+			match transport {
+				Some(state) => data.state = state,
+				None => {}
+			}
+			data.buffer = Self::consume(&data.buffer, consumed)?;
+		}
+		Ok(established)
+	}
+
+	// encrypts `b` and writes what the socket takes without blocking. The
+	// rest is queued for `flush`, the Evh calls it once the socket is
+	// writable. At most NOISE_MAX_PENDING bytes are queued, so like a socket
+	// this may take only part of `b` and returns how much it took, or
+	// EAgain if the queue is full.
+	pub fn send(&self, socket: Socket, b: &[u8]) -> Result<usize> {
+		let _l = self.lock.write();
+		let data = unsafe { &mut *self.data.get() };
+		Self::write_pending(socket, data)?;
+		let overhead = NOISE_FRAME_HEADER_LEN + NOISE_TAG_SIZE;
+		let mut room = NOISE_MAX_PENDING.saturating_sub(Self::queued(data));
+		let mut taken = 0;
+		match &mut data.state {
+			NoiseState::Transport { send, send_n, .. } => {
+				while taken < b.len() && room > overhead {
+					let len = (b.len() - taken)
+						.min(NOISE_MAX_FRAME_PAYLOAD)
+						.min(room - overhead);
+					let chunk = &b[taken..taken + len];
+					let mut header = [0u8; NOISE_FRAME_HEADER_LEN];
+					to_be_bytes_u32((len + NOISE_TAG_SIZE) as u32, &mut header);
+					let sealed = send.seal(*send_n, &header, chunk)?;
+					*send_n += 1;
+					data.pending.extend_from_slice(&header)?;
+					data.pending.extend(&sealed)?;
+					room -= len + overhead;
+					taken += len;
+				}
+			}
+			_ => return err!(IllegalState),
+		}
+		if taken == 0 && b.len() > 0 {
+			return err!(EAgain);
+		}
+		Self::write_pending(socket, data)?;
+		Ok(taken)
+	}
+
+	// writes queued output without blocking. Returns true once all of it
+	// has been written.
+	pub fn flush(&self, socket: Socket) -> Result<bool> {
+		let _l = self.lock.write();
+		let data = unsafe { &mut *self.data.get() };
+		Self::write_pending(socket, data)?;
+		Ok(Self::queued(data) == 0)
+	}
+
+	fn queued(data: &NoiseStreamData) -> usize {
+		data.pending.len() - data.pending_offset
+	}
+
+	// written bytes are only dropped from the front of the queue once they
+	// are at least half of it, so each byte is moved at most once on average
+	fn write_pending(socket: Socket, data: &mut NoiseStreamData) -> Result<()> {
+		let res = loop {
+			if data.pending_offset == data.pending.len() {
+				break Ok(());
+			}
+			match socket.send(&data.pending[data.pending_offset..]) {
+				Ok(len) => data.pending_offset += len,
+				Err(e) => {
+					if e != EAgain {
+						break Err(e);
+					}
+					break Ok(());
+				}
+			}
+		};
+		if data.pending_offset == data.pending.len() {
+			data.pending.clear();
+			data.pending_offset = 0;
+		} else if data.pending_offset >= Self::queued(data) {
+			data.pending = Self::consume(&data.pending, data.pending_offset)?;
+			data.pending_offset = 0;
+		}
+		res
+	}
+
+	fn consume(buffer: &Vec<u8>, n: usize) -> Result<Vec<u8>> {
+		let mut ret = Vec::new();
+		ret.extend_from_slice(&buffer[n..])?;
+		Ok(ret)
+	}
+}
+
+fn write_all(socket: Socket, b: &[u8], deadline: Option<u64>) -> Result<()> {
+	let mut offset = 0;
+	while offset < b.len() {
+		match socket.send(&b[offset..]) {
+			Ok(len) => offset += len,
+			Err(e) => {
+				if e != EAgain {
+					return Err(e);
+				}
+				match deadline {
+					Some(deadline) => {
+						if micros() > deadline {
+							return err!(NoiseHandshake);
+						}
+					}
+					None => {}
+				}
+				sched_yield!();
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use net::evh::*;
+
+	fn echo_server(
+		evh: &mut Evh<u64, u64>,
+		noise: &NoiseConfig,
+		counts: Rc<(u64, u64)>,
+		lock: LockBox,
+	) -> Result<(u16, Socket, Connection<u64, u64>)> {
+		let (port, s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		let mut accept_counts = counts.clone();
+		let accept_lock = lock.clone();
+		let mut close_counts = counts.clone();
+		let close_lock = lock.clone();
+		let recv: OnRecv<u64, u64> = Box::new(
+			move |_ctx: &mut u64, conn: &mut Connection<u64, u64>, bytes: &[u8]| -> Result<()> {
+				assert_eq!(conn.write(bytes)?, bytes.len());
+				Ok(())
+			},
+		)?;
+		let accept: OnAccept<u64, u64> = Box::new(
+			move |_ctx: &mut u64, conn: &mut Connection<u64, u64>| -> Result<()> {
+				assert!(conn.is_noise());
+				assert!(conn.remote_identity().is_some());
+				let _l = accept_lock.write();
+				accept_counts.0 += 1;
+				Ok(())
+			},
+		)?;
+		let close: OnClose<u64, u64> = Box::new(
+			move |_ctx: &mut u64, _conn: &mut Connection<u64, u64>| -> Result<()> {
+				let _l = close_lock.write();
+				close_counts.1 += 1;
+				Ok(())
+			},
+		)?;
+
+		let server = Connection::acceptor_noise(
+			s,
+			noise.clone(),
+			Rc::new(recv)?,
+			Rc::new(accept)?,
+			Rc::new(close)?,
+			0u64,
+		)?;
+		assert!(server.is_noise());
+		evh.register(server.clone())?;
+		Ok((port, s, server))
+	}
+
+	fn wait_for_close(client: Socket) -> Result<()> {
+		let mut buf = [0u8; 100];
+		loop {
+			match client.recv(&mut buf) {
+				Ok(0) => break Ok(()),
+				Ok(_) => {}
+				Err(e) => {
+					if e != EAgain {
+						break Ok(());
+					}
+					sleep(1);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn test_noise_echo() -> Result<()> {
+		let server_noise = NoiseConfig::generate()?;
+		let client_noise = NoiseConfig::generate()?;
+		assert!(!(*server_noise.identity() == *client_noise.identity()));
+
+		let lock = lock_box!()?;
+		let counts = Rc::new((0u64, 0u64))?;
+		let mut evh: Evh<u64, u64> = Evh::new()?;
+		let (port, mut s, mut server) =
+			echo_server(&mut evh, &server_noise, counts.clone(), lock.clone())?;
+		evh.start()?;
+
+		// the client gets its own event loop so that echoing large messages
+		// can't block on itself
+		let mut client_evh: Evh<u64, u64> = Evh::new()?;
+		client_evh.start()?;
+		let received = Rc::new(Vec::new())?;
+		let mut received_clone = received.clone();
+		let lock_clone = lock.clone();
+		let recv_client: OnRecv<u64, u64> = Box::new(
+			move |_ctx: &mut u64, _conn: &mut Connection<u64, u64>, bytes: &[u8]| -> Result<()> {
+				let _l = lock_clone.write();
+				received_clone.extend_from_slice(bytes)?;
+				Ok(())
+			},
+		)?;
+		let close_client: OnClose<u64, u64> = Box::new(
+			move |_ctx: &mut u64, _conn: &mut Connection<u64, u64>| -> Result<()> { Ok(()) },
+		)?;
+
+		let client = Socket::connect([127, 0, 0, 1], port)?;
+		let mut connector = Connection::outbound_noise(
+			client,
+			&client_noise,
+			Some(server_noise.identity()),
+			Rc::new(recv_client)?,
+			Rc::new(close_client)?,
+			0u64,
+		)?;
+		assert!(connector.is_noise());
+		assert!(!connector.is_tls());
+		assert!(connector.remote_identity().unwrap() == *server_noise.identity());
+		client_evh.register(connector.clone())?;
+
+		// spans multiple frames
+		let mut msg = Vec::new();
+		for i in 0..(NOISE_MAX_FRAME_PAYLOAD * 2 + 100) {
+			msg.push((i % 251) as u8)?;
+		}
+		assert_eq!(connector.write(&msg)?, msg.len());
+
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if received.len() == msg.len() {
+				assert_eq!(&received[..], &msg[..]);
+				assert_eq!(counts.0, 1);
+				break;
+			}
+		}
+
+		connector.close()?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if counts.1 == 1 {
+				break;
+			}
+		}
+
+		// the client socket was closed by its event loop
+		client_evh.stop()?;
+		evh.stop()?;
+		s.close()?;
+		unsafe {
+			server.drop_rc();
+			connector.drop_rc();
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_noise_queued() -> Result<()> {
+		let server_noise = NoiseConfig::generate()?;
+		let client_noise = NoiseConfig::generate()?;
+
+		// more than the socket buffers, written as soon as a peer connects
+		let mut big = Vec::new();
+		for i in 0..(8 * 1024 * 1024) {
+			big.push((i % 251) as u8)?;
+		}
+		let big = Rc::new(big)?;
+		let big_clone = big.clone();
+		let lock = lock_box!()?;
+		let accept_lock = lock.clone();
+		let mut accepted = Rc::new(0u64)?;
+		let accepted_clone = accepted.clone();
+		let mut written = Rc::new(0usize)?;
+		let written_clone = written.clone();
+		let recv: OnRecv<u64, u64> = Box::new(
+			move |_ctx: &mut u64, _conn: &mut Connection<u64, u64>, _bytes: &[u8]| -> Result<()> {
+				Ok(())
+			},
+		)?;
+		let accept: OnAccept<u64, u64> = Box::new(
+			move |_ctx: &mut u64, conn: &mut Connection<u64, u64>| -> Result<()> {
+				let _l = accept_lock.write();
+				*accepted += 1;
+				if *accepted == 1 {
+					// more than the queue takes
+					*written = conn.write(&big_clone)?;
+					assert!(*written > 0 && *written <= NOISE_MAX_PENDING);
+				}
+				Ok(())
+			},
+		)?;
+		let close: OnClose<u64, u64> = Box::new(
+			move |_ctx: &mut u64, _conn: &mut Connection<u64, u64>| -> Result<()> { Ok(()) },
+		)?;
+		let (port, mut s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		let mut server = Connection::acceptor_noise(
+			s,
+			server_noise.clone(),
+			Rc::new(recv)?,
+			Rc::new(accept)?,
+			Rc::new(close)?,
+			0u64,
+		)?;
+		let mut evh: Evh<u64, u64> = Evh::new()?;
+		evh.register(server.clone())?;
+		evh.start()?;
+
+		// the first client doesn't read yet
+		let mut client1 = Socket::connect([127, 0, 0, 1], port)?;
+		let stream1 = client_noise.connect(client1, None)?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if *accepted_clone == 1 {
+				break;
+			}
+		}
+
+		// the event loop isn't stuck writing to it
+		let mut client2 = Socket::connect([127, 0, 0, 1], port)?;
+		client_noise.connect(client2, None)?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if *accepted_clone == 2 {
+				break;
+			}
+		}
+		client2.close()?;
+
+		// the rest is flushed as the first client reads
+		let written = *written_clone;
+		let mut received = Vec::new();
+		let mut buf = [0u8; 16 * 1024];
+		while received.len() < written {
+			match client1.recv(&mut buf) {
+				Ok(0) => return err!(IO),
+				Ok(len) => {
+					stream1.process(&buf[0..len], &mut received)?;
+				}
+				Err(e) => {
+					assert_eq!(e, EAgain);
+					sleep(1);
+				}
+			}
+		}
+		assert_eq!(&received[..], &big[0..written]);
+		client1.close()?;
+
+		evh.stop()?;
+		s.close()?;
+		unsafe {
+			server.drop_rc();
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_noise_max_pending() -> Result<()> {
+		let config = NoiseConfig::generate()?;
+		let stream = NoiseStream::new(
+			config.clone(),
+			NoiseState::Transport {
+				send: Aead::new(&[1u8; 32])?,
+				recv: Aead::new(&[2u8; 32])?,
+				send_n: 0,
+				recv_n: 0,
+			},
+		)?;
+		let peer = NoiseStream::new(
+			config,
+			NoiseState::Transport {
+				send: Aead::new(&[2u8; 32])?,
+				recv: Aead::new(&[1u8; 32])?,
+				send_n: 0,
+				recv_n: 0,
+			},
+		)?;
+
+		let (port, mut s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		let mut server = loop {
+			match s.accept() {
+				Ok(server) => break server,
+				Err(e) => assert_eq!(e, EAgain),
+			}
+			sleep(1);
+		};
+
+		// the peer doesn't read, a large write is cut off at the limit. The
+		// data repeats every 251 bytes so it can be continued from any offset.
+		let mut big = Vec::new();
+		for i in 0..(2 * NOISE_MAX_PENDING) {
+			big.push((i % 251) as u8)?;
+		}
+		let mut written = stream.send(server, &big)?;
+		assert!(written > 0 && written <= NOISE_MAX_PENDING);
+		loop {
+			match stream.send(server, &big[written % 251..]) {
+				Ok(len) => written += len,
+				Err(e) => {
+					assert_eq!(e, EAgain);
+					break;
+				}
+			}
+		}
+		let queued = {
+			let _l = stream.lock.read();
+			NoiseStream::queued(unsafe { &*stream.data.get() })
+		};
+		assert!(queued <= NOISE_MAX_PENDING);
+		assert!(queued > NOISE_MAX_PENDING - NOISE_FRAME_HEADER_LEN - NOISE_TAG_SIZE - 1);
+		assert_eq!(stream.send(server, b"x").err(), Some(EAgain));
+
+		// all that was taken arrives once the peer reads
+		let mut received = Vec::new();
+		let mut buf = [0u8; 16 * 1024];
+		while received.len() < written {
+			stream.flush(server)?;
+			match client.recv(&mut buf) {
+				Ok(0) => return err!(IO),
+				Ok(len) => {
+					peer.process(&buf[0..len], &mut received)?;
+				}
+				Err(e) => {
+					assert_eq!(e, EAgain);
+					sleep(1);
+				}
+			}
+		}
+		assert_eq!(received.len(), written);
+		for i in 0..written {
+			assert_eq!(received[i], (i % 251) as u8);
+		}
+		assert!(stream.flush(server)?);
+
+		client.close()?;
+		server.close()?;
+		s.close()?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_noise_identity() -> Result<()> {
+		let server_noise = NoiseConfig::generate()?;
+		let client_noise = NoiseConfig::new(SecretKey::gen(&Ctx::new()?))?;
+		let other = NoiseConfig::generate()?;
+
+		let lock = lock_box!()?;
+		let counts = Rc::new((0u64, 0u64))?;
+		let mut evh: Evh<u64, u64> = Evh::new()?;
+		let (port, mut s, mut server) =
+			echo_server(&mut evh, &server_noise, counts.clone(), lock.clone())?;
+		evh.start()?;
+
+		// the server proves a different identity than expected
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		assert_eq!(
+			client_noise.connect(client, Some(other.identity())).err(),
+			Some(NoiseHandshake)
+		);
+		client.close()?;
+
+		// without an expected identity we learn the server's
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		let stream = client_noise.connect(client, None)?;
+		assert!(stream.is_established());
+		assert!(stream.remote_identity().unwrap() == *server_noise.identity());
+
+		// the server learns ours once it processes the last message
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if counts.0 == 1 {
+				break;
+			}
+		}
+		client.close()?;
+
+		// only the completed handshake was visible to the closures
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if counts.1 == 1 {
+				assert_eq!(counts.0, 1);
+				break;
+			}
+		}
+
+		assert_eq!(
+			NoiseConfig::new(SecretKey::zero()).err(),
+			Some(OperationFailed)
+		);
+
+		evh.stop()?;
+		s.close()?;
+		unsafe {
+			server.drop_rc();
+		}
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_noise_bad_peer() -> Result<()> {
+		let server_noise = NoiseConfig::generate()?;
+		let client_noise = NoiseConfig::generate()?;
+
+		let lock = lock_box!()?;
+		let counts = Rc::new((0u64, 0u64))?;
+		let mut evh: Evh<u64, u64> = Evh::new()?;
+		let (port, mut s, mut server) =
+			echo_server(&mut evh, &server_noise, counts.clone(), lock.clone())?;
+		evh.start()?;
+
+		// an invalid ephemeral key
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		write_all(client, &[9u8; NOISE_MSG1_LEN], None)?;
+		wait_for_close(client)?;
+		client.close()?;
+
+		// a forged frame after a valid handshake
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		let stream = client_noise.connect(client, Some(server_noise.identity()))?;
+		assert_eq!(stream.send(client, b"ok")?, 2);
+		let mut frame = [7u8; NOISE_FRAME_HEADER_LEN + 2 + NOISE_TAG_SIZE];
+		to_be_bytes_u32((2 + NOISE_TAG_SIZE) as u32, &mut frame);
+		write_all(client, &frame, None)?;
+		wait_for_close(client)?;
+		client.close()?;
+
+		// an oversized frame
+		let mut client = Socket::connect([127, 0, 0, 1], port)?;
+		client_noise.connect(client, None)?;
+		write_all(client, &[0xff; NOISE_FRAME_HEADER_LEN], None)?;
+		wait_for_close(client)?;
+		client.close()?;
+
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if counts.1 == 2 {
+				assert_eq!(counts.0, 2);
+				break;
+			}
+		}
+
+		evh.stop()?;
+		s.close()?;
+		unsafe {
+			server.drop_rc();
+		}
+
+		Ok(())
+	}
+}
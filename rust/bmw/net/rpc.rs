@@ -0,0 +1,860 @@
+use core::ops::{Fn, FnMut};
+use net::constants::*;
+use net::errors::*;
+use net::http::{HttpMethod, HttpOnRequest, HttpRequest, HttpResponse, HttpRoute};
+use net::socket::Socket;
+use net::ws::{Handle, Handler, WsOnAccept, WsOnClose, WsOnRecv};
+use prelude::*;
+use util::json::Json;
+use util::lock::LockBox;
+
+// a block is identified by its height on the current chain or its hash
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockId {
+	Height(u64),
+	Hash([u8; 32]),
+}
+
+// The node operations served over JSON-RPC. Implementations are called from
+// the websocket threads and must do their own locking.
+pub trait NodeApi {
+	// the height and hash of the chain tip
+	fn tip(&self) -> Result<(u64, [u8; 32])>;
+	// headers and blocks in their json form, None if unknown
+	fn header(&self, id: &BlockId) -> Result<Option<Json>>;
+	fn block(&self, id: &BlockId) -> Result<Option<Json>>;
+	// `tx` is a serialized transaction, an error means it was rejected
+	fn submit_transaction(&self, tx: &[u8]) -> Result<()>;
+	fn is_unspent(&self, commitment: &[u8; 33]) -> Result<bool>;
+}
+
+// maps the errors of a server's own methods to a JSON-RPC error code and
+// message, None leaves the error to the standard mapping (see
+// Rpc::process_with)
+pub(crate) type RpcErrorMap = fn(&Error) -> Option<(i64, &'static str)>;
+
+struct RpcInner {
+	api: Box<dyn NodeApi>,
+	// websocket connections subscribed to new blocks and their
+	// subscription ids
+	subscribers: Vec<(u64, Handle)>,
+	next_subscription: u64,
+	lock: LockBox,
+}
+
+// JSON-RPC 2.0 server for a NodeApi. Requests are accepted as websocket
+// messages (see `handler`) and http POSTs (see `route`). Batches and
+// notifications are supported. Methods:
+//
+//   get_tip                            -> {"height", "hash"}
+//   get_header [height|hash]           -> header or null
+//   get_block [height|hash]            -> block or null
+//   submit_transaction [tx]            -> true
+//   is_unspent [commitment]            -> bool
+//   subscribe_blocks                   -> subscription id
+//   unsubscribe_blocks [subscription]  -> bool
+//
+// Parameters may be positional or named. Hashes, commitments and
+// transactions are hex encoded. Subscribers receive a "block" notification
+// with params {"subscription", "height", "hash"} for each `notify_block`.
+#[derive(Clone)]
+pub struct Rpc {
+	inner: Rc<RpcInner>,
+}
+
+impl Rpc {
+	pub fn new(api: Box<dyn NodeApi>) -> Result<Self> {
+		Ok(Self {
+			inner: Rc::new(RpcInner {
+				api,
+				subscribers: Vec::new(),
+				next_subscription: 1,
+				lock: lock_box!()?,
+			})?,
+		})
+	}
+
+	// a websocket handler serving requests at `path`. Subscriptions end
+	// when the connection closes.
+	pub fn handler(&self, path: &str) -> Result<Handler> {
+		let rpc = self.clone();
+		let on_recv: WsOnRecv = Box::new(
+			move |handle: &mut Handle, bytes: &[u8], _fin: bool, _op: u8| -> Result<()> {
+				match rpc.process(bytes, Some(handle))? {
+					Some(resp) => handle.send_text(resp.to_string()?.as_str()),
+					None => Ok(()),
+				}
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let rpc = self.clone();
+		let on_close: WsOnClose = Box::new(move |handle: &mut Handle| -> Result<()> {
+			rpc.remove_subscribers(handle.socket())
+		})?;
+		Handler::new(
+			path,
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)
+	}
+
+	// an http POST route serving requests at `path`. A request made up of
+	// notifications only gets a 204.
+	pub fn route(&self, path: &str) -> Result<HttpRoute> {
+		let rpc = self.clone();
+		let on_request: HttpOnRequest = Box::new(
+			move |req: &HttpRequest, resp: &mut HttpResponse| -> Result<()> {
+				match rpc.process(req.body(), None)? {
+					Some(json) => {
+						resp.add_header("Content-Type", "application/json")?;
+						resp.write(json.to_string()?.as_bytes())
+					}
+					None => {
+						resp.set_status(204);
+						Ok(())
+					}
+				}
+			},
+		)?;
+		HttpRoute::new(HttpMethod::Post, path, Rc::new(on_request)?)
+	}
+
+	// processes a request or a batch and returns the response, None if
+	// nothing needs to be sent. `handle` is the websocket the request came
+	// from, if any.
+	pub fn process(&self, bytes: &[u8], handle: Option<&Handle>) -> Result<Option<Json>> {
+		Self::process_with(
+			bytes,
+			|_| None,
+			|method, params| self.dispatch(method, params, handle),
+		)
+	}
+
+	// the JSON-RPC framing shared with other servers, each request is
+	// answered by `dispatch`. Errors that `errors` doesn't map get the
+	// standard codes, see response.
+	pub(crate) fn process_with<F>(
+		bytes: &[u8],
+		errors: RpcErrorMap,
+		mut dispatch: F,
+	) -> Result<Option<Json>>
+	where
+		F: FnMut(&str, Option<&Json>) -> Result<Json>,
+	{
+		let req = match Json::parse(bytes) {
+			Ok(req) => req,
+			Err(_) => {
+				return Ok(Some(Self::error(
+					Json::Null,
+					RPC_PARSE_ERROR,
+					"Parse error",
+				)?))
+			}
+		};
+		match req.as_array() {
+			Some(batch) => {
+				if batch.len() == 0 {
+					return Ok(Some(Self::error(
+						Json::Null,
+						RPC_INVALID_REQUEST,
+						"Invalid Request",
+					)?));
+				}
+				let mut responses = Json::array();
+				let mut count = 0;
+				for req in batch.iter() {
+					match Self::call(req, errors, &mut dispatch)? {
+						Some(resp) => {
+							responses.push(resp)?;
+							count += 1;
+						}
+						None => {}
+					}
+				}
+				Ok(if count > 0 { Some(responses) } else { None })
+			}
+			None => Self::call(&req, errors, &mut dispatch),
+		}
+	}
+
+	// sends a "block" notification to every subscriber. Subscribers that
+	// can't be reached are dropped.
+	pub fn notify_block(&self, height: u64, hash: &[u8; 32]) -> Result<()> {
+		let mut inner = self.inner.clone();
+		let lock = inner.lock.clone();
+		// sending may block on a slow subscriber, so it's done without the
+		// lock held
+		let mut subscribers = Vec::new();
+		{
+			let _l = lock.read();
+			for s in inner.subscribers.iter() {
+				subscribers.push(s.clone())?;
+			}
+		}
+		let mut failed = Vec::new();
+		for (id, handle) in subscribers.iter_mut() {
+			let mut params = Json::object();
+			params.set("subscription", Self::number(*id)?)?;
+			params.set("height", Self::number(height)?)?;
+			params.set("hash", Self::hex(hash)?)?;
+			let mut msg = Json::object();
+			msg.set("jsonrpc", Json::string("2.0")?)?;
+			msg.set("method", Json::string("block")?)?;
+			msg.set("params", params)?;
+			let msg = msg.to_string()?;
+			if handle.send_text(msg.as_str()).is_err() {
+				failed.push(*id)?;
+			}
+		}
+		if failed.len() > 0 {
+			let _l = lock.write();
+			Self::retain(&mut inner, |(id, _)| !failed.iter().any(|f| f == id))?;
+		}
+		Ok(())
+	}
+
+	pub fn subscribers(&self) -> usize {
+		let _l = self.inner.lock.read();
+		self.inner.subscribers.len()
+	}
+
+	fn remove_subscribers(&self, socket: Socket) -> Result<()> {
+		let mut inner = self.inner.clone();
+		let lock = inner.lock.clone();
+		let _l = lock.write();
+		Self::retain(&mut inner, |(_, handle)| handle.socket() != socket)?;
+		Ok(())
+	}
+
+	// keeps the subscribers matching `keep`, returns whether any were removed
+	fn retain<F: Fn(&(u64, Handle)) -> bool>(inner: &mut RpcInner, keep: F) -> Result<bool> {
+		let mut subscribers = Vec::new();
+		for s in inner.subscribers.iter() {
+			if keep(s) {
+				subscribers.push(s.clone())?;
+			}
+		}
+		let removed = subscribers.len() != inner.subscribers.len();
+		inner.subscribers = subscribers;
+		Ok(removed)
+	}
+
+	// a single request, None if it is a notification
+	fn call<F>(req: &Json, errors: RpcErrorMap, dispatch: &mut F) -> Result<Option<Json>>
+	where
+		F: FnMut(&str, Option<&Json>) -> Result<Json>,
+	{
+		let id = req.get("id");
+		let valid_id = match id {
+			None | Some(Json::Null) | Some(Json::Number(_)) | Some(Json::String(_)) => true,
+			_ => false,
+		};
+		let params = req.get("params");
+		let valid_params = match params {
+			None | Some(Json::Array(_)) | Some(Json::Object(_)) => true,
+			_ => false,
+		};
+		let version = req.get("jsonrpc").and_then(|v| v.as_str());
+		let method = match req.get("method").and_then(|v| v.as_str()) {
+			Some(method) if version == Some("2.0") && valid_id && valid_params => method,
+			_ => {
+				let id = match id {
+					Some(id) if valid_id => id.try_clone()?,
+					_ => Json::Null,
+				};
+				return Ok(Some(Self::error(
+					id,
+					RPC_INVALID_REQUEST,
+					"Invalid Request",
+				)?));
+			}
+		};
+
+		let result = dispatch(method, params);
+		match id {
+			Some(id) => Ok(Some(Self::response(id.try_clone()?, result, errors)?)),
+			None => Ok(None),
+		}
+	}
+
+	// the response to a request with `id`, errors are mapped to their codes
+	fn response(id: Json, result: Result<Json>, errors: RpcErrorMap) -> Result<Json> {
+		match result {
+			Ok(result) => {
+				let mut resp = Json::object();
+				resp.set("jsonrpc", Json::string("2.0")?)?;
+				resp.set("result", result)?;
+				resp.set("id", id)?;
+				Ok(resp)
+			}
+			Err(e) => {
+				let (code, message) = if let Some(mapped) = errors(&e) {
+					mapped
+				} else if e == RpcMethodNotFound {
+					(RPC_METHOD_NOT_FOUND, "Method not found")
+				} else if e == RpcInvalidParams {
+					(RPC_INVALID_PARAMS, "Invalid params")
+				} else if e == RpcTxRejected {
+					(RPC_TX_REJECTED, "Transaction rejected")
+				} else if e == RpcNoSubscriptions {
+					(RPC_NO_SUBSCRIPTIONS, "Subscriptions require a websocket")
+				} else {
+					(RPC_INTERNAL_ERROR, "Internal error")
+				};
+				Self::error(id, code, message)
+			}
+		}
+	}
+
+	fn dispatch(
+		&self,
+		method: &str,
+		params: Option<&Json>,
+		handle: Option<&Handle>,
+	) -> Result<Json> {
+		let api = &self.inner.api;
+		match method {
+			"get_tip" => {
+				let (height, hash) = api.tip()?;
+				let mut ret = Json::object();
+				ret.set("height", Self::number(height)?)?;
+				ret.set("hash", Self::hex(&hash)?)?;
+				Ok(ret)
+			}
+			"get_header" => Ok(match api.header(&Self::block_id(params)?)? {
+				Some(header) => header,
+				None => Json::Null,
+			}),
+			"get_block" => Ok(match api.block(&Self::block_id(params)?)? {
+				Some(block) => block,
+				None => Json::Null,
+			}),
+			"submit_transaction" => {
+				let tx = match Self::param(params, "tx", 0).and_then(|v| v.as_str()) {
+					Some(tx) => Self::unhex(tx)?,
+					None => return err!(RpcInvalidParams),
+				};
+				match api.submit_transaction(&tx) {
+					Ok(_) => Ok(Json::Bool(true)),
+					Err(_) => err!(RpcTxRejected),
+				}
+			}
+			"is_unspent" => {
+				let mut commitment = [0u8; 33];
+				match Self::param(params, "commitment", 0).and_then(|v| v.as_str()) {
+					Some(c) => Self::unhex_into(c, &mut commitment)?,
+					None => return err!(RpcInvalidParams),
+				}
+				Ok(Json::Bool(api.is_unspent(&commitment)?))
+			}
+			"subscribe_blocks" => {
+				let handle = match handle {
+					Some(handle) => handle.clone(),
+					None => return err!(RpcNoSubscriptions),
+				};
+				let mut inner = self.inner.clone();
+				let lock = inner.lock.clone();
+				let _l = lock.write();
+				let id = inner.next_subscription;
+				inner.next_subscription += 1;
+				inner.subscribers.push((id, handle))?;
+				Self::number(id)
+			}
+			"unsubscribe_blocks" => {
+				let id = match Self::param(params, "subscription", 0).and_then(|v| v.as_u64()) {
+					Some(id) => id,
+					None => return err!(RpcInvalidParams),
+				};
+				let socket = match handle {
+					Some(handle) => handle.socket(),
+					None => return err!(RpcNoSubscriptions),
+				};
+				let mut inner = self.inner.clone();
+				let lock = inner.lock.clone();
+				let _l = lock.write();
+				// only the subscribing connection may unsubscribe
+				let removed = Self::retain(&mut inner, |(s, h)| *s != id || h.socket() != socket)?;
+				Ok(Json::Bool(removed))
+			}
+			_ => err!(RpcMethodNotFound),
+		}
+	}
+
+	pub(crate) fn error(id: Json, code: i64, message: &str) -> Result<Json> {
+		let mut error = Json::object();
+		error.set("code", Json::Number(code))?;
+		error.set("message", Json::string(message)?)?;
+		let mut resp = Json::object();
+		resp.set("jsonrpc", Json::string("2.0")?)?;
+		resp.set("error", error)?;
+		resp.set("id", id)?;
+		Ok(resp)
+	}
+
+	// a named parameter or, for positional parameters, the one at `index`
+	pub(crate) fn param<'a>(
+		params: Option<&'a Json>,
+		name: &str,
+		index: usize,
+	) -> Option<&'a Json> {
+		match params {
+			Some(Json::Object(_)) => params.and_then(|p| p.get(name)),
+			Some(Json::Array(values)) if index < values.len() => Some(&values[index]),
+			_ => None,
+		}
+	}
+
+	// either a height or a hash
+	fn block_id(params: Option<&Json>) -> Result<BlockId> {
+		if let Some(height) = Self::param(params, "height", 0) {
+			if let Some(height) = height.as_u64() {
+				return Ok(BlockId::Height(height));
+			}
+		}
+		if let Some(hash) = Self::param(params, "hash", 0).and_then(|v| v.as_str()) {
+			let mut ret = [0u8; 32];
+			Self::unhex_into(hash, &mut ret)?;
+			return Ok(BlockId::Hash(ret));
+		}
+		err!(RpcInvalidParams)
+	}
+
+	pub(crate) fn number(n: u64) -> Result<Json> {
+		if n > i64::MAX as u64 {
+			return err!(IllegalArgument);
+		}
+		Ok(Json::Number(n as i64))
+	}
+
+	pub(crate) fn hex(b: &[u8]) -> Result<Json> {
+		let digits = b"0123456789abcdef";
+		let mut s = Vec::with_capacity(b.len() * 2)?;
+		for c in b {
+			s.push(digits[(c >> 4) as usize])?;
+			s.push(digits[(c & 0xf) as usize])?;
+		}
+		Ok(Json::String(String::newb(&s)?))
+	}
+
+	fn unhex(s: &str) -> Result<Vec<u8>> {
+		let mut ret = Vec::new();
+		ret.resize(s.len() / 2)?;
+		Self::unhex_into(s, &mut ret)?;
+		Ok(ret)
+	}
+
+	pub(crate) fn unhex_into(s: &str, out: &mut [u8]) -> Result<()> {
+		let b = s.as_bytes();
+		if b.len() != out.len() * 2 {
+			return err!(RpcInvalidParams);
+		}
+		for i in 0..b.len() {
+			let v = match b[i] {
+				b'0'..=b'9' => b[i] - b'0',
+				b'a'..=b'f' => b[i] - b'a' + 10,
+				b'A'..=b'F' => b[i] - b'A' + 10,
+				_ => return err!(RpcInvalidParams),
+			};
+			if i % 2 == 0 {
+				out[i / 2] = v << 4;
+			} else {
+				out[i / 2] |= v;
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use net::ws::{Connector, Listener, Ws};
+
+	// a chain of three blocks with hashes [1; 32], [2; 32] and [3; 32]
+	struct TestNode;
+
+	impl NodeApi for TestNode {
+		fn tip(&self) -> Result<(u64, [u8; 32])> {
+			Ok((2, [3u8; 32]))
+		}
+
+		fn header(&self, id: &BlockId) -> Result<Option<Json>> {
+			let height = match id {
+				BlockId::Height(h) if *h <= 2 => *h,
+				BlockId::Hash(hash) if hash[0] >= 1 && hash[0] <= 3 && hash[1] == hash[0] => {
+					hash[0] as u64 - 1
+				}
+				_ => return Ok(None),
+			};
+			let mut ret = Json::object();
+			ret.set("height", Json::Number(height as i64))?;
+			Ok(Some(ret))
+		}
+
+		fn block(&self, id: &BlockId) -> Result<Option<Json>> {
+			match self.header(id)? {
+				Some(header) => {
+					let mut ret = Json::object();
+					ret.set("header", header)?;
+					ret.set("kernels", Json::array())?;
+					Ok(Some(ret))
+				}
+				None => Ok(None),
+			}
+		}
+
+		fn submit_transaction(&self, tx: &[u8]) -> Result<()> {
+			if tx == b"ok" {
+				Ok(())
+			} else {
+				err!(IllegalArgument)
+			}
+		}
+
+		fn is_unspent(&self, commitment: &[u8; 33]) -> Result<bool> {
+			if commitment[0] == 0xff {
+				return err!(IllegalState);
+			}
+			Ok(commitment[0] == 9)
+		}
+	}
+
+	fn cat(parts: &[&str]) -> Result<String> {
+		let mut ret = Vec::new();
+		for part in parts {
+			ret.extend_from_slice(part.as_bytes())?;
+		}
+		String::newb(&ret)
+	}
+
+	fn call(rpc: &Rpc, req: &str) -> Result<String> {
+		match rpc.process(req.as_bytes(), None)? {
+			Some(resp) => resp.to_string(),
+			None => Ok(String::empty()),
+		}
+	}
+
+	#[test]
+	fn test_rpc_methods() -> Result<()> {
+		let rpc = Rpc::new(Box::new(TestNode)?)?;
+		let hash3 = "0303030303030303030303030303030303030303030303030303030303030303";
+		let tip = call(
+			&rpc,
+			"{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\",\"id\":1}",
+		)?;
+		let expected = cat(&[
+			"{\"jsonrpc\":\"2.0\",\"result\":{\"height\":2,\"hash\":\"",
+			hash3,
+			"\"},\"id\":1}",
+		])?;
+		assert_eq!(tip, expected);
+
+		// positional and named, by height and by hash
+		for (params, result) in [
+			("[0]", "{\"height\":0}"),
+			("{\"height\":1}", "{\"height\":1}"),
+			(
+				"[\"0202020202020202020202020202020202020202020202020202020202020202\"]",
+				"{\"height\":1}",
+			),
+			(
+				"{\"hash\":\"0303030303030303030303030303030303030303030303030303030303030303\"}",
+				"{\"height\":2}",
+			),
+			("[3]", "null"),
+		] {
+			let req = cat(&[
+				"{\"jsonrpc\":\"2.0\",\"method\":\"get_header\",\"params\":",
+				params,
+				",\"id\":\"a\"}",
+			])?;
+			let expected = cat(&["{\"jsonrpc\":\"2.0\",\"result\":", result, ",\"id\":\"a\"}"])?;
+			assert_eq!(call(&rpc, req.as_str())?, expected);
+		}
+		assert_eq!(
+			call(
+				&rpc,
+				"{\"jsonrpc\":\"2.0\",\"method\":\"get_block\",\"params\":[1],\"id\":2}"
+			)?
+			.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":{\"header\":{\"height\":1},\"kernels\":[]},\"id\":2}"
+		);
+
+		let invalid_params = "{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32602,\"message\":\"Invalid params\"},\"id\":3}";
+		for params in ["[-1]", "[\"0303\"]", "{\"x\":1}", "[]", "[true]"] {
+			let req = cat(&[
+				"{\"jsonrpc\":\"2.0\",\"method\":\"get_block\",\"params\":",
+				params,
+				",\"id\":3}",
+			])?;
+			assert_eq!(call(&rpc, req.as_str())?.as_str(), invalid_params);
+		}
+
+		// "ok" hex encoded is accepted, anything else rejected
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"submit_transaction\",\"params\":[\"6f6b\"],\"id\":4}")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":true,\"id\":4}"
+		);
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"submit_transaction\",\"params\":{\"tx\":\"6f6c\"},\"id\":4}")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32000,\"message\":\"Transaction rejected\"},\"id\":4}"
+		);
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"submit_transaction\",\"params\":[\"6f6\"],\"id\":4}")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32602,\"message\":\"Invalid params\"},\"id\":4}"
+		);
+
+		let mut commitment = Vec::new();
+		for (first, result) in [("09", "true"), ("08", "false")] {
+			commitment.clear();
+			commitment.extend_from_slice(first.as_bytes())?;
+			for _ in 0..32 {
+				commitment.extend_from_slice(b"00")?;
+			}
+			let req = cat(&[
+				"{\"jsonrpc\":\"2.0\",\"method\":\"is_unspent\",\"params\":{\"commitment\":\"",
+				String::newb(&commitment)?.as_str(),
+				"\"},\"id\":5}",
+			])?;
+			let expected = cat(&["{\"jsonrpc\":\"2.0\",\"result\":", result, ",\"id\":5}"])?;
+			assert_eq!(call(&rpc, req.as_str())?, expected);
+		}
+
+		// api errors are internal errors
+		commitment.clear();
+		for _ in 0..33 {
+			commitment.extend_from_slice(b"ff")?;
+		}
+		let req = cat(&[
+			"{\"jsonrpc\":\"2.0\",\"method\":\"is_unspent\",\"params\":[\"",
+			String::newb(&commitment)?.as_str(),
+			"\"],\"id\":6}",
+		])?;
+		assert_eq!(
+			call(&rpc, req.as_str())?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32603,\"message\":\"Internal error\"},\"id\":6}"
+		);
+
+		// subscriptions need a websocket
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"subscribe_blocks\",\"id\":7}")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32001,\"message\":\"Subscriptions require a websocket\"},\"id\":7}"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_rpc_protocol() -> Result<()> {
+		let rpc = Rpc::new(Box::new(TestNode)?)?;
+		let invalid = "{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32600,\"message\":\"Invalid Request\"},\"id\":null}";
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32700,\"message\":\"Parse error\"},\"id\":null}"
+		);
+		for req in ["1", "[]", "{\"jsonrpc\":\"2.0\",\"method\":1}"] {
+			assert_eq!(call(&rpc, req)?.as_str(), invalid);
+		}
+		for req in [
+			"{\"method\":\"get_tip\",\"id\":1}",
+			"{\"jsonrpc\":\"1.0\",\"method\":\"get_tip\",\"id\":1}",
+			"{\"jsonrpc\":\"2.0\",\"id\":1}",
+		] {
+			assert_eq!(
+				call(&rpc, req)?.as_str(),
+				"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32600,\"message\":\"Invalid Request\"},\"id\":1}"
+			);
+		}
+		// an id that isn't a string, number or null can't be echoed
+		assert_eq!(
+			call(
+				&rpc,
+				"{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\",\"id\":{}}"
+			)?
+			.as_str(),
+			invalid
+		);
+		// the id is echoed when it is valid
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\",\"params\":1,\"id\":-5}")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32600,\"message\":\"Invalid Request\"},\"id\":-5}"
+		);
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"nope\",\"id\":null}")?.as_str(),
+			"{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32601,\"message\":\"Method not found\"},\"id\":null}"
+		);
+
+		// notifications get no response, not even errors
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\"}")?.len(),
+			0
+		);
+		assert_eq!(
+			call(&rpc, "{\"jsonrpc\":\"2.0\",\"method\":\"nope\"}")?.len(),
+			0
+		);
+
+		// batches answer each request that isn't a notification
+		assert_eq!(
+			call(
+				&rpc,
+				"[{\"jsonrpc\":\"2.0\",\"method\":\"get_header\",\"params\":[0],\"id\":1},\
+				{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\"},1,\
+				{\"jsonrpc\":\"2.0\",\"method\":\"nope\",\"id\":2}]"
+			)?
+			.as_str(),
+			"[{\"jsonrpc\":\"2.0\",\"result\":{\"height\":0},\"id\":1},\
+			{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32600,\"message\":\"Invalid Request\"},\"id\":null},\
+			{\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32601,\"message\":\"Method not found\"},\"id\":2}]"
+		);
+		assert_eq!(
+			call(
+				&rpc,
+				"[{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\"},{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\"}]"
+			)?
+			.len(),
+			0
+		);
+		Ok(())
+	}
+
+	fn free_port() -> Result<u16> {
+		let (port, mut s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		s.close()?;
+		Ok(port)
+	}
+
+	// sends a request with `Connection: close` and reads until the server
+	// closes the connection
+	fn http_post(port: u16, body: &str) -> Result<Vec<u8>> {
+		let mut s = Socket::connect([127, 0, 0, 1], port)?;
+		let req = format!(
+			"POST /v1/rpc HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+			body.len(),
+			body
+		)?;
+		let req = req.as_bytes();
+		let mut offset = 0;
+		while offset < req.len() {
+			match s.send(&req[offset..]) {
+				Ok(n) => offset += n,
+				Err(_) => sleep(1),
+			}
+		}
+		let mut ret = Vec::new();
+		let mut buf = [0u8; 1024];
+		loop {
+			match s.recv(&mut buf) {
+				Ok(0) => break,
+				Ok(n) => ret.extend_from_slice(&buf[0..n])?,
+				Err(e) => {
+					assert_eq!(e, EAgain);
+					sleep(1);
+				}
+			}
+		}
+		s.close()?;
+		Ok(ret)
+	}
+
+	#[test]
+	fn test_rpc_transports() -> Result<()> {
+		let rpc = Rpc::new(Box::new(TestNode)?)?;
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		server.add_handler(rpc.handler("/v1/rpc")?)?;
+		server.add_route(rpc.route("/v1/rpc")?)?;
+		server.start()?;
+
+		let resp = http_post(
+			port,
+			"{\"jsonrpc\":\"2.0\",\"method\":\"get_header\",\"params\":[1],\"id\":1}",
+		)?;
+		assert!(resp.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n"));
+		assert!(resp.ends_with(b"\r\n\r\n{\"jsonrpc\":\"2.0\",\"result\":{\"height\":1},\"id\":1}"));
+		let resp = http_post(port, "{\"jsonrpc\":\"2.0\",\"method\":\"get_tip\"}")?;
+		assert!(resp.starts_with(b"HTTP/1.1 204 No Content\r\n"));
+
+		// subscribe over a websocket and collect the messages received
+		let lock = lock_box!()?;
+		let msgs: Rc<Vec<String>> = Rc::new(Vec::new())?;
+		let (lock1, mut msgs1) = (lock.clone(), msgs.clone());
+		let on_recv: WsOnRecv = Box::new(
+			move |_handle: &mut Handle, bytes: &[u8], _fin: bool, op: u8| -> Result<()> {
+				assert_eq!(op, 1);
+				let _l = lock1.write();
+				msgs1.push(String::newb(bytes)?)
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let handler = Handler::new(
+			"/v1/rpc",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?;
+		let mut client = Ws::new()?;
+		client.start()?;
+		let mut handle = client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		let wait = |n: usize| loop {
+			sleep(1);
+			let _l = lock.read();
+			if msgs.len() == n {
+				break;
+			}
+		};
+
+		handle.send_text("{\"jsonrpc\":\"2.0\",\"method\":\"subscribe_blocks\",\"id\":1}")?;
+		wait(1);
+		assert_eq!(
+			msgs[0].as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":1,\"id\":1}"
+		);
+		assert_eq!(rpc.subscribers(), 1);
+
+		rpc.notify_block(3, &[4u8; 32])?;
+		wait(2);
+		assert_eq!(
+			msgs[1].as_str(),
+			"{\"jsonrpc\":\"2.0\",\"method\":\"block\",\"params\":{\"subscription\":1,\"height\":3,\
+			\"hash\":\"0404040404040404040404040404040404040404040404040404040404040404\"}}"
+		);
+
+		handle.send_text(
+			"{\"jsonrpc\":\"2.0\",\"method\":\"unsubscribe_blocks\",\"params\":[2],\"id\":2}",
+		)?;
+		wait(3);
+		assert_eq!(
+			msgs[2].as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":false,\"id\":2}"
+		);
+		handle.send_text(
+			"{\"jsonrpc\":\"2.0\",\"method\":\"unsubscribe_blocks\",\"params\":[1],\"id\":3}",
+		)?;
+		wait(4);
+		assert_eq!(
+			msgs[3].as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":true,\"id\":3}"
+		);
+		assert_eq!(rpc.subscribers(), 0);
+
+		// closing the connection ends its subscriptions
+		handle.send_text("{\"jsonrpc\":\"2.0\",\"method\":\"subscribe_blocks\",\"id\":4}")?;
+		wait(5);
+		assert_eq!(
+			msgs[4].as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":2,\"id\":4}"
+		);
+		assert_eq!(rpc.subscribers(), 1);
+		handle.close()?;
+		while rpc.subscribers() != 0 {
+			sleep(1);
+		}
+
+		client.stop()?;
+		server.stop()?;
+		Ok(())
+	}
+}
@@ -0,0 +1,519 @@
+use bible::Bible;
+use core::ops::FnMut;
+use crypto::bip52::Bip52;
+use misc::u256_less_than_or_equal;
+use mw::block::{Block, BlockHeader};
+use net::constants::*;
+use net::errors::*;
+use net::rpc::Rpc;
+use net::socket::Socket;
+use net::ws::{Handle, Handler, WsOnAccept, WsOnClose, WsOnRecv};
+use prelude::*;
+use util::json::Json;
+use util::lock::LockBox;
+
+// called with the assembled block and its hash when a share meets the
+// network target
+pub type OnBlock = Box<dyn FnMut(&Block, [u8; 32]) -> Result<()>>;
+
+struct Job {
+	id: u64,
+	// the template sent to miners
+	header: BlockHeader,
+	block: Block,
+	bip52: Bip52,
+	target: [u8; 32],
+	// the nonce and aux_data_hash of the shares accepted for this job
+	shares: Vec<(u32, [u8; 32])>,
+}
+
+struct StratumInner {
+	key: [u8; 32],
+	share_target: [u8; 32],
+	bible: Bible,
+	// the most recent jobs, indexed by id % STRATUM_MAX_JOBS
+	jobs: Vec<Option<Job>>,
+	next_job: u64,
+	miners: Vec<Handle>,
+	on_block: OnBlock,
+	accepted: u64,
+	lock: LockBox,
+}
+
+// A stratum-style mining server. Miners connect over a websocket (see
+// `handler`) and speak JSON-RPC 2.0. Methods:
+//
+//   subscribe                                       -> current job or null
+//   submit [job_id, nonce, aux_data_hash, hash]     -> true if a block was found
+//
+// Subscribed miners receive a "job" notification for each `set_job` with
+// params {"job_id", "header", "seed", "share_target", "clean"}. `header` is
+// the hex encoded BlockHeader bytes and `seed` the prev_hash the Bip52
+// matrix is generated from. "clean" means earlier jobs are no longer
+// accepted. Miners may roll both the nonce and aux_data_hash.
+#[derive(Clone)]
+pub struct Stratum {
+	inner: Rc<StratumInner>,
+}
+
+impl Stratum {
+	// `key` is the Bip52 network key, shares must meet `share_target`
+	pub fn new(key: [u8; 32], share_target: [u8; 32], on_block: OnBlock) -> Result<Self> {
+		let mut jobs = Vec::new();
+		for _ in 0..STRATUM_MAX_JOBS {
+			jobs.push(None)?;
+		}
+		Ok(Self {
+			inner: Rc::new(StratumInner {
+				key,
+				share_target,
+				bible: Bible::new(),
+				jobs,
+				next_job: 1,
+				miners: Vec::new(),
+				on_block,
+				accepted: 0,
+				lock: lock_box!()?,
+			})?,
+		})
+	}
+
+	// a websocket handler serving miners at `path`
+	pub fn handler(&self, path: &str) -> Result<Handler> {
+		let stratum = self.clone();
+		let on_recv: WsOnRecv = Box::new(
+			move |handle: &mut Handle, bytes: &[u8], _fin: bool, _op: u8| -> Result<()> {
+				match stratum.process(bytes, Some(handle))? {
+					Some(resp) => handle.send_text(resp.to_string()?.as_str()),
+					None => Ok(()),
+				}
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let stratum = self.clone();
+		let on_close: WsOnClose = Box::new(move |handle: &mut Handle| -> Result<()> {
+			stratum.remove_miner(handle.socket())
+		})?;
+		Handler::new(
+			path,
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)
+	}
+
+	// processes a request or a batch from the miner on `handle`
+	pub fn process(&self, bytes: &[u8], handle: Option<&Handle>) -> Result<Option<Json>> {
+		Rpc::process_with(bytes, Self::error_code, |method, params| {
+			self.dispatch(method, params, handle)
+		})
+	}
+
+	// makes `block` the job miners work on and returns its id. The block
+	// should be complete (coinbase added and header finalized). A job with a
+	// new prev_hash replaces all earlier jobs.
+	pub fn set_job(&self, block: Block, target: [u8; 32]) -> Result<u64> {
+		let mut inner = self.inner.clone();
+		let lock = inner.lock.clone();
+		let _l = lock.write();
+		let header = block.header().clone();
+		let prev_hash = header.prev_hash();
+		let mut clean = true;
+		for job in inner.jobs.iter() {
+			if let Some(job) = job {
+				clean = clean && job.header.prev_hash() != prev_hash;
+			}
+		}
+		if clean {
+			for job in inner.jobs.iter_mut() {
+				*job = None;
+			}
+		}
+
+		let id = inner.next_job;
+		inner.next_job += 1;
+		let job = Job {
+			id,
+			header,
+			block,
+			bip52: Bip52::new(inner.key, prev_hash),
+			target,
+			shares: Vec::new(),
+		};
+		let mut params = Self::job_json(&inner, &job)?;
+		params.set("clean", Json::Bool(clean))?;
+		inner.jobs[id as usize % STRATUM_MAX_JOBS] = Some(job);
+
+		let mut msg = Json::object();
+		msg.set("jsonrpc", Json::string("2.0")?)?;
+		msg.set("method", Json::string("job")?)?;
+		msg.set("params", params)?;
+		let msg = msg.to_string()?;
+		let mut miners = Vec::new();
+		for miner in inner.miners.iter_mut() {
+			// miners that can't be reached are dropped
+			if miner.send_text(msg.as_str()).is_ok() {
+				miners.push(miner.clone())?;
+			}
+		}
+		inner.miners = miners;
+		Ok(id)
+	}
+
+	pub fn miners(&self) -> usize {
+		let _l = self.inner.lock.read();
+		self.inner.miners.len()
+	}
+
+	// the number of shares accepted
+	pub fn accepted(&self) -> u64 {
+		let _l = self.inner.lock.read();
+		self.inner.accepted
+	}
+
+	fn remove_miner(&self, socket: Socket) -> Result<()> {
+		let mut inner = self.inner.clone();
+		let lock = inner.lock.clone();
+		let _l = lock.write();
+		let mut miners = Vec::new();
+		for miner in inner.miners.iter() {
+			if miner.socket() != socket {
+				miners.push(miner.clone())?;
+			}
+		}
+		inner.miners = miners;
+		Ok(())
+	}
+
+	// the codes of the share errors, see Rpc::process_with
+	fn error_code(e: &Error) -> Option<(i64, &'static str)> {
+		if *e == StratumStaleJob {
+			Some((STRATUM_STALE_JOB, "Stale job"))
+		} else if *e == StratumInvalidShare {
+			Some((STRATUM_INVALID_SHARE, "Invalid share"))
+		} else if *e == StratumDuplicateShare {
+			Some((STRATUM_DUPLICATE_SHARE, "Duplicate share"))
+		} else {
+			None
+		}
+	}
+
+	fn dispatch(
+		&self,
+		method: &str,
+		params: Option<&Json>,
+		handle: Option<&Handle>,
+	) -> Result<Json> {
+		let mut inner = self.inner.clone();
+		let lock = inner.lock.clone();
+		let _l = lock.write();
+		match method {
+			"subscribe" => {
+				let handle = match handle {
+					Some(handle) => handle.clone(),
+					None => return err!(RpcNoSubscriptions),
+				};
+				if !inner.miners.iter().any(|m| m.socket() == handle.socket()) {
+					inner.miners.push(handle)?;
+				}
+				let id = inner.next_job - 1;
+				match &inner.jobs[id as usize % STRATUM_MAX_JOBS] {
+					Some(job) if job.id == id => Self::job_json(&inner, job),
+					_ => Ok(Json::Null),
+				}
+			}
+			"submit" => {
+				let job_id = match Rpc::param(params, "job_id", 0).and_then(|v| v.as_u64()) {
+					Some(job_id) => job_id,
+					None => return err!(RpcInvalidParams),
+				};
+				let nonce = match Rpc::param(params, "nonce", 1).and_then(|v| v.as_u64()) {
+					Some(nonce) if nonce <= u32::MAX as u64 => nonce as u32,
+					_ => return err!(RpcInvalidParams),
+				};
+				let mut aux_data_hash = [0u8; 32];
+				match Rpc::param(params, "aux_data_hash", 2).and_then(|v| v.as_str()) {
+					Some(aux) => Rpc::unhex_into(aux, &mut aux_data_hash)?,
+					None => return err!(RpcInvalidParams),
+				}
+				let mut hash = [0u8; 32];
+				match Rpc::param(params, "hash", 3).and_then(|v| v.as_str()) {
+					Some(h) => Rpc::unhex_into(h, &mut hash)?,
+					None => return err!(RpcInvalidParams),
+				}
+				Self::submit(&mut inner, job_id, nonce, aux_data_hash, hash).map(Json::Bool)
+			}
+			_ => err!(RpcMethodNotFound),
+		}
+	}
+
+	// checks a share and, if it meets the network target, hands the block to
+	// on_block
+	fn submit(
+		inner: &mut StratumInner,
+		job_id: u64,
+		nonce: u32,
+		aux_data_hash: [u8; 32],
+		hash: [u8; 32],
+	) -> Result<bool> {
+		let job = match &mut inner.jobs[job_id as usize % STRATUM_MAX_JOBS] {
+			Some(job) if job.id == job_id => job,
+			_ => return err!(StratumStaleJob),
+		};
+		let mut header = job.header.clone();
+		header.set_nonce(nonce);
+		header.set_aux_data_hash(aux_data_hash);
+		job.block.set_header(header);
+		if job
+			.block
+			.validate_hash(&job.bip52, inner.share_target, hash, &inner.bible)
+			.is_err()
+		{
+			return err!(StratumInvalidShare);
+		}
+		// checked once the share is known to be valid so an invalid one is
+		// never reported as a duplicate
+		if job.shares.iter().any(|s| *s == (nonce, aux_data_hash)) {
+			return err!(StratumDuplicateShare);
+		}
+		job.shares.push((nonce, aux_data_hash))?;
+		inner.accepted += 1;
+
+		if u256_less_than_or_equal(&job.target, &hash) {
+			(inner.on_block)(&job.block, hash)?;
+			Ok(true)
+		} else {
+			Ok(false)
+		}
+	}
+
+	fn job_json(inner: &StratumInner, job: &Job) -> Result<Json> {
+		let mut ret = Json::object();
+		ret.set("job_id", Rpc::number(job.id)?)?;
+		ret.set("header", Rpc::hex(&job.header.to_bytes())?)?;
+		ret.set("seed", Rpc::hex(&job.header.prev_hash())?)?;
+		ret.set("share_target", Rpc::hex(&inner.share_target)?)?;
+		Ok(ret)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::size_of;
+	use crypto::ctx::Ctx;
+	use mw::keychain::KeyChain;
+	use net::ws::{Connector, Listener, Ws};
+
+	const SHARE_TARGET: [u8; 32] = [
+		0x0F, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+		255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+	];
+	const NETWORK_TARGET: [u8; 32] = [
+		0x00, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+		255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255,
+	];
+
+	fn block(ctx: &Ctx, prev_hash: [u8; 32]) -> Result<Block> {
+		let block = Block::new(prev_hash, [3u8; 32], [9u8; 4], [81u8; 4]);
+		let miner_keychain = KeyChain::from_seed([5u8; 48])?;
+		let coinbase_blind = miner_keychain.derive_key(ctx, &[0, 0]);
+		let mut complete = block.with_coinbase(ctx, &coinbase_blind, 1000)?;
+		complete.finalize_header()?;
+		Ok(complete)
+	}
+
+	fn free_port() -> Result<u16> {
+		let (port, mut s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		s.close()?;
+		Ok(port)
+	}
+
+	fn submit(id: u64, job_id: u64, nonce: u32, aux: &[u8; 32], hash: &[u8; 32]) -> Result<String> {
+		let mut params = Json::array();
+		params.push(Rpc::number(job_id)?)?;
+		params.push(Rpc::number(nonce as u64)?)?;
+		params.push(Rpc::hex(aux)?)?;
+		params.push(Rpc::hex(hash)?)?;
+		let mut req = Json::object();
+		req.set("jsonrpc", Json::string("2.0")?)?;
+		req.set("method", Json::string("submit")?)?;
+		req.set("params", params)?;
+		req.set("id", Rpc::number(id)?)?;
+		req.to_string()
+	}
+
+	fn result(id: u64, block: bool) -> Result<String> {
+		let mut resp = Json::object();
+		resp.set("jsonrpc", Json::string("2.0")?)?;
+		resp.set("result", Json::Bool(block))?;
+		resp.set("id", Rpc::number(id)?)?;
+		resp.to_string()
+	}
+
+	fn error(id: u64, code: i64, message: &str) -> Result<String> {
+		Rpc::error(Rpc::number(id)?, code, message)?.to_string()
+	}
+
+	#[test]
+	fn test_stratum_loopback() -> Result<()> {
+		let ctx = Ctx::new()?;
+		let bible = Bible::new();
+		let key = [1u8; 32];
+
+		// collect the blocks found
+		let lock = lock_box!()?;
+		let found: Rc<Vec<[u8; 32]>> = Rc::new(Vec::new())?;
+		let (lock1, mut found1) = (lock.clone(), found.clone());
+		let on_block: OnBlock = Box::new(move |block: &Block, hash: [u8; 32]| -> Result<()> {
+			assert_eq!(block.tx().kernels().len(), 1);
+			let _l = lock1.write();
+			found1.push(hash)
+		})?;
+		let stratum = Stratum::new(key, SHARE_TARGET, on_block)?;
+
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		server.add_handler(stratum.handler("/v1/mining")?)?;
+		server.start()?;
+
+		let msgs: Rc<Vec<String>> = Rc::new(Vec::new())?;
+		let (lock1, mut msgs1) = (lock.clone(), msgs.clone());
+		let on_recv: WsOnRecv = Box::new(
+			move |_handle: &mut Handle, bytes: &[u8], _fin: bool, _op: u8| -> Result<()> {
+				let _l = lock1.write();
+				msgs1.push(String::newb(bytes)?)
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let handler = Handler::new(
+			"/v1/mining",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?;
+		let mut client = Ws::new()?;
+		client.start()?;
+		let mut handle = client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		let wait = |n: usize| loop {
+			sleep(1);
+			let _l = lock.read();
+			if msgs.len() == n {
+				break;
+			}
+		};
+
+		// no job yet
+		handle.send_text("{\"jsonrpc\":\"2.0\",\"method\":\"subscribe\",\"id\":1}")?;
+		wait(1);
+		assert_eq!(
+			msgs[0].as_str(),
+			"{\"jsonrpc\":\"2.0\",\"result\":null,\"id\":1}"
+		);
+		assert_eq!(stratum.miners(), 1);
+
+		let job_id = stratum.set_job(block(&ctx, [7u8; 32])?, NETWORK_TARGET)?;
+		wait(2);
+		let job = Json::parse(msgs[1].as_str().as_bytes())?;
+		assert_eq!(job.get("method").and_then(|v| v.as_str()), Some("job"));
+		let params = job.get("params").unwrap();
+		assert_eq!(params.get("job_id").and_then(|v| v.as_u64()), Some(job_id));
+		assert_eq!(params.get("clean").and_then(|v| v.as_bool()), Some(true));
+
+		// the miner rebuilds the header and Bip52 matrix from the job
+		let mut header_bytes = [0u8; size_of::<BlockHeader>()];
+		Rpc::unhex_into(
+			params.get("header").and_then(|v| v.as_str()).unwrap(),
+			&mut header_bytes,
+		)?;
+		let mut header = BlockHeader::from_bytes(&header_bytes)?;
+		let mut seed = [0u8; 32];
+		Rpc::unhex_into(
+			params.get("seed").and_then(|v| v.as_str()).unwrap(),
+			&mut seed,
+		)?;
+		assert_eq!(seed, [7u8; 32]);
+		let mut share_target = [0u8; 32];
+		Rpc::unhex_into(
+			params.get("share_target").and_then(|v| v.as_str()).unwrap(),
+			&mut share_target,
+		)?;
+		assert_eq!(share_target, SHARE_TARGET);
+		let bip52 = Bip52::new(key, seed);
+		let aux = header.aux_data_hash();
+
+		// submit shares until one is a block, each gets a response
+		let mut n = 2;
+		let mut id = 2;
+		let mut shares = 0;
+		let mut first = None;
+		let mut nonce = 0u32;
+		loop {
+			header.set_nonce(nonce);
+			let hash = header.hash(&bip52, &bible);
+			if u256_less_than_or_equal(&share_target, &hash) {
+				handle.send_text(submit(id, job_id, nonce, &aux, &hash)?.as_str())?;
+				n += 1;
+				wait(n);
+				let block = u256_less_than_or_equal(&NETWORK_TARGET, &hash);
+				assert_eq!(msgs[n - 1], result(id, block)?);
+				id += 1;
+				shares += 1;
+				if first.is_none() {
+					first = Some((nonce, hash));
+				}
+				if block {
+					let _l = lock.read();
+					assert_eq!(found.len(), 1);
+					assert_eq!(found[0], hash);
+					break;
+				}
+			}
+			nonce += 1;
+		}
+		assert_eq!(stratum.accepted(), shares);
+
+		// duplicate and invalid shares are rejected
+		let (nonce, hash) = first.unwrap();
+		handle.send_text(submit(id, job_id, nonce, &aux, &hash)?.as_str())?;
+		n += 1;
+		wait(n);
+		assert_eq!(
+			msgs[n - 1],
+			error(id, STRATUM_DUPLICATE_SHARE, "Duplicate share")?
+		);
+		id += 1;
+		handle.send_text(submit(id, job_id, nonce + 1, &aux, &hash)?.as_str())?;
+		n += 1;
+		wait(n);
+		assert_eq!(
+			msgs[n - 1],
+			error(id, STRATUM_INVALID_SHARE, "Invalid share")?
+		);
+		id += 1;
+
+		// a new tip makes the old job stale
+		let job_id2 = stratum.set_job(block(&ctx, [8u8; 32])?, NETWORK_TARGET)?;
+		n += 1;
+		wait(n);
+		let job = Json::parse(msgs[n - 1].as_str().as_bytes())?;
+		let params = job.get("params").unwrap();
+		assert_eq!(params.get("job_id").and_then(|v| v.as_u64()), Some(job_id2));
+		assert_eq!(params.get("clean").and_then(|v| v.as_bool()), Some(true));
+		handle.send_text(submit(id, job_id, nonce, &aux, &hash)?.as_str())?;
+		n += 1;
+		wait(n);
+		assert_eq!(msgs[n - 1], error(id, STRATUM_STALE_JOB, "Stale job")?);
+		assert_eq!(stratum.accepted(), shares);
+
+		handle.close()?;
+		while stratum.miners() != 0 {
+			sleep(1);
+		}
+		client.stop()?;
+		server.stop()?;
+		Ok(())
+	}
+}
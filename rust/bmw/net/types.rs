@@ -0,0 +1,4 @@
+#[repr(C)]
+pub struct TlsContext(usize);
+#[repr(C)]
+pub struct TlsSession(usize);
@@ -25,3 +25,12 @@ pub fn websocket_accept_key(sec_key: &str) -> Result<String> {
 		String::new(from_utf8_unchecked(&accept_key))
 	}
 }
+
+// the Sec-WebSocket-Key a client sends: 16 random bytes, base64 encoded
+pub fn websocket_key(nonce: &[u8; 16]) -> Result<String> {
+	let mut key: [u8; 24] = [0; 24];
+	unsafe {
+		Base64encode(key.as_mut_ptr(), nonce.as_ptr(), 16);
+		String::new(from_utf8_unchecked(&key))
+	}
+}
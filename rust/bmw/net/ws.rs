@@ -1,13 +1,21 @@
 #![allow(dead_code)]
+use core::mem::swap;
 use core::ops::FnMut;
 use core::ptr::copy;
-use misc::{from_utf8, to_be_bytes_u16, to_be_bytes_u64};
+use crypto::cpsrng::Cpsrng;
+use misc::{is_utf8_valid, to_be_bytes_u16, to_be_bytes_u64};
+use net::constants::*;
 use net::errors::EAgain;
 use net::evh::*;
+use net::http::{HttpMethod, HttpRequest, HttpResponse, HttpRoute};
 use net::socket::Socket;
-use net::util::websocket_accept_key;
+use net::tls::{TlsClientConfig, TlsServerConfig};
+use net::util::{websocket_accept_key, websocket_key};
 use prelude::*;
+use util::deflate::{Deflater, Inflater};
+use util::lock::LockBox;
 
+#[derive(Clone)]
 pub struct Handle {
 	conn: Connection<WsContext, WsConnection>,
 }
@@ -25,12 +33,60 @@ enum WsState {
 }
 
 #[derive(Clone)]
-pub struct WsContext {}
+pub struct WsContext {
+	max_message_size: usize,
+	max_body_size: usize,
+	close_timeout: u64,
+	deflate: Option<DeflateConfig>,
+	routes: Rc<Vec<HttpRoute>>,
+}
+
+// permessage-deflate (RFC 7692) settings. The same config is used for
+// servers (accepting offers) and clients (making them).
+#[derive(Clone)]
+pub struct DeflateConfig {
+	no_context_takeover: bool,
+	peer_no_context_takeover: bool,
+	max_window_bits: u8,
+}
+
+// the extension parameters of an offer or a response
+struct DeflateParams {
+	server_no_context_takeover: bool,
+	client_no_context_takeover: bool,
+	server_max_window_bits: Option<u8>,
+	client_max_window_bits: Option<u8>,
+	// an offer may include client_max_window_bits without a value
+	client_max_window_bits_offered: bool,
+}
+
+// per connection compression state once the extension is negotiated
+struct WsDeflate {
+	deflater: Deflater,
+	inflater: Inflater,
+	// without context takeover the window is reset after each message
+	reset_deflater: bool,
+	reset_inflater: bool,
+	// serializes compression and writes so the peer sees our stream in order
+	lock: LockBox,
+}
 
 struct WsConnectionInner {
 	rbuf: Vec<u8>,
 	is_upgraded: bool,
 	handler: Option<Handler>,
+	// client connections: the Sec-WebSocket-Accept we expect and the source
+	// of our masking keys
+	accept: Option<String>,
+	rng: Option<Cpsrng>,
+	// the payload and opcode of a fragmented message being reassembled
+	fragments: Vec<u8>,
+	fragment_op: u8,
+	fragment_compressed: bool,
+	close_sent: bool,
+	// millis to wait for the close echo before dropping the connection
+	close_timeout: u64,
+	deflate: Option<WsDeflate>,
 }
 
 #[derive(Clone)]
@@ -39,31 +95,126 @@ struct WsConnection {
 }
 
 impl Handle {
+	// identifies the connection, e.g. to find it again in on_close
+	pub fn socket(&self) -> Socket {
+		self.conn.socket()
+	}
+
 	pub fn send(&mut self, bytes: &[u8]) -> Result<()> {
-		let len = bytes.len();
-		if len <= 125 {
-			Ws::write_fully(&mut self.conn, &[0x82, bytes.len() as u8])?;
-		} else if len <= 65535 {
-			let mut buf = [0x82, 126, 0, 0];
-			to_be_bytes_u16(len as u16, &mut buf[2..]);
-			Ws::write_fully(&mut self.conn, &buf)?;
-		} else {
-			let mut buf = [0x82, 127, 0, 0, 0, 0, 0, 0, 0, 0];
-			to_be_bytes_u64(len as u64, &mut buf[2..]);
-			Ws::write_fully(&mut self.conn, &buf)?;
+		self.send_frame(0x80 | WS_OP_BINARY, bytes)
+	}
+
+	pub fn send_text(&mut self, text: &str) -> Result<()> {
+		self.send_frame(0x80 | WS_OP_TEXT, text.as_bytes())
+	}
+
+	pub fn ping(&mut self, bytes: &[u8]) -> Result<()> {
+		if bytes.len() > WS_MAX_CONTROL_PAYLOAD {
+			return err!(IllegalArgument);
 		}
-		Ws::write_fully(&mut self.conn, bytes)?;
+		self.send_frame(0x80 | WS_OP_PING, bytes)
+	}
 
-		Ok(())
+	// starts the close handshake. The connection is closed when the peer
+	// echoes the close frame, or after the close timeout if it doesn't (see
+	// Ws::set_close_timeout).
+	pub fn close(&mut self) -> Result<()> {
+		self.close_with(WS_CLOSE_NORMAL, "")
+	}
+
+	pub fn close_with(&mut self, code: u16, reason: &str) -> Result<()> {
+		if reason.len() + 2 > WS_MAX_CONTROL_PAYLOAD {
+			return err!(IllegalArgument);
+		}
+		let close_timeout = match self.conn.attach()? {
+			Some(att) => {
+				if att.inner.close_sent {
+					return Ok(());
+				}
+				att.inner.close_sent = true;
+				att.inner.close_timeout
+			}
+			None => return self.conn.close(),
+		};
+		let mut payload = Vec::with_capacity(reason.len() + 2)?;
+		let mut buf = [0u8; 2];
+		to_be_bytes_u16(code, &mut buf);
+		payload.extend_from_slice(&buf)?;
+		payload.extend_from_slice(reason.as_bytes())?;
+		self.send_frame(0x80 | WS_OP_CLOSE, &payload)?;
+
+		// drop the connection if the peer never echoes
+		self.conn.close_after(close_timeout)
+	}
+
+	fn send_frame(&mut self, first: u8, bytes: &[u8]) -> Result<()> {
+		let mut att = match self.conn.attach()? {
+			Some(att) => att.clone(),
+			None => {
+				let frame = Ws::frame(first, bytes, None)?;
+				return Ws::write_fully(&mut self.conn, &frame);
+			}
+		};
+		// frames sent by a client must be masked (RFC 6455 5.3)
+		let mask = Ws::mask(&att);
+		let op = first & 0x0F;
+		match &mut att.inner.deflate {
+			Some(deflate) if op == WS_OP_TEXT || op == WS_OP_BINARY => {
+				let lock = deflate.lock.clone();
+				let _l = lock.write();
+				let mut compressed = Vec::new();
+				deflate.deflater.compress(bytes, &mut compressed)?;
+				if deflate.reset_deflater {
+					deflate.deflater.reset();
+				}
+				// the trailing 0x00 0x00 0xff 0xff is implied (RFC 7692 7.2.1)
+				let len = compressed.len() - 4;
+				let frame = Ws::frame(first | 0x40, &compressed[0..len], mask)?;
+				Ws::write_fully(&mut self.conn, &frame)
+			}
+			_ => {
+				let frame = Ws::frame(first, bytes, mask)?;
+				Ws::write_fully(&mut self.conn, &frame)
+			}
+		}
 	}
 }
 
 impl WsConnection {
-	fn new() -> Result<Self> {
+	fn new(close_timeout: u64) -> Result<Self> {
 		let inner = Rc::new(WsConnectionInner {
 			rbuf: Vec::new(),
 			is_upgraded: false,
 			handler: None,
+			accept: None,
+			rng: None,
+			fragments: Vec::new(),
+			fragment_op: WS_OP_CONTINUATION,
+			fragment_compressed: false,
+			close_sent: false,
+			close_timeout,
+			deflate: None,
+		})?;
+		Ok(Self { inner })
+	}
+
+	fn is_compressed(&self) -> bool {
+		self.inner.deflate.is_some()
+	}
+
+	fn client(handler: Handler, accept: String, rng: Cpsrng, close_timeout: u64) -> Result<Self> {
+		let inner = Rc::new(WsConnectionInner {
+			rbuf: Vec::new(),
+			is_upgraded: false,
+			handler: Some(handler),
+			accept: Some(accept),
+			rng: Some(rng),
+			fragments: Vec::new(),
+			fragment_op: WS_OP_CONTINUATION,
+			fragment_compressed: false,
+			close_sent: false,
+			close_timeout,
+			deflate: None,
 		})?;
 		Ok(Self { inner })
 	}
@@ -72,6 +223,11 @@ impl WsConnection {
 pub struct Ws {
 	evh: Evh<WsContext, WsConnection>,
 	state: WsState,
+	max_message_size: usize,
+	max_body_size: usize,
+	close_timeout: u64,
+	deflate: Option<DeflateConfig>,
+	routes: Rc<Vec<HttpRoute>>,
 	handlers: Rc<RbTree<Handler>>,
 	sockets: Vec<Socket>,
 	acceptors: Vec<Connection<WsContext, WsConnection>>,
@@ -81,6 +237,14 @@ pub struct Listener {
 	addr: [u8; 4],
 	port: u16,
 	backlog: i32,
+	tls: Option<TlsServerConfig>,
+}
+
+pub struct Connector {
+	addr: [u8; 4],
+	port: u16,
+	host: Option<String>,
+	tls: Option<TlsClientConfig>,
 }
 
 struct HandlerProc {
@@ -120,7 +284,9 @@ impl PartialEq for Handler {
 impl Eq for Handler {}
 
 impl Handler {
-	fn new(
+	// on a server, `path` is the uri this handler serves. On a client it is
+	// the uri requested and on_accept is called once the upgrade completes.
+	pub fn new(
 		path: &str,
 		on_recv: Rc<WsOnRecv>,
 		on_accept: Rc<WsOnAccept>,
@@ -147,6 +313,237 @@ impl Handler {
 	}
 }
 
+impl Listener {
+	pub fn new(addr: [u8; 4], port: u16, backlog: i32) -> Self {
+		Self {
+			addr,
+			port,
+			backlog,
+			tls: None,
+		}
+	}
+
+	// serves wss:// on this listener
+	pub fn new_tls(addr: [u8; 4], port: u16, backlog: i32, tls: TlsServerConfig) -> Self {
+		Self {
+			addr,
+			port,
+			backlog,
+			tls: Some(tls),
+		}
+	}
+}
+
+impl Connector {
+	pub fn new(addr: [u8; 4], port: u16) -> Self {
+		Self {
+			addr,
+			port,
+			host: None,
+			tls: None,
+		}
+	}
+
+	// connects to wss://. `host` is sent in the Host header and verified
+	// against the server's certificate.
+	pub fn new_tls(addr: [u8; 4], port: u16, host: &str, tls: TlsClientConfig) -> Result<Self> {
+		Ok(Self {
+			addr,
+			port,
+			host: Some(String::new(host)?),
+			tls: Some(tls),
+		})
+	}
+}
+
+impl DeflateConfig {
+	// `no_context_takeover` compresses each message we send on its own,
+	// `peer_no_context_takeover` asks the peer to do the same and
+	// `max_window_bits` (8 to 15) limits the window our compressor uses.
+	pub fn new(
+		no_context_takeover: bool,
+		peer_no_context_takeover: bool,
+		max_window_bits: u8,
+	) -> Result<Self> {
+		if max_window_bits < 8 || max_window_bits > 15 {
+			return err!(IllegalArgument);
+		}
+		Ok(Self {
+			no_context_takeover,
+			peer_no_context_takeover,
+			max_window_bits,
+		})
+	}
+
+	// the client's offer
+	fn offer(&self) -> Result<String> {
+		let mut params = DeflateParams::new();
+		params.client_no_context_takeover = self.no_context_takeover;
+		params.server_no_context_takeover = self.peer_no_context_takeover;
+		params.client_max_window_bits_offered = true;
+		if self.max_window_bits < 15 {
+			params.client_max_window_bits = Some(self.max_window_bits);
+		}
+		params.header()
+	}
+
+	// the server's response to an acceptable offer
+	fn accept(&self, offer: &DeflateParams) -> Result<(WsDeflate, String)> {
+		let mut params = DeflateParams::new();
+		params.server_no_context_takeover =
+			offer.server_no_context_takeover || self.no_context_takeover;
+		params.client_no_context_takeover =
+			offer.client_no_context_takeover || self.peer_no_context_takeover;
+		let mut window_bits = self.max_window_bits;
+		match offer.server_max_window_bits {
+			Some(bits) if bits < window_bits => window_bits = bits,
+			_ => {}
+		}
+		if window_bits < 15 || offer.server_max_window_bits.is_some() {
+			params.server_max_window_bits = Some(window_bits);
+		}
+		let deflate = WsDeflate::new(
+			window_bits,
+			params.server_no_context_takeover,
+			params.client_no_context_takeover,
+		)?;
+		Ok((deflate, params.header()?))
+	}
+
+	// the client's view of the server's response
+	fn accepted(&self, response: &DeflateParams) -> Result<WsDeflate> {
+		let mut window_bits = self.max_window_bits;
+		match response.client_max_window_bits {
+			Some(bits) if bits < window_bits => window_bits = bits,
+			_ => {}
+		}
+		WsDeflate::new(
+			window_bits,
+			response.client_no_context_takeover || self.no_context_takeover,
+			response.server_no_context_takeover,
+		)
+	}
+}
+
+impl DeflateParams {
+	fn new() -> Self {
+		Self {
+			server_no_context_takeover: false,
+			client_no_context_takeover: false,
+			server_max_window_bits: None,
+			client_max_window_bits: None,
+			client_max_window_bits_offered: false,
+		}
+	}
+
+	// parses one element of a Sec-WebSocket-Extensions list. Returns None
+	// for other extensions and for offers or responses with invalid,
+	// unknown or repeated parameters.
+	fn parse(element: &[u8]) -> Option<Self> {
+		let mut parts = element.split(|b| *b == b';');
+		match parts.next() {
+			Some(name) if Ws::trim(name) == b"permessage-deflate" => {}
+			_ => return None,
+		}
+		let mut ret = Self::new();
+		for part in parts {
+			let part = Ws::trim(part);
+			let (name, value) = match part.iter().position(|b| *b == b'=') {
+				Some(i) => (Ws::trim(&part[0..i]), Some(Ws::trim(&part[i + 1..]))),
+				None => (part, None),
+			};
+			match name {
+				b"server_no_context_takeover" => {
+					if value.is_some() || ret.server_no_context_takeover {
+						return None;
+					}
+					ret.server_no_context_takeover = true;
+				}
+				b"client_no_context_takeover" => {
+					if value.is_some() || ret.client_no_context_takeover {
+						return None;
+					}
+					ret.client_no_context_takeover = true;
+				}
+				b"server_max_window_bits" => {
+					if ret.server_max_window_bits.is_some() {
+						return None;
+					}
+					ret.server_max_window_bits = Some(Self::window_bits(value?)?);
+				}
+				b"client_max_window_bits" => {
+					if ret.client_max_window_bits_offered {
+						return None;
+					}
+					ret.client_max_window_bits_offered = true;
+					ret.client_max_window_bits = match value {
+						Some(value) => Some(Self::window_bits(value)?),
+						None => None,
+					};
+				}
+				_ => return None,
+			}
+		}
+		Some(ret)
+	}
+
+	fn window_bits(value: &[u8]) -> Option<u8> {
+		// the value may be a quoted string
+		let value = if value.len() >= 2 && value[0] == b'"' && value[value.len() - 1] == b'"' {
+			&value[1..value.len() - 1]
+		} else {
+			value
+		};
+		match value {
+			[d @ b'8'..=b'9'] => Some(d - b'0'),
+			[b'1', d @ b'0'..=b'5'] => Some(10 + d - b'0'),
+			_ => None,
+		}
+	}
+
+	fn header(&self) -> Result<String> {
+		let mut header = Vec::new();
+		header.extend_from_slice(b"permessage-deflate")?;
+		if self.server_no_context_takeover {
+			header.extend_from_slice(b"; server_no_context_takeover")?;
+		}
+		if self.client_no_context_takeover {
+			header.extend_from_slice(b"; client_no_context_takeover")?;
+		}
+		match self.server_max_window_bits {
+			Some(bits) => {
+				let param = format!("; server_max_window_bits={}", bits)?;
+				header.extend_from_slice(param.as_bytes())?;
+			}
+			None => {}
+		}
+		match self.client_max_window_bits {
+			Some(bits) => {
+				let param = format!("; client_max_window_bits={}", bits)?;
+				header.extend_from_slice(param.as_bytes())?;
+			}
+			None => {
+				if self.client_max_window_bits_offered {
+					header.extend_from_slice(b"; client_max_window_bits")?;
+				}
+			}
+		}
+		String::newb(&header)
+	}
+}
+
+impl WsDeflate {
+	fn new(window_bits: u8, reset_deflater: bool, reset_inflater: bool) -> Result<Self> {
+		Ok(Self {
+			deflater: Deflater::new(window_bits)?,
+			inflater: Inflater::new(),
+			reset_deflater,
+			reset_inflater,
+			lock: lock_box!()?,
+		})
+	}
+}
+
 impl Drop for Ws {
 	fn drop(&mut self) {
 		let binding = self.handlers.clone();
@@ -185,6 +582,11 @@ impl Ws {
 		Ok(Self {
 			evh,
 			state,
+			max_message_size: WS_DEFAULT_MAX_MESSAGE_SIZE,
+			max_body_size: HTTP_DEFAULT_MAX_BODY_SIZE,
+			close_timeout: WS_DEFAULT_CLOSE_TIMEOUT_MILLIS,
+			deflate: None,
+			routes: Rc::new(Vec::new())?,
 			handlers,
 			acceptors: Vec::new(),
 			sockets: Vec::new(),
@@ -197,10 +599,106 @@ impl Ws {
 			_ => return err!(IllegalState),
 		}
 
-		let ctx = WsContext {};
+		let ctx = WsContext {
+			max_message_size: self.max_message_size,
+			max_body_size: self.max_body_size,
+			close_timeout: self.close_timeout,
+			deflate: self.deflate.clone(),
+			routes: self.routes.clone(),
+		};
 		let socket = Socket::listen(listener.addr, listener.port, listener.backlog)?;
-		let handlers = self.handlers.clone();
+		let (on_recv, on_accept, on_close) = Self::closures(self.handlers.clone())?;
+
+		let conn = match listener.tls {
+			Some(tls) => Connection::acceptor_tls(socket, tls, on_recv, on_accept, on_close, ctx)?,
+			None => Connection::acceptor(socket, on_recv, on_accept, on_close, ctx)?,
+		};
+		self.evh.register(conn.clone())?;
 
+		self.sockets.push(socket)?;
+		self.acceptors.push(conn)?;
+
+		Ok(())
+	}
+
+	// opens a client connection and sends the upgrade request for
+	// `handler`'s path. The returned handle may be used to send once
+	// the handler's on_accept has been called.
+	pub fn connect(&mut self, connector: Connector, handler: Handler) -> Result<Handle> {
+		match &self.state {
+			WsState::Init | WsState::Started => {}
+			_ => return err!(IllegalState),
+		}
+
+		let rng = Cpsrng::new()?;
+		let mut nonce = [0u8; 16];
+		rng.gen(&mut nonce);
+		let key = websocket_key(&nonce)?;
+		let accept = websocket_accept_key(key.as_str())?;
+		let host = match connector.host {
+			Some(host) => host,
+			None => {
+				let a = connector.addr;
+				format!("{}.{}.{}.{}:{}", a[0], a[1], a[2], a[3], connector.port)?
+			}
+		};
+		let extensions = match &self.deflate {
+			Some(deflate) => format!("Sec-WebSocket-Extensions: {}\r\n", deflate.offer()?)?,
+			None => String::empty(),
+		};
+		let request = format!(
+			"GET {} HTTP/1.1\r\n\
+Host: {}\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Key: {}\r\n\
+Sec-WebSocket-Version: 13\r\n{}\r\n",
+			handler.inner.path, host, key, extensions
+		)?;
+
+		let ctx = WsContext {
+			max_message_size: self.max_message_size,
+			max_body_size: self.max_body_size,
+			close_timeout: self.close_timeout,
+			deflate: self.deflate.clone(),
+			routes: self.routes.clone(),
+		};
+		let mut socket = Socket::connect(connector.addr, connector.port)?;
+		let (on_recv, _, on_close) = Self::closures(self.handlers.clone())?;
+		let conn = match &connector.tls {
+			Some(tls) => {
+				Connection::outbound_tls(socket, tls, host.as_str(), on_recv, on_close, ctx)
+			}
+			None => Connection::outbound(socket, on_recv, on_close, ctx),
+		};
+		let mut conn = match conn {
+			Ok(conn) => conn,
+			Err(e) => {
+				let _ = socket.close();
+				return Err(e);
+			}
+		};
+		let att = WsConnection::client(handler, accept, rng, self.close_timeout)?;
+		conn.set_attach(att)?;
+		match self.evh.register(conn.clone()) {
+			Ok(_) => {}
+			Err(e) => {
+				let _ = socket.close();
+				return Err(e);
+			}
+		}
+
+		Self::write_fully(&mut conn, request.as_bytes())?;
+		Ok(Handle { conn })
+	}
+
+	fn closures(
+		handlers: Rc<RbTree<Handler>>,
+	) -> Result<(
+		Rc<OnRecv<WsContext, WsConnection>>,
+		Rc<OnAccept<WsContext, WsConnection>>,
+		Rc<OnClose<WsContext, WsConnection>>,
+	)> {
 		let on_recv: OnRecv<WsContext, WsConnection> = Box::new(
 			move |ctx: &mut WsContext,
 			      conn: &mut Connection<WsContext, WsConnection>,
@@ -225,17 +723,40 @@ impl Ws {
 			      -> Result<()> { Self::proc_on_close(ctx, conn) },
 		)?;
 
-		let on_recv = Rc::new(on_recv)?;
-		let on_accept = Rc::new(on_accept)?;
-		let on_close = Rc::new(on_close)?;
-
-		let conn = Connection::acceptor(socket, on_recv, on_accept, on_close, ctx)?;
-		self.evh.register(conn.clone())?;
-
-		self.sockets.push(socket)?;
-		self.acceptors.push(conn)?;
+		Ok((Rc::new(on_recv)?, Rc::new(on_accept)?, Rc::new(on_close)?))
+	}
 
-		Ok(())
+	// builds a frame with the given first byte (fin, rsv and opcode)
+	fn frame(first: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Result<Vec<u8>> {
+		let len = payload.len();
+		let mask_bit = match mask {
+			Some(_) => 0x80,
+			None => 0x00,
+		};
+		let mut frame = Vec::with_capacity(len + 14)?;
+		if len <= 125 {
+			frame.extend_from_slice(&[first, mask_bit | len as u8])?;
+		} else if len <= 65535 {
+			let mut buf = [first, mask_bit | 126, 0, 0];
+			to_be_bytes_u16(len as u16, &mut buf[2..]);
+			frame.extend_from_slice(&buf)?;
+		} else {
+			let mut buf = [first, mask_bit | 127, 0, 0, 0, 0, 0, 0, 0, 0];
+			to_be_bytes_u64(len as u64, &mut buf[2..]);
+			frame.extend_from_slice(&buf)?;
+		}
+		match mask {
+			Some(mask) => {
+				frame.extend_from_slice(&mask)?;
+				let offset = frame.len();
+				frame.extend_from_slice(payload)?;
+				for i in 0..len {
+					frame[offset + i] ^= mask[i % 4];
+				}
+			}
+			None => frame.extend_from_slice(payload)?,
+		}
+		Ok(frame)
 	}
 
 	pub fn start(&mut self) -> Result<()> {
@@ -254,6 +775,60 @@ impl Ws {
 		}
 	}
 
+	// the largest message (after reassembly) accepted from a peer on
+	// listeners and connections added after this call. Larger messages
+	// close the connection with status 1009.
+	pub fn set_max_message_size(&mut self, max_message_size: usize) -> Result<()> {
+		match &self.state {
+			WsState::Init => {}
+			_ => return err!(IllegalState),
+		}
+		self.max_message_size = max_message_size;
+		Ok(())
+	}
+
+	// how long close waits for the peer to echo the close frame before
+	// dropping the connection, in milliseconds
+	pub fn set_close_timeout(&mut self, millis: u64) -> Result<()> {
+		match &self.state {
+			WsState::Init => {}
+			_ => return err!(IllegalState),
+		}
+		self.close_timeout = millis;
+		Ok(())
+	}
+
+	// enables permessage-deflate on listeners (accepting offers) and
+	// connections (offering it) added after this call
+	pub fn set_deflate(&mut self, deflate: DeflateConfig) -> Result<()> {
+		match &self.state {
+			WsState::Init => {}
+			_ => return err!(IllegalState),
+		}
+		self.deflate = Some(deflate);
+		Ok(())
+	}
+
+	// the largest http request body accepted. Larger requests get a 413.
+	pub fn set_max_body_size(&mut self, max_body_size: usize) -> Result<()> {
+		match &self.state {
+			WsState::Init => {}
+			_ => return err!(IllegalState),
+		}
+		self.max_body_size = max_body_size;
+		Ok(())
+	}
+
+	// serves plain http requests on the listeners alongside websocket
+	// upgrades. Requests that aren't upgrades are routed by method and path.
+	pub fn add_route(&mut self, route: HttpRoute) -> Result<()> {
+		match &self.state {
+			WsState::Init => {}
+			_ => return err!(IllegalState),
+		}
+		HttpRoute::add(&mut self.routes, route)
+	}
+
 	pub fn add_handler(&mut self, handler: Handler) -> Result<()> {
 		match &self.state {
 			WsState::Init => {}
@@ -270,23 +845,25 @@ impl Ws {
 		conn: Connection<WsContext, WsConnection>,
 		bytes: &[u8],
 		handler: Option<Handler>,
+		att: WsConnection,
 	) -> Result<usize> {
 		let len = bytes.len();
-		let fin;
-		let op;
-		let mask;
 		if len < 2 {
 			return Ok(0);
-		} else {
-			fin = bytes[0] & 0x80 != 0;
-
-			if bytes[0] & 0x70 != 0 {
-				conn.close()?;
-				return Ok(0);
-			}
-
-			op = bytes[0] & !0x80;
-			mask = bytes[1] & 0x80 != 0;
+		}
+		let fin = bytes[0] & 0x80 != 0;
+		let op = bytes[0] & 0x0F;
+		let mask = bytes[1] & 0x80 != 0;
+
+		// rsv1 marks the first frame of a compressed message if
+		// permessage-deflate was negotiated, other rsv bits must be clear.
+		// Clients mask every frame and servers never do.
+		let rsv = bytes[0] & 0x70;
+		let compressed =
+			rsv == 0x40 && (op == WS_OP_TEXT || op == WS_OP_BINARY) && att.is_compressed();
+		if (rsv != 0 && !compressed) || mask == att.inner.accept.is_some() {
+			Self::fail(conn, att, WS_CLOSE_PROTOCOL_ERROR)?;
+			return Ok(len);
 		}
 
 		// determine variable payload len
@@ -315,61 +892,224 @@ impl Ws {
 			(payload_len as usize, 2)
 		};
 
+		// control frames can't be fragmented and have small payloads
+		if op & 0x8 != 0 && (!fin || payload_len > WS_MAX_CONTROL_PAYLOAD) {
+			Self::fail(conn, att, WS_CLOSE_PROTOCOL_ERROR)?;
+			return Ok(len);
+		}
+		// don't wait to buffer a frame we will never accept
+		if payload_len > ctx.max_message_size {
+			Self::fail(conn, att, WS_CLOSE_TOO_BIG)?;
+			return Ok(len);
+		}
+
+		// if masking set we add 4 bytes for the masking key
+		if mask {
+			offset += 4;
+		}
 		if offset + payload_len > len {
 			return Ok(0);
 		}
 
 		let mut payload_vec: Vec<u8> = Vec::new();
-
-		// if masking set we add 4 bytes for the masking key
-
 		let payload_bytes = if mask {
-			offset += 4;
-			let masking_key = if offset - 1 < bytes.len() {
-				[
-					bytes[offset - 4],
-					bytes[offset - 3],
-					bytes[offset - 2],
-					bytes[offset - 1],
-				]
-			} else {
-				[0, 0, 0, 0]
-			};
-
+			let masking_key = [
+				bytes[offset - 4],
+				bytes[offset - 3],
+				bytes[offset - 2],
+				bytes[offset - 1],
+			];
 			payload_vec.resize(payload_len)?;
 			for i in 0..payload_len {
-				if i % 4 < masking_key.len() && offset + i < bytes.len() {
-					payload_vec[i] = bytes[offset + i] ^ masking_key[i % 4];
-				}
+				payload_vec[i] = bytes[offset + i] ^ masking_key[i % 4];
 			}
 			&payload_vec[..]
-		} else if offset < offset + payload_len && offset + payload_len < bytes.len() {
-			&bytes[offset..(offset + payload_len)]
 		} else {
-			&[]
+			&bytes[offset..(offset + payload_len)]
 		};
 
-		Self::proc_payload(ctx, conn, payload_bytes, op, fin, handler)?;
+		Self::proc_frame(ctx, conn, payload_bytes, op, fin, compressed, handler, att)?;
+		if op == WS_OP_CLOSE {
+			// nothing may follow a close frame
+			return Ok(len);
+		}
 		Ok(offset + payload_len)
 	}
 
-	fn proc_payload(
-		_ctx: &mut WsContext,
+	fn proc_frame(
+		ctx: &mut WsContext,
 		conn: Connection<WsContext, WsConnection>,
 		bytes: &[u8],
 		op: u8,
 		fin: bool,
+		compressed: bool,
 		handler: Option<Handler>,
+		mut att: WsConnection,
 	) -> Result<()> {
-		let mut handle = Handle { conn };
-		match handler {
-			Some(mut handler) => match &mut handler.inner.handlers {
-				Some(ref mut handlers) => (handlers.on_recv)(&mut handle, bytes, fin, op)?,
-				None => println!("WARN: no handler found1!"),
-			},
-			None => println!("WARN: no handler found2!"),
+		match op {
+			WS_OP_CLOSE => Self::proc_close(conn, att, bytes),
+			WS_OP_PING => {
+				let frame = Self::frame(0x80 | WS_OP_PONG, bytes, Self::mask(&att))?;
+				let mut conn = conn;
+				Self::write_fully(&mut conn, &frame)
+			}
+			WS_OP_PONG => Ok(()),
+			WS_OP_CONTINUATION => {
+				if att.inner.fragment_op == WS_OP_CONTINUATION {
+					return Self::fail(conn, att, WS_CLOSE_PROTOCOL_ERROR);
+				}
+				if att.inner.fragments.len() + bytes.len() > ctx.max_message_size {
+					return Self::fail(conn, att, WS_CLOSE_TOO_BIG);
+				}
+				att.inner.fragments.extend_from_slice(bytes)?;
+				if fin {
+					let op = att.inner.fragment_op;
+					let compressed = att.inner.fragment_compressed;
+					att.inner.fragment_op = WS_OP_CONTINUATION;
+					let mut message = Vec::new();
+					swap(&mut message, &mut att.inner.fragments);
+					Self::proc_message(ctx, conn, &message, op, compressed, handler, att)?;
+				}
+				Ok(())
+			}
+			WS_OP_TEXT | WS_OP_BINARY => {
+				// a new message can't start inside a fragmented one
+				if att.inner.fragment_op != WS_OP_CONTINUATION {
+					return Self::fail(conn, att, WS_CLOSE_PROTOCOL_ERROR);
+				}
+				if fin {
+					Self::proc_message(ctx, conn, bytes, op, compressed, handler, att)
+				} else {
+					att.inner.fragment_op = op;
+					att.inner.fragment_compressed = compressed;
+					att.inner.fragments.extend_from_slice(bytes)
+				}
+			}
+			_ => Self::fail(conn, att, WS_CLOSE_PROTOCOL_ERROR),
 		}
-		Ok(())
+	}
+
+	fn proc_message(
+		ctx: &mut WsContext,
+		conn: Connection<WsContext, WsConnection>,
+		bytes: &[u8],
+		op: u8,
+		compressed: bool,
+		handler: Option<Handler>,
+		mut att: WsConnection,
+	) -> Result<()> {
+		if compressed {
+			let inflated = match Self::inflate(ctx, &mut att, bytes) {
+				Ok(inflated) => inflated,
+				Err(e) => {
+					let code = if e == CapacityExceeded {
+						WS_CLOSE_TOO_BIG
+					} else {
+						WS_CLOSE_INVALID_DATA
+					};
+					return Self::fail(conn, att, code);
+				}
+			};
+			return Self::proc_message(ctx, conn, &inflated, op, false, handler, att);
+		}
+		if op == WS_OP_TEXT && is_utf8_valid(bytes).is_err() {
+			return Self::fail(conn, att, WS_CLOSE_INVALID_DATA);
+		}
+		Self::proc_payload(ctx, conn, bytes, op, true, handler)
+	}
+
+	fn inflate(ctx: &mut WsContext, att: &mut WsConnection, bytes: &[u8]) -> Result<Vec<u8>> {
+		match &mut att.inner.deflate {
+			Some(deflate) => {
+				// restore the sync flush the sender removed
+				let mut input = Vec::with_capacity(bytes.len() + 4)?;
+				input.extend_from_slice(bytes)?;
+				input.extend_from_slice(&[0x00, 0x00, 0xff, 0xff])?;
+				let ret = deflate.inflater.decompress(&input, ctx.max_message_size)?;
+				if deflate.reset_inflater {
+					deflate.inflater.reset();
+				}
+				Ok(ret)
+			}
+			None => err!(IllegalState),
+		}
+	}
+
+	// the close handshake: reply with the peer's status code (or ours if the
+	// close frame is invalid) unless we initiated the close, then disconnect
+	fn proc_close(
+		conn: Connection<WsContext, WsConnection>,
+		att: WsConnection,
+		bytes: &[u8],
+	) -> Result<()> {
+		let code = if bytes.len() == 0 {
+			WS_CLOSE_NORMAL
+		} else if bytes.len() == 1 {
+			WS_CLOSE_PROTOCOL_ERROR
+		} else {
+			let code = (bytes[0] as u16) << 8 | bytes[1] as u16;
+			if is_utf8_valid(&bytes[2..]).is_err() {
+				WS_CLOSE_INVALID_DATA
+			} else if Self::is_valid_close_code(code) {
+				code
+			} else {
+				WS_CLOSE_PROTOCOL_ERROR
+			}
+		};
+		Self::fail(conn, att, code)
+	}
+
+	fn is_valid_close_code(code: u16) -> bool {
+		match code {
+			1000..=1003 | 1007..=1011 | 3000..=4999 => true,
+			_ => false,
+		}
+	}
+
+	// sends a close frame (if we haven't already) and disconnects
+	fn fail(
+		mut conn: Connection<WsContext, WsConnection>,
+		mut att: WsConnection,
+		code: u16,
+	) -> Result<()> {
+		if !att.inner.close_sent {
+			att.inner.close_sent = true;
+			let mut payload = [0u8; 2];
+			to_be_bytes_u16(code, &mut payload);
+			let frame = Self::frame(0x80 | WS_OP_CLOSE, &payload, Self::mask(&att))?;
+			let _ = Self::write_fully(&mut conn, &frame);
+		}
+		conn.close()
+	}
+
+	fn mask(att: &WsConnection) -> Option<[u8; 4]> {
+		match &att.inner.rng {
+			Some(rng) => {
+				let mut mask = [0u8; 4];
+				rng.gen(&mut mask);
+				Some(mask)
+			}
+			None => None,
+		}
+	}
+
+	fn proc_payload(
+		_ctx: &mut WsContext,
+		conn: Connection<WsContext, WsConnection>,
+		bytes: &[u8],
+		op: u8,
+		fin: bool,
+		handler: Option<Handler>,
+	) -> Result<()> {
+		let mut handle = Handle { conn };
+		match handler {
+			Some(mut handler) => match &mut handler.inner.handlers {
+				Some(ref mut handlers) => (handlers.on_recv)(&mut handle, bytes, fin, op)?,
+				None => println!("WARN: no handler found1!"),
+			},
+			None => println!("WARN: no handler found2!"),
+		}
+		Ok(())
 	}
 
 	fn proc_loop(
@@ -392,21 +1132,13 @@ impl Ws {
 					att.inner.is_upgraded,
 					handlers.clone(),
 					att.inner.handler.clone(),
+					att.clone(),
 				)?;
 				// adjust offset as we go
 				offset += clear_through;
 				if upgraded {
-					att.inner.is_upgraded = true;
-					att.inner.handler = handler;
-					let mut handle = Handle { conn: conn.clone() };
-					match att.inner.handler {
-						Some(ref mut handler) => match handler.inner.handlers {
-							Some(ref mut handlers) => (handlers.on_accept)(&mut handle)?,
-							None => println!("WARN: no handler1"),
-						},
-						None => println!("WARN: no handler2"),
-					}
-					break;
+					// frames may follow the upgrade in the same read
+					Self::proc_upgrade(conn.clone(), att, handler)?;
 				} else if clear_through == 0 {
 					break;
 				}
@@ -425,14 +1157,10 @@ impl Ws {
 					att.inner.is_upgraded,
 					handlers.clone(),
 					att.inner.handler.clone(),
+					att.clone(),
 				)?;
 				if upgraded {
-					att.inner.is_upgraded = true;
-					att.inner.handler = handler;
-					// clear the buffer here. should not be anything, but we
-					// explicitly start from scratch
-					att.inner.rbuf.clear();
-					break;
+					Self::proc_upgrade(conn.clone(), att, handler)?;
 				}
 				let rbuf_len = att.inner.rbuf.len();
 				if clear_through >= rbuf_len {
@@ -454,6 +1182,24 @@ impl Ws {
 		Ok(())
 	}
 
+	fn proc_upgrade(
+		conn: Connection<WsContext, WsConnection>,
+		att: &mut WsConnection,
+		handler: Option<Handler>,
+	) -> Result<()> {
+		att.inner.is_upgraded = true;
+		att.inner.handler = handler;
+		let mut handle = Handle { conn };
+		match att.inner.handler {
+			Some(ref mut handler) => match handler.inner.handlers {
+				Some(ref mut handlers) => (handlers.on_accept)(&mut handle)?,
+				None => println!("WARN: no handler1"),
+			},
+			None => println!("WARN: no handler2"),
+		}
+		Ok(())
+	}
+
 	fn proc_on_recv(
 		ctx: &mut WsContext,
 		conn: &mut Connection<WsContext, WsConnection>,
@@ -481,81 +1227,171 @@ impl Ws {
 		upgraded: bool,
 		handlers: Rc<RbTree<Handler>>,
 		handler: Option<Handler>,
+		att: WsConnection,
 	) -> Result<(usize, bool, Option<Handler>)> {
 		if upgraded {
-			return Ok((Self::proc_upgraded(ctx, conn, bytes, handler)?, false, None));
-		}
-		if let Some(pos) = bytes.windows(4).position(|window| window == b"\r\n\r\n") {
-			let mut upgraded = false;
-			let mut handler: Option<Handler> = None;
-			if pos <= bytes.len() {
-				let mut key = "";
-				let mut url = "";
-				if let Some(start) = bytes.windows(1).position(|window| window == b" ") {
-					if start + 1 < bytes.len() {
-						if let Some(end) = bytes[(start + 1)..]
-							.windows(1)
-							.position(|window| window == b" ")
-						{
-							if start + end + 1 < bytes.len() && 1 + start < start + end + 1 {
-								url = from_utf8(&bytes[(1 + start)..(start + end + 1)])?;
-							}
-						}
-					}
-				}
+			return Ok((
+				Self::proc_upgraded(ctx, conn, bytes, handler, att)?,
+				false,
+				None,
+			));
+		}
+		if att.inner.accept.is_some() {
+			return Self::proc_response(ctx, conn, bytes, handler, att);
+		}
+		let (request, consumed) = match HttpRequest::parse(bytes, ctx.max_body_size) {
+			Ok(Some(request)) => request,
+			Ok(None) => return Ok((0, false, None)),
+			Err(e) => {
+				// the rest of the stream can't be framed, respond and close
+				Self::respond(conn, HttpRoute::error_response(e), false, false)?;
+				return Ok((bytes.len(), false, None));
+			}
+		};
 
-				if url == "" {
-					// invalid url specified TODO: return bad request
-					conn.close()?;
-					return Ok((0, false, None));
-				} else {
-					//let node = Ptr::alloc(RbTreeNode::new(Handler::with_path(url)?))?;
-					let node = RbTreeNode::alloc(Handler::with_path(url)?)?;
-					let pair = handlers.search(node);
-					node.release();
-					if pair.cur.is_null() {
-						// path not found TODO: return 404
-						conn.close()?;
-						return Ok((0, false, None));
-					} else {
-						handler = Some((&*(pair.cur.value)).clone());
-					}
-				}
+		if !request.is_upgrade() {
+			let resp = HttpRoute::dispatch(&ctx.routes, &request)?;
+			let keep_alive = request.keep_alive();
+			let head = request.method() == HttpMethod::Head;
+			Self::respond(conn, resp, keep_alive, head)?;
+			return Ok((if keep_alive { consumed } else { bytes.len() }, false, None));
+		}
 
-				let target = b"\nSec-WebSocket-Key: ";
-				if let Some(start) = bytes
-					.windows(target.len())
-					.position(|window| window == target)
-				{
-					if start + 1 < bytes.len() {
-						if let Some(end) = bytes[(start + 1)..]
-							.windows(1)
-							.position(|window| window == b"\r")
-						{
-							let end = end + start + 1;
-							let start = target.len() + start;
-							if start < end && end <= bytes.len() {
-								key = from_utf8(&bytes[start..end])?;
-							}
-						}
-					}
-				};
+		let node = RbTreeNode::alloc(Handler::with_path(request.path())?)?;
+		let pair = handlers.search(node);
+		node.release();
+		if pair.cur.is_null() || request.method() != HttpMethod::Get {
+			let mut resp = HttpResponse::new();
+			if pair.cur.is_null() {
+				resp.set_status(404);
+			} else {
+				// upgrades are only done for GET
+				resp.set_status(405);
+				resp.add_header("Allow", "GET")?;
+			}
+			Self::respond(conn, resp, false, false)?;
+			return Ok((bytes.len(), false, None));
+		}
+		let handler = Some((&*(pair.cur.value)).clone());
+
+		let key = match request.header("sec-websocket-key") {
+			Some(key) => key,
+			None => "",
+		};
+		let mut extensions = Vec::new();
+		for (name, value) in request.headers().iter() {
+			if name
+				.as_str()
+				.eq_ignore_ascii_case("sec-websocket-extensions")
+			{
+				extensions.push(value.as_bytes())?;
+			}
+		}
+		let upgraded = Self::proc_handshake(ctx, conn, key, att, &extensions)?;
+		Ok((consumed, upgraded, handler))
+	}
+
+	// writes an http response, closing the connection unless `keep_alive`
+	fn respond(
+		mut conn: Connection<WsContext, WsConnection>,
+		resp: HttpResponse,
+		keep_alive: bool,
+		head: bool,
+	) -> Result<()> {
+		Self::write_fully(&mut conn, &resp.serialize(keep_alive, head)?)?;
+		if !keep_alive {
+			conn.close()?;
+		}
+		Ok(())
+	}
+
+	// client side: validate the server's response to our upgrade request
+	fn proc_response(
+		ctx: &mut WsContext,
+		conn: Connection<WsContext, WsConnection>,
+		bytes: &[u8],
+		handler: Option<Handler>,
+		mut att: WsConnection,
+	) -> Result<(usize, bool, Option<Handler>)> {
+		let pos = match bytes.windows(4).position(|window| window == b"\r\n\r\n") {
+			Some(pos) => pos,
+			None => return Ok((0, false, None)),
+		};
+		let head = &bytes[0..pos];
+		let switching = head.starts_with(b"HTTP/1.1 101");
+		let mut valid = false;
+		for value in Self::header_values(head, b"sec-websocket-accept")? {
+			valid = match &att.inner.accept {
+				Some(accept) => value == accept.as_bytes(),
+				None => false,
+			};
+		}
 
-				if Self::proc_handshake(ctx, conn, key)? {
-					upgraded = true;
+		// the server must agree to the upgrade (RFC 6455 4.1)
+		let mut upgrade = false;
+		for value in Self::header_values(head, b"upgrade")? {
+			upgrade |= value.eq_ignore_ascii_case(b"websocket");
+		}
+		let mut connection = false;
+		for value in Self::header_values(head, b"connection")? {
+			for token in value.split(|b| *b == b',') {
+				connection |= Self::trim(token).eq_ignore_ascii_case(b"upgrade");
+			}
+		}
+		valid = valid && upgrade && connection;
+
+		// the server may only accept the extension we offered
+		for value in Self::header_values(head, b"sec-websocket-extensions")? {
+			let response = match (&ctx.deflate, &att.inner.deflate) {
+				(Some(_), None) => DeflateParams::parse(value),
+				_ => None,
+			};
+			match (&ctx.deflate, response) {
+				(Some(deflate), Some(response)) => {
+					att.inner.deflate = Some(deflate.accepted(&response)?);
 				}
+				_ => valid = false,
 			}
-			Ok((pos + 4, upgraded, handler))
+		}
+
+		if switching && valid {
+			Ok((pos + 4, true, handler))
 		} else {
+			conn.close()?;
 			Ok((0, false, None))
 		}
 	}
 
 	fn proc_handshake(
-		_ctx: &mut WsContext,
+		ctx: &mut WsContext,
 		mut conn: Connection<WsContext, WsConnection>,
 		key: &str,
+		mut att: WsConnection,
+		extensions: &Vec<&[u8]>,
 	) -> Result<bool> {
+		// accept the first acceptable permessage-deflate offer
+		let mut extension = String::empty();
+		match &ctx.deflate {
+			Some(deflate) => {
+				for value in extensions {
+					for element in value.split(|b| *b == b',') {
+						if att.inner.deflate.is_some() {
+							break;
+						}
+						match DeflateParams::parse(element) {
+							Some(offer) => {
+								let (ws_deflate, header) = deflate.accept(&offer)?;
+								att.inner.deflate = Some(ws_deflate);
+								extension = format!("Sec-WebSocket-Extensions: {}\r\n", header)?;
+							}
+							None => {}
+						}
+					}
+				}
+			}
+			None => {}
+		}
+
 		let msg = if key.len() == 0 {
 			format!("HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")?
 		} else {
@@ -564,8 +1400,8 @@ impl Ws {
 				"HTTP/1.1 101 Switching Protocols\r\n\
 Upgrade: websocket\r\n\
 Connection: Upgrade\r\n\
-Sec-WebSocket-Accept: {}\r\n\r\n",
-				accept
+Sec-WebSocket-Accept: {}\r\n{}\r\n",
+				accept, extension
 			)?
 		};
 
@@ -578,11 +1414,38 @@ Sec-WebSocket-Accept: {}\r\n\r\n",
 		}
 	}
 
+	// the trimmed values of all headers named `name` (lowercase)
+	fn header_values<'a>(head: &'a [u8], name: &[u8]) -> Result<Vec<&'a [u8]>> {
+		let mut ret = Vec::new();
+		for line in head.split(|b| *b == b'\n') {
+			let colon = match line.iter().position(|b| *b == b':') {
+				Some(colon) => colon,
+				None => continue,
+			};
+			if Self::trim(&line[0..colon]).eq_ignore_ascii_case(name) {
+				ret.push(Self::trim(&line[colon + 1..]))?;
+			}
+		}
+		Ok(ret)
+	}
+
+	fn trim(mut b: &[u8]) -> &[u8] {
+		while b.len() > 0 && (b[0] == b' ' || b[0] == b'\t') {
+			b = &b[1..];
+		}
+		while b.len() > 0
+			&& (b[b.len() - 1] == b' ' || b[b.len() - 1] == b'\t' || b[b.len() - 1] == b'\r')
+		{
+			b = &b[0..b.len() - 1];
+		}
+		b
+	}
+
 	fn proc_on_accept(
-		_ctx: &mut WsContext,
+		ctx: &mut WsContext,
 		conn: &mut Connection<WsContext, WsConnection>,
 	) -> Result<()> {
-		let att = WsConnection::new()?;
+		let att = WsConnection::new(ctx.close_timeout)?;
 		conn.set_attach(att.clone())?;
 
 		Ok(())
@@ -641,6 +1504,8 @@ Sec-WebSocket-Accept: {}\r\n\r\n",
 #[cfg(test)]
 mod test {
 	use super::*;
+	use misc::from_utf8;
+	use net::http::HttpOnRequest;
 
 	// ws example
 	/*
@@ -726,4 +1591,908 @@ mod test {
 				*/
 		Ok(())
 	}
+	fn free_port() -> Result<u16> {
+		let (port, mut s) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		s.close()?;
+		Ok(port)
+	}
+
+	#[test]
+	fn test_ws_client() -> Result<()> {
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		let on_recv: WsOnRecv = Box::new(
+			|handle: &mut Handle, bytes: &[u8], fin: bool, op: u8| -> Result<()> {
+				assert!(fin);
+				assert_eq!(op, 2);
+				handle.send(bytes)
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		server.add_handler(Handler::new(
+			"/echo",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?)?;
+		server.start()?;
+
+		let lock = lock_box!()?;
+		let state = Rc::new((0u64, 0u64, Vec::new()))?;
+		let (lock1, lock2, lock3) = (lock.clone(), lock.clone(), lock.clone());
+		let (mut state1, mut state2, mut state3) = (state.clone(), state.clone(), state.clone());
+		let on_recv: WsOnRecv = Box::new(
+			move |_handle: &mut Handle, bytes: &[u8], fin: bool, op: u8| -> Result<()> {
+				assert!(fin);
+				assert_eq!(op, 2);
+				let _l = lock1.write();
+				state1.2.extend_from_slice(bytes)?;
+				Ok(())
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(move |_handle: &mut Handle| -> Result<()> {
+			let _l = lock2.write();
+			state2.0 += 1;
+			Ok(())
+		})?;
+		let on_close: WsOnClose = Box::new(move |_handle: &mut Handle| -> Result<()> {
+			let _l = lock3.write();
+			state3.1 += 1;
+			Ok(())
+		})?;
+		let on_recv = Rc::new(on_recv)?;
+		let on_accept = Rc::new(on_accept)?;
+		let on_close = Rc::new(on_close)?;
+
+		let mut client = Ws::new()?;
+		client.start()?;
+
+		// an unknown path is refused
+		let handler = Handler::new(
+			"/none",
+			on_recv.clone(),
+			on_accept.clone(),
+			on_close.clone(),
+		)?;
+		client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.1 == 1 {
+				assert_eq!(state.0, 0);
+				break;
+			}
+		}
+
+		let handler = Handler::new("/echo", on_recv, on_accept, on_close)?;
+		let mut handle = client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.0 == 1 {
+				break;
+			}
+		}
+
+		// exercise all three payload length encodings
+		let mut expected = Vec::new();
+		for len in [5, 300, 70_000] {
+			let mut msg = Vec::new();
+			for i in 0..len {
+				msg.push((i % 256) as u8)?;
+			}
+			handle.send(&msg)?;
+			expected.extend(&msg)?;
+		}
+
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.2.len() == expected.len() {
+				assert_eq!(&state.2[..], &expected[..]);
+				break;
+			}
+		}
+
+		handle.close()?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.1 == 2 {
+				break;
+			}
+		}
+
+		client.stop()?;
+		server.stop()?;
+		for s in server.get_sockets() {
+			s.clone().close()?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_ws_client_handshake() -> Result<()> {
+		let (port, mut listener) = Socket::listen_rand([127, 0, 0, 1], 10)?;
+		let lock = lock_box!()?;
+		let state = Rc::new((0u64, 0u64))?;
+		let (lock1, lock2) = (lock.clone(), lock.clone());
+		let (mut state1, mut state2) = (state.clone(), state.clone());
+		let on_recv: WsOnRecv =
+			Box::new(|_: &mut Handle, _: &[u8], _: bool, _: u8| -> Result<()> { Ok(()) })?;
+		let on_accept: WsOnAccept = Box::new(move |handle: &mut Handle| -> Result<()> {
+			{
+				let _l = lock1.write();
+				state1.0 += 1;
+			}
+			handle.send(b"hi")
+		})?;
+		let on_close: WsOnClose = Box::new(move |_handle: &mut Handle| -> Result<()> {
+			let _l = lock2.write();
+			state2.1 += 1;
+			Ok(())
+		})?;
+		let on_recv = Rc::new(on_recv)?;
+		let on_accept = Rc::new(on_accept)?;
+		let on_close = Rc::new(on_close)?;
+
+		let mut client = Ws::new()?;
+		client.start()?;
+
+		let read_request = |s: Socket| -> Result<String> {
+			let mut req = Vec::new();
+			let mut buf = [0u8; 1024];
+			loop {
+				match s.recv(&mut buf) {
+					Ok(len) => req.extend_from_slice(&buf[0..len])?,
+					Err(e) => assert_eq!(e, EAgain),
+				}
+				if req.windows(4).position(|w| w == b"\r\n\r\n").is_some() {
+					break;
+				}
+				sleep(1);
+			}
+			let target = b"Sec-WebSocket-Key: ";
+			let start = req.windows(target.len()).position(|w| w == target).unwrap();
+			let start = start + target.len();
+			assert!(req.starts_with(b"GET /chat HTTP/1.1\r\n"));
+			websocket_accept_key(from_utf8(&req[start..start + 24])?)
+		};
+		let accept = || -> Result<Socket> {
+			loop {
+				match listener.accept() {
+					Ok(s) => break Ok(s),
+					Err(e) => assert_eq!(e, EAgain),
+				}
+				sleep(1);
+			}
+		};
+
+		// a server that doesn't prove it saw our key
+		let handler = Handler::new(
+			"/chat",
+			on_recv.clone(),
+			on_accept.clone(),
+			on_close.clone(),
+		)?;
+		client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		let mut s = accept()?;
+		read_request(s)?;
+		let resp = b"HTTP/1.1 101 Switching Protocols\r\n\
+Upgrade: websocket\r\n\
+Connection: Upgrade\r\n\
+Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+		assert_eq!(s.send(resp)?, resp.len());
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.1 == 1 {
+				assert_eq!(state.0, 0);
+				break;
+			}
+		}
+		s.close()?;
+
+		// the right key but no Upgrade header, or a Connection header
+		// without the upgrade token
+		let bad = [
+			"Connection: Upgrade\r\n",
+			"Upgrade: websocket\r\nConnection: keep-alive\r\n",
+		];
+		for i in 0..bad.len() {
+			let handler = Handler::new(
+				"/chat",
+				on_recv.clone(),
+				on_accept.clone(),
+				on_close.clone(),
+			)?;
+			client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+			let mut s = accept()?;
+			let key = read_request(s)?;
+			let resp = format!(
+				"HTTP/1.1 101 Switching Protocols\r\n{}Sec-WebSocket-Accept: {}\r\n\r\n",
+				bad[i], key
+			)?;
+			assert_eq!(s.send(resp.as_bytes())?, resp.len());
+			loop {
+				sleep(1);
+				let _l = lock.read();
+				if state.1 == 2 + i as u64 {
+					assert_eq!(state.0, 0);
+					break;
+				}
+			}
+			s.close()?;
+		}
+
+		// a valid response, with header names and values in another case
+		let handler = Handler::new("/chat", on_recv, on_accept, on_close)?;
+		client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		let mut s = accept()?;
+		let key = read_request(s)?;
+		let resp = format!(
+			"HTTP/1.1 101 Switching Protocols\r\nupgrade: WebSocket\r\n\
+connection: keep-alive, upgrade\r\nsec-websocket-accept: {}\r\n\r\n",
+			key
+		)?;
+		assert_eq!(s.send(resp.as_bytes())?, resp.len());
+
+		// the client's frame is masked
+		let mut buf = [0u8; 8];
+		let mut len = 0;
+		while len < 8 {
+			match s.recv(&mut buf[len..]) {
+				Ok(n) => len += n,
+				Err(e) => assert_eq!(e, EAgain),
+			}
+		}
+		assert_eq!(buf[0], 0x82);
+		assert_eq!(buf[1], 0x80 | 2);
+		assert_eq!(buf[6] ^ buf[2], b'h');
+		assert_eq!(buf[7] ^ buf[3], b'i');
+		{
+			let _l = lock.read();
+			assert_eq!(state.0, 1);
+		}
+
+		s.close()?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.1 == 4 {
+				break;
+			}
+		}
+
+		client.stop()?;
+		listener.close()?;
+		Ok(())
+	}
+
+	fn recv_exact(s: Socket, buf: &mut [u8]) -> Result<()> {
+		let mut len = 0;
+		while len < buf.len() {
+			match s.recv(&mut buf[len..]) {
+				Ok(0) => return err!(IO),
+				Ok(n) => len += n,
+				Err(e) => {
+					assert_eq!(e, EAgain);
+					sleep(1);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn raw_send(s: Socket, bytes: &[u8]) -> Result<()> {
+		let mut offset = 0;
+		while offset < bytes.len() {
+			match s.send(&bytes[offset..]) {
+				Ok(n) => offset += n,
+				Err(e) => {
+					assert_eq!(e, EAgain);
+					sleep(1);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// connects and upgrades with the sample key from RFC 6455
+	fn raw_connect(port: u16, path: &str) -> Result<Socket> {
+		Ok(raw_upgrade(port, path, "")?.0)
+	}
+
+	// returns the socket and the response headers
+	fn raw_upgrade(port: u16, path: &str, headers: &str) -> Result<(Socket, Vec<u8>)> {
+		let s = Socket::connect([127, 0, 0, 1], port)?;
+		let req = format!(
+			"GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n{}\r\n",
+			path, headers
+		)?;
+		raw_send(s, req.as_bytes())?;
+		let mut resp = Vec::new();
+		let mut b = [0u8; 1];
+		while !resp.ends_with(b"\r\n\r\n") {
+			recv_exact(s, &mut b)?;
+			resp.push(b[0])?;
+		}
+		assert!(resp.starts_with(b"HTTP/1.1 101"));
+		Ok((s, resp))
+	}
+
+	// sends a masked frame like a client would
+	fn raw_frame(s: Socket, first: u8, payload: &[u8]) -> Result<()> {
+		raw_send(s, &Ws::frame(first, payload, Some([1, 2, 3, 4]))?)
+	}
+
+	// reads an unmasked frame with a short payload
+	fn raw_read(s: Socket) -> Result<(u8, Vec<u8>)> {
+		let mut header = [0u8; 2];
+		recv_exact(s, &mut header)?;
+		assert_eq!(header[1] & 0x80, 0);
+		assert!(header[1] < 126);
+		let mut payload = Vec::new();
+		payload.resize(header[1] as usize)?;
+		recv_exact(s, &mut payload)?;
+		Ok((header[0], payload))
+	}
+
+	fn raw_closed(s: Socket) -> Result<()> {
+		let mut b = [0u8; 1];
+		assert_eq!(recv_exact(s, &mut b).err(), Some(IO));
+		Ok(())
+	}
+
+	#[test]
+	fn test_ws_control_frames() -> Result<()> {
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.set_max_message_size(100)?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		let on_recv: WsOnRecv = Box::new(
+			|handle: &mut Handle, bytes: &[u8], fin: bool, op: u8| -> Result<()> {
+				assert!(fin);
+				if op == WS_OP_TEXT {
+					handle.send_text(from_utf8(bytes)?)
+				} else {
+					handle.send(bytes)
+				}
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		server.add_handler(Handler::new(
+			"/ctl",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?)?;
+		server.start()?;
+
+		let mut s = raw_connect(port, "/ctl")?;
+
+		// ping is answered with a pong carrying the same payload
+		raw_frame(s, 0x89, b"abc")?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x8A);
+		assert_eq!(&payload[..], b"abc");
+
+		// fragments are reassembled, control frames may be interleaved
+		raw_frame(s, 0x01, b"hel")?;
+		raw_frame(s, 0x89, b"x")?;
+		raw_frame(s, 0x00, b"l")?;
+		raw_frame(s, 0x80, b"o")?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x8A);
+		assert_eq!(&payload[..], b"x");
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x81);
+		assert_eq!(&payload[..], b"hello");
+
+		raw_frame(s, 0x82, &[0, 1, 2])?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x82);
+		assert_eq!(&payload[..], &[0, 1, 2]);
+
+		// the close handshake echoes the status code
+		raw_frame(s, 0x88, &[0x03, 0xE8, b'b', b'y', b'e'])?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x88);
+		assert_eq!(&payload[..], &[0x03, 0xE8]);
+		raw_closed(s)?;
+		s.close()?;
+
+		// each protocol violation gets a specific status code
+		let mut bad: Vec<(Vec<u8>, u16)> = Vec::new();
+		bad.push((Ws::frame(0x81, &[b'a', 0xFF], Some([1, 2, 3, 4]))?, 1007))?;
+		bad.push((Ws::frame(0x82, b"unmasked", None)?, 1002))?;
+		bad.push((Ws::frame(0xC2, b"rsv", Some([1, 2, 3, 4]))?, 1002))?;
+		bad.push((Ws::frame(0x83, b"opcode", Some([1, 2, 3, 4]))?, 1002))?;
+		bad.push((Ws::frame(0x80, b"orphan", Some([1, 2, 3, 4]))?, 1002))?;
+		bad.push((
+			Ws::frame(0x09, b"fragmented ping", Some([1, 2, 3, 4]))?,
+			1002,
+		))?;
+		bad.push((Ws::frame(0x88, &[0x03, 0xEC], Some([1, 2, 3, 4]))?, 1002))?;
+		bad.push((Ws::frame(0x82, &[0u8; 101], Some([1, 2, 3, 4]))?, 1009))?;
+		let mut fragmented = Ws::frame(0x02, &[0u8; 60], Some([1, 2, 3, 4]))?;
+		fragmented.extend(&Ws::frame(0x80, &[0u8; 60], Some([1, 2, 3, 4]))?)?;
+		bad.push((fragmented, 1009))?;
+		let mut interleaved = Ws::frame(0x01, b"a", Some([1, 2, 3, 4]))?;
+		interleaved.extend(&Ws::frame(0x81, b"b", Some([1, 2, 3, 4]))?)?;
+		bad.push((interleaved, 1002))?;
+
+		for (frame, code) in bad {
+			let mut s = raw_connect(port, "/ctl")?;
+			raw_send(s, &frame)?;
+			let (first, payload) = raw_read(s)?;
+			assert_eq!(first, 0x88);
+			assert_eq!(&payload[..], &[(code >> 8) as u8, code as u8]);
+			raw_closed(s)?;
+			s.close()?;
+		}
+
+		server.stop()?;
+		for s in server.get_sockets() {
+			s.clone().close()?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_ws_close_timeout() -> Result<()> {
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.set_close_timeout(100)?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		let on_recv: WsOnRecv = Box::new(
+			|handle: &mut Handle, _bytes: &[u8], _fin: bool, _op: u8| -> Result<()> {
+				handle.close()
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let mut closed = Rc::new(0u64)?;
+		let closed_clone = closed.clone();
+		let on_close: WsOnClose = Box::new(move |_handle: &mut Handle| -> Result<()> {
+			*closed += 1;
+			Ok(())
+		})?;
+		server.add_handler(Handler::new(
+			"/close",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?)?;
+		server.start()?;
+		assert_eq!(server.set_close_timeout(100).err(), Some(IllegalState));
+
+		// the peer never echoes the close frame, it's dropped anyway
+		let mut s = raw_connect(port, "/close")?;
+		raw_frame(s, 0x81, b"bye")?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x88);
+		assert_eq!(&payload[..], &[0x03, 0xE8]);
+		raw_closed(s)?;
+		s.close()?;
+		while *closed_clone == 0 {
+			sleep(1);
+		}
+
+		server.stop()?;
+		for s in server.get_sockets() {
+			s.clone().close()?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_ws_deflate_params() -> Result<()> {
+		let p = DeflateParams::parse(b"permessage-deflate").unwrap();
+		assert!(!p.server_no_context_takeover && !p.client_no_context_takeover);
+		assert!(p.server_max_window_bits.is_none() && !p.client_max_window_bits_offered);
+
+		let p = DeflateParams::parse(
+			b" permessage-deflate ; client_max_window_bits; server_max_window_bits=\"10\";\
+server_no_context_takeover",
+		)
+		.unwrap();
+		assert!(p.server_no_context_takeover && !p.client_no_context_takeover);
+		assert_eq!(p.server_max_window_bits, Some(10));
+		assert!(p.client_max_window_bits_offered);
+		assert_eq!(p.client_max_window_bits, None);
+
+		assert!(DeflateParams::parse(b"x-webkit-deflate-frame").is_none());
+		assert!(DeflateParams::parse(b"permessage-deflate; foo").is_none());
+		assert!(DeflateParams::parse(b"permessage-deflate; server_max_window_bits").is_none());
+		assert!(DeflateParams::parse(b"permessage-deflate; server_max_window_bits=16").is_none());
+		assert!(DeflateParams::parse(b"permessage-deflate; client_max_window_bits=7").is_none());
+		assert!(DeflateParams::parse(b"permessage-deflate; client_max_window_bits=08").is_none());
+		assert!(DeflateParams::parse(
+			b"permessage-deflate; client_no_context_takeover; client_no_context_takeover"
+		)
+		.is_none());
+		assert!(
+			DeflateParams::parse(b"permessage-deflate; server_no_context_takeover=1").is_none()
+		);
+
+		// the client offers what it's configured with
+		let config = DeflateConfig::new(true, false, 12)?;
+		assert_eq!(
+			config.offer()?.as_str(),
+			"permessage-deflate; client_no_context_takeover; client_max_window_bits=12"
+		);
+		assert_eq!(
+			DeflateConfig::new(false, true, 15)?.offer()?.as_str(),
+			"permessage-deflate; server_no_context_takeover; client_max_window_bits"
+		);
+		assert_eq!(
+			DeflateConfig::new(false, false, 7).err(),
+			Some(IllegalArgument)
+		);
+
+		// the server honours the client's requests and adds its own
+		let offer = DeflateParams::parse(b"permessage-deflate; server_max_window_bits=10").unwrap();
+		let (deflate, header) = config.accept(&offer)?;
+		assert_eq!(
+			header.as_str(),
+			"permessage-deflate; server_no_context_takeover; server_max_window_bits=10"
+		);
+		assert!(deflate.reset_deflater && !deflate.reset_inflater);
+
+		let offer = DeflateParams::parse(b"permessage-deflate").unwrap();
+		let (deflate, header) = DeflateConfig::new(false, true, 15)?.accept(&offer)?;
+		assert_eq!(
+			header.as_str(),
+			"permessage-deflate; client_no_context_takeover"
+		);
+		assert!(!deflate.reset_deflater && deflate.reset_inflater);
+		Ok(())
+	}
+
+	#[test]
+	fn test_ws_deflate_raw() -> Result<()> {
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.set_deflate(DeflateConfig::new(false, false, 15)?)?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		let on_recv: WsOnRecv = Box::new(
+			|handle: &mut Handle, bytes: &[u8], _fin: bool, _op: u8| -> Result<()> {
+				handle.send_text(from_utf8(bytes)?)
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		server.add_handler(Handler::new(
+			"/z",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?)?;
+		server.start()?;
+
+		// the first offer is unknown, the second is accepted
+		let (mut s, resp) = raw_upgrade(
+			port,
+			"/z",
+			"Sec-WebSocket-Extensions: permessage-deflate; foo=1, \
+permessage-deflate; client_max_window_bits\r\n",
+		)?;
+		let target = b"Sec-WebSocket-Extensions: permessage-deflate\r\n";
+		assert!(resp
+			.windows(target.len())
+			.position(|w| w == target)
+			.is_some());
+
+		// the compressed "Hello" examples from RFC 7692 7.2.3, the second
+		// refers back into the first
+		let mut inflater = Inflater::new();
+		for payload in [
+			&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00][..],
+			&[0xf2, 0x00, 0x11, 0x00, 0x00][..],
+		] {
+			raw_frame(s, 0xC1, payload)?;
+			let (first, payload) = raw_read(s)?;
+			assert_eq!(first, 0xC1);
+			let mut input = Vec::new();
+			input.extend_from_slice(&payload)?;
+			input.extend_from_slice(&[0x00, 0x00, 0xff, 0xff])?;
+			assert_eq!(&inflater.decompress(&input, 100)?[..], b"Hello");
+		}
+
+		// uncompressed messages are still allowed, the reply is compressed
+		raw_frame(s, 0x81, b"Hello")?;
+		let (first, _) = raw_read(s)?;
+		assert_eq!(first, 0xC1);
+
+		// rsv1 isn't allowed on control frames or continuations
+		raw_frame(s, 0xC9, b"")?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x88);
+		assert_eq!(&payload[..], &[0x03, 0xEA]);
+		raw_closed(s)?;
+		s.close()?;
+
+		// a corrupt stream
+		let (mut s, _) = raw_upgrade(
+			port,
+			"/z",
+			"Sec-WebSocket-Extensions: permessage-deflate\r\n",
+		)?;
+		raw_frame(s, 0xC1, &[0xff, 0xff])?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x88);
+		assert_eq!(&payload[..], &[0x03, 0xEF]);
+		raw_closed(s)?;
+		s.close()?;
+
+		// without an offer rsv1 is a protocol error
+		let mut s = raw_connect(port, "/z")?;
+		raw_frame(s, 0xC1, &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00])?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x88);
+		assert_eq!(&payload[..], &[0x03, 0xEA]);
+		raw_closed(s)?;
+		s.close()?;
+
+		server.stop()?;
+		for s in server.get_sockets() {
+			s.clone().close()?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn test_ws_deflate() -> Result<()> {
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.set_max_message_size(100_000)?;
+		server.set_deflate(DeflateConfig::new(true, false, 15)?)?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		let on_recv: WsOnRecv = Box::new(
+			|handle: &mut Handle, bytes: &[u8], fin: bool, op: u8| -> Result<()> {
+				assert!(fin);
+				assert_eq!(op, 2);
+				handle.send(bytes)
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		server.add_handler(Handler::new(
+			"/echo",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?)?;
+		server.start()?;
+
+		let lock = lock_box!()?;
+		let state = Rc::new((0u64, 0u64, Vec::new()))?;
+		let (lock1, lock2, lock3) = (lock.clone(), lock.clone(), lock.clone());
+		let (mut state1, mut state2, mut state3) = (state.clone(), state.clone(), state.clone());
+		let on_recv: WsOnRecv = Box::new(
+			move |_handle: &mut Handle, bytes: &[u8], _fin: bool, _op: u8| -> Result<()> {
+				let _l = lock1.write();
+				state1.2.extend_from_slice(bytes)?;
+				Ok(())
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(move |_handle: &mut Handle| -> Result<()> {
+			let _l = lock2.write();
+			state2.0 += 1;
+			Ok(())
+		})?;
+		let on_close: WsOnClose = Box::new(move |_handle: &mut Handle| -> Result<()> {
+			let _l = lock3.write();
+			state3.1 += 1;
+			Ok(())
+		})?;
+
+		let mut client = Ws::new()?;
+		client.set_deflate(DeflateConfig::new(false, true, 10)?)?;
+		client.start()?;
+		let handler = Handler::new(
+			"/echo",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?;
+		let mut handle = client.connect(Connector::new([127, 0, 0, 1], port), handler)?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.0 == 1 {
+				break;
+			}
+		}
+		assert!(handle.conn.attach()?.unwrap().is_compressed());
+
+		// repeated messages exercise the client's context takeover. The
+		// decompressed size is what counts against the limit.
+		let mut expected = Vec::new();
+		for len in [5, 300, 70_000, 70_000] {
+			let mut msg = Vec::new();
+			for i in 0..len {
+				msg.push(b"block data "[i % 11])?;
+			}
+			handle.send(&msg)?;
+			expected.extend(&msg)?;
+		}
+
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.2.len() == expected.len() {
+				assert_eq!(&state.2[..], &expected[..]);
+				break;
+			}
+		}
+
+		// too big once inflated
+		let mut msg = Vec::new();
+		msg.resize(100_001)?;
+		handle.send(&msg)?;
+		loop {
+			sleep(1);
+			let _l = lock.read();
+			if state.1 == 1 {
+				break;
+			}
+		}
+
+		client.stop()?;
+		server.stop()?;
+		for s in server.get_sockets() {
+			s.clone().close()?;
+		}
+		Ok(())
+	}
+
+	// reads a response with a Content-Length body, returns the head and body
+	fn raw_response(s: Socket) -> Result<(Vec<u8>, Vec<u8>)> {
+		let mut head = Vec::new();
+		let mut b = [0u8; 1];
+		while !head.ends_with(b"\r\n\r\n") {
+			recv_exact(s, &mut b)?;
+			head.push(b[0])?;
+		}
+		let target = b"Content-Length: ";
+		let start = head
+			.windows(target.len())
+			.position(|w| w == target)
+			.unwrap() + target.len();
+		let mut len = 0;
+		while head[start + len] != b'\r' {
+			len += 1;
+		}
+		let len = from_utf8(&head[start..start + len])?
+			.parse::<usize>()
+			.unwrap();
+		let mut body = Vec::new();
+		body.resize(len)?;
+		recv_exact(s, &mut body)?;
+		Ok((head, body))
+	}
+
+	#[test]
+	fn test_ws_http() -> Result<()> {
+		let port = free_port()?;
+		let mut server = Ws::new()?;
+		server.set_max_body_size(1000)?;
+		server.add_listener(Listener::new([127, 0, 0, 1], port, 10))?;
+		let on_status: HttpOnRequest = Box::new(
+			|_req: &HttpRequest, resp: &mut HttpResponse| -> Result<()> {
+				resp.add_header("Content-Type", "text/plain")?;
+				resp.write(b"ok")
+			},
+		)?;
+		let on_echo: HttpOnRequest =
+			Box::new(|req: &HttpRequest, resp: &mut HttpResponse| -> Result<()> {
+				resp.write(req.body())
+			})?;
+		server.add_route(HttpRoute::new(
+			HttpMethod::Get,
+			"/status",
+			Rc::new(on_status)?,
+		)?)?;
+		server.add_route(HttpRoute::new(
+			HttpMethod::Post,
+			"/echo",
+			Rc::new(on_echo)?,
+		)?)?;
+		let on_recv: WsOnRecv = Box::new(
+			|handle: &mut Handle, bytes: &[u8], _: bool, _: u8| -> Result<()> {
+				handle.send(bytes)
+			},
+		)?;
+		let on_accept: WsOnAccept = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		let on_close: WsOnClose = Box::new(|_handle: &mut Handle| -> Result<()> { Ok(()) })?;
+		server.add_handler(Handler::new(
+			"/ws",
+			Rc::new(on_recv)?,
+			Rc::new(on_accept)?,
+			Rc::new(on_close)?,
+		)?)?;
+		server.start()?;
+
+		// several requests on one connection, some of them pipelined
+		let mut s = Socket::connect([127, 0, 0, 1], port)?;
+		raw_send(s, b"GET /status HTTP/1.1\r\nHost: x\r\n\r\n")?;
+		let (head, body) = raw_response(s)?;
+		assert!(head.starts_with(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n"));
+		assert_eq!(&body[..], b"ok");
+
+		raw_send(
+			s,
+			b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n\
+GET /missing HTTP/1.1\r\n\r\nDELETE /status HTTP/1.1\r\n\r\n\
+HEAD /status HTTP/1.1\r\n\r\nPOST /echo HTTP/1.1\r\nContent-Length: 3\r\n\r\nxyz",
+		)?;
+		let (head, body) = raw_response(s)?;
+		assert!(head.starts_with(b"HTTP/1.1 200 OK\r\n"));
+		assert_eq!(&body[..], b"abcde");
+		let (head, _) = raw_response(s)?;
+		assert!(head.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+		let (head, _) = raw_response(s)?;
+		assert!(head.starts_with(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n"));
+		// the body of a HEAD response is omitted so read just the head
+		let mut head = Vec::new();
+		let mut b = [0u8; 1];
+		while !head.ends_with(b"\r\n\r\n") {
+			recv_exact(s, &mut b)?;
+			head.push(b[0])?;
+		}
+		assert!(head.ends_with(b"Content-Length: 2\r\n\r\n"));
+		let (_, body) = raw_response(s)?;
+		assert_eq!(&body[..], b"xyz");
+
+		raw_send(s, b"GET /status HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+		let (head, _) = raw_response(s)?;
+		assert!(head.ends_with(b"Connection: close\r\n\r\n"));
+		raw_closed(s)?;
+		s.close()?;
+
+		for (req, status) in [
+			(&b"GARBAGE\r\n\r\n"[..], &b"HTTP/1.1 400 "[..]),
+			(
+				b"POST /echo HTTP/1.1\r\nContent-Length: 1001\r\n\r\n",
+				b"HTTP/1.1 413 ",
+			),
+			(b"GET /ws HTTP/1.0\r\n\r\n", b"HTTP/1.1 404 "),
+			(
+				b"GET /none HTTP/1.1\r\nUpgrade: websocket\r\n\r\n",
+				b"HTTP/1.1 404 ",
+			),
+			(
+				b"POST /ws HTTP/1.1\r\nUpgrade: websocket\r\n\r\n",
+				b"HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n",
+			),
+		] {
+			let mut s = Socket::connect([127, 0, 0, 1], port)?;
+			raw_send(s, req)?;
+			let (head, _) = raw_response(s)?;
+			assert!(head.starts_with(status));
+			raw_closed(s)?;
+			s.close()?;
+		}
+
+		// websocket upgrades are served on the same listener
+		let mut s = raw_connect(port, "/ws")?;
+		raw_frame(s, 0x82, b"hi")?;
+		let (first, payload) = raw_read(s)?;
+		assert_eq!(first, 0x82);
+		assert_eq!(&payload[..], b"hi");
+		s.close()?;
+
+		server.stop()?;
+		for s in server.get_sockets() {
+			s.clone().close()?;
+		}
+		Ok(())
+	}
 }
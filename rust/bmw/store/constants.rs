@@ -1,3 +1,6 @@
-pub const ZERO_BYTES: [u8; 8] = [0; 8];
 pub const ALL_ONES: u64 = 0xFFFFFFFFFFFFFFFFu64;
 pub const BITMAP_SIZE: usize = 4096;
+// the on-disk layout of a pmmr, to be bumped whenever it changes
+pub const PMMR_FORMAT_VERSION: u64 = 1;
+// longest table key: the table name, its terminator and the encoded key
+pub const TABLE_MAX_KEY: usize = 128;
@@ -1,3 +1,3 @@
 use prelude::*;
 
-errors!(NotFound);
+errors!(NotFound, Duplicate, LockHeight, UnknownFormat);
@@ -0,0 +1,241 @@
+use core::ops::FnOnce;
+use crypto::pedersen::Commitment;
+use lmdb::db::Lmdb;
+use lmdb::txn::LmdbTxn;
+use misc::slice_copy;
+use mw::transaction::Transaction;
+use prelude::*;
+use store::errors::{Duplicate, LockHeight};
+use store::pmmr::Pmmr;
+use store::table::{Key, Table, Value};
+
+// where a kernel was confirmed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KernelPos {
+	// the height of the block
+	pub height: u64,
+	// the position of the kernel in the kernel mmr
+	pub pos: u64,
+}
+
+// An mmr of every kernel in block order with an index from kernel excess
+// to the block it was confirmed in. A kernel whose excess is already
+// indexed is a replay and is rejected.
+pub struct KernelMmr {
+	db: Lmdb,
+	mmr: Pmmr,
+	excesses: Table<[u8; 33], KernelPos>,
+	// the mmr size after each block
+	blocks: Table<u64, u64>,
+}
+
+impl Value for KernelPos {
+	fn encode<R, F: FnOnce(&[u8]) -> Result<R>>(&self, f: F) -> Result<R> {
+		let mut bytes = [0u8; 16];
+		(self.height, self.pos).encode(&mut bytes)?;
+		f(&bytes)
+	}
+
+	fn decode(bytes: &[u8]) -> Result<Self> {
+		let (height, pos) = <(u64, u64)>::decode(bytes)?;
+		Ok(Self { height, pos })
+	}
+}
+
+impl KernelMmr {
+	pub fn new(db: Lmdb, prefix: &str) -> Result<Self> {
+		let mmr = Pmmr::new(db.try_clone()?, prefix)?;
+		Ok(Self {
+			db,
+			mmr,
+			excesses: Table::new(format!("{}:excess", prefix)?.as_str())?,
+			blocks: Table::new(format!("{}:block", prefix)?.as_str())?,
+		})
+	}
+
+	// appends the kernels of the block at `height`, which must follow the
+	// last block applied. Fails with Duplicate, writing nothing, if any of
+	// the kernels was already confirmed, or with LockHeight if any of them
+	// is locked above `height`.
+	pub fn apply_block(
+		&mut self,
+		height: u64,
+		tx: &Transaction,
+		txn: Option<LmdbTxn>,
+	) -> Result<()> {
+		match txn {
+			Some(txn) => self.apply_block_txn(height, tx, txn),
+			// retried with a grown map if it fills up, see Lmdb::update
+			None => {
+				let db = self.db.try_clone()?;
+				db.update(|txn| self.apply_block_txn(height, tx, txn.clone()))
+			}
+		}
+	}
+
+	fn apply_block_txn(&mut self, height: u64, tx: &Transaction, mut txn: LmdbTxn) -> Result<()> {
+		let next = match self.blocks.last(&txn)? {
+			Some((last, _)) => last + 1,
+			None => 0,
+		};
+		if height != next {
+			return err!(IllegalArgument);
+		}
+
+		// check first so that a duplicate or a kernel locked above `height`
+		// leaves the mmr untouched. Kernels are ordered by excess so a repeat
+		// within the block is adjacent.
+		let mut prev: Option<[u8; 33]> = None;
+		for kernel in tx.kernels().iter() {
+			if kernel.lock_height() > height {
+				return err!(LockHeight);
+			}
+			let key = Self::key(kernel.excess())?;
+			if prev == Some(key) || self.excesses.get(&txn, &key)?.is_some() {
+				return err!(Duplicate);
+			}
+			prev = Some(key);
+		}
+
+		for kernel in tx.kernels().iter() {
+			let key = Self::key(kernel.excess())?;
+			let pos = self.mmr.last_pos(Some(txn.clone()))?;
+			self.mmr.append(&kernel.to_bytes(), Some(txn.clone()))?;
+			self.excesses
+				.put(&mut txn, &key, &KernelPos { height, pos })?;
+		}
+		let size = self.mmr.last_pos(Some(txn.clone()))?;
+		self.blocks.put(&mut txn, &height, &size)?;
+		Ok(())
+	}
+
+	// where the kernel with `excess` was confirmed, None if it wasn't
+	pub fn find(&self, excess: &Commitment, txn: Option<LmdbTxn>) -> Result<Option<KernelPos>> {
+		let txn = self.read_txn(txn)?;
+		self.excesses.get(&txn, &Self::key(excess)?)
+	}
+
+	// the range of mmr positions added by the block at `height`
+	pub fn block_range(&self, height: u64, txn: Option<LmdbTxn>) -> Result<Option<(u64, u64)>> {
+		let txn = self.read_txn(txn)?;
+		let end = match self.blocks.get(&txn, &height)? {
+			Some(end) => end,
+			None => return Ok(None),
+		};
+		let start = if height == 0 {
+			0
+		} else {
+			self.blocks.get(&txn, &(height - 1))?.unwrap_or(0)
+		};
+		Ok(Some((start, end)))
+	}
+
+	// the height of the last block applied
+	pub fn height(&self, txn: Option<LmdbTxn>) -> Result<Option<u64>> {
+		let txn = self.read_txn(txn)?;
+		Ok(self.blocks.last(&txn)?.map(|(height, _)| height))
+	}
+
+	// the excess of every kernel confirmed, in excess order
+	pub fn excesses(&self, txn: Option<LmdbTxn>) -> Result<Vec<Commitment>> {
+		let txn = self.read_txn(txn)?;
+		let mut ret = Vec::new();
+		let mut iter = self.excesses.iter(&txn)?;
+		while let Some((key, _)) = iter.next_entry()? {
+			ret.push(Commitment::from_bytes(&key)?)?;
+		}
+		Ok(ret)
+	}
+
+	pub fn peak_data_hash(&self, txn: Option<LmdbTxn>) -> Result<[u8; 32]> {
+		self.mmr.peak_data_hash(txn)
+	}
+
+	pub fn mmr(&self) -> &Pmmr {
+		&self.mmr
+	}
+
+	fn read_txn(&self, txn: Option<LmdbTxn>) -> Result<LmdbTxn> {
+		Ok(match txn {
+			Some(txn) => txn,
+			None => self.db.read()?,
+		})
+	}
+
+	fn key(excess: &Commitment) -> Result<[u8; 33]> {
+		let mut ret = [0u8; 33];
+		slice_copy(excess.as_ref(), &mut ret, 33)?;
+		Ok(ret)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crypto::ctx::Ctx;
+	use crypto::keys::{PublicKey, SecretKey};
+	use lmdb::{make_lmdb_test_dir, remove_lmdb_test_dir};
+	use mw::kernel::Kernel;
+
+	fn kernel(ctx: &Ctx, fee: u64) -> Result<Kernel> {
+		let blind = SecretKey::gen(ctx);
+		let excess = ctx.commit(0, &blind)?;
+		let message = Kernel::message_for(&excess, fee, 0);
+		let secnonce = SecretKey::gen(ctx);
+		let pubnonce = PublicKey::from(ctx, &secnonce)?;
+		let pubkey = excess.to_pubkey(ctx)?;
+		let s = ctx.sign(&message, &blind, &secnonce, &pubnonce, &pubkey)?;
+		Ok(Kernel::new(excess, s, fee, 0))
+	}
+
+	#[test]
+	fn test_kernel_mmr() -> Result<()> {
+		let db_dir = "bin/.kernel_mmr";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+		let ctx = Ctx::new()?;
+		let mut kmmr = KernelMmr::new(db, "kernels")?;
+		assert_eq!(kmmr.height(None)?, None);
+
+		let k1 = kernel(&ctx, 1)?;
+		let k2 = kernel(&ctx, 2)?;
+		let k3 = kernel(&ctx, 3)?;
+
+		let mut tx = Transaction::empty();
+		tx.add_kernel(k1.clone())?;
+		assert_eq!(kmmr.apply_block(1, &tx, None).err(), Some(IllegalArgument));
+		kmmr.apply_block(0, &tx, None)?;
+
+		let mut tx = Transaction::empty();
+		tx.add_kernel(k2.clone())?;
+		tx.add_kernel(k3.clone())?;
+		kmmr.apply_block(1, &tx, None)?;
+		assert_eq!(kmmr.height(None)?, Some(1));
+		assert_eq!(kmmr.mmr().last_pos(None)?, 4);
+
+		assert_eq!(kmmr.find(k1.excess(), None)?.unwrap().height, 0);
+		assert_eq!(kmmr.find(k1.excess(), None)?.unwrap().pos, 0);
+		let p2 = kmmr.find(k2.excess(), None)?.unwrap();
+		let p3 = kmmr.find(k3.excess(), None)?.unwrap();
+		assert_eq!((p2.height, p3.height), (1, 1));
+		assert!(p2.pos != p3.pos && p2.pos < 4 && p3.pos < 4);
+		assert_eq!(kmmr.mmr().pos(&k2.to_bytes(), None)?, Some(p2.pos));
+		assert_eq!(kmmr.find(kernel(&ctx, 4)?.excess(), None)?, None);
+
+		assert_eq!(kmmr.block_range(0, None)?, Some((0, 1)));
+		assert_eq!(kmmr.block_range(1, None)?, Some((1, 4)));
+		assert_eq!(kmmr.block_range(2, None)?, None);
+
+		// a replayed kernel is rejected and nothing is written
+		let peak_hash = kmmr.peak_data_hash(None)?;
+		let mut tx = Transaction::empty();
+		tx.add_kernel(kernel(&ctx, 5)?)?;
+		tx.add_kernel(k2.clone())?;
+		assert_eq!(kmmr.apply_block(2, &tx, None).err(), Some(Duplicate));
+		assert_eq!(kmmr.height(None)?, Some(1));
+		assert_eq!(kmmr.peak_data_hash(None)?, peak_hash);
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+}
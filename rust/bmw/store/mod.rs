@@ -1,5 +1,9 @@
 mod constants;
-mod errors;
+pub mod errors;
+mod kernels;
 mod pmmr;
+mod table;
 
-pub use store::pmmr::Pmmr;
+pub use store::kernels::{KernelMmr, KernelPos};
+pub use store::pmmr::{Pmmr, PmmrIssue};
+pub use store::table::{Key, Table, TableIter, Value};
@@ -1,15 +1,61 @@
+use core::ops::Bound::{Included, Unbounded};
+use core::ops::{FnMut, FnOnce};
 use core::ptr::copy_nonoverlapping;
 use crypto::sha3::Sha3_256;
 use lmdb::db::Lmdb;
 use lmdb::txn::LmdbTxn;
-use prelude::*;
 use misc::{from_le_bytes_u64, slice_copy, subslice, to_le_bytes_u64};
+use prelude::*;
 use store::constants::*;
-use store::errors::NotFound;
+use store::errors::{NotFound, UnknownFormat};
+use store::table::{Table, Value};
 
 pub struct Pmmr {
 	db: Lmdb,
-	prefix: String,
+	leaves: Table<u64, [u8; 32]>,
+	// keyed by (height, pos)
+	nodes: Table<(u8, u64), [u8; 32]>,
+	// the leaf position of each data hash
+	data: Table<[u8; 32], u64>,
+	// the data of each unpruned leaf by position
+	values: Table<u64, Vec<u8>>,
+	bitmaps: Table<u64, [u8; BITMAP_SIZE]>,
+	size: Table<(), u64>,
+	peaks: Table<(), Vec<PeakInfo>>,
+	// the highest horizon compacted to
+	horizon: Table<(), u64>,
+	// PMMR_FORMAT_VERSION when the pmmr was created, see check_format
+	format: Table<(), u64>,
+	// the size key of the string keyed layout used before the format was
+	// stored
+	legacy_size_key: String,
+}
+
+// an inconsistency found by `Pmmr::verify`
+#[derive(Debug, PartialEq)]
+pub enum PmmrIssue {
+	// the stored size doesn't match the last leaf
+	Size(u64),
+	// a leaf stored at a position that is an interior node
+	StrayLeaf(u64),
+	// an interior node that is missing
+	MissingNode(u64),
+	// a node stored at (height, pos) where there is no such node
+	StrayNode(u8, u64),
+	// a node whose hash isn't the hash of its children
+	NodeHash(u64),
+	// a leaf that isn't in the data index under its hash
+	Unindexed(u64),
+	// a data index entry that points at a missing or different leaf
+	StaleIndex(u64),
+	// an unpruned leaf whose data isn't stored
+	MissingValue(u64),
+	// data stored for a missing leaf or that doesn't hash to its leaf
+	StaleValue(u64),
+	// a bit that doesn't match whether its leaf survives
+	Bitmap(u64),
+	// the stored peaks aren't the peaks of the nodes
+	Peaks,
 }
 
 pub struct PeakInfo {
@@ -60,6 +106,20 @@ impl PeakInfo {
 	}
 }
 
+impl Value for Vec<PeakInfo> {
+	fn encode<R, F: FnOnce(&[u8]) -> Result<R>>(&self, f: F) -> Result<R> {
+		let mut bytes = Vec::with_capacity(self.len() * 41)?;
+		for peak in self {
+			bytes.extend_from_slice(&peak.serialize()?)?;
+		}
+		f(bytes.as_ref())
+	}
+
+	fn decode(bytes: &[u8]) -> Result<Self> {
+		Pmmr::deserialize_peaks(bytes)
+	}
+}
+
 impl Pmmr {
 	// create a PMMR instance with the specified LMDB
 	pub fn new(db: Lmdb, prefix_str: &str) -> Result<Self> {
@@ -70,16 +130,24 @@ impl Pmmr {
 			Some(_) => return err!(IllegalArgument),
 			None => {}
 		}
-		let prefix = String::new(prefix_str)?;
-		Ok(Self { prefix, db })
+		Ok(Self {
+			db,
+			leaves: Table::new(format!("{}:leaf", prefix_str)?.as_str())?,
+			nodes: Table::new(format!("{}:node", prefix_str)?.as_str())?,
+			data: Table::new(format!("{}:data", prefix_str)?.as_str())?,
+			values: Table::new(format!("{}:value", prefix_str)?.as_str())?,
+			bitmaps: Table::new(format!("{}:bitmap", prefix_str)?.as_str())?,
+			size: Table::new(format!("{}:size", prefix_str)?.as_str())?,
+			peaks: Table::new(format!("{}:peaks", prefix_str)?.as_str())?,
+			horizon: Table::new(format!("{}:horizon", prefix_str)?.as_str())?,
+			format: Table::new(format!("{}:format", prefix_str)?.as_str())?,
+			legacy_size_key: format!("{}:meta:size", prefix_str)?,
+		})
 	}
 
 	pub fn get_bitmap(&self, index: u64, txn: &LmdbTxn) -> Result<[u8; BITMAP_SIZE]> {
-		let bitmap_key = format!("{}:bitmap:{}", self.prefix, index)?;
-		let bytes: &[u8] = txn.get(&bitmap_key)?.unwrap_or(&[0u8; BITMAP_SIZE]);
-		let mut ret = [0u8; BITMAP_SIZE];
-		slice_copy(bytes, &mut ret, BITMAP_SIZE)?;
-		Ok(ret)
+		self.check_format(txn)?;
+		Ok(self.bitmaps.get(txn, &index)?.unwrap_or([0u8; BITMAP_SIZE]))
 	}
 
 	/*
@@ -94,15 +162,17 @@ impl Pmmr {
 		if data.is_empty() {
 			return err!(IllegalArgument);
 		}
-		let (mut txn, commit) = self.get_write_txn(txn)?;
+		self.write(txn, |pmmr, txn| pmmr.append_txn(data, txn))
+	}
+
+	fn append_txn(&mut self, data: &[u8], mut txn: LmdbTxn) -> Result<()> {
 		let last_pos = self.last_pos(Some(txn.clone()))?;
-		let leaf_key = format!("{}:leaf:{}", self.prefix, last_pos)?;
 		let mut hash = self.hash_data(data);
-		let data_key = format!("{}:data:{}", self.prefix, hash)?;
-		let mut last_pos_bytes = ZERO_BYTES;
-		to_le_bytes_u64(last_pos, &mut last_pos_bytes)?;
-		txn.put(&data_key, &last_pos_bytes)?;
-		txn.put(&leaf_key, &hash)?;
+		self.data.put(&mut txn, &hash, &last_pos)?;
+		self.leaves.put(&mut txn, &last_pos, &hash)?;
+		let mut value = Vec::with_capacity(data.len())?;
+		value.extend_from_slice(data)?;
+		self.values.put(&mut txn, &last_pos, &value)?;
 
 		let bit_pos = Self::peak_map_height(last_pos).0;
 		self.update_bit(bit_pos, true, &mut txn)?;
@@ -119,29 +189,28 @@ impl Pmmr {
 		} else {
 			while !Self::is_peak(pos, last_pos_update - 1) {
 				let (parent_pos, sibling_pos) = Self::family(pos);
-				let sibling_key = if height == 0 {
-					format!("{}:leaf:{}", self.prefix, sibling_pos)
+				let sibling = if height == 0 {
+					self.leaves.get(&txn, &sibling_pos)?
 				} else {
-					format!("{}:node:{}:{}", self.prefix, height, sibling_pos)
-				}?;
+					self.nodes.get(&txn, &(height, sibling_pos))?
+				};
 
-				let sibling = match txn.get(&sibling_key)? {
+				let sibling = match sibling {
 					Some(s) => s,
 					None => return err!(IllegalState), // Shouldn’t reach here
 				};
 
 				hash = if sibling_pos < pos {
-					self.hash_children(sibling, &hash)
+					self.hash_children(&sibling, &hash)
 				} else {
-					self.hash_children(&hash, sibling)
+					self.hash_children(&hash, &sibling)
 				};
 
 				height += 1;
 				pos = parent_pos;
 
 				last_pos_update += 1;
-				let node_key = format!("{}:node:{}:{}", self.prefix, height, pos)?;
-				txn.put(&node_key, &hash)?;
+				self.nodes.put(&mut txn, &(height, pos), &hash)?;
 
 				let mut peaks = self.get_peaks(Some(txn.clone()))?;
 				let mut peaks_len = peaks.len();
@@ -157,18 +226,16 @@ impl Pmmr {
 						let (parent_pos1, sibling_pos1) = Self::family(peaks[peaks_len - 2].pos());
 						let (parent_pos2, sibling_pos2) = Self::family(peaks[peaks_len - 1].pos());
 						if parent_pos1 == parent_pos2 {
-							let sibling1_key =
-								format!("{}:node:{}:{}", self.prefix, height, sibling_pos1)?;
-							let sibling2_key =
-								format!("{}:node:{}:{}", self.prefix, height, sibling_pos2)?;
-							let sibling_hash1 = match txn.get(&sibling1_key)? {
-								Some(s) => s,
-								None => return err!(IllegalState), // Shouldn’t reach here
-							};
-							let sibling_hash2 = match txn.get(&sibling2_key)? {
-								Some(s) => s,
-								None => return err!(IllegalState), // Shouldn’t reach here
-							};
+							let sibling_hash1 =
+								match self.nodes.get(&txn, &(height, sibling_pos1))? {
+									Some(s) => s,
+									None => return err!(IllegalState), // Shouldn’t reach here
+								};
+							let sibling_hash2 =
+								match self.nodes.get(&txn, &(height, sibling_pos2))? {
+									Some(s) => s,
+									None => return err!(IllegalState), // Shouldn’t reach here
+								};
 
 							height += 1;
 
@@ -177,9 +244,7 @@ impl Pmmr {
 							// comes from the later peak
 							hash = self.hash_children(&sibling_hash2, &sibling_hash1);
 							last_pos_update += 1;
-							let node_key =
-								format!("{}:node:{}:{}", self.prefix, height, parent_pos1)?;
-							txn.put(&node_key, &hash)?;
+							self.nodes.put(&mut txn, &(height, parent_pos1), &hash)?;
 							peaks.truncate(peaks_len - 1)?;
 							peaks[peaks_len - 2] = PeakInfo::new(hash, parent_pos1, height);
 							peaks_len -= 1;
@@ -194,13 +259,7 @@ impl Pmmr {
 			}
 		}
 
-		to_le_bytes_u64(last_pos_update, &mut last_pos_bytes)?;
-		let size_key = format!("{}:meta:size", self.prefix)?;
-		txn.put(&size_key, &last_pos_bytes)?;
-
-		if commit {
-			txn.commit()?;
-		}
+		self.size.put(&mut txn, &(), &last_pos_update)?;
 		Ok(())
 	}
 
@@ -209,14 +268,14 @@ impl Pmmr {
 	// This can be prevented by appending new outputs from a block before pruning the inputs.
 	// This ensures it's valid.
 	pub fn prune(&mut self, data: &[u8], txn: Option<LmdbTxn>) -> Result<()> {
-		let last_pos = self.last_pos(txn.clone())?;
-		let (mut txn, commit) = self.get_write_txn(txn)?;
+		self.write(txn, |pmmr, txn| pmmr.prune_txn(data, txn))
+	}
 
+	fn prune_txn(&mut self, data: &[u8], mut txn: LmdbTxn) -> Result<()> {
+		let last_pos = self.last_pos(Some(txn.clone()))?;
 		let hash = self.hash_data(data);
-		let data_key = format!("{}:data:{}", self.prefix, hash)?;
-		match txn.get(&data_key)? {
-			Some(data_bytes) => {
-				let pos = from_le_bytes_u64(&data_bytes)?;
+		match self.data.get(&txn, &hash)? {
+			Some(pos) => {
 				if pos + 1 == last_pos {
 					// prevent deleting most recent addition to PMMR.
 					// this would cause an invalid state.
@@ -224,19 +283,15 @@ impl Pmmr {
 					// append all outputs from a block before pruning inputs.
 					return err!(IllegalState);
 				}
-				let leaf_key = format!("{}:leaf:{}", self.prefix, pos)?;
-				txn.del(&data_key)?;
-				txn.del(&leaf_key)?;
+				self.data.del(&mut txn, &hash)?;
+				self.leaves.del(&mut txn, &pos)?;
+				self.values.del(&mut txn, &pos)?;
 
 				let bit_pos = Self::peak_map_height(pos).0;
 				self.update_bit(bit_pos, false, &mut txn)?;
 			}
 			None => return err!(NotFound),
 		}
-
-		if commit {
-			txn.commit()?;
-		}
 		Ok(())
 	}
 
@@ -244,23 +299,29 @@ impl Pmmr {
 	pub fn pos(&self, data: &[u8], txn: Option<LmdbTxn>) -> Result<Option<u64>> {
 		let txn = self.get_read_txn(txn)?;
 		let hash = self.hash_data(data);
-		let data_key = format!("{}:data:{}", self.prefix, hash)?;
-		match txn.get(&data_key)? {
-			Some(key) => Ok(Some(from_le_bytes_u64(key)?)),
-			None => Ok(None),
+		self.data.get(&txn, &hash)
+	}
+
+	// the data of every unpruned leaf, by position. Fails with NotFound if
+	// the data of any of them isn't stored.
+	pub fn leaf_values(&self, txn: &LmdbTxn) -> Result<Vec<Vec<u8>>> {
+		self.check_format(txn)?;
+		let mut ret = Vec::new();
+		for (pos, _) in self.leaves.iter(txn)? {
+			match self.values.get(txn, &pos)? {
+				Some(value) => ret.push(value)?,
+				None => return err!(NotFound),
+			}
 		}
+		Ok(ret)
 	}
 
 	// the bit position of a particular entry
 	pub fn bit_pos(&self, data: &[u8], txn: Option<LmdbTxn>) -> Result<Option<u64>> {
 		let txn = self.get_read_txn(txn)?;
 		let hash = self.hash_data(data);
-		let data_key = format!("{}:data:{}", self.prefix, hash)?;
-		match txn.get(&data_key)? {
-			Some(key) => {
-				let leaf_pos = from_le_bytes_u64(key)?;
-				Ok(Some(Self::peak_map_height(leaf_pos).0))
-			}
+		match self.data.get(&txn, &hash)? {
+			Some(leaf_pos) => Ok(Some(Self::peak_map_height(leaf_pos).0)),
 			None => Ok(None),
 		}
 	}
@@ -268,14 +329,13 @@ impl Pmmr {
 	// return the hash of the peak data
 	pub fn peak_data_hash(&self, txn: Option<LmdbTxn>) -> Result<[u8; 32]> {
 		let txn = self.get_read_txn(txn)?;
-		let peaks_key = format!("{}:meta:peaks", self.prefix)?;
 
-		match txn.get(&peaks_key)? {
-			Some(peaks_bytes) => {
+		match self.peaks.get(&txn, &())? {
+			Some(peaks) => peaks.encode(|bytes| {
 				let sha3 = Sha3_256::new();
-				sha3.update(peaks_bytes);
+				sha3.update(bytes);
 				Ok(sha3.finalize())
-			}
+			}),
 			// invalid state to have an empty output mmr.
 			None => err!(IllegalState),
 		}
@@ -284,39 +344,336 @@ impl Pmmr {
 	// return the last position in the pmmr.
 	pub fn last_pos(&self, txn: Option<LmdbTxn>) -> Result<u64> {
 		let txn = self.get_read_txn(txn)?;
-		let size_key = format!("{}:meta:size", self.prefix)?;
-		from_le_bytes_u64(self.get_key_with_default(&txn, &size_key, &ZERO_BYTES)?)
+		Ok(self.size.get(&txn, &())?.unwrap_or(0))
 	}
 
 	// rewind to specified position (reorgs - rewind to the position in the pmmr position in
 	// the header)
-	pub fn rewind(&mut self, _last_pos: u64, _txn: Option<LmdbTxn>) -> Result<()> {
+	pub fn rewind(&mut self, last_pos: u64, txn: Option<LmdbTxn>) -> Result<()> {
+		// nodes needed to rewind past the horizon were removed by compact
+		if last_pos < self.horizon(txn)? {
+			return err!(IllegalArgument);
+		}
 		err!(Todo)
 	}
 
+	// removes the interior nodes under fully pruned subtrees whose root is
+	// before `horizon`. The roots themselves are kept as their hashes are
+	// needed for the peaks and for proofs of their siblings. Afterwards the
+	// pmmr can't be rewound to before the horizon.
+	pub fn compact(&mut self, horizon: u64, txn: Option<LmdbTxn>) -> Result<()> {
+		self.write(txn, |pmmr, txn| pmmr.compact_txn(horizon, txn))
+	}
+
+	fn compact_txn(&mut self, horizon: u64, mut txn: LmdbTxn) -> Result<()> {
+		if horizon > self.last_pos(Some(txn.clone()))? {
+			return err!(IllegalArgument);
+		}
+
+		let mut stack = Vec::new();
+		for pos in 0..horizon {
+			let height = Self::height(pos);
+			if height == 0 {
+				stack.push((pos, self.leaves.get(&txn, &pos)?.is_none()))?;
+			} else {
+				let (children, pruned) = Self::join(&mut stack)?;
+				// the leaves are already gone
+				if pruned && height > 1 {
+					for child in &children {
+						self.nodes.del(&mut txn, &((height - 1) as u8, *child))?;
+					}
+				}
+				stack.push((pos, pruned))?;
+			}
+		}
+
+		if horizon > self.horizon(Some(txn.clone()))? {
+			self.horizon.put(&mut txn, &(), &horizon)?;
+		}
+		Ok(())
+	}
+
+	// the position compacted up to, the pmmr can't be rewound before it
+	pub fn horizon(&self, txn: Option<LmdbTxn>) -> Result<u64> {
+		let txn = self.get_read_txn(txn)?;
+		Ok(self.horizon.get(&txn, &())?.unwrap_or(0))
+	}
+
 	pub fn get_peaks(&self, txn: Option<LmdbTxn>) -> Result<Vec<PeakInfo>> {
 		let txn = self.get_read_txn(txn)?;
-		let peaks_key = format!("{}:meta:peaks", self.prefix)?;
-		let peaks: Vec<PeakInfo> = txn
-			.get(&peaks_key)?
-			.map(|bytes| Self::deserialize_peaks(bytes))
-			.unwrap_or(Ok(Vec::new()))?;
-		Ok(peaks)
+		match self.peaks.get(&txn, &())? {
+			Some(peaks) => Ok(peaks),
+			None => Ok(Vec::new()),
+		}
 	}
 
-	// helpers
-	fn set_peaks(&mut self, peaks: Vec<PeakInfo>, txn: &mut LmdbTxn) -> Result<()> {
-		let peaks_key = format!("{}:meta:peaks", self.prefix)?;
-		let ser_peaks = Self::serialize_peaks(peaks)?;
-		txn.put(&peaks_key, ser_peaks.as_ref())?;
-		Ok(())
+	// walks the whole mmr checking that the nodes, peaks, size, data index
+	// and bitmaps agree with the leaves. Nodes above pruned leaves can only
+	// be checked against their other children and nodes removed by compact
+	// aren't missing.
+	pub fn verify(&self, txn: Option<LmdbTxn>) -> Result<Vec<PmmrIssue>> {
+		let txn = self.get_read_txn(txn)?;
+		let horizon = self.horizon(Some(txn.clone()))?;
+		let mut issues = Vec::new();
+
+		let size = match self.leaves.last(&txn)? {
+			Some((pos, _)) if Self::height(pos) != 0 => {
+				issues.push(PmmrIssue::StrayLeaf(pos))?;
+				return Ok(issues);
+			}
+			Some((pos, _)) => Self::size_for_leaves(Self::peak_map_height(pos).0 + 1),
+			None => 0,
+		};
+		let stored_size = self.last_pos(Some(txn.clone()))?;
+		if stored_size != size {
+			issues.push(PmmrIssue::Size(stored_size))?;
+		}
+
+		// a node is only known to be missing once its parent shows whether
+		// it was compacted
+		let mut stack = Vec::new();
+		let mut leaf_count = 0;
+		for pos in 0..size {
+			let height = Self::height(pos);
+			if height == 0 {
+				let leaf = self.leaves.get(&txn, &pos)?;
+				if self.bit_is_set(leaf_count, &txn)? != leaf.is_some() {
+					issues.push(PmmrIssue::Bitmap(leaf_count))?;
+				}
+				leaf_count += 1;
+				stack.push((pos, leaf.is_none()))?;
+				match leaf {
+					Some(hash) => {
+						match self.data.get(&txn, &hash)? {
+							Some(p) if p == pos => {}
+							_ => issues.push(PmmrIssue::Unindexed(pos))?,
+						}
+						if self.values.get(&txn, &pos)?.is_none() {
+							issues.push(PmmrIssue::MissingValue(pos))?;
+						}
+					}
+					None => {}
+				}
+			} else {
+				let (children, pruned) = Self::join(&mut stack)?;
+				if height > 1 && !(pruned && pos < horizon) {
+					for child in &children {
+						if self
+							.nodes
+							.get(&txn, &((height - 1) as u8, *child))?
+							.is_none()
+						{
+							issues.push(PmmrIssue::MissingNode(*child))?;
+						}
+					}
+				}
+				match self.nodes.get(&txn, &(height as u8, pos))? {
+					Some(hash) => match self.children_hash(pos, height, &txn)? {
+						Some(expected) if expected != hash => {
+							issues.push(PmmrIssue::NodeHash(pos))?
+						}
+						_ => {}
+					},
+					None => {}
+				}
+				stack.push((pos, pruned))?;
+			}
+		}
+		// the peaks
+		for (pos, _) in &stack {
+			let height = Self::height(*pos);
+			if height > 0 && self.nodes.get(&txn, &(height as u8, *pos))?.is_none() {
+				issues.push(PmmrIssue::MissingNode(*pos))?;
+			}
+		}
+
+		for (pos, _) in self.leaves.iter(&txn)? {
+			if Self::height(pos) != 0 {
+				issues.push(PmmrIssue::StrayLeaf(pos))?;
+			}
+		}
+		for ((height, pos), _) in self.nodes.iter(&txn)? {
+			if pos >= size || Self::height(pos) != height as u64 {
+				issues.push(PmmrIssue::StrayNode(height, pos))?;
+			}
+		}
+		for (hash, pos) in self.data.iter(&txn)? {
+			match self.leaves.get(&txn, &pos)? {
+				Some(leaf) if leaf == hash => {}
+				_ => issues.push(PmmrIssue::StaleIndex(pos))?,
+			}
+		}
+		for (pos, value) in self.values.iter(&txn)? {
+			match self.leaves.get(&txn, &pos)? {
+				Some(leaf) if leaf == self.hash_data(&value) => {}
+				_ => issues.push(PmmrIssue::StaleValue(pos))?,
+			}
+		}
+
+		// bits past the last leaf
+		let bits = BITMAP_SIZE as u64 * 8;
+		for (index, bitmap) in
+			self.bitmaps
+				.range(&txn, Included(&(leaf_count / bits)), Unbounded)?
+		{
+			for bit in 0..bits {
+				let bit_pos = index * bits + bit;
+				if bit_pos >= leaf_count && bitmap[(bit / 8) as usize] & (0x1 << (bit % 8)) != 0 {
+					issues.push(PmmrIssue::Bitmap(bit_pos))?;
+				}
+			}
+		}
+
+		let expected = self.expected_peaks(size, &txn)?;
+		let peaks = self.get_peaks(Some(txn.clone()))?;
+		let mut same = peaks.len() == expected.len();
+		for i in 0..peaks.len() {
+			if !same {
+				break;
+			}
+			same = peaks[i].pos == expected[i].pos
+				&& peaks[i].height == expected[i].height
+				&& peaks[i].hash == expected[i].hash;
+		}
+		if !same {
+			issues.push(PmmrIssue::Peaks)?;
+		}
+
+		Ok(issues)
 	}
-	fn serialize_peaks(peaks: Vec<PeakInfo>) -> Result<Vec<u8>> {
-		let mut result = Vec::with_capacity(peaks.len() * 32)?;
-		for peak in peaks {
-			result.extend_from_slice(&peak.serialize()?)?;
+
+	// restores the size, nodes, peaks, data index and bitmaps from the
+	// leaves. Nodes above pruned leaves are recomputed from their other
+	// children where possible and kept as they are otherwise. Leaf data that
+	// doesn't belong to its leaf is removed, missing data can't be restored
+	// and is still reported by verify.
+	pub fn rebuild(&mut self, txn: Option<LmdbTxn>) -> Result<()> {
+		self.write(txn, |pmmr, txn| pmmr.rebuild_txn(txn))
+	}
+
+	fn rebuild_txn(&mut self, mut txn: LmdbTxn) -> Result<()> {
+		let horizon = self.horizon(Some(txn.clone()))?;
+
+		// leaves at interior positions can't be placed
+		let mut stray = Vec::new();
+		for (pos, _) in self.leaves.iter(&txn)? {
+			if Self::height(pos) != 0 {
+				stray.push(pos)?;
+			}
+		}
+		for pos in &stray {
+			self.leaves.del(&mut txn, pos)?;
+		}
+
+		let size = match self.leaves.last(&txn)? {
+			Some((pos, _)) => Self::size_for_leaves(Self::peak_map_height(pos).0 + 1),
+			None => 0,
+		};
+
+		let bits = BITMAP_SIZE as u64 * 8;
+		let mut bitmap = [0u8; BITMAP_SIZE];
+		let mut leaf_count = 0;
+		let mut stack = Vec::new();
+		for pos in 0..size {
+			let height = Self::height(pos);
+			if height == 0 {
+				if leaf_count > 0 && leaf_count % bits == 0 {
+					self.bitmaps
+						.put(&mut txn, &(leaf_count / bits - 1), &bitmap)?;
+					bitmap = [0u8; BITMAP_SIZE];
+				}
+				let leaf = self.leaves.get(&txn, &pos)?;
+				match leaf {
+					Some(hash) => {
+						let bit = leaf_count % bits;
+						bitmap[(bit / 8) as usize] |= 0x1 << (bit % 8);
+						self.data.put(&mut txn, &hash, &pos)?;
+					}
+					None => {}
+				}
+				leaf_count += 1;
+				stack.push((pos, leaf.is_none()))?;
+			} else {
+				// a node that can't be recomputed must still be there unless
+				// it was compacted
+				let (children, pruned) = Self::join(&mut stack)?;
+				if height > 1 && !(pruned && pos < horizon) {
+					for child in &children {
+						if self
+							.nodes
+							.get(&txn, &((height - 1) as u8, *child))?
+							.is_none()
+						{
+							return err!(NotFound);
+						}
+					}
+				}
+				match self.children_hash(pos, height, &txn)? {
+					Some(hash) => self.nodes.put(&mut txn, &(height as u8, pos), &hash)?,
+					None => {}
+				}
+				stack.push((pos, pruned))?;
+			}
+		}
+		for (pos, _) in &stack {
+			let height = Self::height(*pos);
+			if height > 0 && self.nodes.get(&txn, &(height as u8, *pos))?.is_none() {
+				return err!(NotFound);
+			}
+		}
+		if leaf_count > 0 {
+			self.bitmaps
+				.put(&mut txn, &((leaf_count - 1) / bits), &bitmap)?;
+		}
+
+		// remove what doesn't belong to the leaves
+		let mut stale_bitmaps = Vec::new();
+		let first = (leaf_count + bits - 1) / bits;
+		for (index, _) in self.bitmaps.range(&txn, Included(&first), Unbounded)? {
+			stale_bitmaps.push(index)?;
+		}
+		for index in &stale_bitmaps {
+			self.bitmaps.del(&mut txn, index)?;
+		}
+		let mut stale_nodes = Vec::new();
+		for ((height, pos), _) in self.nodes.iter(&txn)? {
+			if pos >= size || Self::height(pos) != height as u64 {
+				stale_nodes.push((height, pos))?;
+			}
+		}
+		for key in &stale_nodes {
+			self.nodes.del(&mut txn, key)?;
+		}
+		let mut stale_data = Vec::new();
+		for (hash, pos) in self.data.iter(&txn)? {
+			match self.leaves.get(&txn, &pos)? {
+				Some(leaf) if leaf == hash => {}
+				_ => stale_data.push(hash)?,
+			}
+		}
+		for hash in &stale_data {
+			self.data.del(&mut txn, hash)?;
+		}
+		let mut stale_values = Vec::new();
+		for (pos, value) in self.values.iter(&txn)? {
+			match self.leaves.get(&txn, &pos)? {
+				Some(leaf) if leaf == self.hash_data(&value) => {}
+				_ => stale_values.push(pos)?,
+			}
+		}
+		for pos in &stale_values {
+			self.values.del(&mut txn, pos)?;
 		}
-		Ok(result)
+
+		let peaks = self.expected_peaks(size, &txn)?;
+		self.set_peaks(peaks, &mut txn)?;
+		self.size.put(&mut txn, &(), &size)?;
+		Ok(())
+	}
+
+	// helpers
+	fn set_peaks(&mut self, peaks: Vec<PeakInfo>, txn: &mut LmdbTxn) -> Result<()> {
+		self.peaks.put(txn, &(), &peaks)
 	}
 
 	fn deserialize_peaks(bytes: &[u8]) -> Result<Vec<PeakInfo>> {
@@ -335,6 +692,74 @@ impl Pmmr {
 		Ok(ret)
 	}
 
+	// the hash of the children of the node at `pos`, None if a child is
+	// missing
+	fn children_hash(&self, pos: u64, height: u64, txn: &LmdbTxn) -> Result<Option<[u8; 32]>> {
+		let left = pos - (1 << height);
+		let right = pos - 1;
+		let (left, right) = if height == 1 {
+			(self.leaves.get(txn, &left)?, self.leaves.get(txn, &right)?)
+		} else {
+			let child = (height - 1) as u8;
+			(
+				self.nodes.get(txn, &(child, left))?,
+				self.nodes.get(txn, &(child, right))?,
+			)
+		};
+		match (left, right) {
+			(Some(left), Some(right)) => Ok(Some(self.hash_children(&left, &right))),
+			_ => Ok(None),
+		}
+	}
+
+	// the peaks of an mmr of `size` with the hashes of the stored nodes
+	fn expected_peaks(&self, size: u64, txn: &LmdbTxn) -> Result<Vec<PeakInfo>> {
+		let mut ret = Vec::new();
+		if size == 0 {
+			return Ok(ret);
+		}
+		let mut base = 0;
+		let mut peak_size = ALL_ONES >> size.leading_zeros();
+		while peak_size != 0 {
+			if size - base >= peak_size {
+				let pos = base + peak_size - 1;
+				let height = Self::height(pos);
+				let hash = if height == 0 {
+					self.leaves.get(txn, &pos)?
+				} else {
+					self.nodes.get(txn, &(height as u8, pos))?
+				};
+				ret.push(PeakInfo::new(hash.unwrap_or([0u8; 32]), pos, height as u8))?;
+				base += peak_size;
+			}
+			peak_size >>= 1;
+		}
+		Ok(ret)
+	}
+
+	// pops the two subtrees joined by the next node in a post-order walk,
+	// returning their roots and whether both are fully pruned
+	fn join(stack: &mut Vec<(u64, bool)>) -> Result<([u64; 2], bool)> {
+		let len = stack.len();
+		if len < 2 {
+			return err!(IllegalState);
+		}
+		let (left, left_pruned) = stack[len - 2];
+		let (right, right_pruned) = stack[len - 1];
+		stack.truncate(len - 2)?;
+		Ok(([left, right], left_pruned && right_pruned))
+	}
+
+	// the height of the node at `pos`
+	fn height(pos: u64) -> u64 {
+		Self::peak_map_height(pos).1
+	}
+
+	// the size of an mmr with `leaves` leaves
+	fn size_for_leaves(leaves: u64) -> u64 {
+		2 * leaves - leaves.count_ones() as u64
+	}
+
 	fn is_peak(pos: u64, last_pos: u64) -> bool {
 		let (_parent_pos, sibling_pos) = Self::family(pos);
 		sibling_pos > last_pos
@@ -381,18 +806,59 @@ impl Pmmr {
 		(peak_map, size)
 	}
 
-	fn get_write_txn(&mut self, txn: Option<LmdbTxn>) -> Result<(LmdbTxn, bool)> {
-		Ok(match txn {
-			Some(txn) => (txn, false),
-			None => (self.db.write()?, true),
-		})
+	// runs `f` in `txn`, or without one in a write transaction of its own
+	// that is committed and retried with a grown map if it fills up
+	fn write<F: FnMut(&mut Self, LmdbTxn) -> Result<()>>(
+		&mut self,
+		txn: Option<LmdbTxn>,
+		mut f: F,
+	) -> Result<()> {
+		match txn {
+			Some(mut txn) => {
+				self.stamp_format(&mut txn)?;
+				f(self, txn)
+			}
+			None => {
+				let db = self.db.try_clone()?;
+				db.update(|txn| {
+					self.stamp_format(txn)?;
+					f(self, txn.clone())
+				})
+			}
+		}
+	}
+
+	fn stamp_format(&self, txn: &mut LmdbTxn) -> Result<()> {
+		if !self.check_format(txn)? {
+			self.format.put(txn, &(), &PMMR_FORMAT_VERSION)?;
+		}
+		Ok(())
 	}
 
 	fn get_read_txn(&self, txn: Option<LmdbTxn>) -> Result<LmdbTxn> {
-		Ok(match txn {
+		let txn = match txn {
 			Some(txn) => txn,
 			None => self.db.read()?,
-		})
+		};
+		self.check_format(&txn)?;
+		Ok(txn)
+	}
+
+	// Fails with UnknownFormat if the pmmr was written in another layout.
+	// Older layouts can't be migrated as they don't keep the leaf data.
+	// Returns false for a new pmmr, which is stamped on its first write.
+	fn check_format(&self, txn: &LmdbTxn) -> Result<bool> {
+		match self.format.get(txn, &())? {
+			Some(version) if version == PMMR_FORMAT_VERSION => Ok(true),
+			Some(_) => err!(UnknownFormat),
+			None => {
+				if self.size.get(txn, &())?.is_some() || txn.get(&self.legacy_size_key)?.is_some() {
+					err!(UnknownFormat)
+				} else {
+					Ok(false)
+				}
+			}
+		}
 	}
 
 	fn hash_data(&self, data: &[u8]) -> [u8; 32] {
@@ -401,15 +867,6 @@ impl Pmmr {
 		sha3.finalize()
 	}
 
-	fn get_key_with_default<'a>(
-		&self,
-		txn: &'a LmdbTxn,
-		key: &String,
-		default_value: &'a [u8],
-	) -> Result<&'a [u8]> {
-		Ok(txn.get(key)?.unwrap_or(default_value))
-	}
-
 	fn hash_children(&self, left: &[u8], right: &[u8]) -> [u8; 32] {
 		let mut dual_hash = [0u8; 64];
 		unsafe {
@@ -425,12 +882,9 @@ impl Pmmr {
 		bytes: [u8; BITMAP_SIZE],
 		txn: &mut LmdbTxn,
 	) -> Result<()> {
-		let bitmap_key = format!("{}:bitmap:{}", self.prefix, index)?;
-		txn.put(&bitmap_key, bytes.as_ref())?;
-		Ok(())
+		self.bitmaps.put(txn, &index, &bytes)
 	}
 
-	#[cfg(test)]
 	fn bit_is_set(&self, bit: u64, txn: &LmdbTxn) -> Result<bool> {
 		let index = bit / (BITMAP_SIZE as u64 * 8);
 		let bmp = self.get_bitmap(index, txn)?;
@@ -616,7 +1070,7 @@ mod test {
 
 		// create pmmr
 		let mut pmmr = Pmmr::new(db, "pmmr1")?;
-		let (mut txn, _commit) = pmmr.get_write_txn(None)?;
+		let mut txn = pmmr.db.write()?;
 		pmmr.update_bit(1, true, &mut txn)?;
 		assert!(pmmr.bit_is_set(1, &txn)?);
 		assert!(!pmmr.bit_is_set(0, &txn)?);
@@ -638,4 +1092,257 @@ mod test {
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_pmmr_savepoint() -> Result<()> {
+		let db_dir = "bin/.pmmr_savepoint";
+		let db_size = 100 * 1024 * 1024;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+		let mut pmmr = Pmmr::new(db.try_clone()?, "pmmr1")?;
+
+		let txn = db.write()?;
+		pmmr.append(&[0u8; 32], Some(txn.clone()))?;
+		let peaks = pmmr.get_peaks(Some(txn.clone()))?;
+
+		// a failed step is rolled back without losing the first append
+		let sp = txn.savepoint()?;
+		pmmr.append(&[1u8; 32], Some(sp.clone()))?;
+		pmmr.append(&[2u8; 32], Some(sp.clone()))?;
+		assert_eq!(pmmr.last_pos(Some(sp.clone()))?, 4);
+		sp.abort();
+		assert_eq!(pmmr.last_pos(Some(txn.clone()))?, 1);
+		assert_eq!(pmmr.pos(&[1u8; 32], Some(txn.clone()))?, None);
+		assert_eq!(pmmr.get_peaks(Some(txn.clone()))?[0].hash, peaks[0].hash);
+
+		let sp = txn.savepoint()?;
+		pmmr.append(&[1u8; 32], Some(sp.clone()))?;
+		sp.commit()?;
+		txn.commit()?;
+
+		assert_eq!(pmmr.last_pos(None)?, 3);
+		assert_eq!(pmmr.pos(&[1u8; 32], None)?, Some(1));
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+	#[test]
+	fn test_pmmr_verify_and_rebuild() -> Result<()> {
+		let db_dir = "bin/.pmmr_verify";
+		let db_size = 100 * 1024 * 1024;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+		let mut pmmr = Pmmr::new(db.try_clone()?, "pmmr1")?;
+		for i in 0..11u8 {
+			pmmr.append(&[i; 32], None)?;
+		}
+		pmmr.prune(&[1u8; 32], None)?;
+		pmmr.prune(&[4u8; 32], None)?;
+		assert_eq!(pmmr.verify(None)?, vec![]?);
+		let peak_hash = pmmr.peak_data_hash(None)?;
+		let size = pmmr.last_pos(None)?;
+
+		{
+			let mut txn = db.write()?;
+			// node 6 is above the pruned leaf 1 but its own children are
+			// nodes, so its hash can still be checked
+			pmmr.nodes.put(&mut txn, &(2, 6), &[9u8; 32])?;
+			pmmr.nodes.del(&mut txn, &(2, 13))?;
+			pmmr.set_peaks(Vec::new(), &mut txn)?;
+			pmmr.nodes.put(&mut txn, &(1, 100), &[9u8; 32])?;
+			pmmr.size.put(&mut txn, &(), &(size + 1))?;
+			pmmr.data.del(&mut txn, &pmmr.hash_data(&[0u8; 32]))?;
+			pmmr.update_bit(1, true, &mut txn)?;
+			pmmr.update_bit(40, true, &mut txn)?;
+			pmmr.values.del(&mut txn, &3)?;
+			let mut value = Vec::new();
+			value.extend_from_slice(&[4u8; 32])?;
+			pmmr.values.put(&mut txn, &7, &value)?;
+			txn.commit()?;
+		}
+
+		let issues = pmmr.verify(None)?;
+		assert_eq!(
+			issues,
+			vec![
+				PmmrIssue::Size(size + 1),
+				PmmrIssue::Unindexed(0),
+				PmmrIssue::Bitmap(1),
+				PmmrIssue::MissingValue(3),
+				PmmrIssue::NodeHash(6),
+				PmmrIssue::MissingNode(13),
+				PmmrIssue::StrayNode(1, 100),
+				PmmrIssue::StaleValue(7),
+				PmmrIssue::Bitmap(40),
+				PmmrIssue::Peaks
+			]?
+		);
+
+		// the missing leaf data can't be rebuilt
+		pmmr.rebuild(None)?;
+		assert_eq!(pmmr.verify(None)?, vec![PmmrIssue::MissingValue(3)]?);
+		{
+			let txn = db.read()?;
+			assert_eq!(pmmr.leaf_values(&txn).err(), Some(NotFound));
+		}
+		{
+			let mut txn = db.write()?;
+			let mut value = Vec::new();
+			value.extend_from_slice(&[2u8; 32])?;
+			pmmr.values.put(&mut txn, &3, &value)?;
+			txn.commit()?;
+		}
+		assert_eq!(pmmr.verify(None)?, vec![]?);
+		{
+			let txn = db.read()?;
+			assert_eq!(pmmr.leaf_values(&txn)?.len(), 9);
+		}
+		assert_eq!(pmmr.last_pos(None)?, size);
+		assert_eq!(pmmr.peak_data_hash(None)?, peak_hash);
+		assert_eq!(pmmr.pos(&[0u8; 32], None)?, Some(0));
+		assert_eq!(pmmr.pos(&[1u8; 32], None)?, None);
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+	#[test]
+	fn test_pmmr_compact() -> Result<()> {
+		let db_dir = "bin/.pmmr_compact";
+		let db_size = 100 * 1024 * 1024;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+		let mut pmmr = Pmmr::new(db.try_clone()?, "pmmr1")?;
+		for i in 0..8u8 {
+			pmmr.append(&[i; 32], None)?;
+		}
+		// the subtree under node 6
+		for i in 0..4u8 {
+			pmmr.prune(&[i; 32], None)?;
+		}
+		let peak_hash = pmmr.peak_data_hash(None)?;
+		assert_eq!(pmmr.last_pos(None)?, 15);
+
+		// node 6 itself is after the horizon so its children stay
+		pmmr.compact(6, None)?;
+		assert_eq!(pmmr.horizon(None)?, 6);
+		{
+			let txn = db.read()?;
+			assert!(pmmr.nodes.get(&txn, &(1, 2))?.is_some());
+			assert!(pmmr.nodes.get(&txn, &(1, 5))?.is_some());
+		}
+
+		pmmr.compact(15, None)?;
+		assert!(pmmr.compact(16, None).is_err());
+		{
+			let txn = db.read()?;
+			assert!(pmmr.nodes.get(&txn, &(1, 2))?.is_none());
+			assert!(pmmr.nodes.get(&txn, &(1, 5))?.is_none());
+			// needed for the root and as the sibling of node 13
+			assert!(pmmr.nodes.get(&txn, &(2, 6))?.is_some());
+			assert!(pmmr.nodes.get(&txn, &(1, 9))?.is_some());
+		}
+		assert_eq!(pmmr.verify(None)?, vec![]?);
+		assert_eq!(pmmr.peak_data_hash(None)?, peak_hash);
+
+		// a lower horizon doesn't move it back
+		pmmr.compact(6, None)?;
+		assert_eq!(pmmr.horizon(None)?, 15);
+		assert_eq!(pmmr.rewind(7, None).err(), Some(IllegalArgument));
+
+		pmmr.rebuild(None)?;
+		assert_eq!(pmmr.verify(None)?, vec![]?);
+		assert_eq!(pmmr.peak_data_hash(None)?, peak_hash);
+
+		pmmr.append(&[8u8; 32], None)?;
+		assert_eq!(pmmr.last_pos(None)?, 16);
+		assert_eq!(pmmr.verify(None)?, vec![]?);
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+	#[test]
+	fn test_pmmr_format() -> Result<()> {
+		let db_dir = "bin/.pmmr_format";
+		let db_size = 100 * 1024 * 1024;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+		let mut pmmr = Pmmr::new(db.try_clone()?, "pmmr1")?;
+		assert_eq!(pmmr.last_pos(None)?, 0);
+		pmmr.append(&[0u8; 32], None)?;
+		{
+			let txn = db.read()?;
+			assert_eq!(pmmr.format.get(&txn, &())?, Some(PMMR_FORMAT_VERSION));
+		}
+
+		// a pmmr written before the format was stored
+		{
+			let mut txn = db.write()?;
+			pmmr.format.del(&mut txn, &())?;
+			txn.commit()?;
+		}
+		assert_eq!(pmmr.last_pos(None).err(), Some(UnknownFormat));
+		assert_eq!(pmmr.append(&[1u8; 32], None).err(), Some(UnknownFormat));
+
+		// or in a later format
+		{
+			let mut txn = db.write()?;
+			pmmr.format.put(&mut txn, &(), &(PMMR_FORMAT_VERSION + 1))?;
+			txn.commit()?;
+		}
+		assert_eq!(pmmr.verify(None).err(), Some(UnknownFormat));
+
+		// the string keyed layout
+		let mut pmmr = Pmmr::new(db.try_clone()?, "pmmr2")?;
+		{
+			let mut txn = db.write()?;
+			txn.put("pmmr2:meta:size", &[1u8, 0, 0, 0, 0, 0, 0, 0])?;
+			txn.commit()?;
+		}
+		assert_eq!(pmmr.append(&[0u8; 32], None).err(), Some(UnknownFormat));
+		{
+			let txn = db.read()?;
+			assert_eq!(pmmr.leaf_values(&txn).err(), Some(UnknownFormat));
+		}
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_pmmr_grow() -> Result<()> {
+		let db_dir = "bin/.pmmr_grow";
+		let db_size = 1024 * 1024;
+		let db_name = "mydb";
+		make_lmdb_test_dir(db_dir)?;
+
+		let db = Lmdb::new(db_dir, db_name, db_size)?;
+		let mut pmmr = Pmmr::new(db.try_clone()?, "pmmr1")?;
+
+		// writes without a transaction grow the map when it fills up
+		let mut data = Vec::new();
+		data.resize(4 * 1024 * 1024)?;
+		data.fill(1);
+		pmmr.append(&data, None)?;
+		assert!(db.size() > db_size);
+		for i in 0..1000u64 {
+			let mut data = Vec::new();
+			data.resize(4096)?;
+			data.fill(0);
+			to_le_bytes_u64(i, &mut data[0..8])?;
+			pmmr.append(&data, None)?;
+		}
+		assert_eq!(pmmr.last_pos(None)?, Pmmr::size_for_leaves(1001));
+		assert_eq!(pmmr.verify(None)?.len(), 0);
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
 }
@@ -0,0 +1,366 @@
+use core::iter::Iterator;
+use core::marker::PhantomData;
+use core::ops::Bound::{self, Excluded, Included, Unbounded};
+use core::ops::FnOnce;
+use lmdb::txn::{LmdbCursor, LmdbTxn};
+use misc::{slice_copy, subslice, subslice_mut};
+use prelude::*;
+use store::constants::TABLE_MAX_KEY;
+
+// Keys encode to exactly SIZE bytes. The encoding must sort in the same
+// order as the keys, so integers are big-endian.
+pub trait Key: Sized {
+	const SIZE: usize;
+	fn encode(&self, bytes: &mut [u8]) -> Result<()>;
+	fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+// Values are passed to `f` as bytes so that implementations can encode on
+// the stack or hand out their own memory.
+pub trait Value: Sized {
+	fn encode<R, F: FnOnce(&[u8]) -> Result<R>>(&self, f: F) -> Result<R>;
+	fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+// A typed table in an lmdb database. The entries of a table are stored
+// under its name followed by a zero byte and the encoded key, so tables with
+// different names can share a database.
+pub struct Table<K: Key, V: Value> {
+	name: Vec<u8>,
+	_marker: PhantomData<(K, V)>,
+}
+
+pub struct TableIter<K: Key, V: Value> {
+	cursor: LmdbCursor,
+	_marker: PhantomData<(K, V)>,
+}
+
+macro_rules! int_key_value {
+	($($t:ty, $size:expr),*) => {
+		$(
+			impl Key for $t {
+				const SIZE: usize = $size;
+				fn encode(&self, bytes: &mut [u8]) -> Result<()> {
+					if bytes.len() != $size {
+						return err!(IllegalArgument);
+					}
+					for i in 0..$size {
+						bytes[i] = (*self >> (8 * ($size - 1 - i))) as u8;
+					}
+					Ok(())
+				}
+				fn decode(bytes: &[u8]) -> Result<Self> {
+					if bytes.len() != $size {
+						return err!(IllegalArgument);
+					}
+					let mut ret = 0u64;
+					for i in 0..$size {
+						ret = (ret << 8) | bytes[i] as u64;
+					}
+					Ok(ret as $t)
+				}
+			}
+
+			impl Value for $t {
+				fn encode<R, F: FnOnce(&[u8]) -> Result<R>>(&self, f: F) -> Result<R> {
+					let mut bytes = [0u8; $size];
+					Key::encode(self, &mut bytes)?;
+					f(&bytes)
+				}
+				fn decode(bytes: &[u8]) -> Result<Self> {
+					<$t as Key>::decode(bytes)
+				}
+			}
+		)*
+	};
+}
+
+macro_rules! array_key_value {
+	($($size:expr),*) => {
+		$(
+			impl Key for [u8; $size] {
+				const SIZE: usize = $size;
+				fn encode(&self, bytes: &mut [u8]) -> Result<()> {
+					slice_copy(self, bytes, $size)
+				}
+				fn decode(bytes: &[u8]) -> Result<Self> {
+					if bytes.len() != $size {
+						return err!(IllegalArgument);
+					}
+					let mut ret = [0u8; $size];
+					slice_copy(bytes, &mut ret, $size)?;
+					Ok(ret)
+				}
+			}
+
+			impl Value for [u8; $size] {
+				fn encode<R, F: FnOnce(&[u8]) -> Result<R>>(&self, f: F) -> Result<R> {
+					f(self)
+				}
+				fn decode(bytes: &[u8]) -> Result<Self> {
+					<[u8; $size] as Key>::decode(bytes)
+				}
+			}
+		)*
+	};
+}
+
+int_key_value!(u8, 1, u16, 2, u32, 4, u64, 8);
+array_key_value!(8, 32, 33, 4096);
+
+// for tables with a single entry
+impl Key for () {
+	const SIZE: usize = 0;
+	fn encode(&self, _bytes: &mut [u8]) -> Result<()> {
+		Ok(())
+	}
+	fn decode(_bytes: &[u8]) -> Result<Self> {
+		Ok(())
+	}
+}
+
+impl<A: Key, B: Key> Key for (A, B) {
+	const SIZE: usize = A::SIZE + B::SIZE;
+	fn encode(&self, bytes: &mut [u8]) -> Result<()> {
+		if bytes.len() != Self::SIZE {
+			return err!(IllegalArgument);
+		}
+		self.0.encode(subslice_mut(bytes, 0, A::SIZE)?)?;
+		self.1.encode(subslice_mut(bytes, A::SIZE, B::SIZE)?)
+	}
+	fn decode(bytes: &[u8]) -> Result<Self> {
+		if bytes.len() != Self::SIZE {
+			return err!(IllegalArgument);
+		}
+		let a = A::decode(subslice(bytes, 0, A::SIZE)?)?;
+		let b = B::decode(subslice(bytes, A::SIZE, B::SIZE)?)?;
+		Ok((a, b))
+	}
+}
+
+impl Value for Vec<u8> {
+	fn encode<R, F: FnOnce(&[u8]) -> Result<R>>(&self, f: F) -> Result<R> {
+		f(self.as_ref())
+	}
+	fn decode(bytes: &[u8]) -> Result<Self> {
+		let mut ret = Vec::new();
+		ret.extend_from_slice(bytes)?;
+		Ok(ret)
+	}
+}
+
+impl<K: Key, V: Value> Iterator for TableIter<K, V> {
+	type Item = (K, V);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		match self.next_entry() {
+			Ok(entry) => entry,
+			Err(_e) => None,
+		}
+	}
+}
+
+impl<K: Key, V: Value> TableIter<K, V> {
+	// like next but entries that fail to decode are an error
+	pub fn next_entry(&mut self) -> Result<Option<(K, V)>> {
+		let (key, value) = match self.cursor.next_ref()? {
+			Some(kv) => kv,
+			None => return Ok(None),
+		};
+		if key.len() < K::SIZE {
+			return err!(IllegalState);
+		}
+		let key = K::decode(subslice(key, key.len() - K::SIZE, K::SIZE)?)?;
+		Ok(Some((key, V::decode(value)?)))
+	}
+}
+
+impl<K: Key, V: Value> Table<K, V> {
+	pub fn new(name: &str) -> Result<Self> {
+		let bytes = name.as_bytes();
+		if bytes.len() == 0 || bytes.len() + 1 + K::SIZE > TABLE_MAX_KEY {
+			return err!(IllegalArgument);
+		}
+		for b in bytes {
+			if *b == 0 {
+				return err!(IllegalArgument);
+			}
+		}
+		let mut name = Vec::with_capacity(bytes.len() + 1)?;
+		name.extend_from_slice(bytes)?;
+		name.push(0)?;
+		Ok(Self {
+			name,
+			_marker: PhantomData,
+		})
+	}
+
+	pub fn get(&self, txn: &LmdbTxn, key: &K) -> Result<Option<V>> {
+		let mut buf = [0u8; TABLE_MAX_KEY];
+		let len = self.key(key, &mut buf)?;
+		match txn.get(subslice(&buf, 0, len)?)? {
+			Some(bytes) => Ok(Some(V::decode(bytes)?)),
+			None => Ok(None),
+		}
+	}
+
+	pub fn put(&self, txn: &mut LmdbTxn, key: &K, value: &V) -> Result<()> {
+		let mut buf = [0u8; TABLE_MAX_KEY];
+		let len = self.key(key, &mut buf)?;
+		let key = subslice(&buf, 0, len)?;
+		value.encode(|bytes| txn.put(key, bytes))
+	}
+
+	pub fn del(&self, txn: &mut LmdbTxn, key: &K) -> Result<bool> {
+		let mut buf = [0u8; TABLE_MAX_KEY];
+		let len = self.key(key, &mut buf)?;
+		txn.del(subslice(&buf, 0, len)?)
+	}
+
+	// all entries in key order
+	pub fn iter(&self, txn: &LmdbTxn) -> Result<TableIter<K, V>> {
+		self.range(txn, Unbounded, Unbounded)
+	}
+
+	pub fn range(
+		&self,
+		txn: &LmdbTxn,
+		start: Bound<&K>,
+		end: Bound<&K>,
+	) -> Result<TableIter<K, V>> {
+		self.cursor(txn, start, end, false)
+	}
+
+	pub fn range_rev(
+		&self,
+		txn: &LmdbTxn,
+		start: Bound<&K>,
+		end: Bound<&K>,
+	) -> Result<TableIter<K, V>> {
+		self.cursor(txn, start, end, true)
+	}
+
+	// the entry with the greatest key
+	pub fn last(&self, txn: &LmdbTxn) -> Result<Option<(K, V)>> {
+		self.range_rev(txn, Unbounded, Unbounded)?.next_entry()
+	}
+
+	fn cursor(
+		&self,
+		txn: &LmdbTxn,
+		start: Bound<&K>,
+		end: Bound<&K>,
+		reverse: bool,
+	) -> Result<TableIter<K, V>> {
+		let mut start_buf = [0u8; TABLE_MAX_KEY];
+		let mut end_buf = [0u8; TABLE_MAX_KEY];
+		let start = match start {
+			Included(key) => {
+				let len = self.key(key, &mut start_buf)?;
+				Included(subslice(&start_buf, 0, len)?)
+			}
+			Excluded(key) => {
+				let len = self.key(key, &mut start_buf)?;
+				Excluded(subslice(&start_buf, 0, len)?)
+			}
+			Unbounded => Included(self.name.as_ref()),
+		};
+		let end = match end {
+			Included(key) => {
+				let len = self.key(key, &mut end_buf)?;
+				Included(subslice(&end_buf, 0, len)?)
+			}
+			Excluded(key) => {
+				let len = self.key(key, &mut end_buf)?;
+				Excluded(subslice(&end_buf, 0, len)?)
+			}
+			Unbounded => {
+				// the name followed by one sorts after every key of the table
+				let len = self.name.len();
+				slice_copy(self.name.as_ref(), &mut end_buf, len)?;
+				end_buf[len - 1] = 1;
+				Excluded(subslice(&end_buf, 0, len)?)
+			}
+		};
+		let cursor = if reverse {
+			txn.range_rev(start, end)?
+		} else {
+			txn.range(start, end)?
+		};
+		Ok(TableIter {
+			cursor,
+			_marker: PhantomData,
+		})
+	}
+
+	fn key(&self, key: &K, buf: &mut [u8; TABLE_MAX_KEY]) -> Result<usize> {
+		let len = self.name.len();
+		slice_copy(self.name.as_ref(), buf, len)?;
+		key.encode(subslice_mut(buf, len, K::SIZE)?)?;
+		Ok(len + K::SIZE)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use lmdb::db::Lmdb;
+	use lmdb::{make_lmdb_test_dir, remove_lmdb_test_dir};
+
+	#[test]
+	fn test_table() -> Result<()> {
+		let db_dir = "bin/.table";
+		make_lmdb_test_dir(db_dir)?;
+		let db = Lmdb::new(db_dir, "mydb", 100 * 1024 * 1024)?;
+
+		let heights: Table<u64, [u8; 32]> = Table::new("heights")?;
+		let nodes: Table<(u8, u64), Vec<u8>> = Table::new("h")?;
+		let size: Table<(), u64> = Table::new("size")?;
+		assert!(Table::<u64, u64>::new("").is_err());
+		assert!(Table::<u64, u64>::new("a\0b").is_err());
+
+		{
+			let mut txn = db.write()?;
+			// numeric order, not text order
+			for h in [1000u64, 9, 256, 10, 0] {
+				heights.put(&mut txn, &h, &[h as u8; 32])?;
+			}
+			nodes.put(&mut txn, &(1, 7), &vec![1, 2, 3]?)?;
+			nodes.put(&mut txn, &(0, 9), &vec![4]?)?;
+			size.put(&mut txn, &(), &5)?;
+			assert!(heights.del(&mut txn, &9)?);
+			assert!(!heights.del(&mut txn, &9)?);
+			txn.commit()?;
+		}
+
+		{
+			let txn = db.read()?;
+			assert_eq!(heights.get(&txn, &256)?, Some([0u8; 32]));
+			assert_eq!(heights.get(&txn, &9)?, None);
+			assert_eq!(size.get(&txn, &())?, Some(5));
+
+			let mut keys = Vec::new();
+			for (h, v) in heights.iter(&txn)? {
+				assert_eq!(v, [h as u8; 32]);
+				keys.push(h)?;
+			}
+			assert_eq!(keys, vec![0, 10, 256, 1000]?);
+
+			let mut keys = Vec::new();
+			for (h, _v) in heights.range_rev(&txn, Included(&10), Excluded(&1000))? {
+				keys.push(h)?;
+			}
+			assert_eq!(keys, vec![256, 10]?);
+			assert_eq!(heights.last(&txn)?.unwrap().0, 1000);
+
+			// "h" is a prefix of "heights" but the tables don't overlap
+			let mut iter = nodes.iter(&txn)?;
+			assert_eq!(iter.next_entry()?, Some(((0, 9), vec![4]?)));
+			assert_eq!(iter.next_entry()?, Some(((1, 7), vec![1, 2, 3]?)));
+			assert_eq!(iter.next_entry()?, None);
+		}
+
+		remove_lmdb_test_dir(db_dir)?;
+		Ok(())
+	}
+}
@@ -1,2 +1,11 @@
 pub const WFLAG: u64 = 0x1u64 << 63u64;
 pub const WREQUEST: u64 = 0x1u64 << 62u64;
+
+pub const DEFLATE_WINDOW_SIZE: usize = 32 * 1024;
+pub const DEFLATE_HASH_SIZE: usize = 1 << 15;
+pub const DEFLATE_MAX_BITS: usize = 15;
+pub const DEFLATE_MIN_MATCH: usize = 3;
+pub const DEFLATE_MAX_MATCH: usize = 258;
+pub const DEFLATE_MAX_CHAIN: usize = 64;
+
+pub const JSON_MAX_DEPTH: usize = 32;
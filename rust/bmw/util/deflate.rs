@@ -0,0 +1,652 @@
+use prelude::*;
+use util::constants::*;
+use util::errors::*;
+
+// A small raw deflate (RFC 1951) implementation. The compressor emits fixed
+// huffman blocks terminated by a sync flush. The decompressor handles all
+// block types. Both keep a sliding window between calls so a stream can be
+// spread over many messages (context takeover) unless reset.
+
+const LEN_BASE: [u16; 29] = [
+	3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+	163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+	0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+	1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+	2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+	0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+	13,
+];
+// order of the code length code lengths in a dynamic block header
+const CLEN_ORDER: [usize; 19] = [
+	16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub struct Deflater {
+	window: usize,
+	history: Vec<u8>,
+}
+
+pub struct Inflater {
+	history: Vec<u8>,
+}
+
+struct BitWriter<'a> {
+	out: &'a mut Vec<u8>,
+	bits: u32,
+	count: u32,
+}
+
+struct BitReader<'a> {
+	input: &'a [u8],
+	pos: usize,
+	bit: u32,
+}
+
+// canonical huffman decoding table
+struct Huffman {
+	count: [u16; DEFLATE_MAX_BITS + 1],
+	symbol: [u16; 288],
+}
+
+impl<'a> BitWriter<'a> {
+	fn new(out: &'a mut Vec<u8>) -> Self {
+		Self {
+			out,
+			bits: 0,
+			count: 0,
+		}
+	}
+
+	fn put(&mut self, value: u32, n: u32) -> Result<()> {
+		self.bits |= value << self.count;
+		self.count += n;
+		while self.count >= 8 {
+			self.out.push(self.bits as u8)?;
+			self.bits >>= 8;
+			self.count -= 8;
+		}
+		Ok(())
+	}
+
+	// huffman codes are packed starting with the most significant bit
+	fn put_code(&mut self, code: u32, n: u32) -> Result<()> {
+		let mut reversed = 0;
+		for i in 0..n {
+			reversed |= ((code >> i) & 1) << (n - 1 - i);
+		}
+		self.put(reversed, n)
+	}
+
+	fn put_symbol(&mut self, sym: u16) -> Result<()> {
+		let sym = sym as u32;
+		if sym < 144 {
+			self.put_code(0x30 + sym, 8)
+		} else if sym < 256 {
+			self.put_code(0x190 + sym - 144, 9)
+		} else if sym < 280 {
+			self.put_code(sym - 256, 7)
+		} else {
+			self.put_code(0xC0 + sym - 280, 8)
+		}
+	}
+
+	fn align(&mut self) -> Result<()> {
+		if self.count > 0 {
+			self.out.push(self.bits as u8)?;
+			self.bits = 0;
+			self.count = 0;
+		}
+		Ok(())
+	}
+}
+
+impl<'a> BitReader<'a> {
+	fn new(input: &'a [u8]) -> Self {
+		Self {
+			input,
+			pos: 0,
+			bit: 0,
+		}
+	}
+
+	fn bits(&mut self, n: u32) -> Result<u32> {
+		let mut ret = 0;
+		for i in 0..n {
+			if self.pos >= self.input.len() {
+				return err!(InflateFormat);
+			}
+			ret |= (((self.input[self.pos] >> self.bit) & 1) as u32) << i;
+			self.bit += 1;
+			if self.bit == 8 {
+				self.bit = 0;
+				self.pos += 1;
+			}
+		}
+		Ok(ret)
+	}
+
+	fn align(&mut self) {
+		if self.bit != 0 {
+			self.bit = 0;
+			self.pos += 1;
+		}
+	}
+
+	fn at_end(&self) -> bool {
+		self.pos >= self.input.len()
+	}
+}
+
+impl Huffman {
+	fn new(lengths: &[u8]) -> Result<Self> {
+		let mut ret = Self {
+			count: [0u16; DEFLATE_MAX_BITS + 1],
+			symbol: [0u16; 288],
+		};
+		for len in lengths {
+			ret.count[*len as usize] += 1;
+		}
+		// reject over-subscribed codes, incomplete codes are allowed
+		let mut left: i32 = 1;
+		for len in 1..=DEFLATE_MAX_BITS {
+			left <<= 1;
+			left -= ret.count[len] as i32;
+			if left < 0 {
+				return err!(InflateFormat);
+			}
+		}
+
+		let mut offsets = [0u16; DEFLATE_MAX_BITS + 1];
+		for len in 1..DEFLATE_MAX_BITS {
+			offsets[len + 1] = offsets[len] + ret.count[len];
+		}
+		for sym in 0..lengths.len() {
+			let len = lengths[sym] as usize;
+			if len != 0 {
+				ret.symbol[offsets[len] as usize] = sym as u16;
+				offsets[len] += 1;
+			}
+		}
+		Ok(ret)
+	}
+
+	fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+		let mut code: i32 = 0;
+		let mut first: i32 = 0;
+		let mut index: i32 = 0;
+		for len in 1..=DEFLATE_MAX_BITS {
+			code |= reader.bits(1)? as i32;
+			let count = self.count[len] as i32;
+			if code - first < count {
+				return Ok(self.symbol[(index + code - first) as usize]);
+			}
+			index += count;
+			first += count;
+			first <<= 1;
+			code <<= 1;
+		}
+		err!(InflateFormat)
+	}
+}
+
+impl Deflater {
+	// window_bits limits how far back matches may refer (8 to 15)
+	pub fn new(window_bits: u8) -> Result<Self> {
+		if window_bits < 8 || window_bits > 15 {
+			return err!(IllegalArgument);
+		}
+		Ok(Self {
+			window: 1 << window_bits,
+			history: Vec::new(),
+		})
+	}
+
+	// forget the window so the next call starts a new stream
+	pub fn reset(&mut self) {
+		self.history.clear();
+	}
+
+	// appends a fixed huffman block holding `input` followed by a sync flush
+	// (an empty stored block) so the output ends on a byte boundary with
+	// 0x00 0x00 0xff 0xff
+	pub fn compress(&mut self, input: &[u8], out: &mut Vec<u8>) -> Result<()> {
+		let start = self.history.len();
+		let mut buf = Vec::with_capacity(start + input.len())?;
+		buf.extend_from_slice(&self.history)?;
+		buf.extend_from_slice(input)?;
+		let len = buf.len();
+
+		let mut head = Vec::new();
+		head.resize(DEFLATE_HASH_SIZE)?;
+		for i in 0..DEFLATE_HASH_SIZE {
+			head[i] = u32::MAX;
+		}
+		let mut prev = Vec::new();
+		prev.resize(len)?;
+
+		// index the history so matches may refer to previous messages
+		for i in 0..start {
+			Self::insert(&buf, &mut head, &mut prev, i);
+		}
+
+		let mut writer = BitWriter::new(out);
+		// BFINAL = 0, BTYPE = 01 (fixed huffman)
+		writer.put(0x2, 3)?;
+		let mut pos = start;
+		while pos < len {
+			let (match_len, dist) = self.longest_match(&buf, &head, &prev, pos);
+			if match_len >= DEFLATE_MIN_MATCH {
+				Self::put_match(&mut writer, match_len, dist)?;
+				for i in pos..pos + match_len {
+					Self::insert(&buf, &mut head, &mut prev, i);
+				}
+				pos += match_len;
+			} else {
+				writer.put_symbol(buf[pos] as u16)?;
+				Self::insert(&buf, &mut head, &mut prev, pos);
+				pos += 1;
+			}
+		}
+		writer.put_symbol(256)?;
+
+		// sync flush: BFINAL = 0, BTYPE = 00, LEN = 0, NLEN = 0xffff
+		writer.put(0, 3)?;
+		writer.align()?;
+		writer.out.extend_from_slice(&[0x00, 0x00, 0xff, 0xff])?;
+
+		let keep = if len > self.window { self.window } else { len };
+		self.history.clear();
+		self.history.extend_from_slice(&buf[len - keep..])?;
+		Ok(())
+	}
+
+	fn hash(buf: &[u8], i: usize) -> usize {
+		(((buf[i] as usize) << 10) ^ ((buf[i + 1] as usize) << 5) ^ buf[i + 2] as usize)
+			& (DEFLATE_HASH_SIZE - 1)
+	}
+
+	fn insert(buf: &[u8], head: &mut Vec<u32>, prev: &mut Vec<u32>, i: usize) {
+		if i + DEFLATE_MIN_MATCH <= buf.len() {
+			let h = Self::hash(buf, i);
+			prev[i] = head[h];
+			head[h] = i as u32;
+		}
+	}
+
+	fn longest_match(
+		&self,
+		buf: &[u8],
+		head: &Vec<u32>,
+		prev: &Vec<u32>,
+		pos: usize,
+	) -> (usize, usize) {
+		let len = buf.len();
+		if pos + DEFLATE_MIN_MATCH > len {
+			return (0, 0);
+		}
+		let max = if len - pos > DEFLATE_MAX_MATCH {
+			DEFLATE_MAX_MATCH
+		} else {
+			len - pos
+		};
+		let mut best = (0, 0);
+		let mut cand = head[Self::hash(buf, pos)];
+		let mut chain = 0;
+		while cand != u32::MAX && chain < DEFLATE_MAX_CHAIN {
+			let c = cand as usize;
+			if pos - c > self.window {
+				break;
+			}
+			let mut l = 0;
+			while l < max && buf[c + l] == buf[pos + l] {
+				l += 1;
+			}
+			if l > best.0 {
+				best = (l, pos - c);
+				if l == max {
+					break;
+				}
+			}
+			cand = prev[c];
+			chain += 1;
+		}
+		best
+	}
+
+	fn put_match(writer: &mut BitWriter, len: usize, dist: usize) -> Result<()> {
+		let mut code = LEN_BASE.len() - 1;
+		while LEN_BASE[code] as usize > len {
+			code -= 1;
+		}
+		writer.put_symbol(257 + code as u16)?;
+		writer.put(
+			(len - LEN_BASE[code] as usize) as u32,
+			LEN_EXTRA[code] as u32,
+		)?;
+
+		let mut code = DIST_BASE.len() - 1;
+		while DIST_BASE[code] as usize > dist {
+			code -= 1;
+		}
+		writer.put_code(code as u32, 5)?;
+		writer.put(
+			(dist - DIST_BASE[code] as usize) as u32,
+			DIST_EXTRA[code] as u32,
+		)
+	}
+}
+
+impl Inflater {
+	pub fn new() -> Self {
+		Self {
+			history: Vec::new(),
+		}
+	}
+
+	// forget the window so the next call starts a new stream
+	pub fn reset(&mut self) {
+		self.history.clear();
+	}
+
+	// decodes blocks until the input is consumed or a final block is seen.
+	// Returns CapacityExceeded if more than `max` bytes would be produced.
+	pub fn decompress(&mut self, input: &[u8], max: usize) -> Result<Vec<u8>> {
+		let start = self.history.len();
+		let mut out = Vec::new();
+		out.extend_from_slice(&self.history)?;
+		let mut reader = BitReader::new(input);
+
+		while !reader.at_end() {
+			let last = reader.bits(1)?;
+			match reader.bits(2)? {
+				0 => Self::stored(&mut reader, &mut out, start, max)?,
+				1 => {
+					let (lit, dist) = Self::fixed()?;
+					Self::codes(&mut reader, &mut out, start, max, &lit, &dist)?;
+				}
+				2 => {
+					let (lit, dist) = Self::dynamic(&mut reader)?;
+					Self::codes(&mut reader, &mut out, start, max, &lit, &dist)?;
+				}
+				_ => return err!(InflateFormat),
+			}
+			if last == 1 {
+				break;
+			}
+		}
+
+		let mut ret = Vec::with_capacity(out.len() - start)?;
+		ret.extend_from_slice(&out[start..])?;
+		let keep = if out.len() > DEFLATE_WINDOW_SIZE {
+			DEFLATE_WINDOW_SIZE
+		} else {
+			out.len()
+		};
+		self.history.clear();
+		self.history.extend_from_slice(&out[out.len() - keep..])?;
+		Ok(ret)
+	}
+
+	fn stored(reader: &mut BitReader, out: &mut Vec<u8>, start: usize, max: usize) -> Result<()> {
+		reader.align();
+		let pos = reader.pos;
+		let input = reader.input;
+		if pos + 4 > input.len() {
+			return err!(InflateFormat);
+		}
+		let len = input[pos] as usize | (input[pos + 1] as usize) << 8;
+		let nlen = input[pos + 2] as usize | (input[pos + 3] as usize) << 8;
+		if len != !nlen & 0xffff || pos + 4 + len > input.len() {
+			return err!(InflateFormat);
+		}
+		if out.len() - start + len > max {
+			return err!(CapacityExceeded);
+		}
+		out.extend_from_slice(&input[pos + 4..pos + 4 + len])?;
+		reader.pos = pos + 4 + len;
+		Ok(())
+	}
+
+	fn fixed() -> Result<(Huffman, Huffman)> {
+		let mut lengths = [0u8; 288];
+		for i in 0..288 {
+			lengths[i] = if i < 144 {
+				8
+			} else if i < 256 {
+				9
+			} else if i < 280 {
+				7
+			} else {
+				8
+			};
+		}
+		Ok((Huffman::new(&lengths)?, Huffman::new(&[5u8; 30])?))
+	}
+
+	fn dynamic(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+		let nlen = reader.bits(5)? as usize + 257;
+		let ndist = reader.bits(5)? as usize + 1;
+		let ncode = reader.bits(4)? as usize + 4;
+		if nlen > 286 || ndist > 30 {
+			return err!(InflateFormat);
+		}
+
+		let mut lengths = [0u8; 320];
+		for i in 0..ncode {
+			lengths[CLEN_ORDER[i]] = reader.bits(3)? as u8;
+		}
+		let lencode = Huffman::new(&lengths[0..19])?;
+
+		let mut lengths = [0u8; 320];
+		let mut index = 0;
+		while index < nlen + ndist {
+			let sym = lencode.decode(reader)?;
+			if sym < 16 {
+				lengths[index] = sym as u8;
+				index += 1;
+				continue;
+			}
+			let (value, repeat) = match sym {
+				16 => {
+					if index == 0 {
+						return err!(InflateFormat);
+					}
+					(lengths[index - 1], 3 + reader.bits(2)? as usize)
+				}
+				17 => (0, 3 + reader.bits(3)? as usize),
+				_ => (0, 11 + reader.bits(7)? as usize),
+			};
+			if index + repeat > nlen + ndist {
+				return err!(InflateFormat);
+			}
+			for _ in 0..repeat {
+				lengths[index] = value;
+				index += 1;
+			}
+		}
+
+		// a block without an end of block code can't be terminated
+		if lengths[256] == 0 {
+			return err!(InflateFormat);
+		}
+		Ok((
+			Huffman::new(&lengths[0..nlen])?,
+			Huffman::new(&lengths[nlen..nlen + ndist])?,
+		))
+	}
+
+	fn codes(
+		reader: &mut BitReader,
+		out: &mut Vec<u8>,
+		start: usize,
+		max: usize,
+		lit: &Huffman,
+		dist: &Huffman,
+	) -> Result<()> {
+		loop {
+			let sym = lit.decode(reader)? as usize;
+			if sym < 256 {
+				if out.len() - start >= max {
+					return err!(CapacityExceeded);
+				}
+				out.push(sym as u8)?;
+			} else if sym == 256 {
+				return Ok(());
+			} else {
+				let sym = sym - 257;
+				if sym >= LEN_BASE.len() {
+					return err!(InflateFormat);
+				}
+				let len = LEN_BASE[sym] as usize + reader.bits(LEN_EXTRA[sym] as u32)? as usize;
+				let sym = dist.decode(reader)? as usize;
+				if sym >= DIST_BASE.len() {
+					return err!(InflateFormat);
+				}
+				let d = DIST_BASE[sym] as usize + reader.bits(DIST_EXTRA[sym] as u32)? as usize;
+				if d > out.len() {
+					return err!(InflateFormat);
+				}
+				if out.len() - start + len > max {
+					return err!(CapacityExceeded);
+				}
+				// copies may overlap their own output
+				let from = out.len() - d;
+				for i in 0..len {
+					let b = out[from + i];
+					out.push(b)?;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn sync(compressed: &[u8]) -> Result<Vec<u8>> {
+		let mut ret = Vec::new();
+		ret.extend_from_slice(compressed)?;
+		ret.extend_from_slice(&[0x00, 0x00, 0xff, 0xff])?;
+		Ok(ret)
+	}
+
+	fn roundtrip(deflater: &mut Deflater, inflater: &mut Inflater, data: &[u8]) -> Result<usize> {
+		let mut compressed = Vec::new();
+		deflater.compress(data, &mut compressed)?;
+		assert!(compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+		let out = inflater.decompress(&compressed, data.len())?;
+		assert_eq!(&out[..], data);
+		Ok(compressed.len())
+	}
+
+	#[test]
+	fn test_inflate_vectors() -> Result<()> {
+		// the examples from RFC 7692 section 7.2.3
+		let mut inflater = Inflater::new();
+		let hello = sync(&[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00])?;
+		assert_eq!(&inflater.decompress(&hello, 100)?[..], b"Hello");
+		// the second message refers back into the first
+		let hello2 = sync(&[0xf2, 0x00, 0x11, 0x00, 0x00])?;
+		assert_eq!(&inflater.decompress(&hello2, 100)?[..], b"Hello");
+		// without the shared window it can't be decoded
+		assert_eq!(
+			Inflater::new().decompress(&hello2, 100).err(),
+			Some(InflateFormat)
+		);
+
+		// stored block
+		let stored = sync(&[
+			0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00,
+		])?;
+		assert_eq!(&Inflater::new().decompress(&stored, 100)?[..], b"Hello");
+
+		// a final block ends the stream
+		let last = [0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00];
+		assert_eq!(&Inflater::new().decompress(&last, 100)?[..], b"Hello");
+
+		// dynamic huffman block produced by zlib
+		let mut expected = Vec::new();
+		for i in 0..300 {
+			expected.push(((i * 31 + i / 7) % 9 + 97) as u8)?;
+		}
+		let dynamic = [
+			0xe4, 0xcb, 0xb9, 0x01, 0x00, 0x30, 0x08, 0x03, 0xb1, 0x59, 0x0d, 0x98, 0x67, 0xff,
+			0x05, 0x52, 0xdc, 0x18, 0x29, 0x55, 0x48, 0xbe, 0xda, 0x9c, 0x9c, 0x68, 0xf9, 0x40,
+			0x80, 0x02, 0x0d, 0x16, 0x08, 0x24, 0xb0, 0x7e, 0xea, 0x0f, 0x00, 0x00, 0xff, 0xff,
+		];
+		assert_eq!(
+			&Inflater::new().decompress(&dynamic, 300)?[..],
+			&expected[..]
+		);
+
+		// output limit
+		assert_eq!(
+			Inflater::new().decompress(&dynamic, 299).err(),
+			Some(CapacityExceeded)
+		);
+		// truncated and corrupt input
+		assert_eq!(
+			Inflater::new().decompress(&dynamic[0..20], 300).err(),
+			Some(InflateFormat)
+		);
+		assert_eq!(
+			Inflater::new().decompress(&[0x06], 300).err(),
+			Some(InflateFormat)
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_deflate_roundtrip() -> Result<()> {
+		let mut deflater = Deflater::new(15)?;
+		let mut inflater = Inflater::new();
+		roundtrip(&mut deflater, &mut inflater, b"")?;
+		roundtrip(&mut deflater, &mut inflater, b"a")?;
+
+		let mut repetitive = Vec::new();
+		for i in 0..10_000 {
+			repetitive.push(b"abcabcabd"[i % 9])?;
+		}
+		assert!(roundtrip(&mut deflater, &mut inflater, &repetitive)? < 200);
+
+		// pseudo random data spanning more than the window
+		let mut random = Vec::new();
+		let mut x = 12345u32;
+		for _ in 0..100_000 {
+			x = x.wrapping_mul(1103515245).wrapping_add(12345);
+			random.push((x >> 24) as u8 & 0x3f)?;
+		}
+		roundtrip(&mut deflater, &mut inflater, &random)?;
+
+		// with context takeover a repeated message is mostly back references
+		let first = roundtrip(&mut deflater, &mut inflater, &random[0..1000])?;
+		let again = roundtrip(&mut deflater, &mut inflater, &random[0..1000])?;
+		assert!(again < first / 10);
+
+		// after a reset the window is empty on both sides
+		deflater.reset();
+		inflater.reset();
+		let mut fresh = Vec::new();
+		Deflater::new(15)?.compress(&random[0..1000], &mut fresh)?;
+		assert_eq!(
+			roundtrip(&mut deflater, &mut inflater, &random[0..1000])?,
+			fresh.len()
+		);
+
+		// a small window still decodes with a full size inflater
+		let mut deflater = Deflater::new(8)?;
+		roundtrip(&mut deflater, &mut Inflater::new(), &random)?;
+		roundtrip(&mut deflater, &mut Inflater::new(), &repetitive)?;
+
+		assert_eq!(Deflater::new(7).err(), Some(IllegalArgument));
+		assert_eq!(Deflater::new(16).err(), Some(IllegalArgument));
+		Ok(())
+	}
+}
@@ -7,5 +7,6 @@ errors!(
 	ThreadDetach,
 	ChannelInit,
 	ChannelSend,
-	ChannelBusy
+	ChannelBusy,
+	InflateFormat
 );
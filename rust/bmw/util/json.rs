@@ -0,0 +1,556 @@
+use misc::i128_as_str;
+use prelude::*;
+use util::constants::JSON_MAX_DEPTH;
+
+// A JSON (RFC 8259) value. Numbers are limited to integers that fit in an
+// i64, fractions and exponents are rejected by the parser. Object members
+// keep their insertion order.
+#[derive(Debug, PartialEq)]
+pub enum Json {
+	Null,
+	Bool(bool),
+	Number(i64),
+	String(String),
+	Array(Vec<Json>),
+	Object(Vec<(String, Json)>),
+}
+
+struct Parser<'a> {
+	b: &'a [u8],
+	pos: usize,
+}
+
+impl TryClone for Json {
+	fn try_clone(&self) -> Result<Self> {
+		Ok(match self {
+			Self::Null => Self::Null,
+			Self::Bool(v) => Self::Bool(*v),
+			Self::Number(v) => Self::Number(*v),
+			Self::String(v) => Self::String(v.clone()),
+			Self::Array(v) => Self::Array(v.try_clone()?),
+			Self::Object(v) => {
+				let mut members = Vec::with_capacity(v.len())?;
+				for (k, v) in v.iter() {
+					members.push((k.clone(), v.try_clone()?))?;
+				}
+				Self::Object(members)
+			}
+		})
+	}
+}
+
+impl Json {
+	// parses a complete document, trailing data other than whitespace is an
+	// error. Errors are ParseError and CapacityExceeded if arrays and objects
+	// are nested more than JSON_MAX_DEPTH deep.
+	pub fn parse(b: &[u8]) -> Result<Self> {
+		let mut p = Parser { b, pos: 0 };
+		let ret = p.value(0)?;
+		p.skip_ws();
+		if p.pos != b.len() {
+			return err!(ParseError);
+		}
+		Ok(ret)
+	}
+
+	pub fn string(s: &str) -> Result<Self> {
+		Ok(Self::String(String::new(s)?))
+	}
+
+	pub fn object() -> Self {
+		Self::Object(Vec::new())
+	}
+
+	pub fn array() -> Self {
+		Self::Array(Vec::new())
+	}
+
+	// sets a member of an object, replacing any existing value
+	pub fn set(&mut self, key: &str, value: Json) -> Result<()> {
+		match self {
+			Self::Object(members) => {
+				for i in 0..members.len() {
+					if members[i].0.as_str() == key {
+						members[i].1 = value;
+						return Ok(());
+					}
+				}
+				members.push((String::new(key)?, value))
+			}
+			_ => err!(IllegalState),
+		}
+	}
+
+	// appends to an array
+	pub fn push(&mut self, value: Json) -> Result<()> {
+		match self {
+			Self::Array(values) => values.push(value),
+			_ => err!(IllegalState),
+		}
+	}
+
+	pub fn get(&self, key: &str) -> Option<&Json> {
+		match self {
+			Self::Object(members) => {
+				for (k, v) in members.iter() {
+					if k.as_str() == key {
+						return Some(v);
+					}
+				}
+				None
+			}
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			Self::String(s) => Some(s.as_str()),
+			_ => None,
+		}
+	}
+
+	pub fn as_i64(&self) -> Option<i64> {
+		match self {
+			Self::Number(n) => Some(*n),
+			_ => None,
+		}
+	}
+
+	pub fn as_u64(&self) -> Option<u64> {
+		match self {
+			Self::Number(n) if *n >= 0 => Some(*n as u64),
+			_ => None,
+		}
+	}
+
+	pub fn as_bool(&self) -> Option<bool> {
+		match self {
+			Self::Bool(v) => Some(*v),
+			_ => None,
+		}
+	}
+
+	pub fn as_array(&self) -> Option<&Vec<Json>> {
+		match self {
+			Self::Array(values) => Some(values),
+			_ => None,
+		}
+	}
+
+	pub fn is_null(&self) -> bool {
+		match self {
+			Self::Null => true,
+			_ => false,
+		}
+	}
+
+	// appends the compact encoding of this value to `out`
+	pub fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+		match self {
+			Self::Null => out.extend_from_slice(b"null"),
+			Self::Bool(true) => out.extend_from_slice(b"true"),
+			Self::Bool(false) => out.extend_from_slice(b"false"),
+			Self::Number(n) => {
+				let mut buf = [0u8; 24];
+				let len = i128_as_str(*n as i128, &mut buf, 10);
+				out.extend_from_slice(&buf[0..len])
+			}
+			Self::String(s) => Self::encode_str(s.as_str(), out),
+			Self::Array(values) => {
+				out.push(b'[')?;
+				for i in 0..values.len() {
+					if i > 0 {
+						out.push(b',')?;
+					}
+					values[i].encode(out)?;
+				}
+				out.push(b']')
+			}
+			Self::Object(members) => {
+				out.push(b'{')?;
+				for i in 0..members.len() {
+					if i > 0 {
+						out.push(b',')?;
+					}
+					Self::encode_str(members[i].0.as_str(), out)?;
+					out.push(b':')?;
+					members[i].1.encode(out)?;
+				}
+				out.push(b'}')
+			}
+		}
+	}
+
+	pub fn to_string(&self) -> Result<String> {
+		let mut out = Vec::new();
+		self.encode(&mut out)?;
+		String::newb(&out)
+	}
+
+	fn encode_str(s: &str, out: &mut Vec<u8>) -> Result<()> {
+		out.push(b'"')?;
+		for c in s.as_bytes() {
+			match c {
+				b'"' => out.extend_from_slice(b"\\\"")?,
+				b'\\' => out.extend_from_slice(b"\\\\")?,
+				b'\n' => out.extend_from_slice(b"\\n")?,
+				b'\r' => out.extend_from_slice(b"\\r")?,
+				b'\t' => out.extend_from_slice(b"\\t")?,
+				0x08 => out.extend_from_slice(b"\\b")?,
+				0x0c => out.extend_from_slice(b"\\f")?,
+				0..=0x1f => {
+					let hex = b"0123456789abcdef";
+					out.extend_from_slice(b"\\u00")?;
+					out.push(hex[(c >> 4) as usize])?;
+					out.push(hex[(c & 0xf) as usize])?;
+				}
+				_ => out.push(*c)?,
+			}
+		}
+		out.push(b'"')
+	}
+}
+
+impl Parser<'_> {
+	fn skip_ws(&mut self) {
+		while self.pos < self.b.len() {
+			match self.b[self.pos] {
+				b' ' | b'\t' | b'\r' | b'\n' => self.pos += 1,
+				_ => break,
+			}
+		}
+	}
+
+	fn peek(&self) -> Result<u8> {
+		if self.pos < self.b.len() {
+			Ok(self.b[self.pos])
+		} else {
+			err!(ParseError)
+		}
+	}
+
+	fn literal(&mut self, lit: &[u8]) -> Result<()> {
+		if self.b.len() - self.pos < lit.len() || &self.b[self.pos..self.pos + lit.len()] != lit {
+			return err!(ParseError);
+		}
+		self.pos += lit.len();
+		Ok(())
+	}
+
+	fn value(&mut self, depth: usize) -> Result<Json> {
+		self.skip_ws();
+		match self.peek()? {
+			b'n' => {
+				self.literal(b"null")?;
+				Ok(Json::Null)
+			}
+			b't' => {
+				self.literal(b"true")?;
+				Ok(Json::Bool(true))
+			}
+			b'f' => {
+				self.literal(b"false")?;
+				Ok(Json::Bool(false))
+			}
+			b'"' => Ok(Json::String(self.string()?)),
+			b'[' => self.array(depth + 1),
+			b'{' => self.object(depth + 1),
+			b'-' | b'0'..=b'9' => self.number(),
+			_ => err!(ParseError),
+		}
+	}
+
+	fn array(&mut self, depth: usize) -> Result<Json> {
+		if depth > JSON_MAX_DEPTH {
+			return err!(CapacityExceeded);
+		}
+		self.pos += 1;
+		let mut values = Vec::new();
+		self.skip_ws();
+		if self.peek()? == b']' {
+			self.pos += 1;
+			return Ok(Json::Array(values));
+		}
+		loop {
+			values.push(self.value(depth)?)?;
+			self.skip_ws();
+			match self.peek()? {
+				b',' => self.pos += 1,
+				b']' => {
+					self.pos += 1;
+					return Ok(Json::Array(values));
+				}
+				_ => return err!(ParseError),
+			}
+		}
+	}
+
+	fn object(&mut self, depth: usize) -> Result<Json> {
+		if depth > JSON_MAX_DEPTH {
+			return err!(CapacityExceeded);
+		}
+		self.pos += 1;
+		let mut members = Vec::new();
+		self.skip_ws();
+		if self.peek()? == b'}' {
+			self.pos += 1;
+			return Ok(Json::Object(members));
+		}
+		loop {
+			self.skip_ws();
+			if self.peek()? != b'"' {
+				return err!(ParseError);
+			}
+			let key = self.string()?;
+			self.skip_ws();
+			if self.peek()? != b':' {
+				return err!(ParseError);
+			}
+			self.pos += 1;
+			let value = self.value(depth)?;
+			members.push((key, value))?;
+			self.skip_ws();
+			match self.peek()? {
+				b',' => self.pos += 1,
+				b'}' => {
+					self.pos += 1;
+					return Ok(Json::Object(members));
+				}
+				_ => return err!(ParseError),
+			}
+		}
+	}
+
+	fn number(&mut self) -> Result<Json> {
+		let neg = self.peek()? == b'-';
+		if neg {
+			self.pos += 1;
+		}
+		let start = self.pos;
+		let mut v = 0u64;
+		while self.pos < self.b.len() && self.b[self.pos].is_ascii_digit() {
+			let d = (self.b[self.pos] - b'0') as u64;
+			v = match v.checked_mul(10).and_then(|v| v.checked_add(d)) {
+				Some(v) => v,
+				None => return err!(ParseError),
+			};
+			self.pos += 1;
+		}
+		let digits = self.pos - start;
+		// no leading zeros, no fractions or exponents
+		if digits == 0 || (digits > 1 && self.b[start] == b'0') {
+			return err!(ParseError);
+		}
+		if self.pos < self.b.len() {
+			match self.b[self.pos] {
+				b'.' | b'e' | b'E' => return err!(ParseError),
+				_ => {}
+			}
+		}
+		if neg {
+			if v > i64::MAX as u64 + 1 {
+				return err!(ParseError);
+			}
+			Ok(Json::Number((v as i64).wrapping_neg()))
+		} else if v > i64::MAX as u64 {
+			err!(ParseError)
+		} else {
+			Ok(Json::Number(v as i64))
+		}
+	}
+
+	fn hex4(&mut self) -> Result<u32> {
+		if self.b.len() - self.pos < 4 {
+			return err!(ParseError);
+		}
+		let mut v = 0u32;
+		for i in 0..4 {
+			let c = self.b[self.pos + i];
+			v = (v << 4)
+				| match c {
+					b'0'..=b'9' => c - b'0',
+					b'a'..=b'f' => c - b'a' + 10,
+					b'A'..=b'F' => c - b'A' + 10,
+					_ => return err!(ParseError),
+				} as u32;
+		}
+		self.pos += 4;
+		Ok(v)
+	}
+
+	fn string(&mut self) -> Result<String> {
+		self.pos += 1;
+		let mut out = Vec::new();
+		loop {
+			let c = self.peek()?;
+			self.pos += 1;
+			match c {
+				b'"' => break,
+				b'\\' => {
+					let e = self.peek()?;
+					self.pos += 1;
+					match e {
+						b'"' | b'\\' | b'/' => out.push(e)?,
+						b'b' => out.push(0x08)?,
+						b'f' => out.push(0x0c)?,
+						b'n' => out.push(b'\n')?,
+						b'r' => out.push(b'\r')?,
+						b't' => out.push(b'\t')?,
+						b'u' => {
+							let mut cp = self.hex4()?;
+							if cp >= 0xd800 && cp < 0xdc00 {
+								// a high surrogate must be followed by a low one
+								self.literal(b"\\u")?;
+								let low = self.hex4()?;
+								if low < 0xdc00 || low >= 0xe000 {
+									return err!(ParseError);
+								}
+								cp = 0x10000 + ((cp - 0xd800) << 10) + (low - 0xdc00);
+							} else if cp >= 0xdc00 && cp < 0xe000 {
+								return err!(ParseError);
+							}
+							Self::push_utf8(cp, &mut out)?;
+						}
+						_ => return err!(ParseError),
+					}
+				}
+				0..=0x1f => return err!(ParseError),
+				_ => out.push(c)?,
+			}
+		}
+		match String::newb(&out) {
+			Ok(s) => Ok(s),
+			Err(_) => err!(ParseError),
+		}
+	}
+
+	fn push_utf8(cp: u32, out: &mut Vec<u8>) -> Result<()> {
+		if cp < 0x80 {
+			out.push(cp as u8)
+		} else if cp < 0x800 {
+			out.push(0xc0 | (cp >> 6) as u8)?;
+			out.push(0x80 | (cp & 0x3f) as u8)
+		} else if cp < 0x10000 {
+			out.push(0xe0 | (cp >> 12) as u8)?;
+			out.push(0x80 | ((cp >> 6) & 0x3f) as u8)?;
+			out.push(0x80 | (cp & 0x3f) as u8)
+		} else {
+			out.push(0xf0 | (cp >> 18) as u8)?;
+			out.push(0x80 | ((cp >> 12) & 0x3f) as u8)?;
+			out.push(0x80 | ((cp >> 6) & 0x3f) as u8)?;
+			out.push(0x80 | (cp & 0x3f) as u8)
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_json_parse() -> Result<()> {
+		let v = Json::parse(
+			b" {\"a\": [1, -2, true, false, null], \"b\" : {\"c\":\"d\"}, \"e\":[], \"f\":{}} ",
+		)?;
+		let a = v.get("a").unwrap().as_array().unwrap();
+		assert_eq!(a.len(), 5);
+		assert_eq!(a[0].as_i64(), Some(1));
+		assert_eq!(a[1].as_i64(), Some(-2));
+		assert_eq!(a[1].as_u64(), None);
+		assert_eq!(a[2].as_bool(), Some(true));
+		assert_eq!(a[3].as_bool(), Some(false));
+		assert!(a[4].is_null());
+		assert_eq!(v.get("b").unwrap().get("c").unwrap().as_str(), Some("d"));
+		assert_eq!(v.get("e"), Some(&Json::array()));
+		assert_eq!(v.get("f"), Some(&Json::object()));
+		assert_eq!(v.get("g"), None);
+
+		// escapes, including a surrogate pair
+		let v = Json::parse(b"\"\\\"\\\\\\/\\b\\f\\n\\r\\t\\u0041\\u00e9\\u20ac\\ud83d\\ude00\"")?;
+		assert_eq!(v.as_str(), Some("\"\\/\x08\x0c\n\r\tAé€😀"));
+
+		// integer limits
+		assert_eq!(
+			Json::parse(b"9223372036854775807")?.as_i64(),
+			Some(i64::MAX)
+		);
+		assert_eq!(
+			Json::parse(b"-9223372036854775808")?.as_i64(),
+			Some(i64::MIN)
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_json_parse_errors() -> Result<()> {
+		for bad in [
+			&b""[..],
+			b" ",
+			b"nul",
+			b"[1,]",
+			b"[1 2]",
+			b"{\"a\" 1}",
+			b"{\"a\":1,}",
+			b"{1:2}",
+			b"01",
+			b"-",
+			b"1.5",
+			b"1e3",
+			b"9223372036854775808",
+			b"-9223372036854775809",
+			b"\"abc",
+			b"\"\\x\"",
+			b"\"\\u12\"",
+			b"\"\\ud800\"",
+			b"\"\\udc00\"",
+			b"\"a\nb\"",
+			b"\"\xff\"",
+			b"[1] x",
+			b"truex",
+		] {
+			assert_eq!(Json::parse(bad).err(), Some(ParseError));
+		}
+
+		let mut deep = Vec::new();
+		for _ in 0..JSON_MAX_DEPTH {
+			deep.push(b'[')?;
+		}
+		for _ in 0..JSON_MAX_DEPTH {
+			deep.push(b']')?;
+		}
+		assert!(Json::parse(&deep).is_ok());
+		deep.push(b']')?;
+		let mut deeper = Vec::new();
+		deeper.push(b'[')?;
+		deeper.extend(&deep)?;
+		assert_eq!(Json::parse(&deeper).err(), Some(CapacityExceeded));
+		Ok(())
+	}
+
+	#[test]
+	fn test_json_encode() -> Result<()> {
+		let mut v = Json::object();
+		v.set("n", Json::Number(-42))?;
+		v.set("s", Json::string("a\"b\\c\n\x01é")?)?;
+		let mut a = Json::array();
+		a.push(Json::Null)?;
+		a.push(Json::Bool(true))?;
+		a.push(Json::object())?;
+		v.set("a", a)?;
+		v.set("n", Json::Number(i64::MAX))?;
+		assert_eq!(
+			v.to_string()?.as_str(),
+			"{\"n\":9223372036854775807,\"s\":\"a\\\"b\\\\c\\n\\u0001é\",\"a\":[null,true,{}]}"
+		);
+
+		// the encoding parses back to the same value
+		let parsed = Json::parse(v.to_string()?.as_bytes())?;
+		assert_eq!(parsed, v);
+		assert_eq!(parsed.try_clone()?, v);
+
+		assert_eq!(Json::Null.set("a", Json::Null).err(), Some(IllegalState));
+		assert_eq!(Json::object().push(Json::Null).err(), Some(IllegalState));
+		Ok(())
+	}
+}
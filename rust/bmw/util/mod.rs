@@ -7,6 +7,8 @@ pub mod macros;
 
 pub mod channel;
 pub mod cstring;
+pub mod deflate;
+pub mod json;
 pub mod lock;
 pub mod rbtree;
 pub mod thread;
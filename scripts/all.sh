@@ -45,7 +45,7 @@ rust/bmw/lib.rs"
 	echo "${COMMAND}"
        	${COMMAND} || exit 1;
 
-	COMMAND="${CC} ${CCFLAGS} ${STATIC} -o bin/bmw .obj/*.o ${OUTPUT}/libcore.rlib.o -L.obj ${LINK_GMP} ${LINK_SECP256K1}"
+	COMMAND="${CC} ${CCFLAGS} ${STATIC} -o bin/bmw .obj/*.o ${OUTPUT}/libcore.rlib.o -L.obj ${LINK_GMP} ${LINK_SECP256K1} ${LINK_SSL}"
 	echo "${COMMAND}"
 	${COMMAND} || exit 1;
 
@@ -100,7 +100,7 @@ rust/macros/lib.rs"
 rust/bmw/lib.rs";
 	echo "${COMMAND}"
 	${COMMAND} || exit 1;
-    COMMAND="${CC} ${CCFLAGS} ${STATIC} -o bin/bmw .obj/*.o -L.obj ${LINK_GMP} ${LINK_SECP256K1}"
+    COMMAND="${CC} ${CCFLAGS} ${STATIC} -o bin/bmw .obj/*.o -L.obj ${LINK_GMP} ${LINK_SECP256K1} ${LINK_SSL}"
     echo "${COMMAND}"
     ${COMMAND} || exit 1;
 fi
@@ -19,8 +19,8 @@ cd c
 for file in *.c
 do
         if [ ! -e ../.obj/${file%.c}.o ] || [ ${file} -nt ../.obj/${file%.c}.o ]; then
-                echo "${CC} ${CCFLAGS} -o ../.obj/${file%.c}.o -c -Ic ${file}"
-                ${CC} ${CCFLAGS} -o ../.obj/${file%.c}.o -c -Ic ${file} || exit 1;
+                echo "${CC} ${CCFLAGS} ${INCLUDE_SSL} -o ../.obj/${file%.c}.o -c -Ic ${file}"
+                ${CC} ${CCFLAGS} ${INCLUDE_SSL} -o ../.obj/${file%.c}.o -c -Ic ${file} || exit 1;
         fi
 done
 cd ..
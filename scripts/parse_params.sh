@@ -76,6 +76,8 @@ if [ "$OS" = "Linux" ]; then
     STATIC=-static
     LINK_GMP="-lgmp -lm"
     LINK_SECP256K1="-lsecp256k1"
+    LINK_SSL="-lssl -lcrypto"
+    INCLUDE_SSL=
 elif [ "$OS" = "Darwin" ]; then
     if ! command -v brew >/dev/null 2>&1; then
         echo "Error: Homebrew is required on macOS to locate GMP" >&2
@@ -86,13 +88,20 @@ elif [ "$OS" = "Darwin" ]; then
         echo "Error: libgmp.a not found at $GMP_PATH. Install with 'brew install gmp'" >&2
         exit 1
     fi
+    SSL_PATH="$(brew --prefix openssl@3)"
+    if [ ! -f "$SSL_PATH/lib/libssl.a" ]; then
+        echo "Error: libssl.a not found at $SSL_PATH/lib. Install with 'brew install openssl@3'" >&2
+        exit 1
+    fi
     MACRO_EXT=.dylib
     STATIC=
     LINK_SECP256K1="-lsecp256k1"
     LINK_GMP="$GMP_PATH"
+    LINK_SSL="$SSL_PATH/lib/libssl.a $SSL_PATH/lib/libcrypto.a"
+    INCLUDE_SSL="-I$SSL_PATH/include"
 else
     echo "Unsupported platform: $OS"
     exit 1
 fi
 
-export CC CCFLAGS RUSTC FAMC OUTPUT MACRO_EXT STATIC FILTER ASAN RUSTFLAGS
+export CC CCFLAGS RUSTC FAMC OUTPUT MACRO_EXT STATIC FILTER ASAN RUSTFLAGS LINK_SSL INCLUDE_SSL
@@ -61,6 +61,8 @@ COMMAND="${RUSTC} -C debuginfo=2 \
 -l static=test \
 -l static=secp256k1 \
 -l static=gmp \
+-l static=ssl \
+-l static=crypto \
 --extern macros=.obj/libmacros${MACRO_EXT} \
 ${RUSTFLAGS} \
 --verbose"